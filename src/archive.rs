@@ -0,0 +1,882 @@
+//! Packing a diary directory into an encrypted diary file and unpacking it again
+
+use crate::{
+    cipher::{self, Algorithm, KdfParams},
+    consts::{DEDUP_MANIFEST, ENTRIES_DB, KEY_LENGTH, SALT_LENGTH, SAVED_MARKER},
+    error::{Context, DiaryError},
+    header::Header,
+    shred,
+};
+use clap::ValueEnum;
+use flate2::read::GzDecoder;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use gzp::{ZBuilder, deflate::Gzip};
+use log::debug;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+};
+use tar::{Archive, Builder};
+
+/// Compression of the tar archive inside a diary file, recorded in its header
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, ValueEnum)]
+pub enum Compression {
+    /// gzip, readable by every version of diary
+    #[default]
+    Gzip,
+    /// Zstandard, much faster than gzip at a similar ratio, which shows on large attachments
+    Zstd,
+}
+
+impl Compression {
+    /// Byte identifying the compression in the header
+    pub fn id(self) -> u8 {
+        match self {
+            Self::Gzip => 0,
+            Self::Zstd => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self, DiaryError> {
+        match id {
+            0 => Ok(Self::Gzip),
+            1 => Ok(Self::Zstd),
+            _ => Err(DiaryError::UnsupportedCompression(id)),
+        }
+    }
+
+    /// Decompress `archive`, which was compressed with this compression
+    pub fn decoder<'a>(self, archive: impl io::Read + 'a) -> io::Result<Box<dyn io::Read + 'a>> {
+        Ok(match self {
+            Self::Gzip => Box::new(GzDecoder::new(archive)),
+            Self::Zstd => Box::new(zstd::Decoder::new(archive)?),
+        })
+    }
+}
+
+/// Decrypt `diary` without keeping the plaintext and check it against its digest
+pub fn verify_diary(mut diary: File, password: &str) -> Result<(), DiaryError> {
+    let header = Header::read(&mut diary)?;
+    let key = header_key(&header, password)?;
+
+    debug!("Verifying existing diary");
+    if cipher::decrypt_digest(diary, key, header.cipher)? != header.digest {
+        return Err(DiaryError::IntegrityCheckFailed);
+    }
+
+    Ok(())
+}
+
+/// Derive the key of a diary from `password`, checking it against the canary in `header`
+pub fn header_key(header: &Header, password: &str) -> Result<[u8; KEY_LENGTH], DiaryError> {
+    debug!("Deriving key (argon2)");
+    let key = cipher::diary_key(password.as_bytes(), &header.salt, header.kdf)?;
+    cipher::check_canary(key, &header.canary)?;
+    Ok(key)
+}
+
+/// Decrypt the rest of `diary` into `archive` with `key`, verify it against `header` and unpack
+/// it into the directory `dir`. A failed archive is overwritten before removal with `secure_delete`
+pub fn unpack_diary(
+    diary: File,
+    header: &Header,
+    key: [u8; KEY_LENGTH],
+    archive: &Path,
+    dir: &Path,
+    secure_delete: bool,
+) -> Result<(), DiaryError> {
+    let mut decrypted = File::create_new(archive).context("Failed to create archive file")?;
+    let archive_guard = PlaintextGuard::new(archive, secure_delete);
+
+    debug!("Decrypting into {}", archive.display());
+    cipher::decrypt(diary, &mut decrypted, key, header.cipher)?;
+
+    decrypted
+        .seek(SeekFrom::Start(0))
+        .context("Failed to seek")?;
+
+    debug!("Verifying archive digest");
+    if cipher::digest(&mut decrypted).context("Failed to hash archive")? != header.digest {
+        return Err(DiaryError::IntegrityCheckFailed);
+    }
+
+    decrypted
+        .seek(SeekFrom::Start(0))
+        .context("Failed to seek")?;
+
+    debug!("Decompressing and unpacking into {}", dir.display());
+    unpack_archive(decrypted, header.compression, dir)?;
+
+    if log::log_enabled!(log::Level::Debug) {
+        let mut files = Vec::new();
+        kept_files(dir, Path::new(""), &FileFilter::default(), &mut files)?;
+        debug!(
+            "Unpacked {} files",
+            files.iter().filter(|(_, path)| !path.is_dir()).count()
+        );
+    }
+
+    restore_duplicates(dir)?;
+
+    // The caller decides what happens to the archive once the diary is unpacked
+    archive_guard.keep();
+    Ok(())
+}
+
+/// Decompress the tar `archive` compressed with `compression` and unpack it into `dir`, refusing
+/// any entry that `check_archive_entry` rejects
+pub fn unpack_archive(
+    archive: impl io::Read,
+    compression: Compression,
+    dir: &Path,
+) -> Result<(), DiaryError> {
+    let mut unpacker = Archive::new(
+        compression
+            .decoder(archive)
+            .context("Failed to decompress diary")?,
+    );
+
+    fs::create_dir(dir).context("Failed to create diary directory")?;
+    for file in unpacker.entries().context("Failed to unpack diary")? {
+        let mut file = file.context("Failed to unpack diary")?;
+        check_archive_entry(&file)?;
+        file.unpack_in(dir).context("Failed to unpack diary")?;
+    }
+
+    Ok(())
+}
+
+/// Decrypt the diary file at `path` with `key` and pass each file of its archive to `visit`,
+/// without writing anything to disk, until `visit` returns false or the archive ends. `visit` can
+/// fail with any error a [`DiaryError`] converts into, which is passed on as it is
+pub fn stream_archive<E: From<DiaryError>>(
+    path: &Path,
+    key: [u8; KEY_LENGTH],
+    mut visit: impl FnMut(tar::Entry<'_, Box<dyn io::Read>>) -> Result<bool, E>,
+) -> Result<(), E> {
+    let mut diary = File::open(path).context("Failed to open diary file")?;
+    let header = Header::read(&mut diary)?;
+    let (reader, writer) = io::pipe().context("Failed to create pipe")?;
+
+    std::thread::scope(|scope| {
+        let decrypting = scope.spawn(move || cipher::decrypt(diary, writer, key, header.cipher));
+
+        let visited = (|| {
+            let failed = |e| E::from(DiaryError::from(e));
+            let mut unpacker = Archive::new(header.compression.decoder(reader).map_err(failed)?);
+            for file in unpacker.entries().map_err(failed)? {
+                if !visit(file.map_err(failed)?)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        })();
+
+        // The unpacker closed the pipe when it was dropped, which stops the decryption if the
+        // rest of the archive isn't needed
+        let decrypted = decrypting.join().expect("decryption thread panicked");
+
+        match visited {
+            Ok(false) => Ok(()),
+            Ok(true) => decrypted.map_err(E::from),
+            Err(e) => Err(decrypted.err().map_or(e, E::from)),
+        }
+    })
+}
+
+/// Read the fingerprint of the directory that `pack_diary` recorded in the diary file at `path`,
+/// if it has one
+pub fn recorded_fingerprint(path: &Path, password: &str) -> Result<Option<String>, DiaryError> {
+    let header = Header::read(File::open(path).context("Failed to open diary file")?)?;
+    let key = header_key(&header, password)?;
+
+    let mut recorded = None;
+    stream_archive::<DiaryError>(path, key, |mut file| {
+        if file.path()? != Path::new(SAVED_MARKER) {
+            return Ok(true);
+        }
+
+        let mut saved = String::new();
+        file.read_to_string(&mut saved)
+            .context("Failed to read saved marker")?;
+        recorded = Some(saved);
+        Ok(false)
+    })?;
+
+    Ok(recorded)
+}
+
+/// Unpack every file of the tar `archive` compressed with `compression` into `dir` that is still
+/// intact, returning how many were unpacked and the name and error of those that were not.
+/// Unpacking stops at the first damaged header, since nothing after it can be located
+pub fn recover_archive(
+    archive: File,
+    compression: Compression,
+    dir: &Path,
+) -> (usize, Vec<(String, String)>) {
+    let mut unpacker = match compression.decoder(archive) {
+        Ok(decoder) => Archive::new(decoder),
+        Err(e) => return (0, vec![("archive".to_string(), e.to_string())]),
+    };
+    let mut recovered = 0;
+    let mut failed = Vec::new();
+
+    let files = match unpacker.entries() {
+        Ok(files) => files,
+        Err(e) => return (0, vec![("archive".to_string(), e.to_string())]),
+    };
+
+    for file in files {
+        let mut file = match file {
+            Ok(file) => file,
+            Err(e) => {
+                failed.push(("rest of archive".to_string(), e.to_string()));
+                break;
+            }
+        };
+
+        let path = file
+            .path()
+            .map(|p| p.display().to_string())
+            .unwrap_or_default();
+
+        if let Err(e) = check_archive_entry(&file) {
+            failed.push((path, e.to_string()));
+            continue;
+        }
+
+        match file.unpack_in(dir) {
+            Ok(_) => {
+                debug!("Recovered {path}");
+                if file.header().entry_type().is_file() {
+                    recovered += 1;
+                }
+            }
+            Err(e) => {
+                // Don't leave a truncated copy behind that looks like the real file
+                let partial = dir.join(&path);
+                if partial.is_file() {
+                    let _ = fs::remove_file(partial);
+                }
+                failed.push((path, e.to_string()));
+            }
+        }
+    }
+
+    (recovered, failed)
+}
+
+/// Refuse an archive entry that could write outside the diary directory: absolute paths, `..`
+/// and links, which `pack_diary` never creates since it follows symlinks
+pub fn check_archive_entry(file: &tar::Entry<impl io::Read>) -> Result<(), DiaryError> {
+    let path = file.path().context("Failed to read archive entry path")?;
+    let link = file.header().entry_type().is_symlink() || file.header().entry_type().is_hard_link();
+
+    if escapes(&path) || link {
+        return Err(DiaryError::UnsafeArchivePath(path.into_owned()));
+    }
+
+    Ok(())
+}
+
+/// Whether the relative `path` of an archived file could point outside the directory it is
+/// unpacked in
+fn escapes(path: &Path) -> bool {
+    path.components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// Expand the deduplication manifest of an unpacked diary in `dir`, if it has one
+pub fn restore_duplicates(dir: &Path) -> Result<(), DiaryError> {
+    let manifest = dir.join(DEDUP_MANIFEST);
+    if manifest.exists() {
+        let duplicates: BTreeMap<PathBuf, PathBuf> =
+            serde_json::from_reader(BufReader::new(File::open(&manifest)?))
+                .context("Failed to read deduplication manifest")?;
+
+        for (duplicate, original) in duplicates {
+            if let Some(path) = [&duplicate, &original].into_iter().find(|p| escapes(p)) {
+                return Err(DiaryError::UnsafeArchivePath(path.clone()));
+            }
+            fs::copy(dir.join(original), dir.join(&duplicate))
+                .with_context(|| format!("Failed to restore {}", duplicate.display()))?;
+        }
+
+        fs::remove_file(manifest).context("Failed to remove deduplication manifest")?;
+    }
+
+    Ok(())
+}
+
+/// How `pack_diary` archives and compresses a diary directory
+#[derive(Default)]
+pub struct PackOptions {
+    pub compression: Compression,
+    /// Compression level, 0 to 9
+    pub level: u32,
+    /// Threads to compress with, defaults to the number of CPUs
+    pub threads: Option<usize>,
+    pub filter: FileFilter,
+    /// Store files with identical content once, see `DEDUP_MANIFEST`
+    pub dedup: bool,
+    pub cipher: Algorithm,
+    /// Argon2 costs to derive the key with
+    pub kdf: KdfParams,
+    /// Unencrypted label for the header
+    pub comment: Option<String>,
+    /// Overwrite the archive before removing it, see `shred::remove_file`
+    pub secure_delete: bool,
+}
+
+/// Archive and compress the diary directory `dir` into `archive`, then encrypt it into `diary`
+/// with `password`, returning the size of the directory, the archive and the diary file
+pub fn pack_diary(
+    dir: &Path,
+    archive: &Path,
+    diary: &Path,
+    password: &str,
+    options: &PackOptions,
+) -> Result<(u64, u64, u64), DiaryError> {
+    let threads = match options.threads {
+        Some(threads) => threads,
+        None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+
+    let archive_file = File::create_new(archive).context("Failed to create archive")?;
+    let archive_guard = PlaintextGuard::new(archive, options.secure_delete);
+
+    fs::write(dir.join(SAVED_MARKER), fingerprint(dir, &options.filter)?)
+        .context("Failed to write saved marker")?;
+
+    let mut files = Vec::new();
+    kept_files(dir, Path::new(""), &options.filter, &mut files)?;
+    // Readers like `get` can then find the entry they need without buffering the archive
+    files.sort_by_key(|(name, _)| name != Path::new("diary.json") && name != Path::new(ENTRIES_DB));
+
+    debug!(
+        "Archiving and compressing {} files with {:?} at level {} on {threads} threads",
+        files.iter().filter(|(_, path)| !path.is_dir()).count(),
+        options.compression,
+        options.level
+    );
+    let mut archive_file = match options.compression {
+        Compression::Gzip => {
+            let compressed = ZBuilder::<Gzip, _>::new()
+                .num_threads(threads)
+                .compression_level(flate2::Compression::new(options.level))
+                .from_writer(archive_file);
+            let mut builder = Builder::new(compressed);
+            append_files(&mut builder, files, options.dedup)?;
+            builder
+                .into_inner()?
+                .finish()
+                .map_err(io::Error::other)
+                .context("Failed to compress archive")?
+        }
+        Compression::Zstd => {
+            let mut compressed = zstd::Encoder::new(archive_file, options.level as i32)
+                .context("Failed to compress archive")?;
+            compressed
+                .multithread(threads as u32)
+                .context("Failed to compress archive")?;
+            let mut builder = Builder::new(compressed);
+            append_files(&mut builder, files, options.dedup)?;
+            builder
+                .into_inner()?
+                .finish()
+                .context("Failed to compress archive")?
+        }
+    };
+
+    let original_size = dir_size(dir).context("Failed to measure diary")?;
+    let archive_size = archive_file.metadata()?.len();
+
+    archive_file
+        .seek(SeekFrom::Start(0))
+        .context("Failed to seek")?;
+
+    debug!("Hashing archive");
+    let digest = cipher::digest(&mut archive_file).context("Failed to hash archive")?;
+
+    archive_file
+        .seek(SeekFrom::Start(0))
+        .context("Failed to seek")?;
+
+    // Closing a diary again whose key this thread derived keeps the salt of the file it replaces,
+    // so Argon2 only runs once. Every other diary file gets a fresh salt
+    let reused = File::open(diary)
+        .ok()
+        .and_then(|file| Header::read(file).ok())
+        .and_then(|header| {
+            cipher::cached_key(password.as_bytes(), &header.salt, options.kdf)
+                .map(|key| (header.salt, key))
+        });
+    let (salt, key) = match reused {
+        Some(reused) => reused,
+        None => {
+            let mut salt = [0u8; SALT_LENGTH];
+            rand::rng().fill_bytes(&mut salt);
+
+            debug!("Deriving key (argon2)");
+            (
+                salt,
+                cipher::diary_key(password.as_bytes(), &salt, options.kdf)?,
+            )
+        }
+    };
+
+    debug!("Encrypting into {}", diary.display());
+    let diary_size = write_atomically(diary, |diary_file| {
+        Header::new(
+            salt,
+            digest,
+            options.cipher,
+            cipher::seal_canary(key)?,
+            options.kdf,
+            options.compression,
+            options.comment.clone(),
+        )
+        .write(&mut *diary_file)?;
+        cipher::encrypt(archive_file, diary_file, key, options.cipher)
+    })?;
+
+    archive_guard
+        .remove()
+        .context("Failed to remove diary archive")?;
+
+    Ok((original_size, archive_size, diary_size))
+}
+
+/// Append the `files` listed by `kept_files` to `builder`, storing files with identical content
+/// once with `dedup`
+fn append_files(
+    builder: &mut Builder<impl Write>,
+    files: Vec<(PathBuf, PathBuf)>,
+    dedup: bool,
+) -> Result<(), DiaryError> {
+    let mut stored = HashMap::<_, PathBuf>::new();
+    let mut duplicates = BTreeMap::new();
+    for (name, path) in files {
+        if path.is_dir() {
+            builder.append_dir(&name, &path)?;
+            continue;
+        }
+
+        if dedup {
+            let hash = cipher::digest(File::open(&path)?).context("Failed to hash file")?;
+            if let Some(original) = stored.get(&hash) {
+                duplicates.insert(name, original.clone());
+                continue;
+            }
+            stored.insert(hash, name.clone());
+        }
+
+        builder.append_path_with_name(&path, &name)?;
+    }
+
+    if !duplicates.is_empty() {
+        let manifest =
+            serde_json::to_vec(&duplicates).context("Failed to write deduplication manifest")?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(manifest.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, DEDUP_MANIFEST, manifest.as_slice())?;
+    }
+
+    Ok(())
+}
+
+/// Path for the intermediate archive of the diary directory `dir`, beside it and unique to this
+/// run so concurrent runs don't write over each other's archive
+pub fn archive_path(dir: &Path) -> PathBuf {
+    let mut name = dir.file_name().unwrap_or_default().to_owned();
+    name.push(format!(
+        ".{}-{:08x}.tar.gz",
+        std::process::id(),
+        rand::rng().next_u32()
+    ));
+    dir.with_file_name(name)
+}
+
+/// Archives of the diary directory `dir` left beside it by interrupted runs, as named by
+/// `archive_path` or as `{name}.tar.gz` by older versions
+pub fn leftover_archives(dir: &Path) -> Result<Vec<PathBuf>, DiaryError> {
+    let Some(name) = dir.file_name().and_then(|name| name.to_str()) else {
+        return Ok(Vec::new());
+    };
+    let parent = dir.parent().unwrap_or(Path::new(""));
+
+    let mut leftovers = Vec::new();
+    let listing = if parent.as_os_str().is_empty() {
+        fs::read_dir(".")
+    } else {
+        fs::read_dir(parent)
+    };
+    for entry in listing.context("Failed to read directory")? {
+        let file_name = entry?.file_name();
+        let Some(file_name) = file_name.to_str() else {
+            continue;
+        };
+
+        let unique = file_name
+            .strip_prefix(name)
+            .and_then(|rest| rest.strip_prefix('.'))
+            .and_then(|rest| rest.strip_suffix(".tar.gz"))
+            .and_then(|middle| middle.split_once('-'))
+            .is_some_and(|(pid, suffix)| {
+                !pid.is_empty()
+                    && pid.chars().all(|c| c.is_ascii_digit())
+                    && suffix.len() == 8
+                    && suffix.chars().all(|c| c.is_ascii_hexdigit())
+            });
+
+        if unique || file_name == format!("{name}.tar.gz") {
+            leftovers.push(parent.join(file_name));
+        }
+    }
+
+    Ok(leftovers)
+}
+
+/// Remove a decrypted file or directory, overwriting its files first with `secure`
+pub fn remove_plaintext(path: &Path, secure: bool) -> io::Result<()> {
+    match (path.is_dir(), secure) {
+        (true, true) => shred::remove_dir_all(path),
+        (true, false) => fs::remove_dir_all(path),
+        (false, true) => shred::remove_file(path),
+        (false, false) => fs::remove_file(path),
+    }
+}
+
+/// Removes a plaintext file with `remove_plaintext` when dropped, so no error path leaves it
+/// behind
+pub struct PlaintextGuard<'a> {
+    path: &'a Path,
+    secure: bool,
+}
+
+impl<'a> PlaintextGuard<'a> {
+    /// Guard the plaintext file at `path`, overwriting it before removal if `secure` is set
+    pub fn new(path: &'a Path, secure: bool) -> Self {
+        Self { path, secure }
+    }
+
+    /// Remove the file now, reporting if that fails
+    pub fn remove(self) -> io::Result<()> {
+        let removed = remove_plaintext(self.path, self.secure);
+        std::mem::forget(self);
+        removed
+    }
+
+    /// Leave the file in place
+    pub fn keep(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for PlaintextGuard<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = remove_plaintext(self.path, self.secure) {
+            log::warn!("Failed to remove {}: {e}", self.path.display());
+        }
+    }
+}
+
+/// Write `path` with `write` through a temporary file beside it, which is synced to disk and
+/// renamed over `path` only once `write` has succeeded, returning the size of the new file
+pub fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut File) -> Result<(), DiaryError>,
+) -> Result<u64, DiaryError> {
+    let dir = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let mut prefix = path.file_name().unwrap_or_default().to_owned();
+    prefix.push(".");
+
+    // Removed again when dropped, unless it is persisted
+    let mut tmp = tempfile::Builder::new()
+        .prefix(&prefix)
+        .suffix(".tmp")
+        .tempfile_in(dir)
+        .with_context(|| format!("Failed to create temporary file for {}", path.display()))?;
+
+    write(tmp.as_file_mut())?;
+    tmp.as_file().sync_all()?;
+
+    let size = tmp.as_file().metadata()?.len();
+
+    tmp.persist(path)
+        .map_err(|e| e.error)
+        .with_context(|| format!("Failed to replace {}", path.display()))?;
+
+    #[cfg(unix)]
+    File::open(dir)
+        .and_then(|d| d.sync_all())
+        .context("Failed to sync directory")?;
+
+    Ok(size)
+}
+
+/// Which files of a diary directory are put into its archive
+#[derive(Default)]
+pub struct FileFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl FileFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self, DiaryError> {
+        let include = if include.is_empty() {
+            None
+        } else {
+            Some(glob_set(include)?)
+        };
+
+        Ok(Self {
+            include,
+            exclude: glob_set(exclude)?,
+        })
+    }
+
+    /// Whether the file at `path`, relative to the diary directory, goes into the archive
+    pub fn keeps(&self, path: &Path) -> bool {
+        if path == Path::new("diary.json")
+            || path == Path::new(ENTRIES_DB)
+            || path == Path::new(SAVED_MARKER)
+        {
+            return true;
+        }
+
+        self.include.as_ref().is_none_or(|set| set.is_match(path)) && !self.exclude.is_match(path)
+    }
+}
+
+pub fn glob_set(globs: &[String]) -> Result<GlobSet, DiaryError> {
+    let invalid = |glob: &str| {
+        let glob = glob.to_string();
+        move |source| DiaryError::InvalidGlob { glob, source }
+    };
+
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob).map_err(invalid(glob))?);
+    }
+    builder.build().map_err(invalid(&globs.join(" ")))
+}
+
+/// Collect the directories and files under `dir` that `filter` keeps into `files`, as pairs of
+/// their name relative to the diary directory, starting with `prefix`, and their path
+pub fn kept_files(
+    dir: &Path,
+    prefix: &Path,
+    filter: &FileFilter,
+    files: &mut Vec<(PathBuf, PathBuf)>,
+) -> std::io::Result<()> {
+    let mut children = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    children.sort_by_key(|e| e.file_name());
+
+    for child in children {
+        let path = child.path();
+        let name = prefix.join(child.file_name());
+
+        if path.is_dir() {
+            if filter.exclude.is_match(&name) {
+                continue;
+            }
+            files.push((name.clone(), path.clone()));
+            kept_files(&path, &name, filter, files)?;
+        } else if filter.keeps(&name) {
+            files.push((name, path));
+        }
+    }
+
+    Ok(())
+}
+
+/// Hash of the names and contents of the files in the diary directory `dir` that `filter` keeps,
+/// leaving out the saved marker itself
+pub fn fingerprint(dir: &Path, filter: &FileFilter) -> std::io::Result<String> {
+    let mut files = Vec::new();
+    kept_files(dir, Path::new(""), filter, &mut files)?;
+
+    let mut hasher = Sha256::new();
+    for (name, path) in files {
+        if path.is_dir() || name == Path::new(SAVED_MARKER) {
+            continue;
+        }
+        hasher.update(name.as_os_str().as_encoded_bytes());
+        hasher.update([0]);
+        hasher.update(fs::metadata(&path)?.len().to_le_bytes());
+        std::io::copy(&mut File::open(&path)?, &mut hasher)?;
+    }
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+pub fn dir_size(path: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Pack a diary with one entry in `dir` into a diary file there for tests, with the cheapest
+/// Argon2 costs so they don't spend their time deriving keys
+#[cfg(test)]
+pub(crate) fn packed(dir: &Path) -> PathBuf {
+    let diary = dir.join("diary");
+    fs::create_dir(&diary).unwrap();
+    fs::write(diary.join("diary.json"), "{}").unwrap();
+    fs::write(diary.join("entry.md"), "dear diary").unwrap();
+
+    let file = dir.join("diary.diary");
+    let options = PackOptions {
+        kdf: KdfParams {
+            memory: 8,
+            iterations: 1,
+            parallelism: 1,
+        },
+        ..Default::default()
+    };
+    pack_diary(&diary, &archive_path(&diary), &file, "password", &options).unwrap();
+    file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrong_password_is_not_corruption() {
+        let temp = tempfile::tempdir().unwrap();
+        let file = packed(temp.path());
+
+        let verified = verify_diary(File::open(&file).unwrap(), "wrong password");
+        assert!(matches!(verified, Err(DiaryError::WrongPassword)));
+
+        let mut bytes = fs::read(&file).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        fs::write(&file, bytes).unwrap();
+
+        let verified = verify_diary(File::open(&file).unwrap(), "password");
+        assert!(matches!(verified, Err(DiaryError::Corrupt(_))));
+    }
+
+    #[test]
+    fn archive_escaping_its_directory_is_refused() {
+        let temp = tempfile::tempdir().unwrap();
+
+        // `tar::Builder` refuses to write such a path, so the header is filled in by hand
+        let content = b"outside the diary";
+        let mut header = tar::Header::new_old();
+        header.as_old_mut().name[..9].copy_from_slice(b"../escape");
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+
+        let mut builder = Builder::new(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        ));
+        builder.append(&header, &content[..]).unwrap();
+        let archive = builder.into_inner().unwrap().finish().unwrap();
+
+        let dir = temp.path().join("diary");
+        let unpacked = unpack_archive(&archive[..], Compression::Gzip, &dir);
+        assert!(matches!(
+            unpacked,
+            Err(DiaryError::UnsafeArchivePath(path)) if path == Path::new("../escape")
+        ));
+        assert!(!temp.path().join("escape").exists());
+    }
+
+    #[test]
+    fn manifest_escaping_its_directory_is_refused() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path().join("diary");
+        fs::create_dir(&dir).unwrap();
+        fs::write(temp.path().join("secret"), "outside the diary").unwrap();
+
+        for manifest in [
+            r#"{"copy": "../secret"}"#,
+            r#"{"../copy": "entry.md"}"#,
+            r#"{"/tmp/copy": "entry.md"}"#,
+        ] {
+            fs::write(dir.join(DEDUP_MANIFEST), manifest).unwrap();
+            let restored = restore_duplicates(&dir);
+            assert!(matches!(restored, Err(DiaryError::UnsafeArchivePath(_))));
+        }
+        assert!(!dir.join("copy").exists());
+        assert!(!temp.path().join("copy").exists());
+    }
+
+    #[test]
+    fn failed_atomic_write_keeps_the_old_file() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("x.diary");
+        fs::write(&path, b"old").unwrap();
+
+        let failed = write_atomically(&path, |file| {
+            file.write_all(b"half of the new")?;
+            Err(DiaryError::TooLarge)
+        });
+        assert!(matches!(failed, Err(DiaryError::TooLarge)));
+        assert_eq!(fs::read(&path).unwrap(), b"old");
+        assert_eq!(fs::read_dir(temp.path()).unwrap().count(), 1);
+
+        let size = write_atomically(&path, |file| Ok(file.write_all(b"new")?)).unwrap();
+        assert_eq!(size, 3);
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(fs::read_dir(temp.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn archive_paths_are_unique_and_found_as_leftovers() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path().join("x");
+        let paths = (0..100)
+            .map(|_| archive_path(&dir))
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(paths.len(), 100);
+
+        let leftovers = paths.iter().take(2).cloned().collect::<Vec<_>>();
+        for path in leftovers.iter().chain([&temp.path().join("x.tar.gz")]) {
+            assert_eq!(path.parent(), Some(temp.path()));
+            fs::write(path, "").unwrap();
+        }
+        // Archives of other diaries and files that only look similar
+        for other in [
+            "xy.1-0000000a.tar.gz",
+            "x.1-0a.tar.gz",
+            "x.a-0000000a.tar.gz",
+            "x.diary",
+        ] {
+            fs::write(temp.path().join(other), "").unwrap();
+        }
+        fs::write(archive_path(&temp.path().join("xy")), "").unwrap();
+
+        let mut found = leftover_archives(&dir).unwrap();
+        found.sort();
+        let mut expected = [leftovers, vec![temp.path().join("x.tar.gz")]].concat();
+        expected.sort();
+        assert_eq!(found, expected);
+    }
+}
@@ -1,85 +1,499 @@
-use crate::consts::{CAPACITY, KEY_LENGTH, NONCE_LENGTH, OVERHEAD, SALT_LENGTH};
+use crate::{
+    consts::{
+        CANARY, CANARY_LENGTH, CANARY_NONCE_LENGTH, CAPACITY, CHUNK_LENGTH, DIGEST_LENGTH,
+        KEY_LENGTH, MAX_CHUNKS, NONCE_LENGTH, OVERHEAD, SALT_LENGTH,
+    },
+    error::DiaryError,
+};
 use aes_gcm_siv::{
-    Aes256GcmSiv, KeyInit,
-    aead::stream::{DecryptorBE32, EncryptorBE32},
+    Aes256GcmSiv, KeyInit, KeySizeUser,
+    aead::{
+        Aead, AeadCore, AeadInPlace,
+        generic_array::{
+            ArrayLength,
+            typenum::{U5, Unsigned},
+        },
+        stream::{DecryptorBE32, EncryptorBE32, Nonce, NonceSize, StreamBE32},
+    },
 };
-use argon2::Argon2;
-use color_eyre::eyre::anyhow;
+use argon2::{Argon2, Params};
+use chacha20poly1305::XChaCha20Poly1305;
+use clap::ValueEnum;
+use hkdf::Hkdf;
 use rand::Rng;
-use std::io::{Read, Write};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    cell::RefCell,
+    io::{self, BufReader, BufWriter, Read, Write},
+    ops::Sub,
+};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+// The header layout relies on these matching the cipher, so changing it must fail to compile
+// until the constants are updated to match
+const _: () = assert!(KEY_LENGTH == <Aes256GcmSiv as KeySizeUser>::KeySize::USIZE);
+const _: () = assert!(CANARY_NONCE_LENGTH == <Aes256GcmSiv as AeadCore>::NonceSize::USIZE);
+const _: () = assert!(OVERHEAD == <Aes256GcmSiv as AeadCore>::TagSize::USIZE);
+const _: () = assert!(NONCE_LENGTH == NonceSize::<Aes256GcmSiv, StreamBE32<Aes256GcmSiv>>::USIZE);
+const _: () = assert!(KEY_LENGTH == <XChaCha20Poly1305 as KeySizeUser>::KeySize::USIZE);
+const _: () = assert!(OVERHEAD == <XChaCha20Poly1305 as AeadCore>::TagSize::USIZE);
+
+/// Argon2id costs a diary's key is derived with, recorded in its header
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub struct KdfParams {
+    /// Memory in KiB
+    pub memory: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    /// Argon2's defaults, which every diary written before the header recorded them used
+    fn default() -> Self {
+        Self {
+            memory: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+/// A derived key with the salt and costs it was derived with, and the digest of the password it
+/// was derived from, salted like the key so equal passwords don't share a digest across diaries
+struct CachedKey {
+    salt: [u8; SALT_LENGTH],
+    params: KdfParams,
+    digest: [u8; DIGEST_LENGTH],
+    key: Zeroizing<[u8; KEY_LENGTH]>,
+}
+
+thread_local! {
+    /// The last diary key `diary_key` derived on this thread, so a diary closed more than once in
+    /// one run (such as a snapshot followed by a close) only goes through Argon2 once. Only the
+    /// one key is kept, and keys of encrypted entries never are
+    static LAST_KEY: RefCell<Option<CachedKey>> = const { RefCell::new(None) };
+}
+
+#[cfg(test)]
+thread_local! {
+    /// How many keys Argon2 derived on this thread
+    pub static DERIVATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+pub fn hash_password(
+    key: &[u8],
+    salt: &[u8; SALT_LENGTH],
+    params: KdfParams,
+) -> Result<[u8; KEY_LENGTH], DiaryError> {
+    #[cfg(test)]
+    DERIVATIONS.with(|derivations| derivations.set(derivations.get() + 1));
 
-pub fn hash_password(key: &[u8], salt: &[u8; SALT_LENGTH]) -> argon2::Result<[u8; KEY_LENGTH]> {
     let mut out = [0u8; KEY_LENGTH];
-    Argon2::default().hash_password_into(key, salt, &mut out)?;
+    Argon2::new(
+        argon2::Algorithm::Argon2id,
+        argon2::Version::V0x13,
+        Params::new(params.memory, params.iterations, params.parallelism, None)?,
+    )
+    .hash_password_into(key, salt, &mut out)?;
     Ok(out)
 }
 
+/// `hash_password` for the key of a diary file, reusing the last one derived on this thread if it
+/// was for the same password, salt and costs
+pub fn diary_key(
+    password: &[u8],
+    salt: &[u8; SALT_LENGTH],
+    params: KdfParams,
+) -> Result<[u8; KEY_LENGTH], DiaryError> {
+    if let Some(key) = cached_key(password, salt, params) {
+        return Ok(key);
+    }
+
+    let key = hash_password(password, salt, params)?;
+    LAST_KEY.with_borrow_mut(|last| {
+        *last = Some(CachedKey {
+            salt: *salt,
+            params,
+            digest: password_digest(password, salt),
+            key: Zeroizing::new(key),
+        })
+    });
+    Ok(key)
+}
+
+/// The last key `diary_key` derived on this thread, if it was for `password` with `salt` and
+/// `params`
+pub fn cached_key(
+    password: &[u8],
+    salt: &[u8; SALT_LENGTH],
+    params: KdfParams,
+) -> Option<[u8; KEY_LENGTH]> {
+    let digest = password_digest(password, salt);
+    LAST_KEY.with_borrow(|last| {
+        last.as_ref()
+            .filter(|cached| cached.salt == *salt && cached.params == params)
+            .filter(|cached| cached.digest == digest)
+            .map(|cached| *cached.key)
+    })
+}
+
+fn password_digest(password: &[u8], salt: &[u8; SALT_LENGTH]) -> [u8; DIGEST_LENGTH] {
+    Sha256::new()
+        .chain_update(salt)
+        .chain_update(password)
+        .finalize()
+        .into()
+}
+
+/// Salt and canary of the password of a diary's encrypted entries, stored with its entries once
+/// one is encrypted. Unlike the diary password, which the open diary keeps in `diary.json`, the
+/// entry password itself is never stored
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EntryLock {
+    #[serde(with = "hex_bytes")]
+    salt: [u8; SALT_LENGTH],
+    #[serde(with = "hex_bytes")]
+    canary: [u8; CANARY_LENGTH],
+    kdf: KdfParams,
+}
+
+impl EntryLock {
+    /// Lock entries with `password`, stretched with Argon2id at `kdf` costs, returning the lock
+    /// and the key it guards
+    pub fn new(password: &str, kdf: KdfParams) -> Result<(Self, EntryKey), DiaryError> {
+        let mut salt = [0u8; SALT_LENGTH];
+        rand::rng().fill_bytes(&mut salt);
+
+        let key = hash_password(password.as_bytes(), &salt, kdf)?;
+        let lock = Self {
+            salt,
+            canary: seal_canary(key)?,
+            kdf,
+        };
+
+        Ok((lock, EntryKey(Zeroizing::new(key))))
+    }
+
+    /// Derive the key of the entries from `password`, checking it against the canary
+    pub fn unlock(&self, password: &str) -> Result<EntryKey, DiaryError> {
+        let key = hash_password(password.as_bytes(), &self.salt, self.kdf)?;
+        check_canary(key, &self.canary)?;
+
+        Ok(EntryKey(Zeroizing::new(key)))
+    }
+}
+
+/// Key of the encrypted entry files of a diary, which each file's own key is derived from
+#[derive(PartialEq, Eq)]
+pub struct EntryKey(Zeroizing<[u8; KEY_LENGTH]>);
+
+impl EntryKey {
+    /// Key of the entry files of a diary without an [`EntryLock`], encrypted before entries had
+    /// their own password, which were keyed by the diary password alone
+    pub fn legacy(password: &str) -> Self {
+        let (prk, _) = Hkdf::<Sha256>::extract(None, password.as_bytes());
+        Self(Zeroizing::new(prk.into()))
+    }
+
+    /// Key of the file of the entry with `id`, so no two entry files share one
+    pub fn file_key(&self, id: &Uuid) -> [u8; KEY_LENGTH] {
+        let mut out = [0u8; KEY_LENGTH];
+        Hkdf::<Sha256>::from_prk(self.0.as_slice())
+            .expect("KEY_LENGTH is a valid HKDF-SHA256 key length")
+            .expand(id.as_bytes(), &mut out)
+            .expect("KEY_LENGTH is a valid HKDF-SHA256 output length");
+        out
+    }
+}
+
+/// Byte arrays as hex strings, for the salt and canary in `diary.json`
+mod hex_bytes {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer, const N: usize>(
+        bytes: &[u8; N],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+        serializer.serialize_str(&hex)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+        deserializer: D,
+    ) -> Result<[u8; N], D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        if hex.len() != N * 2 {
+            return Err(D::Error::custom(format!("expected {} hex digits", N * 2)));
+        }
+
+        let mut bytes = [0u8; N];
+        for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            *byte = str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| D::Error::custom("invalid hex digit"))?;
+        }
+
+        Ok(bytes)
+    }
+}
+
+/// Encrypt the fixed canary with `key`, so a wrong password can be told apart from corruption
+pub fn seal_canary(key: [u8; KEY_LENGTH]) -> Result<[u8; CANARY_LENGTH], DiaryError> {
+    let mut nonce = [0u8; CANARY_NONCE_LENGTH];
+    rand::rng().fill_bytes(&mut nonce);
+
+    let sealed = Aes256GcmSiv::new(&key.into())
+        .encrypt(&nonce.into(), CANARY.as_slice())
+        .map_err(|_| DiaryError::Encryption)?;
+
+    let mut canary = [0u8; CANARY_LENGTH];
+    canary[..CANARY_NONCE_LENGTH].copy_from_slice(&nonce);
+    canary[CANARY_NONCE_LENGTH..].copy_from_slice(&sealed);
+    Ok(canary)
+}
+
+/// Check that `key` opens a canary made by [`seal_canary`]
+pub fn check_canary(key: [u8; KEY_LENGTH], canary: &[u8; CANARY_LENGTH]) -> Result<(), DiaryError> {
+    let (nonce, sealed) = canary.split_at(CANARY_NONCE_LENGTH);
+
+    match Aes256GcmSiv::new(&key.into()).decrypt(nonce.into(), sealed) {
+        Ok(opened) if opened == CANARY => Ok(()),
+        _ => Err(DiaryError::WrongPassword),
+    }
+}
+
+pub fn digest(mut from: impl Read) -> io::Result<[u8; DIGEST_LENGTH]> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut from, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+/// Read until `buf` is full or `from` is exhausted, returning the number of bytes read
+fn read_full(mut from: impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match from.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Stream cipher a diary is encrypted with, recorded in its header
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    /// AES-256-GCM-SIV, fastest with AES hardware acceleration
+    #[default]
+    #[value(name = "aes256gcmsiv")]
+    Aes256GcmSiv,
+    /// XChaCha20-Poly1305, fast everywhere without relying on hardware support
+    #[value(name = "xchacha20")]
+    XChaCha20Poly1305,
+}
+
+impl Algorithm {
+    /// Byte identifying the cipher in the header
+    pub fn id(self) -> u8 {
+        match self {
+            Self::Aes256GcmSiv => 0,
+            Self::XChaCha20Poly1305 => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self, DiaryError> {
+        match id {
+            0 => Ok(Self::Aes256GcmSiv),
+            1 => Ok(Self::XChaCha20Poly1305),
+            _ => Err(DiaryError::UnsupportedCipher(id)),
+        }
+    }
+}
+
+/// Encrypt `from` into `to` with `algorithm`, writing the stream nonce before the ciphertext
 pub fn encrypt(
-    mut from: impl Read,
-    mut to: impl Write,
+    from: impl Read,
+    to: impl Write,
     key: [u8; KEY_LENGTH],
-) -> color_eyre::Result<()> {
-    let mut nonce = [0u8; NONCE_LENGTH];
+    algorithm: Algorithm,
+) -> Result<(), DiaryError> {
+    match algorithm {
+        Algorithm::Aes256GcmSiv => {
+            encrypt_stream::<Aes256GcmSiv>(from, to, key, CAPACITY, MAX_CHUNKS)
+        }
+        Algorithm::XChaCha20Poly1305 => {
+            encrypt_stream::<XChaCha20Poly1305>(from, to, key, CAPACITY, MAX_CHUNKS)
+        }
+    }
+}
+
+/// Decrypt a stream written by [`encrypt`] with the same `algorithm` from `from` into `to`
+pub fn decrypt(
+    from: impl Read,
+    to: impl Write,
+    key: [u8; KEY_LENGTH],
+    algorithm: Algorithm,
+) -> Result<(), DiaryError> {
+    match algorithm {
+        Algorithm::Aes256GcmSiv => decrypt_stream::<Aes256GcmSiv>(from, to, key),
+        Algorithm::XChaCha20Poly1305 => decrypt_stream::<XChaCha20Poly1305>(from, to, key),
+    }
+}
+
+/// Encrypt `from` in chunks of `capacity` bytes, refusing to write more than `max_chunks` full ones
+///
+/// Only tests pass anything but [`CAPACITY`] and [`MAX_CHUNKS`], since [`decrypt_stream`] expects
+/// chunks of [`CAPACITY`] bytes
+fn encrypt_stream<A>(
+    from: impl Read,
+    to: impl Write,
+    key: [u8; KEY_LENGTH],
+    capacity: usize,
+    max_chunks: u32,
+) -> Result<(), DiaryError>
+where
+    A: AeadInPlace + KeyInit,
+    A::NonceSize: Sub<U5>,
+    <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+{
+    let mut from = BufReader::new(from);
+    let mut to = BufWriter::new(to);
+
+    // The nonce length depends on the cipher, 7 bytes for AES-GCM-SIV and 19 for XChaCha20
+    let mut nonce = Nonce::<A, StreamBE32<A>>::default();
     rand::rng().fill_bytes(&mut nonce);
 
     to.write_all(&nonce)?;
 
-    let cipher = Aes256GcmSiv::new(&key.into());
-    let mut stream = EncryptorBE32::from_aead(cipher, &nonce.into());
-
-    let mut buf = vec![0u8; CAPACITY];
+    let cipher = A::new_from_slice(&key).map_err(|_| DiaryError::Encryption)?;
+    let mut stream = EncryptorBE32::from_aead(cipher, &nonce);
+    let mut buf = vec![0u8; capacity];
+    let mut chunks = 0u32;
     loop {
-        let read = from.read(&mut buf)?;
+        let read = read_full(&mut from, &mut buf)?;
+
+        if read == capacity {
+            if chunks == max_chunks {
+                return Err(DiaryError::TooLarge);
+            }
+            chunks += 1;
 
-        if read == CAPACITY {
             stream
                 .encrypt_next_in_place(b"", &mut buf)
-                .map_err(|e| anyhow!("Error encrypting full chunk: {}", e))?;
+                .map_err(|_| DiaryError::Encryption)?;
             to.write_all(&buf)?;
-            buf.truncate(CAPACITY);
+            buf.truncate(capacity);
         } else {
             buf.truncate(read);
             stream
                 .encrypt_last_in_place(b"", &mut buf)
-                .map_err(|e| anyhow!("Error encrypting last chunk: {}", e))?;
+                .map_err(|_| DiaryError::Encryption)?;
             to.write_all(&buf)?;
             break;
         }
     }
 
+    to.flush()?;
+
     Ok(())
 }
 
-pub fn decrypt(
-    mut from: impl Read,
-    mut to: impl Write,
+fn decrypt_stream<A>(
+    from: impl Read,
+    to: impl Write,
     key: [u8; KEY_LENGTH],
-) -> color_eyre::Result<()> {
-    let mut nonce = [0u8; NONCE_LENGTH];
+) -> Result<(), DiaryError>
+where
+    A: AeadInPlace + KeyInit,
+    A::NonceSize: Sub<U5>,
+    <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+{
+    let mut from = BufReader::new(from);
+    let mut to = BufWriter::new(to);
+
+    let mut nonce = Nonce::<A, StreamBE32<A>>::default();
     from.read_exact(&mut nonce)?;
 
-    let cipher = Aes256GcmSiv::new(&key.into());
-    let mut stream = DecryptorBE32::from_aead(cipher, &nonce.into());
+    let cipher = A::new_from_slice(&key).map_err(|_| DiaryError::Encryption)?;
+    let mut stream = DecryptorBE32::from_aead(cipher, &nonce);
 
-    let mut buf = vec![0u8; CAPACITY + OVERHEAD];
+    let mut buf = vec![0u8; CHUNK_LENGTH];
+    let mut chunks = 0u64;
     loop {
-        let read = from.read(&mut buf)?;
+        let read = read_full(&mut from, &mut buf)?;
+
+        let failed = |_| DiaryError::Corrupt(format!("chunk {chunks} failed to decrypt"));
 
-        if read == CAPACITY + OVERHEAD {
+        if read == CHUNK_LENGTH {
             stream
                 .decrypt_next_in_place(b"", &mut buf)
-                .map_err(|e| anyhow!("Error decrypting full chunk: {}", e))?;
+                .map_err(failed)?;
             to.write_all(&buf)?;
-            buf.resize(CAPACITY + OVERHEAD, 0);
+            buf.resize(CHUNK_LENGTH, 0);
+            chunks += 1;
         } else {
             buf.truncate(read);
             stream
                 .decrypt_last_in_place(b"", &mut buf)
-                .map_err(|e| anyhow!("Error decrypting last chunk: {}", e))?;
+                .map_err(failed)?;
             to.write_all(&buf)?;
             break;
         }
     }
 
+    to.flush()?;
+
     Ok(())
 }
+
+/// Decrypt `from` without keeping the plaintext, returning its digest
+pub fn decrypt_digest(
+    from: impl Read,
+    key: [u8; KEY_LENGTH],
+    algorithm: Algorithm,
+) -> Result<[u8; DIGEST_LENGTH], DiaryError> {
+    let mut hasher = Sha256::new();
+    decrypt(from, &mut hasher, key, algorithm)?;
+    Ok(hasher.finalize().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypt_refuses_more_than_max_chunks() {
+        let key = [7u8; KEY_LENGTH];
+        let encrypt = |length: usize| {
+            let plaintext = vec![0u8; length];
+            encrypt_stream::<Aes256GcmSiv>(&plaintext[..], io::sink(), key, 16, 2)
+        };
+
+        // Two full chunks and a short last one fit, a third full chunk does not
+        assert!(encrypt(47).is_ok());
+        assert!(matches!(encrypt(48), Err(DiaryError::TooLarge)));
+    }
+
+    #[test]
+    fn multi_chunk_round_trip() {
+        let key = [7u8; KEY_LENGTH];
+        let mut plaintext = vec![0u8; CAPACITY * 5 / 2];
+        rand::rng().fill_bytes(&mut plaintext);
+
+        for algorithm in [Algorithm::Aes256GcmSiv, Algorithm::XChaCha20Poly1305] {
+            let mut ciphertext = Vec::new();
+            encrypt(&plaintext[..], &mut ciphertext, key, algorithm).unwrap();
+            assert!(ciphertext.len() > 2 * CHUNK_LENGTH);
+
+            let mut decrypted = Vec::new();
+            decrypt(&ciphertext[..], &mut decrypted, key, algorithm).unwrap();
+            assert!(decrypted == plaintext);
+        }
+    }
+}
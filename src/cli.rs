@@ -1,4 +1,16 @@
-use clap::{Parser, Subcommand, value_parser};
+use crate::{
+    archive::Compression,
+    cipher::Algorithm,
+    consts::{MAX_COMMENT_LENGTH, MAX_ENTRY_NAME_LENGTH, MAX_MOOD, TEMPLATES_DIR},
+    entries::MetadataFormat,
+};
+use clap::{Args, Parser, Subcommand, ValueEnum, builder::RangedU64ValueParser, value_parser};
+use clap_complete::{ArgValueCandidates, CompletionCandidate, Shell};
+use std::path::PathBuf;
+use time::{
+    Date, Duration, Month, OffsetDateTime, UtcOffset, format_description::well_known::Rfc3339,
+    macros::format_description,
+};
 
 #[derive(Parser)]
 #[command(name = env!("CARGO_PKG_NAME"))]
@@ -8,6 +20,47 @@ use clap::{Parser, Subcommand, value_parser};
 pub struct Cli {
     #[clap(subcommand)]
     pub command: Command,
+
+    /// Print the actions destructive commands would take without performing them
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
+    /// Display timestamps in UTC instead of local time
+    #[arg(long, global = true)]
+    pub no_local_time: bool,
+
+    /// Don't print messages about what was done, only results and errors
+    #[arg(long, short = 'q', global = true)]
+    pub quiet: bool,
+
+    /// Log each step to stderr, repeat for more detail
+    #[arg(long, short = 'v', global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Shell command whose output is the password of encrypted entries instead of prompting
+    #[arg(long, global = true, required = false, default_value = None)]
+    pub entry_password_command: Option<String>,
+
+    /// Largest entry file to load into memory, in bytes or with a K, M or G suffix
+    #[arg(long, global = true, default_value = "50M", value_parser = parse_size)]
+    pub max_entry_size: u64,
+
+    /// How to report the outcome of the command on stdout
+    #[arg(long, global = true, value_enum, default_value_t = OutputMode::Human)]
+    pub output: OutputMode,
+
+    /// Report the outcome as JSON, the same as `--output json`
+    #[arg(long, global = true, conflicts_with = "output")]
+    pub json: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum OutputMode {
+    /// Plain text as each command prints it
+    Human,
+    /// A single line JSON object with the outcome, everything the command printed, structured
+    /// results under `data` for commands that have them, and an error `code` on failure
+    Json,
 }
 
 #[derive(Subcommand, Clone)]
@@ -16,26 +69,651 @@ pub enum Command {
     New {
         /// Name for new diary
         name: String,
+
+        #[command(flatten)]
+        password: PasswordArgs,
+
+        /// Cipher to encrypt the diary with when it is closed
+        #[arg(long, value_enum, default_value_t = Algorithm::default())]
+        cipher: Algorithm,
+
+        /// Keep entry files in an `entries` subdirectory instead of next to diary.json
+        #[arg(long)]
+        entries_dir: bool,
+
+        /// Refuse passwords that are estimated to be weak instead of only warning about them
+        #[arg(long)]
+        require_strong: bool,
+
+        /// How to store the metadata of entries
+        #[arg(long, value_enum, default_value_t = MetadataFormat::Json)]
+        metadata: MetadataFormat,
     },
     /// Open a diary
     Open {
         /// Name of diary to open
         name: String,
+
+        #[command(flatten)]
+        password: PasswordArgs,
+
+        /// Number of password attempts before giving up
+        #[arg(long, default_value_t = 3, value_parser = value_parser!(u32).range(1..))]
+        tries: u32,
+
+        /// Overwrite the decrypted archive with random bytes before removing it. This is slow and
+        /// has no effect on SSDs or copy-on-write filesystems, which keep the old blocks
+        #[arg(long)]
+        secure_delete: bool,
+
+        /// Unpack into a RAM-backed directory ($XDG_RUNTIME_DIR or /dev/shm) linked from `{name}`,
+        /// so no plaintext is written to disk. The diary file is kept until the diary is closed.
+        /// Memory can still be swapped out, and the open diary is lost on reboot
+        #[arg(long)]
+        memory: bool,
+
+        /// Open the local diary file without pulling first, when the directory is synced with git
+        #[arg(long)]
+        no_sync: bool,
+
+        /// Diary file to use instead of `{name}.diary`
+        #[arg(long, short = 'f', required = false, default_value = None)]
+        file: Option<PathBuf>,
+    },
+    /// Open a diary in a temporary directory for one shell session, closing it again afterwards
+    Shell {
+        /// Name of diary to open
+        name: String,
+
+        #[command(flatten)]
+        password: PasswordArgs,
+
+        /// Command to run instead of an interactive $SHELL
+        #[arg(long, short = 'c', required = false, default_value = None)]
+        command: Option<String>,
+
+        /// Level of compression to use when closing, 0 stores files without compressing them
+        #[arg(long, short = 'L', required = false, default_value_t = 1, value_parser=value_parser!(u32).range(0..=9))]
+        level: u32,
+    },
+    /// List the diaries in a directory
+    List {
+        /// Directory to look for diaries in
+        #[arg(default_value = ".")]
+        dir: PathBuf,
+    },
+    /// Decrypt and unpack a diary while keeping the encrypted file
+    Extract {
+        /// Name of diary to extract
+        name: String,
+
+        #[command(flatten)]
+        password: PasswordArgs,
+
+        /// Keep the decrypted archive next to the diary
+        #[arg(long)]
+        keep_archive: bool,
+
+        /// Overwrite the decrypted archive with random bytes before removing it. This is slow and
+        /// has no effect on SSDs or copy-on-write filesystems, which keep the old blocks
+        #[arg(long)]
+        secure_delete: bool,
+    },
+    /// Print a single entry of a closed diary without unpacking the rest of it
+    Get {
+        /// Name of diary to read from
+        name: String,
+
+        /// Name of entry to print
+        entry: String,
+
+        #[command(flatten)]
+        password: PasswordArgs,
+
+        /// Write the entry to this file instead of stdout
+        #[arg(long, short = 'o', required = false, default_value = None)]
+        out_file: Option<PathBuf>,
+
+        /// Diary file to use instead of `{name}.diary`
+        #[arg(long, short = 'f', required = false, default_value = None)]
+        file: Option<PathBuf>,
+    },
+    /// Convert a diary written by the first versions of diary, which have no header, to the
+    /// current format
+    Migrate {
+        /// Name of diary to migrate
+        name: String,
+
+        #[command(flatten)]
+        password: PasswordArgs,
+
+        /// Diary file to use instead of `{name}.diary`
+        #[arg(long, short = 'f', required = false, default_value = None)]
+        file: Option<PathBuf>,
+    },
+    /// Salvage what can still be decrypted and unpacked from a damaged diary into
+    /// `{name}-recovered`, leaving the diary file in place
+    Recover {
+        /// Name of diary to recover
+        name: String,
+
+        #[command(flatten)]
+        password: PasswordArgs,
+
+        /// Overwrite the decrypted archive with random bytes before removing it. This is slow and
+        /// has no effect on SSDs or copy-on-write filesystems, which keep the old blocks
+        #[arg(long)]
+        secure_delete: bool,
+    },
+    /// Change the password of a closed diary
+    Passwd {
+        /// Name of diary to change the password of
+        name: String,
+
+        #[command(flatten)]
+        password: PasswordArgs,
+
+        /// Shell command whose output is used as the new password instead of prompting
+        #[arg(long, required = false, default_value = None)]
+        new_password_command: Option<String>,
+
+        /// Keyfile needed along with the new password, see --keyfile. Without it the new password
+        /// is enough on its own
+        #[arg(long, required = false, default_value = None)]
+        new_keyfile: Option<PathBuf>,
+
+        /// Refuse new passwords that are estimated to be weak instead of only warning about them
+        #[arg(long)]
+        require_strong: bool,
+
+        /// Diary file to use instead of `{name}.diary`
+        #[arg(long, short = 'f', required = false, default_value = None)]
+        file: Option<PathBuf>,
     },
     /// Close close a diary
     Close {
         /// Name of diary to close
         name: String,
 
-        /// Level of compression to use
-        #[arg(long, short = 'L', required = false, default_value_t = 1, value_parser=value_parser!(u32).range(1..=9))]
+        #[command(flatten)]
+        password: PasswordArgs,
+
+        /// Level of compression to use. With gzip 0 stores files without compressing them, with
+        /// zstd it picks zstd's default level of 3
+        #[arg(long, short = 'L', required = false, default_value_t = 1, value_parser=value_parser!(u32).range(0..=9))]
         level: u32,
+
+        /// Compression to use, recorded in the diary file so `open` picks the right decoder
+        #[arg(long, value_enum, default_value_t = Compression::Gzip)]
+        compression: Compression,
+
+        /// Finish a close that was interrupted, removing the directory if an existing diary file is
+        /// intact and holds everything in it
+        #[arg(long)]
+        resume: bool,
+
+        /// Number of threads to compress with, 0 compresses on the current thread [default: number of CPUs]
+        #[arg(long, short = 'T', required = false, default_value = None)]
+        threads: Option<usize>,
+
+        /// Only archive files matching this glob, may be repeated (diary.json is always kept)
+        #[arg(long, required = false)]
+        include: Vec<String>,
+
+        /// Leave files matching this glob out of the archive, may be repeated; they are deleted
+        /// along with the directory
+        #[arg(long, required = false)]
+        exclude: Vec<String>,
+
+        /// Store files with identical content only once, such as attachments shared by entries
+        #[arg(long)]
+        dedup: bool,
+
+        /// Cipher to encrypt the diary with, remembered for later closes [default: the diary's cipher]
+        #[arg(long, value_enum, required = false, default_value = None)]
+        cipher: Option<Algorithm>,
+
+        /// Write the diary file as a snapshot but keep the directory open, replacing any earlier
+        /// snapshot
+        #[arg(long, visible_alias = "snapshot", conflicts_with = "resume")]
+        keep_open: bool,
+
+        /// Label stored UNENCRYPTED in the diary file's header so it can be identified without the
+        /// password, remembered for later closes; an empty label removes it
+        #[arg(long, required = false, default_value = None, value_parser = parse_comment)]
+        comment: Option<String>,
+
+        /// Overwrite the decrypted archive and the files of the diary directory with random bytes
+        /// before removing them. This is slow and has no effect on SSDs or copy-on-write
+        /// filesystems, which keep the old blocks
+        #[arg(long)]
+        secure_delete: bool,
+
+        /// Leave the diary file uncommitted, when the directory is synced with git
+        #[arg(long)]
+        no_sync: bool,
+
+        /// Diary file to use instead of `{name}.diary`
+        #[arg(long, short = 'f', required = false, default_value = None)]
+        file: Option<PathBuf>,
+    },
+    /// Show the unencrypted header of a closed diary without asking for its password
+    Peek {
+        /// Name of diary to peek at
+        name: String,
+
+        /// Diary file to use instead of `{name}.diary`
+        #[arg(long, short = 'f', required = false, default_value = None)]
+        file: Option<PathBuf>,
+    },
+    /// Rename a closed or open diary
+    Rename {
+        /// Current name of diary
+        old: String,
+
+        /// New name for diary
+        new: String,
+    },
+    /// Copy a closed diary into another directory under a timestamped name
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+    Backup {
+        /// Name of diary to back up
+        #[arg(required = true)]
+        name: Option<String>,
+
+        /// Directory to place the backup in
+        #[arg(required = true)]
+        dest: Option<PathBuf>,
+
+        /// Only keep this many of the most recent backups in the destination
+        #[arg(long, short = 'k', required = false, default_value = None, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+        keep: Option<usize>,
+
+        #[clap(subcommand)]
+        backup_command: Option<BackupCommand>,
+    },
+    /// Copy every entry of one open diary into another
+    Merge {
+        /// Directory of the open diary to merge into
+        into: PathBuf,
+
+        /// Directory of the open diary to copy entries from
+        from: PathBuf,
+    },
+    /// Move a single entry from one open diary to another
+    MoveEntry {
+        /// Name of entry to move
+        name: String,
+
+        /// Directory of the open diary to move the entry out of
+        from: PathBuf,
+
+        /// Directory of the open diary to move the entry into
+        to: PathBuf,
+    },
+    /// Compare the entries of two closed diaries, such as a diary and one of its backups
+    Diff {
+        /// Older diary file
+        a: PathBuf,
+
+        /// Newer diary file
+        b: PathBuf,
+
+        #[command(flatten)]
+        password: PasswordArgs,
+
+        /// Ask for one password and use it for both diaries
+        #[arg(long)]
+        same_password: bool,
+
+        /// Show the changed lines of modified entries
+        #[arg(long, short = 'c')]
+        content: bool,
+    },
+    /// Show the current and longest run of consecutive days with entries
+    Streak,
+    /// Open today's entry of the open diary in the current directory in $VISUAL or $EDITOR,
+    /// creating it first if needed. It is named after the date, like 2024-03-01
+    Today {
+        /// Editor command to use instead of $VISUAL or $EDITOR
+        #[arg(long, required = false, default_value = None)]
+        editor: Option<String>,
+
+        /// Template to seed the entry with when it is created, as for entry add --template
+        #[arg(long, required = false, default_value = None, add = ArgValueCandidates::new(template_names))]
+        template: Option<String>,
+
+        /// File extension for the entry's file when it is created
+        #[arg(long, default_value = "md", value_parser = parse_ext)]
+        ext: String,
+
+        /// Leave the entry as the editor wrote it instead of tidying its markdown
+        #[arg(long)]
+        no_format: bool,
+    },
+    /// Report whether the open diary in the current directory changed since it was last closed
+    Status,
+    /// Summarize the entries of the open diary in the current directory
+    Info {
+        /// Output format
+        #[arg(long, value_enum, default_value_t = Format::Text)]
+        format: Format,
+    },
+    /// Report entries and words per month of the open diary in the current directory, with its
+    /// longest and shortest entries
+    Report {
+        /// Output format
+        #[arg(long, value_enum, default_value_t = Format::Text)]
+        format: Format,
+    },
+    /// Show writing statistics of the open diary in the current directory: entries and words
+    /// overall and per month, streaks and the most used tags
+    Stats {
+        /// How many of the most used tags to show
+        #[arg(long, default_value_t = 5)]
+        top_tags: usize,
+
+        /// Show the average mood of rated entries per month instead, with how it changed
+        #[arg(long, conflicts_with = "top_tags")]
+        mood: bool,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t = Format::Text)]
+        format: Format,
+    },
+    /// Rename the entry files of the open diary in the current directory after their entry names
+    Normalize,
+    /// Print the entry names of the diary in the current directory, for shell completion
+    #[command(hide = true)]
+    CompleteEntries,
+    /// Print a script that sets up tab completion for a shell, completing entry names inside an
+    /// open diary. For bash, add `source <(diary completions bash)` to ~/.bashrc
+    Completions {
+        /// Shell to complete in
+        shell: Shell,
     },
     /// Manipulate entries
     Entry {
         #[clap(subcommand)]
         entry_command: EntryCommand,
     },
+    /// Write a diary out in another format
+    Export {
+        #[clap(subcommand)]
+        export_command: ExportCommand,
+    },
+    /// Keep the closed diaries of the current directory in sync with a remote copy
+    Sync {
+        #[clap(subcommand)]
+        sync_command: SyncCommand,
+    },
+    /// Add the entries of another journaling tool to the open diary in the current directory
+    Import {
+        #[clap(subcommand)]
+        import_command: ImportCommand,
+    },
+    /// Browse, search, add, edit and delete the entries of the open diary in the current directory
+    /// in a full screen interface
+    Tui {
+        /// Editor to edit entries with instead of $VISUAL or $EDITOR
+        #[arg(long, required = false, default_value = None)]
+        editor: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Clone)]
+pub enum ExportCommand {
+    /// Render every entry to a static HTML site with an index, a page per tag and an archive by
+    /// month
+    Html {
+        /// Name of diary to export, open or closed
+        name: String,
+
+        /// Directory to write the site into
+        #[arg(long, short = 'o')]
+        out: PathBuf,
+
+        /// Write into `out` even if it already has files in it, replacing pages of the same name
+        #[arg(long)]
+        force: bool,
+
+        #[command(flatten)]
+        password: PasswordArgs,
+
+        /// Diary file to use instead of `{name}.diary` when the diary is closed
+        #[arg(long, short = 'f', required = false, default_value = None)]
+        file: Option<PathBuf>,
+    },
+    /// Write the name, id, times, tags and path of every entry as JSON or CSV
+    Metadata {
+        /// Name of diary to export, open or closed
+        name: String,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+
+        /// File to write to instead of stdout
+        #[arg(long, short = 'o', required = false, default_value = None)]
+        out: Option<PathBuf>,
+
+        /// Replace `out` if it already exists
+        #[arg(long, requires = "out")]
+        force: bool,
+
+        #[command(flatten)]
+        password: PasswordArgs,
+
+        /// Diary file to use instead of `{name}.diary` when the diary is closed
+        #[arg(long, short = 'f', required = false, default_value = None)]
+        file: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Clone)]
+pub enum BackupCommand {
+    /// Back diary files up to S3-compatible storage or WebDAV servers
+    Remote {
+        #[clap(subcommand)]
+        remote_command: RemoteCommand,
+    },
+}
+
+#[derive(Subcommand, Clone)]
+pub enum RemoteCommand {
+    /// Add a backup target, remembered in remotes.json in the configuration directory
+    Add {
+        #[clap(subcommand)]
+        target: NewTarget,
+    },
+    /// Forget a backup target, leaving the backups already on it
+    Remove {
+        /// Name of target to remove
+        name: String,
+    },
+    /// List the backup targets
+    List,
+    /// Upload a closed diary to every backup target, or to one
+    Push {
+        /// Name of diary to back up
+        name: String,
+
+        /// Only upload to this target
+        #[arg(long, short = 't', required = false, default_value = None)]
+        target: Option<String>,
+
+        /// Diary file to use instead of `{name}.diary`
+        #[arg(long, short = 'f', required = false, default_value = None)]
+        file: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Clone)]
+pub enum NewTarget {
+    /// A bucket of S3 or another S3-compatible service, addressed by path
+    S3 {
+        /// Name to refer to the target by
+        name: String,
+
+        /// Endpoint URL of the service, such as https://s3.eu-west-1.amazonaws.com
+        #[arg(long)]
+        endpoint: String,
+
+        /// Bucket to upload into
+        #[arg(long)]
+        bucket: String,
+
+        /// Region of the bucket
+        #[arg(long, default_value = "us-east-1")]
+        region: String,
+
+        /// Access key ID
+        #[arg(long)]
+        access_key: String,
+
+        /// Shell command whose output is the secret access key, which is never stored
+        #[arg(long)]
+        secret_command: String,
+
+        /// Prefix for the name of every backup, such as `diaries/`
+        #[arg(long, default_value = "")]
+        prefix: String,
+
+        #[command(flatten)]
+        options: RemoteOptions,
+    },
+    /// A collection on a WebDAV server, such as a Nextcloud folder
+    Webdav {
+        /// Name to refer to the target by
+        name: String,
+
+        /// URL of the collection to upload into
+        #[arg(long)]
+        url: String,
+
+        /// User to sign in as with basic authentication
+        #[arg(long, required = false, default_value = None)]
+        username: Option<String>,
+
+        /// Shell command whose output is the password, which is never stored
+        #[arg(long, required = false, default_value = None, requires = "username")]
+        password_command: Option<String>,
+
+        #[command(flatten)]
+        options: RemoteOptions,
+    },
+}
+
+#[derive(Args, Clone)]
+pub struct RemoteOptions {
+    /// Only keep this many of the most recent backups of each diary on the target
+    #[arg(long, short = 'k', required = false, default_value = None, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub keep: Option<usize>,
+
+    /// Upload the diary file written by every close to this target
+    #[arg(long)]
+    pub on_close: bool,
+}
+
+#[derive(Subcommand, Clone)]
+pub enum SyncCommand {
+    /// Version the encrypted diary files in a git repository. Once set up, `close` commits the
+    /// diary file and pushes it, and `open` pulls before decrypting
+    Git {
+        #[clap(subcommand)]
+        git_command: GitCommand,
+    },
+}
+
+#[derive(Subcommand, Clone)]
+pub enum GitCommand {
+    /// Make the current directory a repository that tracks only its diary files. To sync with a
+    /// remote that already has them, clone it and run init inside the clone
+    Init {
+        /// Repository to push to and pull from
+        #[arg(long, required = false, default_value = None)]
+        remote: Option<String>,
+    },
+    /// Commit the diary files and push them to the remote
+    Push,
+    /// Pull the diary files from the remote, rebasing local commits onto them
+    Pull,
+}
+
+#[derive(Subcommand, Clone)]
+pub enum ImportCommand {
+    /// Import a jrnl journal, either its plain text journal file or the output of
+    /// `jrnl --export json`, as one entry per jrnl entry with its date, tags and star
+    Jrnl {
+        /// Journal file or JSON export to import
+        file: PathBuf,
+
+        /// File extension for the new entries
+        #[arg(long, default_value = "md", value_parser = parse_ext)]
+        ext: String,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+/// Formats for entry metadata meant for other programs
+#[derive(ValueEnum, Clone, Copy)]
+pub enum ExportFormat {
+    /// An array with an object per entry
+    Json,
+    /// A header row and a row per entry, with tags separated by `;`
+    Csv,
+}
+
+impl Command {
+    /// Name of the diary file the command works on, for commands that take one
+    pub fn diary_name(&self) -> Option<&str> {
+        match self {
+            Command::New { name, .. }
+            | Command::Open { name, .. }
+            | Command::Shell { name, .. }
+            | Command::Extract { name, .. }
+            | Command::Get { name, .. }
+            | Command::Migrate { name, .. }
+            | Command::Recover { name, .. }
+            | Command::Passwd { name, .. }
+            | Command::Close { name, .. }
+            | Command::Peek { name, .. }
+            | Command::Backup {
+                name: Some(name), ..
+            } => Some(name),
+            Command::Rename { old, .. } => Some(old),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Args, Clone)]
+pub struct PasswordArgs {
+    /// Shell command whose output is used as the password instead of prompting
+    #[arg(long, required = false, default_value = None)]
+    pub password_command: Option<String>,
+
+    /// File whose first line is used as the password instead of prompting
+    #[arg(long, required = false, default_value = None, conflicts_with = "password_command")]
+    pub password_file: Option<PathBuf>,
+
+    /// Refuse a --password-file that other users can read, instead of warning about it
+    #[arg(long, requires = "password_file")]
+    pub strict_perms: bool,
+
+    /// File whose contents are needed along with the password, such as one kept on removable
+    /// media. Its SHA-256 digest is combined with the password, so the file must never change
+    #[arg(long, required = false, default_value = None)]
+    pub keyfile: Option<PathBuf>,
+
+    /// Use the keyfile alone, without a password
+    #[arg(long, requires = "keyfile", conflicts_with_all = ["password_command", "password_file"])]
+    pub no_password: bool,
 }
 
 #[derive(Subcommand, Clone)]
@@ -43,6 +721,7 @@ pub enum EntryCommand {
     /// Add an entry
     Add {
         /// Name for entry
+        #[arg(value_parser = parse_entry_name)]
         name: String,
 
         /// Optional description
@@ -52,17 +731,620 @@ pub enum EntryCommand {
         /// Optional location
         #[arg(long, short = 'L', required = false, default_value = None)]
         location: Option<String>,
+
+        /// Date for entry (RFC3339 or YYYY-MM-DD), defaults to now
+        #[arg(long, short = 'd', required = false, default_value = None, value_parser = parse_date)]
+        date: Option<OffsetDateTime>,
+
+        /// Tag to attach to entry, may be repeated
+        #[arg(long = "tag", short = 't', required = false)]
+        tags: Vec<String>,
+
+        /// Keep the entry's file encrypted while the diary is open, with the entry password,
+        /// which is chosen the first time an entry is encrypted
+        #[arg(long, short = 'e')]
+        encrypt: bool,
+
+        /// File extension for the entry's file, such as md, txt or org
+        #[arg(long, default_value = "md", value_parser = parse_ext)]
+        ext: String,
+
+        /// Commit the new entry when the diary directory is a git repository
+        #[arg(long)]
+        commit: bool,
+
+        /// Open the new entry in $VISUAL or $EDITOR once it is created
+        #[arg(long)]
+        edit: bool,
+        /// Editor command to use instead of $VISUAL or $EDITOR
+        #[arg(long, required = false, default_value = None, requires = "edit")]
+        editor: Option<String>,
+
+        /// Leave the entry as the editor wrote it instead of tidying its markdown
+        #[arg(long, requires = "edit")]
+        no_format: bool,
+
+        /// How you feel, from 1, the worst, to 5, the best
+        #[arg(long, value_parser = parse_mood)]
+        mood: Option<i8>,
+
+        /// Template to seed the new entry with, a file in the diary's templates directory named
+        /// with or without its extension, or the path of any other file. {{name}}, {{title}},
+        /// {{id}}, {{date}} and {{time}} are replaced with the entry's values
+        #[arg(long, required = false, default_value = None, add = ArgValueCandidates::new(template_names))]
+        template: Option<String>,
+    },
+    /// Open an existing entry in $VISUAL or $EDITOR
+    Edit {
+        /// Name of entry to edit, picked interactively if omitted
+        #[arg(add = ArgValueCandidates::new(entry_names))]
+        name: Option<String>,
+
+        /// Editor command to use instead of $VISUAL or $EDITOR
+        #[arg(long, required = false, default_value = None)]
+        editor: Option<String>,
+
+        /// Leave the entry as the editor wrote it instead of tidying its markdown
+        #[arg(long)]
+        no_format: bool,
+
+        /// Commit the edited entry when the diary directory is a git repository
+        #[arg(long)]
+        commit: bool,
+    },
+    /// Encrypt an entry's file with the entry password so it stays protected while the diary is
+    /// open. The password is chosen the first time, and has to differ from the diary password
+    Encrypt {
+        /// Name of entry to encrypt
+        #[arg(add = ArgValueCandidates::new(entry_names))]
+        name: String,
+    },
+    /// Decrypt an entry's file back to plain text
+    Decrypt {
+        /// Name of entry to decrypt
+        #[arg(add = ArgValueCandidates::new(entry_names))]
+        name: String,
+    },
+    /// Add or remove tags on an entry
+    Tag {
+        #[clap(subcommand)]
+        tag_command: TagCommand,
+    },
+    /// Append a line of text to an entry without opening an editor
+    Append {
+        /// Name of entry to append to
+        #[arg(add = ArgValueCandidates::new(entry_names))]
+        name: String,
+
+        /// Text to append, read from stdin if omitted
+        text: Option<String>,
+
+        /// Start a section headed with the current time before the text
+        #[arg(long, short = 't')]
+        timestamp: bool,
+    },
+    /// Print the content of an entry
+    Show {
+        /// Name of entry to print, picked interactively if omitted
+        #[arg(add = ArgValueCandidates::new(entry_names))]
+        name: Option<String>,
+
+        /// Start with the entry's metadata as YAML front-matter, like export
+        #[arg(long, short = 'm')]
+        metadata: bool,
+    },
+    /// Write an entry to a file with YAML front-matter, for static-site generators
+    Export {
+        /// Name of entry to export
+        #[arg(add = ArgValueCandidates::new(entry_names))]
+        name: String,
+
+        /// File to write the entry to
+        out: PathBuf,
+
+        /// Overwrite `out` if it already exists
+        #[arg(long, short = 'f')]
+        force: bool,
+    },
+    /// Show the git commits that changed an entry, when the diary directory is a git repository
+    History {
+        /// Name of entry to show the history of
+        #[arg(add = ArgValueCandidates::new(entry_names))]
+        name: String,
+    },
+    /// Tidy an entry's markdown: trim trailing whitespace and end it with a single newline
+    Format {
+        /// Name of entry to format
+        #[arg(add = ArgValueCandidates::new(entry_names))]
+        name: String,
+
+        /// Also break lines longer than this many characters, outside code blocks and tables
+        #[arg(long, short = 'w', required = false, default_value = None, value_parser = value_parser!(u64).range(1..))]
+        wrap: Option<u64>,
+    },
+    /// Rate how you felt in an entry from 1, the worst, to 5, the best, or show its rating
+    Mood {
+        /// Name of entry to rate
+        #[arg(add = ArgValueCandidates::new(entry_names))]
+        name: String,
+
+        /// Rating to give the entry, prints the current rating when left out
+        #[arg(value_parser = parse_mood)]
+        mood: Option<i8>,
+
+        /// Remove the entry's rating
+        #[arg(long, conflicts_with = "mood")]
+        clear: bool,
+    },
+    /// Change the date of an entry
+    Touch {
+        /// Name of entry to change
+        #[arg(add = ArgValueCandidates::new(entry_names))]
+        name: String,
+
+        /// New date for entry (RFC3339 or YYYY-MM-DD), defaults to now
+        #[arg(value_parser = parse_date)]
+        date: Option<OffsetDateTime>,
+    },
+    /// Copy an entry and its content under a new name
+    Duplicate {
+        /// Name of entry to copy
+        #[arg(add = ArgValueCandidates::new(entry_names))]
+        source: String,
+
+        /// Name for the copy
+        #[arg(value_parser = parse_entry_name)]
+        new: String,
     },
-    /// Remove an entry
+    /// Copy a file such as a photo or PDF into the diary and attach it to an entry
+    Attach {
+        /// Name of entry to attach the file to
+        #[arg(add = ArgValueCandidates::new(entry_names))]
+        name: String,
+
+        /// File to copy into the diary's attachments directory
+        file: PathBuf,
+    },
+    /// List the files attached to an entry, relative to the diary root
+    Attachments {
+        /// Name of entry to list the attachments of
+        #[arg(add = ArgValueCandidates::new(entry_names))]
+        name: String,
+    },
+    /// Move an entry to the trash
     Remove {
-        /// Name of entry to remove
+        /// Name of entry to remove, picked interactively if omitted
+        #[arg(add = ArgValueCandidates::new(entry_names))]
+        name: Option<String>,
+    },
+    /// Restore an entry from the trash
+    Restore {
+        /// Name of trashed entry to restore
         name: String,
     },
-    /// List entries
-    List,
+    /// Permanently delete every entry in the trash
+    EmptyTrash,
+    /// Pin an entry so it is listed first
+    Pin {
+        /// Name of entry to pin
+        #[arg(add = ArgValueCandidates::new(entry_names))]
+        name: String,
+    },
+    /// Unpin an entry
+    Unpin {
+        /// Name of entry to unpin
+        #[arg(add = ArgValueCandidates::new(entry_names))]
+        name: String,
+    },
+    /// Archive an entry so it is hidden from listings and searches
+    Archive {
+        /// Name of entry to archive
+        #[arg(add = ArgValueCandidates::new(entry_names))]
+        name: String,
+    },
+    /// Bring an archived entry back into listings and searches
+    Unarchive {
+        /// Name of entry to unarchive
+        #[arg(add = ArgValueCandidates::new(entry_names))]
+        name: String,
+    },
+    /// Record that one entry links to another
+    Link {
+        /// Name of entry to link from
+        #[arg(add = ArgValueCandidates::new(entry_names))]
+        from: String,
+
+        /// Name of entry to link to
+        #[arg(add = ArgValueCandidates::new(entry_names))]
+        to: String,
+    },
+    /// List the entries that link to an entry
+    Backlinks {
+        /// Name of entry to find links to
+        #[arg(add = ArgValueCandidates::new(entry_names))]
+        name: String,
+    },
+    /// List entries, pinned entries first
+    List {
+        /// Only print entry names, one per line
+        #[arg(long, short = 'n')]
+        names_only: bool,
+
+        /// Only list pinned entries
+        #[arg(long, short = 'p')]
+        pinned_only: bool,
+
+        /// Only print the number of entries that would be listed
+        #[arg(long, short = 'c', conflicts_with = "names_only")]
+        count: bool,
+
+        /// Only list entries with this tag, may be repeated to require several
+        #[arg(long = "tag", short = 't', required = false)]
+        tags: Vec<String>,
+
+        #[command(flatten)]
+        archived: ArchivedArgs,
+
+        #[command(flatten)]
+        dates: DateRangeArgs,
+
+        /// Order to list entries in, pinned entries always coming first
+        #[arg(long, value_enum, default_value_t = SortOrder::Created)]
+        sort: SortOrder,
+
+        /// List entries in the opposite order
+        #[arg(long, short = 'r')]
+        reverse: bool,
+
+        /// Print the metadata of the entries as JSON or CSV instead of as text
+        #[arg(long, value_enum, conflicts_with_all = ["names_only", "count"])]
+        format: Option<ExportFormat>,
+    },
     /// Search for entries by their name
     Search {
         /// Search through entry names, locations, and descriptions to find matches
         query: String,
+
+        /// Only print the number of matching entries
+        #[arg(long, short = 'c')]
+        count: bool,
+
+        /// Only match entries with this tag, may be repeated to require several
+        #[arg(long = "tag", short = 't', required = false)]
+        tags: Vec<String>,
+
+        #[command(flatten)]
+        archived: ArchivedArgs,
+
+        #[command(flatten)]
+        dates: DateRangeArgs,
+    },
+    /// Search the contents of entries, printing each matching line with its line number
+    Grep {
+        /// Text to look for, ignoring case
+        query: String,
+
+        /// Also print this many lines before and after each matching line
+        #[arg(long, short = 'C', default_value_t = 0)]
+        context: usize,
+
+        #[command(flatten)]
+        archived: ArchivedArgs,
+    },
+}
+
+#[derive(Subcommand, Clone)]
+pub enum TagCommand {
+    /// Add tags to an entry
+    Add {
+        /// Name of entry to tag
+        #[arg(add = ArgValueCandidates::new(entry_names))]
+        name: String,
+
+        /// Tags to add
+        #[arg(required = true)]
+        tags: Vec<String>,
+    },
+    /// Remove tags from an entry
+    Remove {
+        /// Name of entry to untag
+        #[arg(add = ArgValueCandidates::new(entry_names))]
+        name: String,
+
+        /// Tags to remove
+        #[arg(required = true)]
+        tags: Vec<String>,
+    },
+    /// List the tags of an entry, or every tag in the diary with how many entries have it
+    List {
+        /// Name of entry to list the tags of
+        #[arg(add = ArgValueCandidates::new(entry_names))]
+        name: Option<String>,
     },
 }
+
+#[derive(Args, Clone, Copy)]
+pub struct ArchivedArgs {
+    /// Also show archived entries
+    #[arg(long, short = 'a')]
+    pub include_archived: bool,
+
+    /// Only show archived entries
+    #[arg(long, conflicts_with = "include_archived")]
+    pub archived_only: bool,
+}
+
+impl ArchivedArgs {
+    /// Whether an entry that is or isn't archived should be shown
+    pub fn shows(&self, archived: bool) -> bool {
+        if self.archived_only {
+            archived
+        } else {
+            self.include_archived || !archived
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum SortOrder {
+    /// By entry name
+    Name,
+    /// By timestamp, oldest first
+    Created,
+    /// By when the entry was last edited, or else its timestamp, least recent first
+    Modified,
+}
+
+#[derive(Args, Clone, Copy)]
+pub struct DateRangeArgs {
+    /// Only show entries from this time on: a YYYY-MM-DD date, a YYYY-MM month, an RFC3339
+    /// timestamp, a span back from now like 7d or 2w, or today, yesterday, this-week,
+    /// last-week, this-month, last-month, this-year or last-year
+    #[arg(long, value_parser = parse_period)]
+    pub since: Option<Period>,
+
+    /// Only show entries up to the end of this time, given like --since
+    #[arg(long, value_parser = parse_period)]
+    pub until: Option<Period>,
+}
+
+impl DateRangeArgs {
+    /// Whether an entry with `timestamp` falls within the range
+    pub fn contains(&self, timestamp: OffsetDateTime) -> bool {
+        self.since.is_none_or(|since| timestamp >= since.start)
+            && self.until.is_none_or(|until| timestamp < until.end)
+    }
+}
+
+/// Span of time a date given on the command line stands for, from `start` up to but not
+/// including `end`
+#[derive(Clone, Copy)]
+pub struct Period {
+    pub start: OffsetDateTime,
+    pub end: OffsetDateTime,
+}
+
+/// Names of the entries of the open diary in the current directory, or none outside of one
+fn entry_names() -> Vec<CompletionCandidate> {
+    let Ok(entries) = crate::load_entries(std::path::Path::new(".")) else {
+        return Vec::new();
+    };
+
+    let mut names = entries.entries.into_keys().collect::<Vec<_>>();
+    names.sort();
+    names.into_iter().map(CompletionCandidate::new).collect()
+}
+
+fn template_names() -> Vec<CompletionCandidate> {
+    let Ok(dir) = std::fs::read_dir(TEMPLATES_DIR) else {
+        return Vec::new();
+    };
+
+    let mut names = dir
+        .flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect::<Vec<_>>();
+    names.sort();
+    names.into_iter().map(CompletionCandidate::new).collect()
+}
+
+pub fn parse_entry_name(s: &str) -> Result<String, String> {
+    if s.trim().is_empty() {
+        return Err("entry names cannot be empty".to_string());
+    }
+
+    if s.chars().count() > MAX_ENTRY_NAME_LENGTH {
+        return Err(format!(
+            "entry names can be at most {MAX_ENTRY_NAME_LENGTH} characters long"
+        ));
+    }
+
+    if s.chars().any(char::is_control) {
+        return Err("entry names cannot contain newlines or other control characters".to_string());
+    }
+
+    Ok(s.to_string())
+}
+
+fn parse_comment(s: &str) -> Result<String, String> {
+    if s.len() > MAX_COMMENT_LENGTH {
+        return Err(format!(
+            "comments can be at most {MAX_COMMENT_LENGTH} bytes long"
+        ));
+    }
+
+    if s.chars().any(char::is_control) {
+        return Err("comments cannot contain newlines or other control characters".to_string());
+    }
+
+    Ok(s.to_string())
+}
+
+fn parse_mood(s: &str) -> Result<i8, String> {
+    s.parse::<i8>()
+        .ok()
+        .filter(|mood| (1..=MAX_MOOD).contains(mood))
+        .ok_or_else(|| format!("expected a mood from 1 to {MAX_MOOD}, got {s}"))
+}
+
+fn parse_ext(s: &str) -> Result<String, String> {
+    let ext = s.strip_prefix('.').unwrap_or(s);
+
+    if ext.is_empty() || !ext.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(format!("{s} is not a valid file extension"));
+    }
+
+    Ok(ext.to_string())
+}
+
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1024),
+        Some((i, 'm' | 'M')) => (&s[..i], 1024 * 1024),
+        Some((i, 'g' | 'G')) => (&s[..i], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("expected a size in bytes or with a K, M or G suffix, got {s}"))
+}
+
+fn parse_period(s: &str) -> Result<Period, String> {
+    if let Ok(timestamp) = OffsetDateTime::parse(s, &Rfc3339) {
+        return Ok(Period {
+            start: timestamp,
+            end: timestamp + Duration::NANOSECOND,
+        });
+    }
+
+    let now = OffsetDateTime::now_utc();
+    let today = now
+        .to_offset(UtcOffset::local_offset_at(now).unwrap_or(UtcOffset::UTC))
+        .date();
+    let month_start = |date: Date| date.replace_day(1).expect("every month has a first day");
+    let next_month = |date: Date| month_start(date) + Duration::days(32);
+    let year_start = |date: Date| {
+        month_start(date)
+            .replace_month(Month::January)
+            .expect("every year has a January")
+    };
+    let week_start =
+        |date: Date| date - Duration::days(date.weekday().number_days_from_monday().into());
+
+    let (first, last) = match s {
+        "today" => (today, today),
+        "yesterday" => (today - Duration::DAY, today - Duration::DAY),
+        "this-week" => (week_start(today), week_start(today) + Duration::days(6)),
+        "last-week" => {
+            let start = week_start(today) - Duration::WEEK;
+            (start, start + Duration::days(6))
+        }
+        "this-month" => (
+            month_start(today),
+            month_start(next_month(today)) - Duration::DAY,
+        ),
+        "last-month" => {
+            let start = month_start(month_start(today) - Duration::DAY);
+            (start, month_start(today) - Duration::DAY)
+        }
+        "this-year" => {
+            let start = year_start(today);
+            let next_year = start
+                .replace_year(start.year() + 1)
+                .map_err(|e| e.to_string())?;
+            (start, next_year - Duration::DAY)
+        }
+        "last-year" => {
+            let end = year_start(today) - Duration::DAY;
+            (year_start(end), end)
+        }
+        _ => {
+            if let Some(span) = parse_span(s)? {
+                let start = now
+                    .checked_sub(span)
+                    .ok_or_else(|| format!("span {s} reaches too far back"))?;
+                return Ok(Period { start, end: start });
+            }
+
+            if let Ok(date) = Date::parse(s, format_description!("[year]-[month]-[day]")) {
+                (date, date)
+            } else if let Ok(date) = Date::parse(
+                &format!("{s}-01"),
+                format_description!("[year]-[month]-[day]"),
+            ) {
+                (date, month_start(next_month(date)) - Duration::DAY)
+            } else {
+                return Err(format!(
+                    "expected a date, month, RFC3339 timestamp, span like 7d or a name like last-month, got {s}"
+                ));
+            }
+        }
+    };
+
+    let local_midnight = |date: Date| {
+        let midnight = date.midnight();
+        midnight.assume_offset(
+            UtcOffset::local_offset_at(midnight.assume_utc()).unwrap_or(UtcOffset::UTC),
+        )
+    };
+    Ok(Period {
+        start: local_midnight(first),
+        end: local_midnight(last + Duration::DAY),
+    })
+}
+
+/// A span back from now such as `7d` or `2w`, or `None` if `s` doesn't look like one
+fn parse_span(s: &str) -> Result<Option<Duration>, String> {
+    let Some((count, unit)) = s.len().checked_sub(1).and_then(|i| s.split_at_checked(i)) else {
+        return Ok(None);
+    };
+    let seconds_per_unit: i64 = match unit {
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Ok(None),
+    };
+    let Ok(count) = count.parse::<i64>() else {
+        return Ok(None);
+    };
+
+    if count < 0 {
+        return Err(format!("spans cannot be negative, got {s}"));
+    }
+
+    count
+        .checked_mul(seconds_per_unit)
+        .map(|seconds| Some(Duration::seconds(seconds)))
+        .ok_or_else(|| format!("span {s} is too long"))
+}
+
+fn parse_date(s: &str) -> Result<OffsetDateTime, String> {
+    if let Ok(timestamp) = OffsetDateTime::parse(s, &Rfc3339) {
+        return Ok(timestamp);
+    }
+
+    let date = Date::parse(s, format_description!("[year]-[month]-[day]"))
+        .map_err(|_| format!("expected an RFC3339 timestamp or YYYY-MM-DD date, got {s}"))?;
+    let offset =
+        UtcOffset::local_offset_at(date.midnight().assume_utc()).map_err(|e| e.to_string())?;
+
+    Ok(date.midnight().assume_offset(offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_names_are_checked() {
+        assert!(parse_entry_name(&"a".repeat(1000)).is_err());
+        assert!(parse_entry_name("first line\nsecond line").is_err());
+        assert!(parse_entry_name("  ").is_err());
+
+        assert_eq!(parse_entry_name("日記 ☀️").unwrap(), "日記 ☀️");
+        let longest = "é".repeat(MAX_ENTRY_NAME_LENGTH);
+        assert_eq!(parse_entry_name(&longest).unwrap(), longest);
+    }
+}
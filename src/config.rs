@@ -0,0 +1,120 @@
+//! Defaults from `config.toml` in the configuration directory, which flags given on the command
+//! line override
+
+use crate::cipher::KdfParams;
+use color_eyre::eyre::{Context, bail};
+use serde::Deserialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+use time::format_description::{self, OwnedFormatItem};
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Editor for entries when --editor isn't given, used before $VISUAL and $EDITOR
+    pub editor: Option<String>,
+    /// Compression level for `close` and `shell`
+    pub level: Option<u32>,
+    /// Diary for commands given no diary name, a name or a path without `.diary`
+    pub diary: Option<String>,
+    /// How timestamps are displayed, as a `time` format description like
+    /// `[year]-[month]-[day] [hour]:[minute]`
+    pub time_format: Option<String>,
+    /// Argon2id costs for the keys of diary files written from now on
+    pub argon2: Option<Argon2>,
+
+    #[serde(skip)]
+    time_format_items: Option<OwnedFormatItem>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Argon2 {
+    /// Memory in KiB
+    pub memory: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Config {
+    /// Read the configuration, or the defaults if there is no configuration file
+    pub fn load() -> color_eyre::Result<Self> {
+        let path = dir()?.join("config.toml");
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+
+        let mut config: Self =
+            toml::from_str(&text).with_context(|| format!("Failed to parse {}", path.display()))?;
+
+        if config.level.is_some_and(|level| level > 9) {
+            bail!("level in {} has to be between 0 and 9", path.display());
+        }
+        if let Some(argon2) = &config.argon2 {
+            argon2::Params::new(argon2.memory, argon2.iterations, argon2.parallelism, None)
+                .with_context(|| format!("Invalid argon2 costs in {}", path.display()))?;
+        }
+        if let Some(time_format) = &config.time_format {
+            config.time_format_items = Some(
+                format_description::parse_owned::<2>(time_format)
+                    .with_context(|| format!("Invalid time_format in {}", path.display()))?,
+            );
+        }
+        if let Some(diary) = &mut config.diary {
+            *diary = expand_home(diary);
+        }
+
+        Ok(config)
+    }
+
+    /// Make this the configuration `get` returns
+    pub fn install(self) {
+        let _ = CONFIG.set(self);
+    }
+
+    /// Argon2id costs for new diary files, the defaults unless the configuration sets them
+    pub fn kdf(&self) -> KdfParams {
+        self.argon2
+            .as_ref()
+            .map_or_else(KdfParams::default, |argon2| KdfParams {
+                memory: argon2.memory,
+                iterations: argon2.iterations,
+                parallelism: argon2.parallelism,
+            })
+    }
+
+    pub fn time_format(&self) -> Option<&OwnedFormatItem> {
+        self.time_format_items.as_ref()
+    }
+}
+
+/// The installed configuration, or the defaults before one is installed
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+/// Directory for diary's own configuration, `$XDG_CONFIG_HOME/diary` or else `~/.config/diary`
+pub fn dir() -> color_eyre::Result<PathBuf> {
+    if let Some(config) = std::env::var_os("XDG_CONFIG_HOME").filter(|c| !c.is_empty()) {
+        return Ok(PathBuf::from(config).join("diary"));
+    }
+
+    let Some(home) = std::env::var_os("HOME").filter(|h| !h.is_empty()) else {
+        bail!("Neither $XDG_CONFIG_HOME nor $HOME is set");
+    };
+    Ok(PathBuf::from(home).join(".config").join("diary"))
+}
+
+fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => Path::new(&home).join(rest).to_string_lossy().into_owned(),
+        _ => path.to_string(),
+    }
+}
@@ -1,5 +1,40 @@
+/// Start of every diary file, so other files are rejected before anything is decrypted
+pub const MAGIC: &[u8; 5] = b"DIARY";
+/// Version of the diary file layout, raised whenever the header or stream changes
+pub const FORMAT_VERSION: u8 = 3;
+/// Longest plain text comment a diary file's header can hold, in bytes
+pub const MAX_COMMENT_LENGTH: usize = 1024;
 pub const KEY_LENGTH: usize = 32;
 pub const SALT_LENGTH: usize = 32;
+/// Stream nonce, the cipher's 12 byte nonce minus the 5 bytes the BE32 stream keeps for its counter
 pub const NONCE_LENGTH: usize = 7;
+pub const DIGEST_LENGTH: usize = 32;
+pub const CANARY_NONCE_LENGTH: usize = 12;
+pub const CANARY: &[u8; 16] = b"diary canary v1\0";
+/// Nonce, encrypted canary and its tag, stored in the header to detect a wrong password
+pub const CANARY_LENGTH: usize = CANARY_NONCE_LENGTH + CANARY.len() + OVERHEAD;
 pub const OVERHEAD: usize = 16;
 pub const CAPACITY: usize = 2 * 1024 * 1024;
+/// Size of an encrypted chunk, always derived from `CAPACITY` so encryption and decryption agree
+pub const CHUNK_LENGTH: usize = CAPACITY + OVERHEAD;
+/// Full chunks the 32-bit stream counter can address before the final chunk,
+/// capping a diary at `CAPACITY * MAX_CHUNKS` bytes (about 8 PiB at 2 MiB chunks)
+pub const MAX_CHUNKS: u32 = u32::MAX;
+pub const TRASH_DIR: &str = ".trash";
+/// Subdirectory entry files are created in by diaries made with `new --entries-dir`
+pub const ENTRIES_DIR: &str = "entries";
+/// Subdirectory holding the files attached to entries, one directory per entry id
+pub const ATTACHMENTS_DIR: &str = "attachments";
+/// Subdirectory of a diary holding the templates `entry add --template` fills new entries from
+pub const TEMPLATES_DIR: &str = "templates";
+/// Moods are rated from 1, the worst, to `MAX_MOOD`, the best
+pub const MAX_MOOD: i8 = 5;
+pub const MAX_ENTRY_NAME_LENGTH: usize = 200;
+/// Largest entry file loaded into memory unless `--max-entry-size` says otherwise
+pub const DEFAULT_MAX_ENTRY_SIZE: u64 = 50 * 1024 * 1024;
+/// Written on close with a hash of the diary directory, so `status` can tell if it has changed
+pub const SAVED_MARKER: &str = ".saved";
+/// Maps files left out of a deduplicated archive to the file with the same content, expanded on open
+pub const DEDUP_MANIFEST: &str = ".dedup.json";
+/// Entry metadata of diaries created with `--metadata sqlite`, in place of `diary.json`
+pub const ENTRIES_DB: &str = "diary.db";
@@ -1,19 +1,430 @@
+use crate::{
+    archive::write_atomically,
+    cipher::{self, Algorithm, EntryKey, EntryLock, KdfParams},
+    consts::{ATTACHMENTS_DIR, DEFAULT_MAX_ENTRY_SIZE, ENTRIES_DB, KEY_LENGTH, TRASH_DIR},
+    error::{Context, DiaryError},
+    sqlite,
+};
+use clap::ValueEnum;
+use rusqlite::Connection;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
 use time::OffsetDateTime;
 use uuid::Uuid;
 
+/// Set by `--max-entry-size` to cap the entry files `read_content` loads into memory
+pub static MAX_ENTRY_SIZE: AtomicU64 = AtomicU64::new(DEFAULT_MAX_ENTRY_SIZE);
+
 #[derive(Serialize, Deserialize)]
 pub struct Entries {
     pub entries: HashMap<String, Entry>,
     pub key: String,
+    #[serde(default)]
+    pub trashed: HashMap<String, Entry>,
+    /// Cipher the diary is encrypted with when it is closed
+    #[serde(default)]
+    pub cipher: Algorithm,
+    /// Subdirectory new entry files are created in, older diaries keep them in the root
+    #[serde(default)]
+    pub entries_dir: Option<PathBuf>,
+    /// Label written unencrypted into the header of the diary file when it is closed
+    #[serde(default)]
+    pub comment: Option<String>,
+    /// Checks the password of encrypted entries, set when the first one is encrypted
+    #[serde(default)]
+    pub entry_lock: Option<EntryLock>,
+    /// Where the entries were loaded from and are saved to
+    #[serde(skip)]
+    pub format: MetadataFormat,
+}
+
+/// How a diary stores the metadata of its entries
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, ValueEnum)]
+pub enum MetadataFormat {
+    /// A single `diary.json`, rewritten on every change
+    #[default]
+    Json,
+    /// An SQLite database, `diary.db`, with the entries and tags indexed
+    Sqlite,
+}
+
+impl Entries {
+    /// Path of a new entry file named `file_name`, relative to the diary root
+    pub fn entry_path(&self, file_name: PathBuf) -> PathBuf {
+        match &self.entries_dir {
+            Some(entries_dir) => entries_dir.join(file_name),
+            None => file_name,
+        }
+    }
+
+    /// Key of the encrypted entries of a diary without an entry password, which are keyed by the
+    /// diary password, see [`EntryKey::legacy`]
+    pub fn legacy_key(&self) -> Option<EntryKey> {
+        self.entry_lock
+            .is_none()
+            .then(|| EntryKey::legacy(&self.key))
+    }
+
+    /// Create the subdirectory for entry files of the diary in `dir` if it uses one and it is
+    /// missing
+    pub fn create_entries_dir(&self, dir: &Path) -> Result<(), DiaryError> {
+        if let Some(entries_dir) = &self.entries_dir {
+            fs::create_dir_all(dir.join(entries_dir))
+                .context("Failed to create entries directory")?;
+        }
+
+        Ok(())
+    }
+
+    /// Give the encrypted entries of the diary in `dir` their own `password`, stretched with
+    /// Argon2id at `kdf` costs, and save the entries. Entries encrypted before then, with the
+    /// diary password, are re-encrypted with it
+    pub fn lock(
+        &mut self,
+        dir: &Path,
+        password: &str,
+        kdf: KdfParams,
+    ) -> Result<EntryKey, DiaryError> {
+        let (lock, key) = EntryLock::new(password, kdf)?;
+        self.rekey(dir, &EntryKey::legacy(&self.key), &key)?;
+        self.entry_lock = Some(lock);
+        save_entries(self, dir)?;
+
+        Ok(key)
+    }
+
+    /// Re-encrypt the encrypted entry files in `dir`, trashed ones included, from `from` to `to`
+    pub fn rekey(&self, dir: &Path, from: &EntryKey, to: &EntryKey) -> Result<(), DiaryError> {
+        let trashed = self.trashed.values().map(|e| Entry {
+            path: trash_path(&e.path),
+            ..e.clone()
+        });
+        for entry in self.entries.values().cloned().chain(trashed) {
+            if entry.encrypted {
+                let content = entry.read_content(dir, Some(from))?;
+                entry.write_content(dir, Some(to), &content)?;
+            }
+        }
+
+        Ok(())
+    }
 }
 
-#[derive(Hash, Serialize, Deserialize)]
+#[derive(Clone, Hash, Serialize, Deserialize)]
 pub struct Entry {
     pub id: Uuid,
     pub path: PathBuf,
     pub timestamp: OffsetDateTime,
     pub location: Option<String>,
     pub description: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub encrypted: bool,
+    #[serde(default)]
+    pub pinned: bool,
+    #[serde(default)]
+    pub archived: bool,
+    /// Ids of the entries this entry links to
+    #[serde(default)]
+    pub links: Vec<Uuid>,
+    /// When the entry was last changed with `entry edit`
+    #[serde(default)]
+    pub modified: Option<OffsetDateTime>,
+    /// Files attached with `entry attach`, relative to the diary root, see `attachments_dir`
+    #[serde(default)]
+    pub attachments: Vec<PathBuf>,
+    /// How the writer felt, from 1 to `MAX_MOOD`, set with `entry add --mood` or `entry mood`
+    #[serde(default)]
+    pub mood: Option<i8>,
+}
+
+impl Entry {
+    /// An entry with `id` whose file is at `path`, dated `timestamp`, with nothing else set
+    pub fn new(id: Uuid, path: PathBuf, timestamp: OffsetDateTime) -> Self {
+        Self {
+            id,
+            path,
+            timestamp,
+            location: None,
+            description: None,
+            tags: Vec::new(),
+            encrypted: false,
+            pinned: false,
+            archived: false,
+            links: Vec::new(),
+            modified: None,
+            attachments: Vec::new(),
+            mood: None,
+        }
+    }
+
+    /// Read the content of this entry's file in `dir`, decrypting it with `key` if needed, unless
+    /// it is larger than `MAX_ENTRY_SIZE`
+    pub fn read_content(&self, dir: &Path, key: Option<&EntryKey>) -> Result<Vec<u8>, DiaryError> {
+        let file = File::open(dir.join(&self.path)).context("Failed to open entry file")?;
+
+        let size = file.metadata().context("Failed to read entry file")?.len();
+        let limit = MAX_ENTRY_SIZE.load(Ordering::Relaxed);
+        if size > limit {
+            return Err(DiaryError::EntryTooLarge { size, limit });
+        }
+
+        let mut content = Vec::new();
+        if self.encrypted {
+            cipher::decrypt(
+                file,
+                &mut content,
+                self.file_key(key)?,
+                Algorithm::default(),
+            )?;
+        } else {
+            BufReader::new(file)
+                .read_to_end(&mut content)
+                .context("Failed to read entry file")?;
+        }
+
+        Ok(content)
+    }
+
+    /// Replace the content of this entry's file in `dir`, encrypting it with `key` if needed. The
+    /// old content is only replaced once the new content is written in full
+    pub fn write_content(
+        &self,
+        dir: &Path,
+        key: Option<&EntryKey>,
+        content: &[u8],
+    ) -> Result<(), DiaryError> {
+        write_atomically(&dir.join(&self.path), |file| {
+            if self.encrypted {
+                cipher::encrypt(content, file, self.file_key(key)?, Algorithm::default())?;
+            } else {
+                let mut writer = BufWriter::new(file);
+                writer
+                    .write_all(content)
+                    .and_then(|_| writer.flush())
+                    .context("Failed to write entry file")?;
+            }
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    /// Append to the content of this entry's file in `dir`, re-encrypting it with `key` if needed
+    pub fn append_content(
+        &self,
+        dir: &Path,
+        key: Option<&EntryKey>,
+        content: &[u8],
+    ) -> Result<(), DiaryError> {
+        if self.encrypted {
+            let mut existing = self.read_content(dir, key)?;
+            existing.extend_from_slice(content);
+            return self.write_content(dir, key, &existing);
+        }
+
+        OpenOptions::new()
+            .append(true)
+            .open(dir.join(&self.path))
+            .and_then(|mut file| file.write_all(content))
+            .context("Failed to append to entry file")
+    }
+
+    /// Copy this entry's file in `from`, encrypted with `key` if it is encrypted, to the file of
+    /// `to` in `into`, re-encrypting it with `new_key` when the entry id or the key changes
+    pub fn copy_content(
+        &self,
+        from: &Path,
+        key: Option<&EntryKey>,
+        to: &Entry,
+        into: &Path,
+        new_key: Option<&EntryKey>,
+    ) -> Result<(), DiaryError> {
+        if self.encrypted && (self.id != to.id || key != new_key) {
+            let content = self.read_content(from, key)?;
+            to.write_content(into, new_key, &content)
+        } else {
+            fs::copy(from.join(&self.path), into.join(&to.path))
+                .context("Failed to copy entry file")?;
+            Ok(())
+        }
+    }
+
+    /// Key of this entry's file, derived from the key of the diary's encrypted entries
+    fn file_key(&self, key: Option<&EntryKey>) -> Result<[u8; KEY_LENGTH], DiaryError> {
+        key.map(|key| key.file_key(&self.id))
+            .ok_or_else(|| DiaryError::EntryLocked(self.path.clone()))
+    }
+
+    /// Copy the attachments of this entry in `from` to those of `to` in `into`, which has the
+    /// same attachments, under its own id if it has a different one
+    pub fn copy_attachments(&self, from: &Path, to: &Entry, into: &Path) -> Result<(), DiaryError> {
+        for (attachment, copy) in self.attachments.iter().zip(&to.attachments) {
+            let copy = into.join(copy);
+            if let Some(parent) = copy.parent() {
+                fs::create_dir_all(parent).context("Failed to create attachments directory")?;
+            }
+            fs::copy(from.join(attachment), &copy)
+                .with_context(|| format!("Failed to copy attachment {}", attachment.display()))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Directory the attachments of the entry with `id` are copied into, relative to the diary root
+pub fn attachments_dir(id: Uuid) -> PathBuf {
+    Path::new(ATTACHMENTS_DIR).join(id.to_string())
+}
+
+/// File name for an entry with `id`, keeping the extension of `original`
+pub fn entry_file_name(id: Uuid, original: &Path) -> PathBuf {
+    match original.extension() {
+        Some(ext) => PathBuf::from(format!("{id}.{}", ext.display())),
+        None => PathBuf::from(id.to_string()),
+    }
+}
+
+/// `name`, suffixed with a counter if it is already taken in `entries`
+pub fn unique_name(entries: &HashMap<String, Entry>, name: &str) -> String {
+    let mut key = name.to_string();
+    let mut suffix = 2;
+    while entries.contains_key(&key) {
+        key = format!("{name} ({suffix})");
+        suffix += 1;
+    }
+    key
+}
+
+/// Where the file at `path` goes when its entry is trashed, relative to the diary root
+pub fn trash_path(path: &Path) -> PathBuf {
+    Path::new(TRASH_DIR).join(path.file_name().unwrap_or(path.as_os_str()))
+}
+
+/// File in `dir` holding the metadata of the diary's entries, `diary.db` if the diary has one
+pub fn metadata_path(dir: &Path) -> PathBuf {
+    let db = dir.join(ENTRIES_DB);
+    if db.is_file() {
+        db
+    } else {
+        dir.join("diary.json")
+    }
+}
+
+/// Read `diary.db` in `dir` if the diary has one, or else `diary.json` through a buffered reader
+pub fn load_entries(dir: &Path) -> Result<Entries, DiaryError> {
+    let db = dir.join(ENTRIES_DB);
+    if db.is_file() {
+        return sqlite::load(&Connection::open(&db)?);
+    }
+
+    let path = dir.join("diary.json");
+
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound && dir == Path::new(".") => {
+            return Err(DiaryError::NotInsideDiary);
+        }
+        Err(e) => return Err(e).with_context(|| format!("Failed to open {}", path.display())),
+    };
+
+    serde_json::from_reader(BufReader::new(file))
+        .with_context(|| format!("Failed to deserialize {}", path.display()))
+}
+
+/// Read the `size` byte `diary.db` of a diary from `from` into memory and load its entries, for
+/// reading them straight out of an archive
+pub fn load_entries_db(from: impl Read, size: u64) -> Result<Entries, DiaryError> {
+    let mut conn = Connection::open_in_memory()?;
+    conn.deserialize_read_exact(rusqlite::MAIN_DB, from, size as usize, true)?;
+    sqlite::load(&conn)
+}
+
+/// Atomically replace `diary.json` in `dir`, syncing the new file and the directory to disk, or
+/// update `diary.db` in one transaction for SQLite diaries
+pub fn save_entries(entries: &Entries, dir: &Path) -> Result<(), DiaryError> {
+    if entries.format == MetadataFormat::Sqlite {
+        let db = dir.join(ENTRIES_DB);
+        return sqlite::save(entries, &mut Connection::open(&db)?);
+    }
+
+    write_atomically(&dir.join("diary.json"), |file| {
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(&mut writer, entries).context("Failed to save diary file")?;
+        writer.flush().context("Failed to save diary file")
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Entries of a diary with nothing in it yet
+    fn empty() -> Entries {
+        serde_json::from_str(r#"{"entries": {}, "key": "password"}"#).unwrap()
+    }
+
+    #[test]
+    fn saved_diary_json_loads() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut entries = empty();
+        let id = Uuid::new_v4();
+        entries.entries.insert(
+            "today".to_string(),
+            Entry {
+                tags: vec!["work".to_string()],
+                ..Entry::new(
+                    id,
+                    entry_file_name(id, Path::new("x.md")),
+                    OffsetDateTime::UNIX_EPOCH,
+                )
+            },
+        );
+
+        save_entries(&entries, temp.path()).unwrap();
+        save_entries(&entries, temp.path()).unwrap();
+
+        let saved: Entries =
+            serde_json::from_slice(&fs::read(temp.path().join("diary.json")).unwrap()).unwrap();
+        assert_eq!(saved.entries["today"].id, id);
+        assert_eq!(saved.entries["today"].tags, ["work"]);
+        let files = fs::read_dir(temp.path()).unwrap().count();
+        assert_eq!(files, 1, "no temporary file is left behind");
+    }
+
+    #[test]
+    fn large_diary_json_loads() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut json = BufWriter::new(File::create(temp.path().join("diary.json")).unwrap());
+        write!(json, r#"{{"key": "password", "entries": {{"#).unwrap();
+        for i in 0..50_000 {
+            let id = Uuid::from_u128(i);
+            write!(
+                json,
+                r#"{}"entry {i}": {{"id": "{id}", "path": "{id}.md", "timestamp": [2026, 288, 8, 0, 0, 0, 0, 0, 0], "location": null, "description": "{}", "tags": ["tag {}"]}}"#,
+                if i == 0 { "" } else { ", " },
+                "x".repeat(100),
+                i % 7,
+            )
+            .unwrap();
+        }
+        write!(json, "}}}}").unwrap();
+        json.into_inner().unwrap().sync_all().unwrap();
+
+        let entries = load_entries(temp.path()).unwrap();
+        assert_eq!(entries.entries.len(), 50_000);
+        let last = &entries.entries["entry 49999"];
+        assert_eq!(last.id, Uuid::from_u128(49999));
+        assert_eq!(last.tags, ["tag 5"]);
+        assert_eq!(last.timestamp.ordinal(), 288);
+    }
 }
@@ -0,0 +1,142 @@
+use crate::consts::CAPACITY;
+use std::{io, path::PathBuf};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum DiaryError {
+    #[error("Wrong password")]
+    WrongPassword,
+    #[error("Diary is corrupt: {0}")]
+    Corrupt(String),
+    #[error("Integrity check failed: decrypted archive does not match its digest")]
+    IntegrityCheckFailed,
+    #[error("Not a diary file")]
+    NotADiary,
+    #[error("Diary file has format version {0}, which this version of diary can't read")]
+    UnsupportedVersion(u8),
+    #[error(
+        "Diary file is truncated or corrupt: header is {expected} bytes, but only {actual} could be read"
+    )]
+    Truncated { expected: usize, actual: usize },
+    #[error(
+        "Diary was written with a chunk size of {0} bytes, but only {CAPACITY} byte chunks can be read"
+    )]
+    UnsupportedChunkSize(u32),
+    #[error("Diary was encrypted with an unknown cipher ({0})")]
+    UnsupportedCipher(u8),
+    #[error("Diary was compressed with an unknown compression ({0})")]
+    UnsupportedCompression(u8),
+    #[error("Diary too large for current chunk size ({CAPACITY} bytes); increase chunk size")]
+    TooLarge,
+    #[error("Failed to encrypt chunk")]
+    Encryption,
+    #[error("Archive entry {0} would be written outside the diary directory")]
+    UnsafeArchivePath(PathBuf),
+    #[error("Diary {0} already exists")]
+    DiaryExists(String),
+    #[error(
+        "Entry file is {size} bytes, larger than the limit of {limit} bytes (see --max-entry-size)"
+    )]
+    EntryTooLarge { size: u64, limit: u64 },
+    #[error("Entry file {0} is encrypted and needs the entry password")]
+    EntryLocked(PathBuf),
+    #[error("Not inside a diary directory")]
+    NotInsideDiary,
+    #[error("Entry {0} does not exist")]
+    NoSuchEntry(String),
+    #[error("An entry named {0} already exists")]
+    EntryExists(String),
+    #[error("Invalid glob {glob}")]
+    InvalidGlob {
+        glob: String,
+        #[source]
+        source: globset::Error,
+    },
+    #[error("{context}")]
+    File {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("{context}")]
+    Metadata {
+        context: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("Failed to access diary database")]
+    Database(#[from] rusqlite::Error),
+    #[error("Failed to derive key: {0}")]
+    Kdf(#[from] argon2::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl DiaryError {
+    /// Stable name of the kind of error, for scripts reading `--json` output
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::WrongPassword => "wrong_password",
+            Self::Corrupt(_)
+            | Self::Truncated { .. }
+            | Self::IntegrityCheckFailed
+            | Self::Metadata { .. } => "corrupt",
+            Self::NotADiary => "not_a_diary",
+            Self::UnsupportedVersion(_)
+            | Self::UnsupportedChunkSize(_)
+            | Self::UnsupportedCipher(_)
+            | Self::UnsupportedCompression(_) => "unsupported",
+            Self::TooLarge | Self::EntryTooLarge { .. } => "too_large",
+            Self::Encryption | Self::Kdf(_) => "crypto",
+            Self::UnsafeArchivePath(_) => "unsafe_path",
+            Self::DiaryExists(_) | Self::EntryExists(_) => "exists",
+            Self::EntryLocked(_) => "entry_locked",
+            Self::NotInsideDiary => "not_inside_diary",
+            Self::NoSuchEntry(_) => "no_such_entry",
+            Self::InvalidGlob { .. } => "invalid_glob",
+            Self::Database(_) => "database",
+            Self::Io(_) | Self::File { .. } => "io",
+        }
+    }
+}
+
+/// Says what was being done when an error of a library the diary builds on occurred, like eyre's
+/// `Context` does for the command line tool
+pub(crate) trait Context<T> {
+    fn context(self, context: &str) -> Result<T, DiaryError>;
+
+    fn with_context(self, context: impl FnOnce() -> String) -> Result<T, DiaryError>;
+}
+
+/// Errors that become a [`DiaryError`] along with what was being done
+pub(crate) trait Contextual {
+    fn with(self, context: String) -> DiaryError;
+}
+
+impl Contextual for io::Error {
+    fn with(self, context: String) -> DiaryError {
+        DiaryError::File {
+            context,
+            source: self,
+        }
+    }
+}
+
+impl Contextual for serde_json::Error {
+    fn with(self, context: String) -> DiaryError {
+        DiaryError::Metadata {
+            context,
+            source: self,
+        }
+    }
+}
+
+impl<T, E: Contextual> Context<T> for Result<T, E> {
+    fn context(self, context: &str) -> Result<T, DiaryError> {
+        self.map_err(|e| e.with(context.to_string()))
+    }
+
+    fn with_context(self, context: impl FnOnce() -> String) -> Result<T, DiaryError> {
+        self.map_err(|e| e.with(context()))
+    }
+}
@@ -0,0 +1,228 @@
+use crate::{
+    archive::Compression,
+    cipher::{Algorithm, KdfParams},
+    consts::{
+        CANARY_LENGTH, CAPACITY, DIGEST_LENGTH, FORMAT_VERSION, MAGIC, MAX_COMMENT_LENGTH,
+        SALT_LENGTH,
+    },
+    error::DiaryError,
+};
+use std::io::{self, Read, Write};
+
+/// Unencrypted data at the start of a diary file, followed by the nonce and ciphertext
+pub struct Header {
+    pub version: u8,
+    pub salt: [u8; SALT_LENGTH],
+    pub digest: [u8; DIGEST_LENGTH],
+    pub chunk_size: u32,
+    pub cipher: Algorithm,
+    pub canary: [u8; CANARY_LENGTH],
+    /// Argon2 costs of the key, the defaults in version 1 files which don't record them
+    pub kdf: KdfParams,
+    /// Compression of the archive, gzip in version 1 and 2 files which don't record it
+    pub compression: Compression,
+    /// Label stored in plain text so diary files can be told apart, never confidential
+    pub comment: Option<String>,
+}
+
+impl Header {
+    pub fn new(
+        salt: [u8; SALT_LENGTH],
+        digest: [u8; DIGEST_LENGTH],
+        cipher: Algorithm,
+        canary: [u8; CANARY_LENGTH],
+        kdf: KdfParams,
+        compression: Compression,
+        comment: Option<String>,
+    ) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            salt,
+            digest,
+            chunk_size: CAPACITY as u32,
+            cipher,
+            canary,
+            kdf,
+            compression,
+            comment,
+        }
+    }
+
+    /// Length of the fixed part of the header in bytes. From version 2 it is followed by the
+    /// Argon2 memory, iterations and parallelism as u32s, from version 3 by the compression as a
+    /// byte, then in every version by the length of the comment as a u16 and the comment itself
+    pub const LENGTH: usize = MAGIC.len() + 1 + SALT_LENGTH + DIGEST_LENGTH + 4 + 1 + CANARY_LENGTH;
+
+    /// Length of the Argon2 parameters following the fixed part from version 2
+    const KDF_LENGTH: usize = 3 * 4;
+
+    pub fn read(mut from: impl Read) -> Result<Self, DiaryError> {
+        let mut bytes = Vec::with_capacity(Self::LENGTH);
+        (&mut from)
+            .take(Self::LENGTH as u64)
+            .read_to_end(&mut bytes)?;
+
+        if !bytes.starts_with(MAGIC) {
+            return Err(DiaryError::NotADiary);
+        }
+
+        if bytes.len() < Self::LENGTH {
+            return Err(DiaryError::Truncated {
+                expected: Self::LENGTH,
+                actual: bytes.len(),
+            });
+        }
+
+        let (_, rest) = bytes.split_at(MAGIC.len());
+        let (version, rest) = rest.split_at(1);
+        let (salt, rest) = rest.split_at(SALT_LENGTH);
+        let (digest, rest) = rest.split_at(DIGEST_LENGTH);
+        let (chunk_size, rest) = rest.split_at(4);
+        let (cipher, canary) = rest.split_at(1);
+
+        if !(1..=FORMAT_VERSION).contains(&version[0]) {
+            return Err(DiaryError::UnsupportedVersion(version[0]));
+        }
+
+        let chunk_size = u32::from_le_bytes(chunk_size.try_into().unwrap());
+
+        if chunk_size as usize != CAPACITY {
+            return Err(DiaryError::UnsupportedChunkSize(chunk_size));
+        }
+
+        let cipher = Algorithm::from_id(cipher[0])?;
+
+        let (kdf, offset) = if version[0] >= 2 {
+            let kdf = read_field(&mut from, Self::LENGTH, Self::KDF_LENGTH)?;
+            let cost = |i: usize| u32::from_le_bytes(kdf[i * 4..i * 4 + 4].try_into().unwrap());
+            let kdf = KdfParams {
+                memory: cost(0),
+                iterations: cost(1),
+                parallelism: cost(2),
+            };
+            (kdf, Self::LENGTH + Self::KDF_LENGTH)
+        } else {
+            (KdfParams::default(), Self::LENGTH)
+        };
+
+        let (compression, offset) = if version[0] >= 3 {
+            let compression = read_field(&mut from, offset, 1)?;
+            (Compression::from_id(compression[0])?, offset + 1)
+        } else {
+            (Compression::Gzip, offset)
+        };
+
+        let comment_length = read_field(&mut from, offset, 2)?;
+        let comment_length = u16::from_le_bytes(comment_length.try_into().unwrap()) as usize;
+        let comment = read_field(&mut from, offset + 2, comment_length)?;
+        let comment = String::from_utf8(comment)
+            .map_err(|_| DiaryError::Corrupt("header comment is not valid UTF-8".to_string()))?;
+
+        Ok(Self {
+            version: version[0],
+            salt: salt.try_into().unwrap(),
+            digest: digest.try_into().unwrap(),
+            chunk_size,
+            cipher,
+            canary: canary.try_into().unwrap(),
+            kdf,
+            compression,
+            comment: (!comment.is_empty()).then_some(comment),
+        })
+    }
+
+    pub fn write(&self, mut to: impl Write) -> io::Result<()> {
+        let comment = self.comment.as_deref().unwrap_or_default().as_bytes();
+        if comment.len() > MAX_COMMENT_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("header comment is longer than {MAX_COMMENT_LENGTH} bytes"),
+            ));
+        }
+
+        to.write_all(MAGIC)?;
+        to.write_all(&[self.version])?;
+        to.write_all(&self.salt)?;
+        to.write_all(&self.digest)?;
+        to.write_all(&self.chunk_size.to_le_bytes())?;
+        to.write_all(&[self.cipher.id()])?;
+        to.write_all(&self.canary)?;
+        if self.version >= 2 {
+            to.write_all(&self.kdf.memory.to_le_bytes())?;
+            to.write_all(&self.kdf.iterations.to_le_bytes())?;
+            to.write_all(&self.kdf.parallelism.to_le_bytes())?;
+        }
+        if self.version >= 3 {
+            to.write_all(&[self.compression.id()])?;
+        }
+        to.write_all(&(comment.len() as u16).to_le_bytes())?;
+        to.write_all(comment)
+    }
+}
+
+/// Read the `length` byte field of the header starting at `offset`
+fn read_field(from: impl Read, offset: usize, length: usize) -> Result<Vec<u8>, DiaryError> {
+    let mut bytes = Vec::with_capacity(length);
+    from.take(length as u64).read_to_end(&mut bytes)?;
+
+    if bytes.len() < length {
+        return Err(DiaryError::Truncated {
+            expected: offset + length,
+            actual: offset + bytes.len(),
+        });
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::{packed, verify_diary};
+    use std::fs::{self, File};
+
+    #[test]
+    fn flipped_digest_fails_integrity_check() {
+        let temp = tempfile::tempdir().unwrap();
+        let file = packed(temp.path());
+        verify_diary(File::open(&file).unwrap(), "password").unwrap();
+
+        let mut bytes = fs::read(&file).unwrap();
+        bytes[MAGIC.len() + 1 + SALT_LENGTH] ^= 1;
+        fs::write(&file, bytes).unwrap();
+
+        let verified = verify_diary(File::open(&file).unwrap(), "password");
+        assert!(matches!(verified, Err(DiaryError::IntegrityCheckFailed)));
+    }
+
+    #[test]
+    fn other_chunk_size_is_unsupported() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut bytes = fs::read(packed(temp.path())).unwrap();
+        let offset = MAGIC.len() + 1 + SALT_LENGTH + DIGEST_LENGTH;
+        bytes[offset..offset + 4].copy_from_slice(&1024u32.to_le_bytes());
+
+        let header = Header::read(&bytes[..]);
+        assert!(matches!(
+            header,
+            Err(DiaryError::UnsupportedChunkSize(1024))
+        ));
+    }
+
+    #[test]
+    fn short_file_is_truncated_or_not_a_diary() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.resize(10, 0);
+        let header = Header::read(&bytes[..]);
+        assert!(matches!(
+            header,
+            Err(DiaryError::Truncated {
+                expected: Header::LENGTH,
+                actual: 10
+            })
+        ));
+
+        let header = Header::read(&[0u8; 10][..]);
+        assert!(matches!(header, Err(DiaryError::NotADiary)));
+    }
+}
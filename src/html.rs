@@ -0,0 +1,383 @@
+//! `diary export html`: a static site of a diary's entries, with an index, a chronological
+//! archive and a page per tag
+
+use crate::{cipher::EntryKey, display_time, entries::Entries, entries::Entry};
+use color_eyre::eyre::Context;
+use pulldown_cmark::{Options, Parser, html::push_html};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    fs,
+    path::Path,
+};
+use time::{OffsetDateTime, macros::format_description};
+use uuid::Uuid;
+
+const STYLE: &str = "\
+body { max-width: 46rem; margin: 2rem auto; padding: 0 1rem; font: 1rem/1.6 Georgia, serif; color: #222; background: #fdfdfb; }
+nav { font-family: sans-serif; font-size: 0.9rem; margin-bottom: 2rem; }
+nav a { margin-right: 1rem; }
+a { color: #2a5db0; text-decoration: none; }
+a:hover { text-decoration: underline; }
+h1, h2, h3 { font-family: sans-serif; line-height: 1.25; }
+.meta { color: #666; font-family: sans-serif; font-size: 0.9rem; }
+.tag { display: inline-block; background: #eef2f8; border-radius: 0.25rem; padding: 0 0.4rem; margin-right: 0.3rem; }
+ul.entries { list-style: none; padding: 0; }
+ul.entries li { margin: 0.6rem 0; }
+article img, .attachments img { max-width: 100%; }
+pre { background: #f3f3f0; padding: 0.8rem; overflow-x: auto; }
+blockquote { border-left: 3px solid #ccc; margin-left: 0; padding-left: 1rem; color: #555; }
+";
+
+/// Write the site for the diary `title` whose entries are in `dir` into `out`, decrypting
+/// encrypted entries with `key`, returning how many entry pages were written. Every page sets its
+/// base to the root of the site, so links and attachment paths are the same on every page
+pub fn export(
+    title: &str,
+    entries: &Entries,
+    key: Option<&EntryKey>,
+    dir: &Path,
+    out: &Path,
+    utc: bool,
+) -> color_eyre::Result<usize> {
+    let mut sorted = entries.entries.iter().collect::<Vec<_>>();
+    sorted.sort_by_key(|(name, entry)| (entry.timestamp, name.as_str()));
+
+    let ids = entries
+        .entries
+        .iter()
+        .map(|(name, entry)| (entry.id, name.as_str()))
+        .collect::<HashMap<_, _>>();
+    let mut backlinks = HashMap::<Uuid, Vec<Uuid>>::new();
+    for (_, entry) in &sorted {
+        for link in &entry.links {
+            backlinks.entry(*link).or_default().push(entry.id);
+        }
+    }
+
+    fs::create_dir_all(out.join("entries")).context("Failed to create output directory")?;
+    fs::create_dir_all(out.join("tags")).context("Failed to create output directory")?;
+    write_page(out, "style.css", STYLE)?;
+
+    for (i, (name, entry)) in sorted.iter().enumerate() {
+        let content = entry
+            .read_content(dir, key)
+            .with_context(|| format!("Failed to read entry {name}"))?;
+
+        let mut body = format!("<h1>{}</h1>\n", escape(name));
+        body.push_str(&entry_meta(entry, utc));
+        if let Some(description) = &entry.description {
+            writeln!(body, "<p><em>{}</em></p>", escape(description))?;
+        }
+
+        body.push_str("<article>\n");
+        let text = String::from_utf8_lossy(&content);
+        if entry
+            .path
+            .extension()
+            .is_some_and(|e| e == "md" || e == "markdown")
+        {
+            push_html(&mut body, Parser::new_ext(&text, Options::all()));
+        } else {
+            writeln!(body, "<pre>{}</pre>", escape(&text))?;
+        }
+        body.push_str("</article>\n");
+
+        if !entry.attachments.is_empty() {
+            body.push_str("<h2>Attachments</h2>\n<ul class=\"attachments\">\n");
+            for attachment in &entry.attachments {
+                let copy = out.join(attachment);
+                if let Some(parent) = copy.parent() {
+                    fs::create_dir_all(parent).context("Failed to create output directory")?;
+                }
+                fs::copy(dir.join(attachment), &copy).with_context(|| {
+                    format!("Failed to copy attachment {}", attachment.display())
+                })?;
+
+                let href = escape(&attachment.to_string_lossy());
+                let file_name =
+                    escape(&attachment.file_name().unwrap_or_default().to_string_lossy());
+                if is_image(attachment) {
+                    writeln!(body, "<li><img src=\"{href}\" alt=\"{file_name}\"></li>")?;
+                } else {
+                    writeln!(body, "<li><a href=\"{href}\">{file_name}</a></li>")?;
+                }
+            }
+            body.push_str("</ul>\n");
+        }
+
+        let linked = |heading: &str, targets: &[Uuid], body: &mut String| {
+            let names = targets
+                .iter()
+                .filter_map(|id| ids.get(id).map(|name| (id, name)))
+                .collect::<Vec<_>>();
+            if names.is_empty() {
+                return;
+            }
+            body.push_str(&format!("<h2>{heading}</h2>\n<ul>\n"));
+            for (id, name) in names {
+                body.push_str(&format!(
+                    "<li><a href=\"entries/{id}.html\">{}</a></li>\n",
+                    escape(name)
+                ));
+            }
+            body.push_str("</ul>\n");
+        };
+        linked("Links", &entry.links, &mut body);
+        linked(
+            "Linked from",
+            backlinks.get(&entry.id).map_or(&[], Vec::as_slice),
+            &mut body,
+        );
+
+        body.push_str("<nav>");
+        if let Some((previous, entry)) = i.checked_sub(1).map(|i| sorted[i]) {
+            write!(
+                body,
+                "<a href=\"entries/{}.html\">&larr; {}</a>",
+                entry.id,
+                escape(previous)
+            )?;
+        }
+        if let Some((next, entry)) = sorted.get(i + 1) {
+            write!(
+                body,
+                "<a href=\"entries/{}.html\">{} &rarr;</a>",
+                entry.id,
+                escape(next)
+            )?;
+        }
+        body.push_str("</nav>\n");
+
+        write_page(
+            out,
+            &format!("entries/{}.html", entry.id),
+            &page(title, name, "../", &body),
+        )?;
+    }
+
+    let mut tags = BTreeMap::<&str, Vec<(&String, &Entry)>>::new();
+    for &(name, entry) in sorted.iter().rev() {
+        for tag in &entry.tags {
+            tags.entry(tag).or_default().push((name, entry));
+        }
+    }
+
+    let mut body = format!("<h1>{}</h1>\n", escape(title));
+    writeln!(
+        body,
+        "<p class=\"meta\">{} entries, <a href=\"archive.html\">by month</a></p>",
+        sorted.len()
+    )?;
+    if !tags.is_empty() {
+        body.push_str("<p>");
+        for (tag, tagged) in &tags {
+            write!(
+                body,
+                "<a class=\"tag\" href=\"tags/{}.html\">{} ({})</a>",
+                slug(tag),
+                escape(tag),
+                tagged.len()
+            )?;
+        }
+        body.push_str("</p>\n");
+    }
+    body.push_str(&entry_list(sorted.iter().rev().copied(), utc));
+    write_page(out, "index.html", &page(title, title, "./", &body))?;
+
+    let mut body = String::from("<h1>Archive</h1>\n");
+    let mut months = BTreeMap::<(i32, u8), Vec<(&String, &Entry)>>::new();
+    for &(name, entry) in &sorted {
+        let date = display_time(entry.timestamp, utc).date();
+        months
+            .entry((date.year(), date.month() as u8))
+            .or_default()
+            .push((name, entry));
+    }
+    for ((year, month), in_month) in months {
+        let month = time::Month::try_from(month).map_or_else(|_| String::new(), |m| m.to_string());
+        writeln!(body, "<h2>{month} {year}</h2>")?;
+        body.push_str(&entry_list(in_month.into_iter(), utc));
+    }
+    write_page(out, "archive.html", &page(title, "Archive", "./", &body))?;
+
+    for (tag, tagged) in tags {
+        let mut body = format!("<h1>Tagged {}</h1>\n", escape(tag));
+        body.push_str(&entry_list(tagged.into_iter(), utc));
+        write_page(
+            out,
+            &format!("tags/{}.html", slug(tag)),
+            &page(title, tag, "../", &body),
+        )?;
+    }
+
+    Ok(sorted.len())
+}
+
+/// Whole HTML document titled `heading` on the site of diary `title`, at `base` from the root
+fn page(title: &str, heading: &str, base: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">
+<base href=\"{base}\">
+<title>{heading} - {title}</title>
+<link rel=\"stylesheet\" href=\"style.css\">
+</head>
+<body>
+<nav><a href=\"index.html\">{title}</a><a href=\"archive.html\">Archive</a></nav>
+{body}</body>
+</html>
+",
+        heading = escape(heading),
+        title = escape(title),
+    )
+}
+
+fn write_page(out: &Path, path: &str, content: &str) -> color_eyre::Result<()> {
+    fs::write(out.join(path), content).with_context(|| format!("Failed to write {path}"))
+}
+
+/// Date, location and tags of an entry, as shown under its name
+fn entry_meta(entry: &Entry, utc: bool) -> String {
+    let mut meta = format!(
+        "<p class=\"meta\">{}",
+        format_time(display_time(entry.timestamp, utc))
+    );
+    if let Some(location) = &entry.location {
+        meta.push_str(&format!(" &middot; {}", escape(location)));
+    }
+    if entry.archived {
+        meta.push_str(" &middot; archived");
+    }
+    if !entry.tags.is_empty() {
+        meta.push_str(" &middot; ");
+        for tag in &entry.tags {
+            meta.push_str(&format!(
+                "<a class=\"tag\" href=\"tags/{}.html\">{}</a>",
+                slug(tag),
+                escape(tag)
+            ));
+        }
+    }
+    meta.push_str("</p>\n");
+    meta
+}
+
+fn entry_list<'a>(entries: impl Iterator<Item = (&'a String, &'a Entry)>, utc: bool) -> String {
+    let mut list = String::from("<ul class=\"entries\">\n");
+    for (name, entry) in entries {
+        list.push_str(&format!(
+            "<li><a href=\"entries/{}.html\">{}</a>{}",
+            entry.id,
+            escape(name),
+            entry_meta(entry, utc)
+        ));
+        if let Some(description) = &entry.description {
+            list.push_str(&format!("<em>{}</em>", escape(description)));
+        }
+        list.push_str("</li>\n");
+    }
+    list.push_str("</ul>\n");
+    list
+}
+
+fn format_time(time: OffsetDateTime) -> String {
+    time.format(format_description!("[year]-[month]-[day] [hour]:[minute]"))
+        .unwrap_or_default()
+}
+
+fn is_image(path: &Path) -> bool {
+    path.extension().and_then(|e| e.to_str()).is_some_and(|e| {
+        ["png", "jpg", "jpeg", "gif", "webp", "svg"].contains(&e.to_lowercase().as_str())
+    })
+}
+
+/// File name for the page of `tag`, keeping lowercase ASCII letters, digits and `-` and spelling
+/// out every other character as its code point in hex between `_`s, so different tags never share
+/// a page, not even on case-insensitive filesystems
+fn slug(tag: &str) -> String {
+    let mut slug = String::new();
+    for c in tag.chars() {
+        if c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' {
+            slug.push(c);
+        } else {
+            slug.push_str(&format!("_{:x}_", c as u32));
+        }
+    }
+    slug
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn names_tags_and_descriptions_are_escaped() {
+        let temp = tempfile::tempdir().unwrap();
+        let (dir, out) = (temp.path().join("diary"), temp.path().join("site"));
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("entry.txt"), "<b>not bold</b>").unwrap();
+
+        let mut entries: Entries =
+            serde_json::from_str(r#"{"entries": {}, "key": "password"}"#).unwrap();
+        let id = Uuid::new_v4();
+        entries.entries.insert(
+            "<script>alert(1)</script>".to_string(),
+            Entry {
+                tags: vec!["a&b".to_string()],
+                description: Some("\"quoted\" <i>".to_string()),
+                ..Entry::new(id, "entry.txt".into(), OffsetDateTime::UNIX_EPOCH)
+            },
+        );
+
+        export("Tom & Jerry", &entries, None, &dir, &out, true).unwrap();
+
+        let index = fs::read_to_string(out.join("index.html")).unwrap();
+        let page = fs::read_to_string(out.join(format!("entries/{id}.html"))).unwrap();
+        let tag = fs::read_to_string(out.join(format!("tags/{}.html", slug("a&b")))).unwrap();
+        for html in [&index, &page, &tag] {
+            assert!(html.contains("&lt;script&gt;alert(1)&lt;/script&gt;"));
+            assert!(html.contains("Tom &amp; Jerry"));
+            assert!(!html.contains("<script>"));
+        }
+        for html in [&index, &page] {
+            assert!(html.contains(">a&amp;b</a>"));
+            assert!(html.contains("&quot;quoted&quot; &lt;i&gt;"));
+        }
+        assert!(page.contains("&lt;b&gt;not bold&lt;/b&gt;"));
+    }
+
+    #[test]
+    fn different_tags_get_different_pages() {
+        let tags = [
+            "work", "Work", "WORK", "a b", "a_20", "a_20_", "\u{1}f", "\u{1f}", "é", "e", "-",
+            "_2d_",
+        ];
+        let slugs = tags.iter().map(|tag| slug(tag)).collect::<HashSet<_>>();
+        assert_eq!(slugs.len(), tags.len());
+        assert_eq!(slug("work-2024"), "work-2024");
+        assert!(slugs.iter().all(|s| {
+            s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_".contains(c))
+        }));
+    }
+}
@@ -0,0 +1,183 @@
+//! `diary import jrnl`: reading the entries of a jrnl journal, either its plain text journal
+//! file or the output of `jrnl --export json`
+
+use color_eyre::eyre::{Context, bail};
+use serde::Deserialize;
+use time::{Date, OffsetDateTime, PrimitiveDateTime, Time, UtcOffset, macros::format_description};
+
+/// One jrnl entry
+pub struct Record {
+    pub title: String,
+    pub body: String,
+    pub timestamp: OffsetDateTime,
+    /// Tags without their `@`, in the order they first appear
+    pub tags: Vec<String>,
+    pub starred: bool,
+}
+
+impl Record {
+    /// Markdown content of the entry: the title, then the body as its own paragraphs
+    pub fn content(&self) -> String {
+        let body = self.body.trim();
+        if body.is_empty() {
+            format!("{}\n", self.title)
+        } else {
+            format!("{}\n\n{body}\n", self.title)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Export {
+    entries: Vec<ExportEntry>,
+}
+
+#[derive(Deserialize)]
+struct ExportEntry {
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    body: String,
+    date: String,
+    time: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    starred: bool,
+}
+
+/// Parse a jrnl JSON export or plain text journal, oldest entry first. jrnl stores times
+/// without an offset, so they are taken to be local times
+pub fn parse(text: &str) -> color_eyre::Result<Vec<Record>> {
+    let mut records = if text.trim_start().starts_with('{') {
+        parse_json(text)?
+    } else {
+        parse_text(text)?
+    };
+
+    records.sort_by_key(|record| record.timestamp);
+    Ok(records)
+}
+
+fn parse_json(text: &str) -> color_eyre::Result<Vec<Record>> {
+    let export: Export = serde_json::from_str(text).context("Failed to parse jrnl JSON export")?;
+
+    export
+        .entries
+        .into_iter()
+        .map(|entry| {
+            let Some(timestamp) = parse_timestamp(&format!("{} {}", entry.date, entry.time)) else {
+                bail!("Invalid date {} {} in jrnl export", entry.date, entry.time);
+            };
+
+            let mut tags = Vec::new();
+            for tag in &entry.tags {
+                push_tag(&mut tags, tag);
+            }
+
+            Ok(Record {
+                title: entry.title.trim().to_string(),
+                body: entry.body,
+                timestamp,
+                tags,
+                starred: entry.starred,
+            })
+        })
+        .collect()
+}
+
+/// Each entry starts with a line like `[2024-03-01 09:15] Title`, or `2024-03-01 09:15 Title`
+/// in journals from older versions, followed by its body. A starred title ends in ` *`
+fn parse_text(text: &str) -> color_eyre::Result<Vec<Record>> {
+    let mut records = Vec::new();
+
+    for line in text.lines() {
+        if let Some((timestamp, title)) = parse_header(line) {
+            let (title, starred) = match title.strip_suffix('*') {
+                Some(title) => (title.trim_end(), true),
+                None => (title, false),
+            };
+
+            records.push(Record {
+                title: title.to_string(),
+                body: String::new(),
+                timestamp,
+                tags: Vec::new(),
+                starred,
+            });
+        } else if let Some(record) = records.last_mut() {
+            record.body.push_str(line);
+            record.body.push('\n');
+        } else if !line.trim().is_empty() {
+            bail!("Expected a jrnl entry to start with a [date time] line, got {line:?}");
+        }
+    }
+
+    for record in &mut records {
+        for word in record
+            .title
+            .split_whitespace()
+            .chain(record.body.split_whitespace())
+        {
+            if let Some(tag) = word.strip_prefix('@') {
+                // jrnl tags are made of word characters and -+*#/
+                let end = tag
+                    .find(|c: char| !c.is_alphanumeric() && !"_-+*#/".contains(c))
+                    .unwrap_or(tag.len());
+                push_tag(&mut record.tags, &tag[..end]);
+            }
+        }
+    }
+
+    Ok(records)
+}
+
+fn parse_header(line: &str) -> Option<(OffsetDateTime, &str)> {
+    if let Some(rest) = line.strip_prefix('[') {
+        let (stamp, title) = rest.split_once(']')?;
+        return Some((parse_timestamp(stamp)?, title.trim()));
+    }
+
+    // Unbracketed headers are always `YYYY-MM-DD HH:MM`
+    let stamp = line.get(..16)?;
+    let title = &line[16..];
+    if !title.is_empty() && !title.starts_with(' ') {
+        return None;
+    }
+    Some((parse_timestamp(stamp)?, title.trim()))
+}
+
+/// Parse `YYYY-MM-DD HH:MM`, with optional seconds and an optional AM or PM
+fn parse_timestamp(stamp: &str) -> Option<OffsetDateTime> {
+    let mut parts = stamp.split_whitespace();
+    let date = Date::parse(parts.next()?, format_description!("[year]-[month]-[day]")).ok()?;
+
+    let mut clock = parts.next()?.split(':');
+    let mut hour: u8 = clock.next()?.parse().ok()?;
+    let minute = clock.next()?.parse().ok()?;
+    let second = clock.next().map_or(Some(0), |s| s.parse().ok())?;
+    if clock.next().is_some() {
+        return None;
+    }
+
+    match parts.next().map(str::to_ascii_uppercase).as_deref() {
+        None => {}
+        Some("AM") if (1..=12).contains(&hour) => hour %= 12,
+        Some("PM") if (1..=12).contains(&hour) => hour = hour % 12 + 12,
+        Some(_) => return None,
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+
+    let time = PrimitiveDateTime::new(date, Time::from_hms(hour, minute, second).ok()?);
+    let offset = UtcOffset::local_offset_at(time.assume_utc()).unwrap_or(UtcOffset::UTC);
+    Some(time.assume_offset(offset).to_offset(UtcOffset::UTC))
+}
+
+fn push_tag(tags: &mut Vec<String>, tag: &str) {
+    let tag = tag.strip_prefix('@').unwrap_or(tag);
+    if !tag.is_empty() && !tags.iter().any(|t| t == tag) {
+        tags.push(tag.to_string());
+    }
+}
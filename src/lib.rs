@@ -0,0 +1,1219 @@
+//! The diary file format and the operations on it, shared by the `diary` command line tool and
+//! anything else that wants to read or write diaries without running it.
+//!
+//! A closed diary is a single encrypted file, see [`header::Header`] for its layout. Opening it
+//! unpacks it into a directory holding `diary.json` and one file per entry, which [`Diary`]
+//! works on until it is closed again.
+
+pub mod archive;
+pub mod cipher;
+pub mod consts;
+pub mod entries;
+pub mod error;
+pub mod header;
+pub mod shred;
+mod sqlite;
+pub mod stats;
+
+use archive::{
+    FileFilter, PackOptions, archive_path, fingerprint, header_key, leftover_archives, pack_diary,
+    recorded_fingerprint, remove_plaintext, stream_archive, unpack_diary, verify_diary,
+};
+use cipher::{Algorithm, EntryKey, KdfParams};
+use consts::{DEDUP_MANIFEST, ENTRIES_DB, KEY_LENGTH, TRASH_DIR};
+use entries::{
+    Entries, Entry, MetadataFormat, attachments_dir, entry_file_name, load_entries,
+    load_entries_db, save_entries, trash_path, unique_name,
+};
+use error::{Context, DiaryError};
+use header::Header;
+use rand::Rng;
+use stats::{MoodStats, Report, Stats, Summary, display_time, streaks};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+/// An open diary: the directory it is unpacked in and the entries of its `diary.json`
+pub struct Diary {
+    dir: PathBuf,
+    entries: Entries,
+    /// Key of the encrypted entries, once they are unlocked
+    entry_key: Option<EntryKey>,
+}
+
+/// A diary file whose password has been checked against its header, see [`Diary::unlock`]
+pub struct Unlocked {
+    path: PathBuf,
+    file: File,
+    header: Header,
+    key: [u8; KEY_LENGTH],
+}
+
+/// How [`Unlocked::unpack`] unpacks a diary file
+#[derive(Default)]
+pub struct UnpackOptions {
+    /// Overwrite the decrypted archive before removing it, see `shred::remove_file`
+    pub secure_delete: bool,
+    /// Keep the decrypted archive at this path, instead of removing it once it is unpacked
+    pub keep_archive: Option<PathBuf>,
+}
+
+/// How a diary file compares to the open diary, see [`Diary::saved_state`]
+pub enum SavedState {
+    /// The file is intact and holds everything in the diary directory
+    Complete,
+    /// The file is intact, but the diary has changed since it was written
+    Changed,
+    /// The file failed verification with this error, such as a close that was interrupted
+    Incomplete(DiaryError),
+}
+
+impl Diary {
+    /// Create an empty diary in the directory `dir`, which must not exist yet, protected by
+    /// `password` and encrypted with `cipher` when it is closed. Entry files go into the
+    /// subdirectory `entries_dir` if one is given, and their metadata is stored as `format`
+    pub fn create(
+        dir: impl Into<PathBuf>,
+        password: String,
+        cipher: Algorithm,
+        entries_dir: Option<PathBuf>,
+        format: MetadataFormat,
+    ) -> Result<Self, DiaryError> {
+        let dir = dir.into();
+        if dir.exists() {
+            return Err(DiaryError::DiaryExists(dir.display().to_string()));
+        }
+
+        fs::create_dir(&dir).context("Failed to create directory for diary")?;
+
+        let entries = Entries {
+            entries: HashMap::default(),
+            key: password,
+            trashed: HashMap::default(),
+            cipher,
+            entries_dir,
+            comment: None,
+            entry_lock: None,
+            format,
+        };
+
+        entries.create_entries_dir(&dir)?;
+        save_entries(&entries, &dir)?;
+
+        Ok(Self {
+            dir,
+            entry_key: entries.legacy_key(),
+            entries,
+        })
+    }
+
+    /// Load the diary that is already open in the directory `dir`
+    pub fn load(dir: impl Into<PathBuf>) -> Result<Self, DiaryError> {
+        let dir = dir.into();
+        let entries = load_entries(&dir)?;
+
+        Ok(Self {
+            dir,
+            entry_key: entries.legacy_key(),
+            entries,
+        })
+    }
+
+    /// Read the header of the diary file `file` and check `password` against it, without
+    /// decrypting anything else yet
+    pub fn unlock(file: &Path, password: &str) -> Result<Unlocked, DiaryError> {
+        let path = file.to_path_buf();
+        let mut file = File::open(file).context("Failed to open diary file")?;
+        let header = Header::read(&mut file)?;
+        let key = header_key(&header, password)?;
+
+        Ok(Unlocked {
+            path,
+            file,
+            header,
+            key,
+        })
+    }
+
+    /// Decrypt the diary file `file` with `password`, check it against its digest and unpack it
+    /// into the directory `dir`
+    pub fn open(file: &Path, dir: impl Into<PathBuf>, password: &str) -> Result<Self, DiaryError> {
+        Self::unlock(file, password)?.unpack(dir, &UnpackOptions::default())
+    }
+
+    /// Pack, compress and encrypt the diary into the file `file` as `options` describe, leaving
+    /// it open. Returns the size of the directory, the archive and the diary file
+    pub fn snapshot(
+        &self,
+        file: &Path,
+        options: &PackOptions,
+    ) -> Result<(u64, u64, u64), DiaryError> {
+        pack_diary(
+            &self.dir,
+            &archive_path(&self.real_dir()?),
+            file,
+            &self.entries.key,
+            options,
+        )
+    }
+
+    /// Pack the diary into the file `file` like `snapshot`, then remove its directory
+    pub fn close(self, file: &Path, options: &PackOptions) -> Result<(u64, u64, u64), DiaryError> {
+        let sizes = self.snapshot(file, options)?;
+        self.discard(options.secure_delete)?;
+
+        Ok(sizes)
+    }
+
+    /// Pack the diary into the file `file` like `snapshot`, through a new file beside it which
+    /// only replaces `file` once it decrypts and matches its digest
+    pub fn replace(&self, file: &Path, options: &PackOptions) -> Result<(), DiaryError> {
+        let new = file.with_file_name(format!(
+            "{}.{}-{:08x}.new",
+            file.file_name().unwrap_or_default().display(),
+            std::process::id(),
+            rand::rng().next_u32()
+        ));
+
+        self.snapshot(&new, options)?;
+
+        if let Err(e) = verify_diary(
+            File::open(&new).context("Failed to open new diary file")?,
+            &self.entries.key,
+        ) {
+            fs::remove_file(&new).context("Failed to remove new diary file")?;
+            return Err(e);
+        }
+
+        fs::rename(&new, file).with_context(|| format!("Failed to replace {}", file.display()))
+    }
+
+    /// Remove the directory of the diary without packing it, overwriting its files first with
+    /// `secure_delete`. A diary opened in memory is a link to a directory there, which is
+    /// removed along with the temporary directory holding it
+    pub fn discard(self, secure_delete: bool) -> Result<(), DiaryError> {
+        let removed = (|| {
+            if !fs::symlink_metadata(&self.dir)?.is_symlink() {
+                return remove_plaintext(&self.dir, secure_delete);
+            }
+
+            let target = fs::read_link(&self.dir)?;
+            if target.exists() {
+                remove_plaintext(&target, secure_delete)?;
+            }
+            if let Some(temp) = target.parent() {
+                let _ = fs::remove_dir(temp);
+            }
+            fs::remove_file(&self.dir)
+        })();
+
+        removed.context("Failed to remove diary directory")
+    }
+
+    /// Remove the archives left beside the diary directory by interrupted runs, overwriting them
+    /// first with `secure_delete`
+    pub fn remove_leftover_archives(&self, secure_delete: bool) -> Result<(), DiaryError> {
+        for leftover in leftover_archives(&self.real_dir()?)? {
+            remove_plaintext(&leftover, secure_delete)
+                .with_context(|| format!("Failed to remove {}", leftover.display()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Check whether the diary file `file` holds the diary as it is now, as far as `filter`
+    /// keeps its files
+    pub fn saved_state(&self, file: &Path, filter: &FileFilter) -> Result<SavedState, DiaryError> {
+        let existing = File::open(file).context("Failed to open diary file")?;
+        if let Err(e) = verify_diary(existing, &self.entries.key) {
+            return Ok(SavedState::Incomplete(e));
+        }
+
+        let recorded = recorded_fingerprint(file, &self.entries.key)?;
+        let current = fingerprint(&self.dir, filter).context("Failed to hash diary")?;
+
+        Ok(match recorded {
+            Some(recorded) if recorded == current => SavedState::Complete,
+            _ => SavedState::Changed,
+        })
+    }
+
+    /// Directory the diary is unpacked in
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Entries of the diary, as recorded in its `diary.json`
+    pub fn entries(&self) -> &Entries {
+        &self.entries
+    }
+
+    /// Key of the encrypted entries, if they are unlocked
+    pub fn entry_key(&self) -> Option<&EntryKey> {
+        self.entry_key.as_ref()
+    }
+
+    /// Protect the diary with `password` from now on
+    pub fn set_password(&mut self, password: String) -> Result<(), DiaryError> {
+        // Entries encrypted before the diary had an entry password are keyed with the diary
+        // password as well
+        if self.entries.entry_lock.is_none() {
+            let key = EntryKey::legacy(&password);
+            self.entries
+                .rekey(&self.dir, &EntryKey::legacy(&self.entries.key), &key)?;
+            self.entry_key = Some(key);
+        }
+
+        self.entries.key = password;
+        save_entries(&self.entries, &self.dir)
+    }
+
+    /// Encrypt the diary with `cipher` when it is closed from now on
+    pub fn set_cipher(&mut self, cipher: Algorithm) -> Result<(), DiaryError> {
+        self.entries.cipher = cipher;
+        save_entries(&self.entries, &self.dir)
+    }
+
+    /// Label the diary file with `comment` when it is closed from now on, or with nothing
+    pub fn set_comment(&mut self, comment: Option<String>) -> Result<(), DiaryError> {
+        self.entries.comment = comment;
+        save_entries(&self.entries, &self.dir)
+    }
+
+    /// Add an entry called `name` with `content` as its markdown file, dated now
+    pub fn add_entry(&mut self, name: &str, content: &[u8]) -> Result<&Entry, DiaryError> {
+        let id = Uuid::new_v4();
+        let entry = Entry::new(
+            id,
+            self.entries.entry_path(PathBuf::from(format!("{id}.md"))),
+            OffsetDateTime::now_utc(),
+        );
+
+        self.insert_entry(name, entry, content)
+    }
+
+    /// Add `entry` as the entry called `name`, with `content` as its file. An encrypted entry
+    /// needs the encrypted entries to be unlocked
+    pub fn insert_entry(
+        &mut self,
+        name: &str,
+        entry: Entry,
+        content: &[u8],
+    ) -> Result<&Entry, DiaryError> {
+        if self.entries.entries.contains_key(name) {
+            return Err(DiaryError::EntryExists(name.to_string()));
+        }
+
+        self.entries.create_entries_dir(&self.dir)?;
+
+        // The new file is only kept once the diary knows about it
+        let path = self.dir.join(&entry.path);
+        let persisted = entry
+            .write_content(&self.dir, self.entry_key.as_ref(), content)
+            .and_then(|()| {
+                self.entries.entries.insert(name.to_string(), entry);
+                save_entries(&self.entries, &self.dir)
+            });
+
+        if let Err(e) = persisted {
+            self.entries.entries.remove(name);
+            match fs::remove_file(&path) {
+                Err(removing) if removing.kind() != io::ErrorKind::NotFound => {
+                    log::warn!("Failed to remove {}: {removing}", path.display());
+                }
+                _ => {}
+            }
+            return Err(e);
+        }
+
+        Ok(&self.entries.entries[name])
+    }
+
+    /// Content of the entry called `name`, decrypted if its file is encrypted
+    pub fn read_entry(&self, name: &str) -> Result<Vec<u8>, DiaryError> {
+        let Some(entry) = self.entries.entries.get(name) else {
+            return Err(DiaryError::NoSuchEntry(name.to_string()));
+        };
+
+        entry.read_content(&self.dir, self.entry_key.as_ref())
+    }
+
+    /// Load `diary.json` again after something else changed it, keeping the entries unlocked
+    pub fn reload(&mut self) -> Result<(), DiaryError> {
+        self.entries = load_entries(&self.dir)?;
+        if self.entries.entry_lock.is_none() {
+            self.entry_key = self.entries.legacy_key();
+        }
+
+        Ok(())
+    }
+
+    /// Change the metadata of the entry called `name` with `change` and save the diary
+    pub fn update_entry(
+        &mut self,
+        name: &str,
+        change: impl FnOnce(&mut Entry),
+    ) -> Result<&Entry, DiaryError> {
+        let Some(entry) = self.entries.entries.get_mut(name) else {
+            return Err(DiaryError::NoSuchEntry(name.to_string()));
+        };
+
+        change(entry);
+        save_entries(&self.entries, &self.dir)?;
+
+        Ok(&self.entries.entries[name])
+    }
+
+    /// Move the entry called `name` and its file to the trash, where it is kept under a numbered
+    /// name if another trashed entry has its name. Returns the id of the entry
+    pub fn trash_entry(&mut self, name: &str) -> Result<Uuid, DiaryError> {
+        let Some(entry) = self.entries.entries.get(name) else {
+            return Err(DiaryError::NoSuchEntry(name.to_string()));
+        };
+
+        fs::create_dir_all(self.dir.join(TRASH_DIR)).context("Failed to create trash directory")?;
+        fs::rename(
+            self.dir.join(&entry.path),
+            self.dir.join(trash_path(&entry.path)),
+        )
+        .context("Failed to move entry file to trash")?;
+
+        let entry = self
+            .entries
+            .entries
+            .remove(name)
+            .expect("entry was found above");
+        let id = entry.id;
+        let trashed = unique_name(&self.entries.trashed, name);
+        self.entries.trashed.insert(trashed, entry);
+        save_entries(&self.entries, &self.dir)?;
+
+        Ok(id)
+    }
+
+    /// Move the trashed entry called `name` and its file out of the trash, returning its id
+    pub fn restore_entry(&mut self, name: &str) -> Result<Uuid, DiaryError> {
+        if self.entries.entries.contains_key(name) {
+            return Err(DiaryError::EntryExists(name.to_string()));
+        }
+        let Some(entry) = self.entries.trashed.get(name) else {
+            return Err(DiaryError::NoSuchEntry(name.to_string()));
+        };
+
+        fs::rename(
+            self.dir.join(trash_path(&entry.path)),
+            self.dir.join(&entry.path),
+        )
+        .context("Failed to move entry file out of the trash")?;
+
+        let entry = self
+            .entries
+            .trashed
+            .remove(name)
+            .expect("entry was found above");
+        let id = entry.id;
+        self.entries.entries.insert(name.to_string(), entry);
+        save_entries(&self.entries, &self.dir)?;
+
+        Ok(id)
+    }
+
+    /// Delete the entries in the trash for good, with their files and attachments, returning the
+    /// names and ids of those deleted
+    pub fn empty_trash(&mut self) -> Result<Vec<(String, Uuid)>, DiaryError> {
+        let mut names = self.entries.trashed.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+
+        let mut deleted = Vec::new();
+        let mut purged = Ok(());
+        for name in names {
+            purged = self.purge_files(&self.entries.trashed[&name]);
+            if purged.is_err() {
+                break;
+            }
+            let entry = self.entries.trashed.remove(&name).expect("name is a key");
+            deleted.push((name, entry.id));
+        }
+
+        // Save the entries purged before any failure, so diary.json doesn't list deleted files
+        save_entries(&self.entries, &self.dir)?;
+        purged?;
+        Ok(deleted)
+    }
+
+    /// Delete the file and attachments of the trashed `entry`, counting those already gone as
+    /// deleted
+    fn purge_files(&self, entry: &Entry) -> Result<(), DiaryError> {
+        let ignore_missing = |result: io::Result<()>| match result {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        };
+
+        ignore_missing(fs::remove_file(self.dir.join(trash_path(&entry.path))))
+            .context("Failed to delete trashed entry file")?;
+        if !entry.attachments.is_empty() {
+            ignore_missing(fs::remove_dir_all(self.dir.join(attachments_dir(entry.id))))
+                .context("Failed to delete attachments of trashed entry")?;
+        }
+        Ok(())
+    }
+
+    /// Copy the entries of `source` into this diary along with their files and attachments, each
+    /// under its own name or a numbered one if that is taken. Entries this diary already has, by
+    /// id, are skipped. Encrypted entries need the entries of both diaries to be unlocked.
+    /// Returns the name each entry of `source` got here, or `None` if it was skipped
+    pub fn merge(&mut self, source: &Diary) -> Result<Vec<(String, Option<String>)>, DiaryError> {
+        let mut copied = Vec::new();
+        let merged = self
+            .merge_entries(source, &mut copied)
+            .and_then(|merged| save_entries(&self.entries, &self.dir).map(|()| merged));
+
+        // Don't leave files behind that the saved diary.json doesn't know about
+        if merged.is_err() {
+            for name in copied {
+                if let Some(copy) = self.entries.entries.remove(&name) {
+                    self.remove_entry_files(&copy);
+                }
+            }
+        }
+        merged
+    }
+
+    /// The copying part of `merge`, pushing the name of every entry added here to `copied`
+    fn merge_entries(
+        &mut self,
+        source: &Diary,
+        copied: &mut Vec<String>,
+    ) -> Result<Vec<(String, Option<String>)>, DiaryError> {
+        let mut names = source.entries.entries.keys().collect::<Vec<_>>();
+        names.sort();
+
+        let mut merged = Vec::new();
+        for name in names {
+            let entry = &source.entries.entries[name];
+
+            if self.entries.entries.values().any(|e| e.id == entry.id) {
+                merged.push((name.clone(), None));
+                continue;
+            }
+
+            let copy = Entry {
+                path: self
+                    .entries
+                    .entry_path(entry_file_name(entry.id, &entry.path)),
+                ..entry.clone()
+            };
+            self.copy_entry_files(entry, source, &copy)?;
+
+            let key = unique_name(&self.entries.entries, name);
+            self.entries.entries.insert(key.clone(), copy);
+            copied.push(key.clone());
+            merged.push((name.clone(), Some(key)));
+        }
+
+        Ok(merged)
+    }
+
+    /// Move the entry called `name` with its file and attachments into the diary `to`, under its
+    /// name or a numbered one if that is taken there. An encrypted entry needs the entries of
+    /// both diaries to be unlocked. Returns the name it got in `to`
+    pub fn move_entry(&mut self, name: &str, to: &mut Diary) -> Result<String, DiaryError> {
+        let Some(entry) = self.entries.entries.get(name).cloned() else {
+            return Err(DiaryError::NoSuchEntry(name.to_string()));
+        };
+
+        let moved = Entry {
+            path: to
+                .entries
+                .entry_path(entry_file_name(entry.id, &entry.path)),
+            ..entry.clone()
+        };
+        to.copy_entry_files(&entry, self, &moved)?;
+
+        let key = unique_name(&to.entries.entries, name);
+        to.entries.entries.insert(key.clone(), moved);
+        if let Err(e) = save_entries(&to.entries, &to.dir) {
+            let moved = to
+                .entries
+                .entries
+                .remove(&key)
+                .expect("entry was inserted above");
+            to.remove_entry_files(&moved);
+            return Err(e);
+        }
+
+        // Only remove the original once both diaries are saved, so a failure part way leaves the
+        // entry in one of them
+        self.entries.entries.remove(name);
+        if let Err(e) = save_entries(&self.entries, &self.dir) {
+            self.entries.entries.insert(name.to_string(), entry);
+            let moved = to
+                .entries
+                .entries
+                .remove(&key)
+                .expect("entry was inserted above");
+            if save_entries(&to.entries, &to.dir).is_ok() {
+                to.remove_entry_files(&moved);
+            }
+            return Err(e);
+        }
+
+        fs::remove_file(self.dir.join(&entry.path))
+            .context("Failed to remove original entry file")?;
+        if !entry.attachments.is_empty() {
+            fs::remove_dir_all(self.dir.join(attachments_dir(entry.id)))
+                .context("Failed to remove original attachments")?;
+        }
+
+        Ok(key)
+    }
+
+    /// Copy the file and attachments of `entry` in `source` into this diary as `copy`, removing
+    /// whatever was copied if that fails part way
+    fn copy_entry_files(
+        &self,
+        entry: &Entry,
+        source: &Diary,
+        copy: &Entry,
+    ) -> Result<(), DiaryError> {
+        if self.dir.join(&copy.path).exists() {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists))
+                .with_context(|| format!("File {} already exists", copy.path.display()));
+        }
+
+        self.entries.create_entries_dir(&self.dir)?;
+        let copied = entry
+            .copy_content(
+                &source.dir,
+                source.entry_key.as_ref(),
+                copy,
+                &self.dir,
+                self.entry_key.as_ref(),
+            )
+            .and_then(|()| entry.copy_attachments(&source.dir, copy, &self.dir));
+        if copied.is_err() {
+            self.remove_entry_files(copy);
+        }
+        copied
+    }
+
+    /// Remove the file and attachments of `entry` as far as they exist, undoing a copy
+    fn remove_entry_files(&self, entry: &Entry) {
+        let _ = fs::remove_file(self.dir.join(&entry.path));
+        if !entry.attachments.is_empty() {
+            let _ = fs::remove_dir_all(self.dir.join(attachments_dir(entry.id)));
+        }
+    }
+
+    /// Counts over the entries, see [`Summary`]
+    pub fn summary(&self) -> Result<Summary, DiaryError> {
+        Summary::new(&self.entries, &self.dir)
+    }
+
+    /// Entries and words per month, in UTC if `utc` is set. Encrypted entries need to be unlocked
+    pub fn report(&self, utc: bool) -> Result<Report, DiaryError> {
+        Report::new(&self.entries, &self.dir, self.entry_key.as_ref(), utc)
+    }
+
+    /// Writing statistics with the `top_tags` most used tags, in UTC if `utc` is set. Encrypted
+    /// entries need to be unlocked
+    pub fn stats(&self, top_tags: usize, utc: bool) -> Result<Stats, DiaryError> {
+        Stats::new(
+            &self.entries,
+            &self.dir,
+            self.entry_key.as_ref(),
+            top_tags,
+            utc,
+        )
+    }
+
+    /// Moods of the rated entries by month, in UTC if `utc` is set
+    pub fn mood_stats(&self, utc: bool) -> MoodStats {
+        MoodStats::new(&self.entries, utc)
+    }
+
+    /// Current and longest runs of days with an entry, in UTC if `utc` is set, see [`streaks`]
+    pub fn streaks(&self, utc: bool) -> (usize, usize) {
+        let days = self
+            .entries
+            .entries
+            .values()
+            .map(|e| display_time(e.timestamp, utc).date())
+            .collect::<BTreeSet<_>>();
+        let today = display_time(OffsetDateTime::now_utc(), utc).date();
+
+        streaks(&days, today)
+    }
+
+    /// Unlock the encrypted entries of the diary with their `password`, so they can be read and
+    /// written. Entries of a diary without an entry password need no unlocking
+    pub fn unlock_entries(&mut self, password: &str) -> Result<(), DiaryError> {
+        if let Some(lock) = &self.entries.entry_lock {
+            self.entry_key = Some(lock.unlock(password)?);
+        }
+
+        Ok(())
+    }
+
+    /// Unlock the encrypted entries of the diary with `password` like `unlock_entries`, making
+    /// it their password first if they have none yet, see [`Entries::lock`]
+    pub fn lock_entries(&mut self, password: &str, kdf: KdfParams) -> Result<(), DiaryError> {
+        if self.entries.entry_lock.is_some() {
+            return self.unlock_entries(password);
+        }
+
+        self.entry_key = Some(self.entries.lock(&self.dir, password, kdf)?);
+        Ok(())
+    }
+
+    /// The diary directory itself rather than the link to it left by opening it in memory, so
+    /// archives are written beside it and the plaintext stays in memory
+    fn real_dir(&self) -> Result<PathBuf, DiaryError> {
+        if fs::symlink_metadata(&self.dir)
+            .context("Failed to read diary directory")?
+            .is_symlink()
+        {
+            return fs::read_link(&self.dir).context("Failed to read diary directory link");
+        }
+
+        Ok(self.dir.clone())
+    }
+}
+
+impl Unlocked {
+    /// Header of the diary file
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Key the diary file is encrypted with
+    pub fn key(&self) -> [u8; KEY_LENGTH] {
+        self.key
+    }
+
+    /// Decrypt the diary, check it against its digest and unpack it into the directory `dir`,
+    /// which must not exist yet. The decrypted archive goes beside `dir`
+    pub fn unpack(
+        self,
+        dir: impl Into<PathBuf>,
+        options: &UnpackOptions,
+    ) -> Result<Diary, DiaryError> {
+        let dir = dir.into();
+        // Unpacking over an existing directory would write the diary's older files over newer
+        // ones
+        if fs::symlink_metadata(&dir).is_ok() {
+            return Err(DiaryError::DiaryExists(dir.display().to_string()));
+        }
+
+        let archive = match &options.keep_archive {
+            Some(archive) => archive.clone(),
+            None => archive_path(&dir),
+        };
+        unpack_diary(
+            self.file,
+            &self.header,
+            self.key,
+            &archive,
+            &dir,
+            options.secure_delete,
+        )?;
+
+        if options.keep_archive.is_none() {
+            remove_plaintext(&archive, options.secure_delete)
+                .context("Failed to remove diary archive")?;
+        }
+
+        Diary::load(dir)
+    }
+
+    /// Decrypt the file of the entry called `name` straight out of the diary file into `to`,
+    /// without unpacking anything else. `unlock` is asked for the key of the encrypted entries if
+    /// the entry is encrypted, and can fail with any error a [`DiaryError`] converts into
+    pub fn read_entry<E: From<DiaryError>>(
+        &self,
+        name: &str,
+        unlock: impl FnOnce(&Entries) -> Result<EntryKey, E>,
+        mut to: impl Write,
+    ) -> Result<(), E> {
+        let failed = |e: io::Error| E::from(DiaryError::from(e));
+        let mut unlock = Some(unlock);
+
+        // diary.json or diary.db is archived first, but older archives may have the entry before it.
+        // A deduplicated entry is only archived as the file the manifest at the end points it to
+        let mut found: Option<(Entry, Option<EntryKey>)> = None;
+        let mut archived_as = None;
+        let mut written = false;
+        for _ in 0..2 {
+            stream_archive::<E>(&self.path, self.key, |mut archived| {
+                let path = archived.path().map_err(failed)?.into_owned();
+
+                let is_db = path == Path::new(ENTRIES_DB);
+                if (path == Path::new("diary.json") || is_db) && found.is_none() {
+                    let entries = if is_db {
+                        let size = archived.header().size().map_err(failed)?;
+                        load_entries_db(&mut archived, size)?
+                    } else {
+                        serde_json::from_reader(&mut archived)
+                            .context("Failed to read diary.json")?
+                    };
+                    let Some(target) = entries.entries.get(name) else {
+                        return Err(DiaryError::NoSuchEntry(name.to_string()).into());
+                    };
+                    let key = match unlock.take() {
+                        Some(unlock) if target.encrypted => Some(unlock(&entries)?),
+                        _ => None,
+                    };
+                    archived_as = Some(target.path.clone());
+                    found = Some((target.clone(), key));
+                } else if path == Path::new(DEDUP_MANIFEST)
+                    && let Some((target, _)) = &found
+                {
+                    let mut duplicates: BTreeMap<PathBuf, PathBuf> =
+                        serde_json::from_reader(&mut archived)
+                            .context("Failed to read deduplication manifest")?;
+                    if let Some(original) = duplicates.remove(&target.path) {
+                        archived_as = Some(original);
+                    }
+                } else if let Some((target, key)) = &found
+                    && archived_as.as_ref() == Some(&path)
+                {
+                    match key {
+                        Some(key) => cipher::decrypt(
+                            archived,
+                            &mut to,
+                            key.file_key(&target.id),
+                            Algorithm::default(),
+                        )?,
+                        None => {
+                            io::copy(&mut archived, &mut to).context("Failed to write entry")?;
+                        }
+                    }
+                    to.flush().context("Failed to write entry")?;
+                    written = true;
+                }
+
+                Ok(!written)
+            })?;
+
+            if written || found.is_none() {
+                break;
+            }
+        }
+
+        match found {
+            _ if written => Ok(()),
+            Some((target, _)) => Err(DiaryError::Corrupt(format!(
+                "file {} of entry {name} is missing",
+                target.path.display()
+            ))
+            .into()),
+            None => Err(DiaryError::Corrupt("no diary.json or diary.db".to_string()).into()),
+        }
+    }
+
+    /// Decrypt the archive in the diary file into `to` without checking it against its digest.
+    /// Chunks are written out as they are decrypted, so a failure keeps everything before it
+    pub fn decrypt(self, to: impl Write) -> Result<(), DiaryError> {
+        cipher::decrypt(self.file, to, self.key, self.header.cipher)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use archive::Compression;
+    use cipher::KdfParams;
+
+    /// Cheapest Argon2 costs, so tests don't spend their time deriving keys
+    const KDF: KdfParams = KdfParams {
+        memory: 8,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    fn create(dir: &Path) -> Diary {
+        Diary::create(
+            dir,
+            "password".to_string(),
+            Algorithm::default(),
+            None,
+            MetadataFormat::Json,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn create_refuses_existing_directory() {
+        let temp = tempfile::tempdir().unwrap();
+
+        let created = Diary::create(
+            temp.path(),
+            "password".to_string(),
+            Algorithm::default(),
+            None,
+            MetadataFormat::Json,
+        );
+        assert!(matches!(created, Err(DiaryError::DiaryExists(_))));
+    }
+
+    #[test]
+    fn add_entry_refuses_taken_name() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut diary = create(&temp.path().join("diary"));
+
+        diary.add_entry("today", b"first").unwrap();
+        let added = diary.add_entry("today", b"second");
+        assert!(matches!(added, Err(DiaryError::EntryExists(name)) if name == "today"));
+        assert_eq!(diary.read_entry("today").unwrap(), b"first");
+    }
+
+    #[test]
+    fn failed_merge_leaves_no_copies() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut source = create(&temp.path().join("source"));
+        source.add_entry("a", b"first").unwrap();
+        source.add_entry("b", b"second").unwrap();
+        fs::remove_file(source.dir().join(&source.entries().entries["b"].path)).unwrap();
+        let mut target = create(&temp.path().join("target"));
+
+        assert!(target.merge(&source).is_err());
+        assert!(target.entries().entries.is_empty());
+        assert!(load_entries(target.dir()).unwrap().entries.is_empty());
+        let copied = source.entries().entries["a"].path.file_name().unwrap();
+        assert!(
+            !target
+                .dir()
+                .join(target.entries().entry_path(copied.into()))
+                .exists()
+        );
+    }
+
+    #[test]
+    fn failed_move_keeps_entry_in_source() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut source = create(&temp.path().join("source"));
+        source.add_entry("today", b"dear diary").unwrap();
+        let entry = source
+            .update_entry("today", |entry| {
+                entry.attachments = vec![attachments_dir(entry.id).join("missing.jpg")];
+            })
+            .unwrap()
+            .clone();
+        let mut target = create(&temp.path().join("target"));
+
+        assert!(source.move_entry("today", &mut target).is_err());
+        assert!(source.entries().entries.contains_key("today"));
+        assert_eq!(source.read_entry("today").unwrap(), b"dear diary");
+        assert!(target.entries().entries.is_empty());
+        let copied = target
+            .entries()
+            .entry_path(entry_file_name(entry.id, &entry.path));
+        assert!(!target.dir().join(copied).exists());
+    }
+
+    #[test]
+    fn empty_trash_saves_what_it_deleted() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut diary = create(&temp.path().join("diary"));
+        for name in ["a", "b", "c"] {
+            diary.add_entry(name, name.as_bytes()).unwrap();
+            diary.trash_entry(name).unwrap();
+        }
+        let trashed = |diary: &Diary, name| {
+            diary
+                .dir()
+                .join(trash_path(&diary.entries().trashed[name].path))
+        };
+        // Already gone, as after an earlier failed attempt
+        fs::remove_file(trashed(&diary, "a")).unwrap();
+        // Can't be removed as a file
+        let stuck = trashed(&diary, "b");
+        fs::remove_file(&stuck).unwrap();
+        fs::create_dir_all(stuck.join("in the way")).unwrap();
+
+        assert!(diary.empty_trash().is_err());
+        let mut left = load_entries(diary.dir())
+            .unwrap()
+            .trashed
+            .into_keys()
+            .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(left, ["b", "c"]);
+
+        fs::remove_dir_all(&stuck).unwrap();
+        let mut diary = Diary::load(diary.dir()).unwrap();
+        let deleted = diary.empty_trash().unwrap();
+        assert_eq!(
+            deleted
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>(),
+            ["b", "c"]
+        );
+        assert!(load_entries(diary.dir()).unwrap().trashed.is_empty());
+    }
+
+    #[test]
+    fn deduplicated_entries_open_and_get() {
+        let temp = tempfile::tempdir().unwrap();
+        let file = temp.path().join("diary.diary");
+        let mut diary = create(&temp.path().join("diary"));
+        diary.add_entry("monday", b"same again").unwrap();
+        diary.add_entry("tuesday", b"same again").unwrap();
+        diary.add_entry("wednesday", b"different").unwrap();
+        diary
+            .close(
+                &file,
+                &PackOptions {
+                    kdf: KDF,
+                    dedup: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let unlocked = Diary::unlock(&file, "password").unwrap();
+        for (name, content) in [
+            ("monday", &b"same again"[..]),
+            ("tuesday", b"same again"),
+            ("wednesday", b"different"),
+        ] {
+            let mut read = Vec::new();
+            unlocked
+                .read_entry::<DiaryError>(name, |_| unreachable!("nothing is encrypted"), &mut read)
+                .unwrap();
+            assert_eq!(read, content);
+        }
+
+        let diary = Diary::open(&file, temp.path().join("opened"), "password").unwrap();
+        assert_eq!(diary.read_entry("monday").unwrap(), b"same again");
+        assert_eq!(diary.read_entry("tuesday").unwrap(), b"same again");
+        assert!(!diary.dir().join(DEDUP_MANIFEST).exists());
+    }
+
+    #[test]
+    fn closing_again_derives_the_key_once() {
+        let temp = tempfile::tempdir().unwrap();
+        let file = temp.path().join("diary.diary");
+        let mut diary = create(&temp.path().join("diary"));
+        let options = PackOptions {
+            kdf: KDF,
+            ..Default::default()
+        };
+
+        let before = cipher::DERIVATIONS.with(|d| d.get());
+        diary.add_entry("monday", b"first").unwrap();
+        diary.snapshot(&file, &options).unwrap();
+        fs::copy(&file, temp.path().join("snapshot.diary")).unwrap();
+        diary.add_entry("tuesday", b"second").unwrap();
+        diary.close(&file, &options).unwrap();
+        assert_eq!(cipher::DERIVATIONS.with(|d| d.get()) - before, 1);
+
+        let snapshot = temp.path().join("snapshot.diary");
+        let diary = Diary::open(&snapshot, temp.path().join("snapshot"), "password").unwrap();
+        assert_eq!(diary.read_entry("monday").unwrap(), b"first");
+        assert!(!diary.entries().entries.contains_key("tuesday"));
+        let diary = Diary::open(&file, temp.path().join("closed"), "password").unwrap();
+        assert_eq!(diary.read_entry("tuesday").unwrap(), b"second");
+    }
+
+    #[test]
+    fn trashed_entry_restores() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut diary = create(&temp.path().join("diary"));
+        diary.add_entry("today", b"dear diary").unwrap();
+
+        let id = diary.trash_entry("today").unwrap();
+        assert!(!diary.entries().entries.contains_key("today"));
+        assert_eq!(diary.entries().trashed["today"].id, id);
+
+        let restored = diary.restore_entry("today").unwrap();
+        assert_eq!(restored, id);
+        let diary = Diary::load(diary.dir()).unwrap();
+        assert_eq!(diary.read_entry("today").unwrap(), b"dear diary");
+        assert!(matches!(
+            Diary::load(diary.dir()).unwrap().restore_entry("today"),
+            Err(DiaryError::EntryExists(_))
+        ));
+    }
+
+    #[test]
+    fn read_entry_refuses_unknown_name() {
+        let temp = tempfile::tempdir().unwrap();
+        let diary = create(&temp.path().join("diary"));
+
+        let read = diary.read_entry("missing");
+        assert!(matches!(read, Err(DiaryError::NoSuchEntry(name)) if name == "missing"));
+    }
+
+    #[test]
+    fn load_refuses_directory_without_diary() {
+        let temp = tempfile::tempdir().unwrap();
+
+        let loaded = Diary::load(temp.path());
+        assert!(matches!(loaded, Err(DiaryError::File { source, .. })
+            if source.kind() == std::io::ErrorKind::NotFound));
+    }
+
+    #[test]
+    fn open_checks_password() {
+        let temp = tempfile::tempdir().unwrap();
+        let file = temp.path().join("diary.diary");
+        let mut diary = create(&temp.path().join("diary"));
+        diary.add_entry("today", b"dear diary").unwrap();
+        diary
+            .close(
+                &file,
+                &PackOptions {
+                    kdf: KDF,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let opened = Diary::open(&file, temp.path().join("wrong"), "not the password");
+        assert!(matches!(opened, Err(DiaryError::WrongPassword)));
+        assert!(!temp.path().join("wrong").exists());
+
+        let diary = Diary::open(&file, temp.path().join("right"), "password").unwrap();
+        assert_eq!(diary.read_entry("today").unwrap(), b"dear diary");
+    }
+
+    fn encrypted_entry(diary: &Diary) -> Entry {
+        let id = Uuid::new_v4();
+        Entry {
+            encrypted: true,
+            ..Entry::new(
+                id,
+                diary
+                    .entries()
+                    .entry_path(PathBuf::from(format!("{id}.md"))),
+                OffsetDateTime::now_utc(),
+            )
+        }
+    }
+
+    #[test]
+    fn insert_entry_reports_why_it_failed() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut diary = create(&temp.path().join("diary"));
+        diary.lock_entries("entry password", KDF).unwrap();
+
+        // Loading forgets the entry key, so the entry can't be encrypted
+        let mut diary = Diary::load(diary.dir()).unwrap();
+        let entry = encrypted_entry(&diary);
+        let path = diary.dir().join(&entry.path);
+
+        let inserted = diary.insert_entry("secret", entry, b"hidden");
+        assert!(matches!(inserted, Err(DiaryError::EntryLocked(_))));
+        assert!(!path.exists());
+        assert!(
+            !load_entries(diary.dir())
+                .unwrap()
+                .entries
+                .contains_key("secret")
+        );
+
+        diary.unlock_entries("entry password").unwrap();
+        let entry = encrypted_entry(&diary);
+        diary.insert_entry("secret", entry, b"hidden").unwrap();
+        assert_eq!(diary.read_entry("secret").unwrap(), b"hidden");
+    }
+
+    #[test]
+    fn saved_state_follows_changes() {
+        let temp = tempfile::tempdir().unwrap();
+        let file = temp.path().join("diary.diary");
+        let mut diary = create(&temp.path().join("diary"));
+        let options = PackOptions {
+            kdf: KDF,
+            ..Default::default()
+        };
+        let filter = FileFilter::default();
+
+        diary.snapshot(&file, &options).unwrap();
+        assert!(matches!(
+            diary.saved_state(&file, &filter),
+            Ok(SavedState::Complete)
+        ));
+
+        diary.add_entry("today", b"dear diary").unwrap();
+        assert!(matches!(
+            diary.saved_state(&file, &filter),
+            Ok(SavedState::Changed)
+        ));
+
+        diary.snapshot(&file, &options).unwrap();
+        let length = fs::metadata(&file).unwrap().len();
+        File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_len(length - 1)
+            .unwrap();
+        assert!(matches!(
+            diary.saved_state(&file, &filter),
+            Ok(SavedState::Incomplete(_))
+        ));
+    }
+
+    #[test]
+    fn set_password_rekeys_legacy_entries() {
+        let temp = tempfile::tempdir().unwrap();
+        let file = temp.path().join("diary.diary");
+        let mut diary = create(&temp.path().join("diary"));
+        let entry = encrypted_entry(&diary);
+        diary.insert_entry("secret", entry, b"hidden").unwrap();
+
+        diary.set_password("new password".to_string()).unwrap();
+        diary
+            .replace(
+                &file,
+                &PackOptions {
+                    kdf: KDF,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        assert!(matches!(
+            Diary::unlock(&file, "password"),
+            Err(DiaryError::WrongPassword)
+        ));
+        let diary = Diary::open(&file, temp.path().join("opened"), "new password").unwrap();
+        assert_eq!(diary.read_entry("secret").unwrap(), b"hidden");
+    }
+
+    #[test]
+    fn large_entry_survives_close_and_open() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut large = vec![0; (5 << 20) + 123];
+        rand::rng().fill_bytes(&mut large);
+
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let file = temp.path().join("diary.diary");
+            let mut diary = create(&temp.path().join("diary"));
+            diary.add_entry("photo", &large).unwrap();
+            diary
+                .close(
+                    &file,
+                    &PackOptions {
+                        compression,
+                        kdf: KDF,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+
+            let diary = Diary::open(&file, temp.path().join("diary"), "password").unwrap();
+            assert!(diary.read_entry("photo").unwrap() == large);
+            diary.discard(false).unwrap();
+            fs::remove_file(&file).unwrap();
+        }
+    }
+}
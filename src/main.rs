@@ -1,244 +1,5073 @@
-use cipher::hash_password;
-use clap::Parser;
-use cli::{Cli, Command, EntryCommand};
-use color_eyre::eyre::{Context, bail};
-use entries::{Entries, Entry};
-use flate2::{Compression, read::GzDecoder, write::GzEncoder};
-use rand::Rng;
+use cipher::{Algorithm, EntryKey, KdfParams, hash_password};
+use clap::{CommandFactory, FromArgMatches, ValueEnum};
+use clap_complete::{CompleteEnv, env::Shells};
+use cli::{
+    BackupCommand, Cli, Command, EntryCommand, ExportCommand, ExportFormat, Format, GitCommand,
+    ImportCommand, NewTarget, OutputMode, PasswordArgs, RemoteCommand, SortOrder, SyncCommand,
+    TagCommand, parse_entry_name,
+};
+use color_eyre::eyre::{Context, bail, eyre};
+use entries::{
+    Entries, Entry, attachments_dir, entry_file_name, load_entries, metadata_path, save_entries,
+    trash_path, unique_name,
+};
+use error::DiaryError;
+use header::Header;
+use log::{LevelFilter, debug};
+use remote::{Remote, Remotes, Target};
+use serde::Serialize;
+use similar::TextDiff;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet},
     fs::{self, File},
-    io::{Read, Seek, SeekFrom, Write},
-    path::PathBuf,
+    io::{self, IsTerminal, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+use time::{
+    OffsetDateTime, UtcOffset, format_description::well_known::Rfc3339, macros::format_description,
 };
-use tar::{Archive, Builder};
-use time::OffsetDateTime;
 use uuid::Uuid;
 
-mod consts;
-use consts::SALT_LENGTH;
+/// Set by `--quiet` to silence `info!`
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Set by `--output json` to collect what commands print into a single JSON object
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// Set by `--entry-password-command` to read the password of encrypted entries without prompting
+static ENTRY_PASSWORD_COMMAND: OnceLock<String> = OnceLock::new();
+
+/// Messages and output collected in JSON output mode
+static CAPTURED: Mutex<Captured> = Mutex::new(Captured {
+    messages: Vec::new(),
+    output: Vec::new(),
+    data: None,
+});
+
+struct Captured {
+    messages: Vec<String>,
+    output: Vec<u8>,
+    data: Option<serde_json::Value>,
+}
+
+/// Give the result of a command as `data` in JSON output mode, alongside what it printed
+fn emit(data: impl Serialize) {
+    if JSON_OUTPUT.load(Ordering::Relaxed) {
+        CAPTURED.lock().unwrap().data = serde_json::to_value(data).ok();
+    }
+}
+
+/// Print an informational message, unless `--quiet` was given
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            if JSON_OUTPUT.load(Ordering::Relaxed) {
+                CAPTURED.lock().unwrap().messages.push(format!($($arg)*));
+            } else {
+                println!($($arg)*);
+            }
+        }
+    };
+}
+
+/// Print command output to stdout, or collect it in JSON output mode
+macro_rules! out {
+    ($($arg:tt)*) => {
+        CommandOutput
+            .write_fmt(format_args!($($arg)*))
+            .expect("failed printing to stdout")
+    };
+}
+
+/// Like `out!` with a newline
+macro_rules! outln {
+    () => {
+        out!("\n")
+    };
+    ($($arg:tt)*) => {
+        out!("{}\n", format_args!($($arg)*))
+    };
+}
+
+/// Stdout of a command, collected instead of written in JSON output mode
+struct CommandOutput;
+
+impl Write for CommandOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if JSON_OUTPUT.load(Ordering::Relaxed) {
+            CAPTURED.lock().unwrap().output.extend_from_slice(buf);
+            Ok(buf.len())
+        } else {
+            io::stdout().write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if JSON_OUTPUT.load(Ordering::Relaxed) {
+            Ok(())
+        } else {
+            io::stdout().flush()
+        }
+    }
+}
+
+/// Outcome of a command as printed by `--output json`
+#[derive(Serialize)]
+struct Outcome {
+    success: bool,
+    command: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diary: Option<String>,
+    messages: Vec<String>,
+    output: String,
+    /// What the command produced, such as the id of a new entry or the entries listed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// Kind of error for programs to match on, such as `wrong_password`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'static str>,
+}
+
+/// Code for the kind of `error`, from the first diary error in its chain
+fn error_code(error: &color_eyre::Report) -> &'static str {
+    if let Some(e) = error.chain().find_map(|e| e.downcast_ref::<DiaryError>()) {
+        return e.code();
+    }
+    if error
+        .chain()
+        .any(|e| e.downcast_ref::<io::Error>().is_some())
+    {
+        return "io";
+    }
+    "failed"
+}
+
+/// Report the result of `command` in the output mode that was asked for. In JSON mode the
+/// outcome is printed as one line on stdout and failures only set the exit code
+fn report(
+    result: color_eyre::Result<()>,
+    mode: OutputMode,
+    command: String,
+    diary: Option<String>,
+) -> color_eyre::Result<()> {
+    if mode == OutputMode::Human {
+        return result;
+    }
+
+    let outcome = outcome(&result, command, diary);
+    println!(
+        "{}",
+        serde_json::to_string(&outcome).context("Failed to serialize outcome")?
+    );
+
+    if !outcome.success {
+        process::exit(1);
+    }
+
+    Ok(())
+}
+
+/// Collect the outcome of `command` from `result` and what it captured in JSON output mode
+fn outcome(result: &color_eyre::Result<()>, command: String, diary: Option<String>) -> Outcome {
+    let captured = std::mem::replace(
+        &mut *CAPTURED.lock().unwrap(),
+        Captured {
+            messages: Vec::new(),
+            output: Vec::new(),
+            data: None,
+        },
+    );
+    Outcome {
+        success: result.is_ok(),
+        command,
+        diary,
+        messages: captured.messages,
+        output: String::from_utf8_lossy(&captured.output).into_owned(),
+        data: captured.data,
+        error: result.as_ref().err().map(|e| format!("{e:#}")),
+        code: result.as_ref().err().map(error_code),
+    }
+}
+
+use archive::{
+    Compression, FileFilter, PackOptions, PlaintextGuard, archive_path, dir_size, fingerprint,
+    recover_archive, restore_duplicates, unpack_archive,
+};
+use consts::{
+    ENTRIES_DIR, MAGIC, MAX_ENTRY_NAME_LENGTH, MAX_MOOD, SALT_LENGTH, SAVED_MARKER, TEMPLATES_DIR,
+};
+use diary::{
+    Diary, SavedState, Unlocked, UnpackOptions, archive, cipher, consts, entries, error, header,
+    shred, stats::display_time,
+};
 
-mod cipher;
 mod cli;
-mod entries;
+mod config;
+mod html;
+mod jrnl;
+mod password;
+mod remote;
+mod sync;
+mod tui;
 
 fn main() -> color_eyre::Result<()> {
     color_eyre::install()?;
 
-    let cli = Cli::parse();
+    let config = config::Config::load()?;
+    // Completion requests from the scripts `completions` prints are answered here and exit
+    CompleteEnv::with_factory(|| with_config_defaults(Cli::command(), &config)).complete();
+    let matches = with_config_defaults(Cli::command(), &config).get_matches();
+    config.install();
+    let mut cli = Cli::from_arg_matches(&matches)?;
+    apply_global_flags(&mut cli);
+
+    env_logger::Builder::new()
+        .filter_level(log_level(cli.verbose))
+        .format_target(false)
+        .format_timestamp(None)
+        .init();
+
+    let mode = cli.output;
+    let command = matches.subcommand_name().unwrap_or_default().to_string();
+    let diary = cli.command.diary_name().map(str::to_string);
+
+    report(run(cli), mode, command, diary)
+}
+
+/// How much to log for `--verbose` given `verbose` times: warnings only, every step, or everything
+fn log_level(verbose: u8) -> LevelFilter {
+    match verbose {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Store the global flags of `cli` that commands read from statics rather than from `cli`
+fn apply_global_flags(cli: &mut Cli) {
+    QUIET.store(cli.quiet, Ordering::Relaxed);
+    if cli.json {
+        cli.output = OutputMode::Json;
+    }
+    JSON_OUTPUT.store(cli.output == OutputMode::Json, Ordering::Relaxed);
+    entries::MAX_ENTRY_SIZE.store(cli.max_entry_size, Ordering::Relaxed);
+    if let Some(command) = cli.entry_password_command.take() {
+        let _ = ENTRY_PASSWORD_COMMAND.set(command);
+    }
+}
+
+/// Make the configured diary and compression level the defaults of the arguments they stand in
+/// for, so flags still override them
+fn with_config_defaults(mut command: clap::Command, config: &config::Config) -> clap::Command {
+    if let Some(level) = config.level {
+        for name in ["close", "shell"] {
+            command = command.mut_subcommand(name, |c| {
+                c.mut_arg("level", |a| a.default_value(level.to_string()))
+            });
+        }
+    }
+
+    if let Some(diary) = &config.diary {
+        let default_name = |c: clap::Command| {
+            c.mut_arg("name", |a| a.default_value(diary.clone()).required(false))
+        };
+        for name in [
+            "open", "close", "shell", "extract", "migrate", "recover", "passwd", "peek",
+        ] {
+            command = command.mut_subcommand(name, default_name);
+        }
+        command = command.mut_subcommand("export", |c| {
+            c.mut_subcommand("html", default_name)
+                .mut_subcommand("metadata", default_name)
+        });
+    }
+
+    command
+}
 
+fn run(cli: Cli) -> color_eyre::Result<()> {
     match cli.command {
-        Command::New { name } => {
-            let key = {
-                let p1 = rpassword::prompt_password("Enter password: ")?;
-                let p2 = rpassword::prompt_password("Re-enter password: ")?;
+        Command::New {
+            name,
+            password,
+            cipher,
+            entries_dir,
+            require_strong,
+            metadata,
+        } => {
+            if Path::new(&name).exists() || Path::new(&format!("{name}.diary")).exists() {
+                return Err(DiaryError::DiaryExists(name).into());
+            }
+
+            let key = password::read_new_password(&password)?;
+            password::check_strength(&key, &[&name], require_strong)?;
+
+            Diary::create(
+                &name,
+                key,
+                cipher,
+                entries_dir.then(|| PathBuf::from(ENTRIES_DIR)),
+                metadata,
+            )?;
+
+            info!("Created diary {name}");
+        }
+        Command::Open {
+            name,
+            password,
+            tries,
+            file,
+            secure_delete,
+            memory,
+            no_sync,
+        } => {
+            let file = diary_file(&name, file)?;
+
+            let repo = repo_dir(&file);
+            if !no_sync && !cli.dry_run && sync::enabled(repo) && sync::has_upstream(repo) {
+                sync::pull(repo)
+                    .wrap_err("Failed to pull, rerun with --no-sync to open the local copy")?;
+            }
+
+            if memory {
+                let ram = ram_dir()?;
 
-                if p1 != p2 {
-                    bail!("Passwords do not match");
+                if cli.dry_run {
+                    outln!("Would decrypt {} into {}", file.display(), ram.display());
+                    outln!("Would link {name} to the unpacked diary");
+                    return Ok(());
                 }
 
-                p1
-            };
+                if fs::symlink_metadata(&name).is_ok() {
+                    bail!("{name} already exists");
+                }
 
-            fs::create_dir(&name).context("Failed to create directory for diary")?;
+                let unlocked = unlock_diary(&file, &password, "Enter password: ", tries)?;
 
-            let mut diary_handle = File::create_new(format!("{name}/diary.json"))
-                .context("Failed to create diary file")?;
+                // Dropping the directory removes it again if anything fails before it is linked
+                let mut temp = tempfile::Builder::new()
+                    .prefix("diary-")
+                    .tempdir_in(&ram)
+                    .context("Failed to create directory in memory")?;
+                let dir = temp.path().join(&name);
 
-            let entries = Entries {
-                entries: HashMap::default(),
-                key,
-            };
+                unlocked.unpack(&dir, &UnpackOptions::default())?;
+                link_dir(&dir, Path::new(&name)).context("Failed to link diary directory")?;
+                temp.disable_cleanup(true);
 
-            serde_json::to_writer(&mut diary_handle, &entries)
-                .context("Failed to save diary file")?;
+                info!(
+                    "Diary opened in {}, {} is kept until it is closed.",
+                    dir.display(),
+                    file.display()
+                );
+                return Ok(());
+            }
+
+            // Checked before asking for the password, unpacking refuses it as well
+            if fs::symlink_metadata(&name).is_ok() {
+                return Err(DiaryError::DiaryExists(name).into());
+            }
+
+            if cli.dry_run {
+                let archive = archive_path(Path::new(&name));
+                outln!(
+                    "Would decrypt {} into {}",
+                    file.display(),
+                    archive.display()
+                );
+                outln!("Would unpack {} into directory {name}", archive.display());
+                outln!("Would remove {}", file.display());
+                outln!("Would remove {}", archive.display());
+                return Ok(());
+            }
+
+            unlock_diary(&file, &password, "Enter password: ", tries)?.unpack(
+                &name,
+                &UnpackOptions {
+                    secure_delete,
+                    ..Default::default()
+                },
+            )?;
 
-            println!("Created diary {name}");
+            fs::remove_file(&file).context("Failed to remove diary file")?;
+
+            info!("Diary opened.");
         }
-        Command::Open { name } => {
-            let mut diary =
-                File::open(format!("{name}.diary")).context("Failed to open diary file")?;
+        Command::Shell {
+            name,
+            password,
+            command,
+            level,
+        } => {
+            let file = PathBuf::from(format!("{name}.diary"));
+            let unlocked = unlock_diary(&file, &password, "Enter password: ", 1)?;
+            let compression = unlocked.header().compression;
 
-            let key = rpassword::prompt_password("Enter password: ")?;
+            let temp = tempfile::Builder::new()
+                .prefix("diary-")
+                .tempdir()
+                .context("Failed to create temporary directory")?;
+            let dir = temp.path().join(&name);
 
-            let mut salt = [0u8; SALT_LENGTH];
-            diary.read_exact(&mut salt)?;
+            unlocked.unpack(&dir, &UnpackOptions::default())?;
 
-            let key = hash_password(key.as_bytes(), &salt)?;
+            // Interrupts go to the session, this process has to survive them to close the diary.
+            // A handler installed by an earlier session in this process does that already
+            match ctrlc::set_handler(|| {}) {
+                Ok(()) | Err(ctrlc::Error::MultipleHandlers) => {}
+                Err(e) => return Err(e).context("Failed to install interrupt handler"),
+            }
 
-            let mut decrypted = File::create_new(format!("{name}.tar.gz"))
-                .context("Failed to create archive file")?;
+            let command = command
+                .unwrap_or_else(|| std::env::var("SHELL").unwrap_or_else(|_| "sh".to_string()));
+            info!(
+                "Diary opened in {}, exit the session to close it.",
+                dir.display()
+            );
+            let status = shell_command(&command).current_dir(&dir).status();
+
+            // The session may have changed anything, down to the cipher
+            let closed = Diary::load(&dir).and_then(|diary| {
+                let options = PackOptions {
+                    compression,
+                    level,
+                    cipher: diary.entries().cipher,
+                    kdf: config::get().kdf(),
+                    comment: diary.entries().comment.clone(),
+                    ..Default::default()
+                };
+                diary.close(&file, &options)
+            });
+
+            if let Err(e) = closed {
+                let kept = temp.keep();
+                return Err(color_eyre::Report::from(e).wrap_err(format!(
+                    "Failed to close diary, the session is kept in {}",
+                    kept.display()
+                )));
+            }
+
+            temp.close()
+                .context("Failed to remove temporary directory")?;
+
+            match status {
+                Ok(status) if !status.success() => outln!("Session exited with {status}"),
+                Ok(_) => {}
+                Err(e) => outln!("Failed to start session: {e}"),
+            }
 
-            cipher::decrypt(diary, &mut decrypted, key).context("Failed to decrypt")?;
+            info!("Diary closed.");
+        }
+        Command::List { dir } => {
+            let mut diaries = Vec::new();
+
+            for entry in fs::read_dir(&dir).context("Failed to read directory")? {
+                let entry = entry?;
+                let path = entry.path();
+                // Follows the link left by `open --memory`, which dangles after a reboot
+                let Ok(metadata) = fs::metadata(&path) else {
+                    continue;
+                };
+
+                if metadata.is_dir() && metadata_path(&path).is_file() {
+                    let size = dir_size(&path).context("Failed to measure diary")?;
+                    diaries.push((entry.file_name(), "open", size, metadata.modified()?));
+                } else if metadata.is_file() && path.extension().is_some_and(|e| e == "diary") {
+                    let name = path.file_stem().unwrap_or_default().to_os_string();
+                    diaries.push((name, "closed", metadata.len(), metadata.modified()?));
+                }
+            }
+
+            diaries.sort();
+
+            emit(
+                diaries
+                    .iter()
+                    .map(|(name, state, size, modified)| {
+                        serde_json::json!({
+                            "name": name.to_string_lossy(),
+                            "state": state,
+                            "size": size,
+                            "modified": OffsetDateTime::from(*modified).format(&Rfc3339).ok(),
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+            );
+            for (name, state, size, modified) in diaries {
+                outln!(
+                    "{} ({}):\n\tsize: {} bytes\n\tmodified: {}",
+                    name.display(),
+                    state,
+                    size,
+                    format_time(modified.into(), cli.no_local_time)
+                );
+            }
+        }
+        Command::Extract {
+            name,
+            password,
+            keep_archive,
+            secure_delete,
+        } => {
+            if fs::symlink_metadata(&name).is_ok() {
+                return Err(DiaryError::DiaryExists(name).into());
+            }
+
+            let file = PathBuf::from(format!("{name}.diary"));
+            let archive = keep_archive.then(|| archive_path(Path::new(&name)));
+            unlock_diary(&file, &password, "Enter password: ", 1)?.unpack(
+                &name,
+                &UnpackOptions {
+                    secure_delete,
+                    keep_archive: archive.clone(),
+                },
+            )?;
+
+            if let Some(archive) = archive {
+                info!("Kept decrypted archive {}", archive.display());
+            }
+
+            info!("Diary extracted, {name}.diary was left in place.");
+        }
+        Command::Get {
+            name,
+            entry,
+            password,
+            out_file: output,
+            file,
+        } => {
+            let file = diary_file(&name, file)?;
+            let unlocked = unlock_diary(&file, &password, "Enter password: ", 1)?;
+
+            if let Some(output) = output.as_ref().filter(|o| o.exists()) {
+                bail!("File {} already exists", output.display());
+            }
+
+            let out: Box<dyn Write> = match &output {
+                Some(output) => Box::new(
+                    File::create_new(output)
+                        .with_context(|| format!("Failed to create {}", output.display()))?,
+                ),
+                None => Box::new(CommandOutput),
+            };
+
+            let read = unlocked.read_entry(&entry, unlock_entries, out);
+            if let (Err(_), Some(output)) = (&read, &output) {
+                let _ = fs::remove_file(output);
+            }
+            read.with_context(|| format!("Failed to get entry {entry} from {}", file.display()))?;
+        }
+        Command::Migrate {
+            name,
+            password,
+            file,
+        } => {
+            let file = diary_file(&name, file)?;
+            let mut diary = File::open(&file).context("Failed to open diary file")?;
+
+            let mut magic = Vec::new();
+            (&mut diary)
+                .take(MAGIC.len() as u64)
+                .read_to_end(&mut magic)?;
+            if magic == MAGIC {
+                info!("{} is already in the current format", file.display());
+                return Ok(());
+            }
 
+            if cli.dry_run {
+                outln!("Would decrypt {} with the legacy layout", file.display());
+                outln!(
+                    "Would replace {} with a diary in the current format",
+                    file.display()
+                );
+                return Ok(());
+            }
+
+            // The first versions wrote a bare salt, then the same AES-GCM-SIV stream as now
+            diary.seek(SeekFrom::Start(0)).context("Failed to seek")?;
+            let mut salt = [0u8; SALT_LENGTH];
+            diary
+                .read_exact(&mut salt)
+                .context("Diary file is too short to be a diary")?;
+
+            let password = password::read_password(&password, "Enter password: ")?;
+            let key = hash_password(password.as_bytes(), &salt, KdfParams::default())?;
+
+            let temp = tempfile::Builder::new()
+                .prefix("diary-")
+                .tempdir()
+                .context("Failed to create temporary directory")?;
+            let dir = temp.path().join(&name);
+            let archive = temp.path().join(format!("{name}.tar.gz"));
+
+            let mut decrypted = File::options()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&archive)
+                .context("Failed to create archive file")?;
+            cipher::decrypt(diary, &mut decrypted, key, Algorithm::Aes256GcmSiv).wrap_err(
+                "Failed to decrypt, the password is wrong or this is not a diary from a version without headers",
+            )?;
             decrypted
                 .seek(SeekFrom::Start(0))
                 .context("Failed to seek")?;
+            unpack_archive(decrypted, Compression::Gzip, &dir)?;
+            fs::remove_file(&archive).context("Failed to remove diary archive")?;
+
+            let diary = Diary::load(&dir)?;
+            replace_diary(&diary, &file, Compression::Gzip)?;
 
-            let decompressed = GzDecoder::new(decrypted);
-            let mut archive = Archive::new(decompressed);
+            temp.close()
+                .context("Failed to remove temporary directory")?;
 
-            archive.unpack(&name).context("Failed to unpack diary")?;
+            info!(
+                "Migrated {} with {} entries to the current format.",
+                file.display(),
+                diary.entries().entries.len()
+            );
+        }
+        Command::Passwd {
+            name,
+            password,
+            new_password_command,
+            new_keyfile,
+            require_strong,
+            file,
+        } => {
+            let file = diary_file(&name, file)?;
+            if Path::new(&name).exists() {
+                bail!("Diary {name} is open, close it before changing its password");
+            }
+
+            let unlocked = unlock_diary(&file, &password, "Enter current password: ", 3)?;
+
+            let new_password = password::read_new_password(&PasswordArgs {
+                password_command: new_password_command,
+                password_file: None,
+                strict_perms: false,
+                keyfile: new_keyfile,
+                no_password: false,
+            })?;
+            password::check_strength(&new_password, &[&name], require_strong)?;
+
+            if cli.dry_run {
+                outln!("Would re-encrypt {} with the new password", file.display());
+                return Ok(());
+            }
+
+            let temp = tempfile::Builder::new()
+                .prefix("diary-")
+                .tempdir()
+                .context("Failed to create temporary directory")?;
+            let compression = unlocked.header().compression;
+
+            let mut diary = unlocked.unpack(temp.path().join(&name), &UnpackOptions::default())?;
+            diary.set_password(new_password)?;
+            replace_diary(&diary, &file, compression)?;
 
-            fs::remove_file(format!("{name}.diary")).context("Failed to remove diary file")?;
-            fs::remove_file(format!("{name}.tar.gz")).context("Failed to remove diary archive")?;
+            temp.close()
+                .context("Failed to remove temporary directory")?;
 
-            println!("Diary opened.");
+            info!("Changed the password of {}", file.display());
         }
-        Command::Close { name, level } => {
-            let diary_handle =
-                File::open(format!("{name}/diary.json")).context("Failed to open diary file")?;
-            let entries: Entries =
-                serde_json::from_reader(diary_handle).context("Failed to deserialize diary")?;
+        Command::Recover {
+            name,
+            password,
+            secure_delete,
+        } => {
+            let dir = PathBuf::from(format!("{name}-recovered"));
+            let archive = archive_path(&dir);
+            if dir.exists() {
+                bail!("{} already exists", dir.display());
+            }
+
+            let unlocked = unlock_diary(
+                Path::new(&format!("{name}.diary")),
+                &password,
+                "Enter password: ",
+                1,
+            )?;
+            let (digest, compression) = (unlocked.header().digest, unlocked.header().compression);
 
-            let archive_file =
-                File::create_new(format!("{name}.tar.gz")).context("Failed to create archive")?;
-            let compressed = GzEncoder::new(archive_file, Compression::new(level));
-            let mut archive = Builder::new(compressed);
+            let mut decrypted = File::options()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&archive)
+                .context("Failed to create archive file")?;
+            let plaintext = PlaintextGuard::new(&archive, secure_delete);
 
-            archive.append_dir_all(".", &name)?;
+            // Chunks are written out as they are decrypted, so a failure keeps everything before it
+            if let Err(e) = unlocked.decrypt(&mut decrypted) {
+                outln!("Decryption stopped early ({e}), recovering what was decrypted");
+            }
 
-            let mut archive_file = archive.into_inner()?.finish()?;
+            decrypted
+                .seek(SeekFrom::Start(0))
+                .context("Failed to seek")?;
+            if cipher::digest(&mut decrypted).context("Failed to hash archive")? != digest {
+                outln!("Decrypted archive does not match its digest, some files may be damaged");
+            }
 
-            archive_file
+            decrypted
                 .seek(SeekFrom::Start(0))
                 .context("Failed to seek")?;
+            fs::create_dir(&dir).context("Failed to create recovery directory")?;
+            let (recovered, failed) = recover_archive(decrypted, compression, &dir);
+            plaintext
+                .remove()
+                .context("Failed to remove diary archive")?;
 
-            let mut diary =
-                File::create_new(format!("{name}.diary")).context("Failed to create diary file")?;
+            if let Err(e) = restore_duplicates(&dir) {
+                outln!("Failed to restore deduplicated files: {e}");
+            }
 
-            let mut salt = [0u8; SALT_LENGTH];
-            rand::rng().fill_bytes(&mut salt);
+            for (path, e) in &failed {
+                outln!("Failed to recover {path}: {e}");
+            }
+
+            info!(
+                "Recovered {recovered} files into {}, {} failed, {name}.diary was left in place.",
+                dir.display(),
+                failed.len()
+            );
+        }
+        Command::Close {
+            name,
+            password,
+            level,
+            resume,
+            threads,
+            include,
+            exclude,
+            dedup,
+            file,
+            cipher,
+            compression,
+            keep_open,
+            secure_delete,
+            comment,
+            no_sync,
+        } => {
+            let file = diary_file(&name, file)?;
+            let filter = FileFilter::new(&include, &exclude)?;
+
+            let mut diary = Diary::load(&name)?;
+            let key = &diary.entries().key;
+
+            if password::supplied_password(&password)?.is_some_and(|p| p != *key) {
+                if password.keyfile.is_none() && key.starts_with("keyfile:") {
+                    bail!("Password does not match the diary's password, which needs a --keyfile");
+                }
+                bail!("Password does not match the diary's password");
+            }
+
+            // A diary opened in memory keeps its file, which the snapshot replaces like any other,
+            // and is archived beside its directory so the plaintext stays in memory
+            let in_memory = fs::symlink_metadata(&name)?.is_symlink();
+
+            if cli.dry_run {
+                let archive = if in_memory {
+                    archive_path(&fs::read_link(&name)?)
+                } else {
+                    archive_path(Path::new(&name))
+                };
+                outln!("Would archive directory {name} into {}", archive.display());
+                outln!(
+                    "Would encrypt {} into {}",
+                    archive.display(),
+                    file.display()
+                );
+                if !keep_open {
+                    outln!("Would remove directory {name}");
+                }
+                outln!("Would remove {}", archive.display());
+                return Ok(());
+            }
+
+            // Without --resume an existing diary file, such as a snapshot, is replaced once the
+            // new one is complete. With it, the directory is only removed if the existing file
+            // holds everything in it
+            if resume && file.exists() && !keep_open && !in_memory {
+                match diary.saved_state(&file, &filter)? {
+                    SavedState::Complete => {
+                        info!("Existing {} is valid, finishing close", file.display());
+
+                        diary.remove_leftover_archives(secure_delete)?;
+                        diary.discard(secure_delete)?;
 
-            let key = hash_password(entries.key.as_bytes(), &salt)?;
+                        info!("Diary closed.");
+                        return Ok(());
+                    }
+                    SavedState::Changed => bail!(
+                        "{name} has changed since {} was written, close without --resume to replace it",
+                        file.display()
+                    ),
+                    SavedState::Incomplete(e) => {
+                        info!(
+                            "Existing {} is incomplete ({e}), closing again",
+                            file.display()
+                        );
+                        fs::remove_file(&file).context("Failed to remove incomplete diary file")?;
+                    }
+                }
+            }
+
+            if let Some(cipher) = cipher.filter(|c| *c != diary.entries().cipher) {
+                diary.set_cipher(cipher)?;
+            }
+
+            let comment = comment.map(|c| (!c.is_empty()).then_some(c));
+            if let Some(comment) = comment.filter(|c| *c != diary.entries().comment) {
+                diary.set_comment(comment)?;
+            }
+
+            if resume {
+                diary.remove_leftover_archives(secure_delete)?;
+            }
+
+            // Read before closing, so a broken remotes.json can't fail a close that already happened
+            let remotes = Remotes::load()?;
+
+            let options = PackOptions {
+                compression,
+                level,
+                threads,
+                filter,
+                dedup,
+                cipher: diary.entries().cipher,
+                kdf: config::get().kdf(),
+                comment: diary.entries().comment.clone(),
+                secure_delete,
+            };
+
+            let (original_size, archive_size, diary_size) = if keep_open {
+                let sizes = diary.snapshot(&file, &options)?;
+                info!("Snapshot written to {}, diary left open.", file.display());
+                sizes
+            } else {
+                let sizes = diary.close(&file, &options)?;
+                info!("Diary closed.");
+                sizes
+            };
 
-            diary.write_all(&salt)?;
+            // Like pushing, a failed upload leaves the diary closed and only warns
+            for (target, remote) in remotes.remotes.iter().filter(|(_, r)| r.on_close) {
+                if let Err(e) = upload_backup(&name, &file, target, remote) {
+                    log::warn!("Failed to back up {} to {target}: {e:#}", file.display());
+                }
+            }
 
-            cipher::encrypt(archive_file, diary, key).context("Failed to encrypt")?;
+            let repo = repo_dir(&file);
+            if !no_sync && sync::enabled(repo) {
+                sync::commit(repo, std::slice::from_ref(&file), &format!("Close {name}"))?;
 
-            fs::remove_dir_all(&name).context("Failed to remove diary directory")?;
-            fs::remove_file(format!("{name}.tar.gz")).context("Failed to remove diary archive")?;
+                // The diary is closed either way, an unreachable remote only delays the backup
+                if sync::has_remote(repo)
+                    && let Err(e) = sync::push(repo)
+                {
+                    log::warn!("Failed to push {}: {e:#}", file.display());
+                }
+            }
 
-            println!("Diary closed.");
+            emit(serde_json::json!({
+                "file": file,
+                "original_size": original_size,
+                "archive_size": archive_size,
+                "diary_size": diary_size,
+            }));
+            if !QUIET.load(Ordering::Relaxed) {
+                outln!(
+                    "{original_size} bytes in {name}, {archive_size} bytes compressed ({:.1}%), {diary_size} bytes in {} ({:.1}%)",
+                    ratio(archive_size, original_size),
+                    file.display(),
+                    ratio(diary_size, original_size),
+                );
+            }
         }
-        Command::Entry { entry_command } => {
-            let mut entries: Entries = serde_json::from_reader(
-                File::open("diary.json").context("Not inside a diary directory")?,
-            )
-            .context("Failed to deserialize diary")?;
+        Command::Peek { name, file } => {
+            let file = diary_file(&name, file)?;
+            let header = Header::read(File::open(&file).context("Failed to open diary file")?)?;
 
-            match entry_command {
-                EntryCommand::Add {
-                    name,
-                    description,
-                    location,
-                } => {
-                    let id = Uuid::new_v4();
-                    let timestamp = OffsetDateTime::now_local()?;
-                    let path = PathBuf::from(format!("{id}.md"));
+            outln!("{}:", file.display());
+            outln!("\tformat version: {}", header.version);
+            outln!(
+                "\tcipher: {}",
+                header
+                    .cipher
+                    .to_possible_value()
+                    .map_or_else(String::new, |v| v.get_name().to_string())
+            );
+            outln!(
+                "\tcompression: {}",
+                header
+                    .compression
+                    .to_possible_value()
+                    .map_or_else(String::new, |v| v.get_name().to_string())
+            );
+            outln!("\tchunk size: {} bytes", header.chunk_size);
+            outln!(
+                "\tkey derivation: argon2id, {} KiB, {} iterations, {} lanes",
+                header.kdf.memory,
+                header.kdf.iterations,
+                header.kdf.parallelism
+            );
+            outln!("\tsalt: present ({} bytes)", header.salt.len());
+            if let Some(comment) = header.comment {
+                outln!("\tcomment: {comment}");
+            }
+        }
+        Command::Rename { old, new } => {
+            validate_diary_name(&old)?;
+            validate_diary_name(&new)?;
 
-                    File::create_new(&path).context("Failed to create new file for entry")?;
+            let closed = Path::new(&format!("{old}.diary")).exists();
+            let open = metadata_path(Path::new(&old)).exists();
 
-                    println!("Created entry {} at path {}", name, path.display());
+            if Path::new(&format!("{new}.diary")).exists() || Path::new(&new).exists() {
+                bail!("A diary named {new} already exists");
+            }
 
-                    entries.entries.insert(
-                        name,
-                        Entry {
-                            id,
-                            path,
-                            timestamp,
-                            location,
-                            description,
-                        },
-                    );
+            let (from, to) = match (closed, open) {
+                (true, true) => bail!(
+                    "Diary {old} is both open and closed, finish closing it with close --resume first"
+                ),
+                (true, false) => (format!("{old}.diary"), format!("{new}.diary")),
+                (false, true) => (old.clone(), new.clone()),
+                (false, false) => bail!("Diary {old} does not exist"),
+            };
+
+            if cli.dry_run {
+                outln!("Would rename {from} to {to}");
+                return Ok(());
+            }
 
-                    serde_json::to_writer(
-                        File::create("diary.json.new")
-                            .context("Failed to create new diary file")?,
-                        &entries,
-                    )
-                    .context("Failed to save diary file")?;
-                    fs::rename("diary.json.new", "diary.json")
-                        .context("Failed to replace old diary file")?;
+            // Another run may have created the new name since it was checked
+            match rename_new(Path::new(&from), Path::new(&to)) {
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    bail!("A diary named {new} already exists")
                 }
-                EntryCommand::Remove { name } => {
-                    match entries.entries.remove(&name) {
-                        Some(entry) => {
-                            fs::remove_file(entry.path)?;
-                            println!("Removed entry {} ({})", name, entry.id);
-                        }
-                        None => println!("Entry does not exist :("),
-                    }
-
-                    serde_json::to_writer(
-                        File::create("diary.json.new")
-                            .context("Failed to create new diary file")?,
-                        &entries,
-                    )
-                    .context("Failed to save diary file")?;
-                    fs::rename("diary.json.new", "diary.json")
-                        .context("Failed to replace old diary file")?;
-                }
-                EntryCommand::List => {
-                    for (key, entry) in entries.entries.iter() {
-                        println!(
-                            "{} ({}):\n\tpath: {}\n\ttimestamp: {}{}{}",
-                            key,
-                            entry.id,
-                            entry.path.display(),
-                            entry.timestamp,
-                            {
-                                match entry.location.as_ref() {
-                                    Some(l) => format!("\n\tlocation: {l}"),
-                                    None => String::new(),
-                                }
+                renamed => renamed.context("Failed to rename diary")?,
+            }
+
+            info!("Renamed diary {old} to {new}");
+        }
+        Command::Backup {
+            backup_command: Some(BackupCommand::Remote { remote_command }),
+            ..
+        } => {
+            let mut remotes = Remotes::load()?;
+
+            match remote_command {
+                RemoteCommand::Add { target } => {
+                    let (name, remote) = match target {
+                        NewTarget::S3 {
+                            name,
+                            endpoint,
+                            bucket,
+                            region,
+                            access_key,
+                            secret_command,
+                            prefix,
+                            options,
+                        } => (
+                            name,
+                            Remote {
+                                target: Target::S3 {
+                                    endpoint,
+                                    bucket,
+                                    region,
+                                    access_key,
+                                    secret_command,
+                                    prefix,
+                                },
+                                keep: options.keep,
+                                on_close: options.on_close,
                             },
-                            {
-                                match entry.description.as_ref() {
-                                    Some(d) => format!("\n\tdescription: {d}"),
-                                    None => String::new(),
-                                }
+                        ),
+                        NewTarget::Webdav {
+                            name,
+                            url,
+                            username,
+                            password_command,
+                            options,
+                        } => (
+                            name,
+                            Remote {
+                                target: Target::Webdav {
+                                    url,
+                                    username,
+                                    password_command,
+                                },
+                                keep: options.keep,
+                                on_close: options.on_close,
                             },
-                        );
+                        ),
+                    };
+
+                    if remotes.remotes.contains_key(&name) {
+                        bail!("A backup target named {name} already exists");
+                    }
+                    if cli.dry_run {
+                        outln!("Would add backup target {name} ({})", remote.location());
+                        return Ok(());
                     }
+
+                    info!("Added backup target {name} ({})", remote.location());
+                    remotes.remotes.insert(name, remote);
+                    remotes.save()?;
                 }
-                EntryCommand::Search { query } => {
-                    for (key, entry) in entries.entries.iter().filter(|(k, v)| {
-                        let matches_key = k.to_lowercase().contains(&query.to_lowercase());
-                        let matches_location = v
-                            .location
-                            .as_ref()
-                            .is_some_and(|l| l.to_lowercase().contains(&query.to_lowercase()));
-                        let matches_description = v
-                            .description
-                            .as_ref()
-                            .is_some_and(|d| d.to_lowercase().contains(&query.to_lowercase()));
-                        matches_key || matches_location || matches_description
-                    }) {
-                        println!(
-                            "{} ({}):\n\tpath: {}\n\ttimestamp: {}{}{}",
-                            key,
-                            entry.id,
-                            entry.path.display(),
-                            entry.timestamp,
-                            {
-                                match entry.location.as_ref() {
-                                    Some(l) => format!("\n\tlocation: {l}"),
-                                    None => String::new(),
-                                }
-                            },
-                            {
-                                match entry.description.as_ref() {
-                                    Some(d) => format!("\n\tdescription: {d}"),
-                                    None => String::new(),
-                                }
-                            },
+                RemoteCommand::Remove { name } => {
+                    if !remotes.remotes.contains_key(&name) {
+                        bail!("Backup target {name} does not exist");
+                    }
+                    if cli.dry_run {
+                        outln!("Would remove backup target {name}");
+                        return Ok(());
+                    }
+
+                    remotes.remotes.remove(&name);
+                    remotes.save()?;
+                    info!("Removed backup target {name}");
+                }
+                RemoteCommand::List => {
+                    for (name, remote) in &remotes.remotes {
+                        let keep = remote
+                            .keep
+                            .map_or_else(|| "all".to_string(), |k| k.to_string());
+                        outln!(
+                            "{name}: {}, keeping {keep}{}",
+                            remote.location(),
+                            if remote.on_close { ", after close" } else { "" }
                         );
                     }
                 }
+                RemoteCommand::Push { name, target, file } => {
+                    let file = diary_file(&name, file)?;
+                    if !file.exists() {
+                        bail!("Diary file {} does not exist", file.display());
+                    }
+
+                    let targets = match &target {
+                        Some(target) => match remotes.remotes.get(target) {
+                            Some(remote) => vec![(target, remote)],
+                            None => bail!("Backup target {target} does not exist"),
+                        },
+                        None => remotes.remotes.iter().collect(),
+                    };
+                    if targets.is_empty() {
+                        bail!("No backup targets, add one with backup remote add");
+                    }
+
+                    for (target, remote) in targets {
+                        if cli.dry_run {
+                            outln!("Would upload {} to {target}", file.display());
+                            continue;
+                        }
+                        upload_backup(&name, &file, target, remote)?;
+                    }
+                }
             }
         }
-    }
+        Command::Backup {
+            name: Some(name),
+            dest: Some(dest),
+            keep,
+            ..
+        } => {
+            let source = format!("{name}.diary");
+            let source_len = fs::metadata(&source)
+                .context("Failed to read diary file")?
+                .len();
 
-    Ok(())
+            let backup = dest.join(backup_name(&name));
+
+            if backup.exists() {
+                bail!("Backup {} already exists", backup.display());
+            }
+
+            let copied = fs::copy(&source, &backup).context("Failed to copy diary file")?;
+
+            if copied != source_len || fs::metadata(&backup)?.len() != source_len {
+                bail!(
+                    "Backup {} is incomplete ({copied} of {source_len} bytes)",
+                    backup.display()
+                );
+            }
+
+            info!("Backed up {source} to {}", backup.display());
+
+            if let Some(keep) = keep {
+                let mut backups = fs::read_dir(&dest)
+                    .context("Failed to read backup directory")?
+                    .filter_map(|e| e.ok())
+                    .map(|e| e.path())
+                    .filter(|p| {
+                        p.file_name()
+                            .and_then(|f| f.to_str())
+                            .is_some_and(|f| is_backup_of(f, &name))
+                    })
+                    .collect::<Vec<_>>();
+
+                backups.sort();
+
+                for old in backups.iter().rev().skip(keep) {
+                    if cli.dry_run {
+                        outln!("Would remove old backup {}", old.display());
+                    } else {
+                        fs::remove_file(old).context("Failed to remove old backup")?;
+                        info!("Removed old backup {}", old.display());
+                    }
+                }
+            }
+        }
+        Command::Backup { .. } => unreachable!("clap requires a name and destination"),
+        Command::Merge { into, from } => {
+            let mut target = Diary::load(&into)?;
+            let mut source = Diary::load(&from)?;
+
+            if source.entries().entries.values().any(|e| e.encrypted) {
+                unlock_to_read(&mut source)?;
+                unlock_diary_entries(&mut target)?;
+            }
+
+            let mut merged = 0;
+            for (name, key) in target.merge(&source)? {
+                match key {
+                    Some(key) => {
+                        info!("Merged entry {name} as {key}");
+                        merged += 1;
+                    }
+                    None => info!("Skipping entry {name}, already present"),
+                }
+            }
+
+            info!("Merged {merged} entries into {}", into.display());
+        }
+        Command::MoveEntry { name, from, to } => {
+            let mut source = Diary::load(&from)?;
+            let mut target = Diary::load(&to)?;
+
+            let Some(entry) = source.entries().entries.get(&name) else {
+                bail!("Entry {name} does not exist in {}", from.display());
+            };
+            let id = entry.id;
+            if entry.encrypted {
+                unlock_to_read(&mut source)?;
+                unlock_diary_entries(&mut target)?;
+            }
+
+            let key = source.move_entry(&name, &mut target)?;
+            info!(
+                "Moved entry {} ({}) from {} to {} as {}",
+                name,
+                id,
+                from.display(),
+                to.display(),
+                key
+            );
+        }
+        Command::Diff {
+            a,
+            b,
+            password,
+            same_password,
+            content,
+        } => {
+            let temp = tempfile::Builder::new()
+                .prefix("diary-")
+                .tempdir()
+                .context("Failed to create temporary directory")?;
+
+            let shared = if same_password {
+                Some(password::read_password(&password, "Enter password: ")?)
+            } else {
+                None
+            };
+
+            let snapshot = |file: &Path, side: &str| -> color_eyre::Result<(Entries, PathBuf)> {
+                let unlocked = match &shared {
+                    Some(shared) => Diary::unlock(file, shared)
+                        .with_context(|| format!("Failed to unlock {}", file.display()))?,
+                    None => unlock_diary(
+                        file,
+                        &password,
+                        &format!("Enter password for {}: ", file.display()),
+                        1,
+                    )?,
+                };
+
+                let dir = temp.path().join(side);
+                unlocked.unpack(&dir, &UnpackOptions::default())?;
+
+                Ok((load_entries(&dir)?, dir))
+            };
+
+            let (old, old_dir) = snapshot(&a, "a")?;
+            let (new, new_dir) = snapshot(&b, "b")?;
+
+            // Only entries in both diaries are read
+            let old_key = entry_key(
+                &old,
+                old.entries
+                    .iter()
+                    .filter(|(name, _)| new.entries.contains_key(*name))
+                    .map(|(_, entry)| entry),
+            )?;
+            let new_key = entry_key(
+                &new,
+                new.entries
+                    .iter()
+                    .filter(|(name, _)| old.entries.contains_key(*name))
+                    .map(|(_, entry)| entry),
+            )?;
+
+            let (mut added, mut removed, mut modified) = (0, 0, 0);
+            let names = old
+                .entries
+                .keys()
+                .chain(new.entries.keys())
+                .collect::<BTreeSet<_>>();
+
+            for name in names {
+                match (old.entries.get(name), new.entries.get(name)) {
+                    (None, Some(_)) => {
+                        outln!("+ {name}");
+                        added += 1;
+                    }
+                    (Some(_), None) => {
+                        outln!("- {name}");
+                        removed += 1;
+                    }
+                    (Some(before), Some(after)) => {
+                        let before_content = before.read_content(&old_dir, old_key.as_ref())?;
+                        let after_content = after.read_content(&new_dir, new_key.as_ref())?;
+
+                        if before.id == after.id && before_content == after_content {
+                            continue;
+                        }
+
+                        outln!("~ {name}");
+                        modified += 1;
+
+                        if content {
+                            let before_text = String::from_utf8_lossy(&before_content);
+                            let after_text = String::from_utf8_lossy(&after_content);
+                            out!(
+                                "{}",
+                                TextDiff::from_lines(&before_text, &after_text)
+                                    .unified_diff()
+                                    .header(&a.display().to_string(), &b.display().to_string())
+                            );
+                        }
+                    }
+                    (None, None) => unreachable!(),
+                }
+            }
+
+            outln!("{added} added, {removed} removed, {modified} modified");
+
+            temp.close()
+                .context("Failed to remove temporary directory")?;
+        }
+        Command::Streak => {
+            let (current, longest) = Diary::load(".")?.streaks(cli.no_local_time);
+
+            emit(serde_json::json!({ "current": current, "longest": longest }));
+            outln!("Current streak: {current} days");
+            outln!("Longest streak: {longest} days");
+        }
+        Command::Today {
+            editor,
+            template,
+            ext,
+            no_format,
+        } => {
+            let mut diary = Diary::load(".")?;
+
+            let now = OffsetDateTime::now_utc();
+            let name = display_time(now, cli.no_local_time).date().to_string();
+
+            if !diary.entries().entries.contains_key(&name) {
+                let template = template.as_deref().map(read_template).transpose()?;
+                let id = Uuid::new_v4();
+                let entry = Entry::new(
+                    id,
+                    diary
+                        .entries()
+                        .entry_path(PathBuf::from(format!("{id}.{ext}"))),
+                    now,
+                );
+
+                let content = template
+                    .map(|template| fill_template(&template, &name, &entry, cli.no_local_time))
+                    .unwrap_or_default();
+                diary.insert_entry(&name, entry, content.as_bytes())?;
+                info!("Created entry {name}");
+            } else if template.is_some() {
+                log::warn!("Entry {name} already exists, ignoring --template");
+            }
+
+            let entry = &diary.entries().entries[&name];
+            emit(serde_json::json!({ "name": name, "id": entry.id, "path": entry.path }));
+
+            if entry.encrypted {
+                unlock_to_read(&mut diary)?;
+            }
+            edit_and_save(&mut diary, &name, editor.as_deref(), no_format)?;
+            info!("Edited entry {name}");
+        }
+        Command::Status => {
+            load_entries(Path::new("."))?;
+
+            let saved = fs::read_to_string(SAVED_MARKER).ok();
+            let current = fingerprint(Path::new("."), &FileFilter::default())
+                .context("Failed to hash diary")?;
+
+            let clean = saved.is_some_and(|saved| saved == current);
+            emit(serde_json::json!({ "clean": clean }));
+            if clean {
+                outln!("Diary is clean.");
+            } else {
+                outln!("Diary has unsaved changes.");
+            }
+        }
+        Command::Info { format } => {
+            let summary = Diary::load(".")?.summary()?;
+            emit(&summary);
+
+            match format {
+                Format::Json => outln!("{}", serde_json::to_string_pretty(&summary)?),
+                Format::Text => {
+                    outln!("Entries: {}", summary.entries);
+                    if let (Some(earliest), Some(latest)) = (summary.earliest, summary.latest) {
+                        outln!("Earliest: {}", format_time(earliest, cli.no_local_time));
+                        outln!("Latest: {}", format_time(latest, cli.no_local_time));
+                    }
+                    outln!("Total size: {} bytes", summary.total_bytes);
+                    outln!("With location: {}", summary.with_location);
+                    outln!("With description: {}", summary.with_description);
+                    outln!("With tags: {}", summary.with_tags);
+                }
+            }
+        }
+        Command::Report { format } => {
+            let mut diary = Diary::load(".")?;
+            if diary.entries().entries.values().any(|e| e.encrypted) {
+                unlock_to_read(&mut diary)?;
+            }
+
+            let report = diary.report(cli.no_local_time)?;
+            emit(&report);
+
+            match format {
+                Format::Json => outln!("{}", serde_json::to_string_pretty(&report)?),
+                Format::Text => {
+                    outln!("{:<8} {:>7} {:>9}", "Month", "Entries", "Words");
+                    for month in &report.months {
+                        outln!("{:<8} {:>7} {:>9}", month.month, month.entries, month.words);
+                    }
+                    if let (Some(longest), Some(shortest)) = (&report.longest, &report.shortest) {
+                        outln!("Longest: {} ({} words)", longest.name, longest.words);
+                        outln!("Shortest: {} ({} words)", shortest.name, shortest.words);
+                    }
+                }
+            }
+        }
+        Command::Stats {
+            mood: true, format, ..
+        } => {
+            let mood = Diary::load(".")?.mood_stats(cli.no_local_time);
+            emit(&mood);
+
+            match format {
+                Format::Json => outln!("{}", serde_json::to_string_pretty(&mood)?),
+                Format::Text => {
+                    let Some(average) = mood.average else {
+                        outln!("No entries have a mood, rate one with entry mood");
+                        return Ok(());
+                    };
+
+                    outln!("Rated entries: {}", mood.rated);
+                    outln!("Average mood: {average:.1}/{MAX_MOOD}");
+                    if let [.., previous, latest] = mood.months.as_slice() {
+                        outln!(
+                            "Trend: {:+.1} in {} since {}",
+                            latest.average - previous.average,
+                            latest.month,
+                            previous.month
+                        );
+                    }
+
+                    outln!();
+                    outln!("{:<8} {:>5} {:>7}", "Month", "Rated", "Average");
+                    for month in &mood.months {
+                        outln!(
+                            "{:<8} {:>5} {:>7.1} {}",
+                            month.month,
+                            month.rated,
+                            month.average,
+                            "#".repeat(month.average.round() as usize)
+                        );
+                    }
+                }
+            }
+        }
+        Command::Stats {
+            top_tags,
+            mood: false,
+            format,
+        } => {
+            let mut diary = Diary::load(".")?;
+            if diary.entries().entries.values().any(|e| e.encrypted) {
+                unlock_to_read(&mut diary)?;
+            }
+
+            let stats = diary.stats(top_tags, cli.no_local_time)?;
+            emit(&stats);
+
+            match format {
+                Format::Json => outln!("{}", serde_json::to_string_pretty(&stats)?),
+                Format::Text => {
+                    outln!("Entries: {}", stats.entries);
+                    outln!(
+                        "Words: {} ({:.1} per entry)",
+                        stats.words,
+                        stats.words_per_entry
+                    );
+                    outln!(
+                        "Per month: {:.1} entries, {:.1} words",
+                        stats.entries_per_month,
+                        stats.words_per_month
+                    );
+                    outln!("Current streak: {} days", stats.current_streak);
+                    outln!("Longest streak: {} days", stats.longest_streak);
+                    if !stats.top_tags.is_empty() {
+                        let tags = stats
+                            .top_tags
+                            .iter()
+                            .map(|(tag, count)| format!("{tag} ({count})"))
+                            .collect::<Vec<_>>();
+                        outln!("Top tags: {}", tags.join(", "));
+                    }
+                    if let Some(average) = stats.mood.average {
+                        outln!(
+                            "Average mood: {average:.1}/{MAX_MOOD} ({} rated entries)",
+                            stats.mood.rated
+                        );
+                    }
+
+                    if !stats.months.is_empty() {
+                        outln!();
+                        outln!(
+                            "{:<8} {:>7} {:>9} {:>11}",
+                            "Month",
+                            "Entries",
+                            "Words",
+                            "Words/entry"
+                        );
+                        for month in &stats.months {
+                            outln!(
+                                "{:<8} {:>7} {:>9} {:>11.1}",
+                                month.month,
+                                month.entries,
+                                month.words,
+                                month.words as f64 / month.entries as f64
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        Command::Normalize => {
+            let mut entries = load_entries(Path::new("."))?;
+
+            let mut names = entries.entries.keys().cloned().collect::<Vec<_>>();
+            names.sort();
+
+            let short = names
+                .iter()
+                .map(|name| readable_file_name(name, &entries.entries[name], false))
+                .collect::<Vec<_>>();
+            let mut renames = Vec::new();
+            for (name, path) in names.iter().zip(&short) {
+                let entry = &entries.entries[name];
+                // Slugs can clash, the full id can't
+                let path = if short.iter().filter(|p| *p == path).count() > 1 {
+                    readable_file_name(name, entry, true)
+                } else {
+                    path.clone()
+                };
+
+                if path == entry.path {
+                    continue;
+                }
+                if path.exists() {
+                    bail!("File {} already exists", path.display());
+                }
+
+                renames.push((name.clone(), path));
+            }
+
+            if renames.is_empty() {
+                info!("Entry files already follow their names");
+                return Ok(());
+            }
+
+            for (name, path) in renames {
+                let Some(entry) = entries.entries.get_mut(&name) else {
+                    continue;
+                };
+
+                if cli.dry_run {
+                    outln!(
+                        "Would rename {} to {}",
+                        entry.path.display(),
+                        path.display()
+                    );
+                    continue;
+                }
+
+                if let Err(e) = fs::rename(&entry.path, &path) {
+                    // Keep the paths of the files that were already renamed
+                    save_entries(&entries, Path::new("."))?;
+                    return Err(e)
+                        .with_context(|| format!("Failed to rename file of entry {name}"));
+                }
+
+                info!("Renamed {} to {}", entry.path.display(), path.display());
+                entry.path = path;
+            }
+
+            if !cli.dry_run {
+                save_entries(&entries, Path::new("."))?;
+            }
+        }
+        Command::CompleteEntries => {
+            // Completion runs on every tab press, so anything that isn't a diary completes nothing
+            if let Ok(entries) = load_entries(Path::new(".")) {
+                for name in entries.entries.keys().collect::<BTreeSet<_>>() {
+                    outln!("{name}");
+                }
+            }
+        }
+        Command::Completions { shell } => {
+            let mut script = Vec::new();
+            let name = env!("CARGO_PKG_NAME");
+            Shells::builtins()
+                .completer(&shell.to_string())
+                .ok_or_else(|| eyre!("Completion for {shell} is not supported"))?
+                .write_registration("COMPLETE", name, name, name, &mut script)
+                .context("Failed to write completion script")?;
+            out!("{}", String::from_utf8_lossy(&script));
+        }
+        Command::Export { export_command } => match export_command {
+            ExportCommand::Html {
+                name,
+                out,
+                force,
+                password,
+                file,
+            } => {
+                let has_files = fs::read_dir(&out).is_ok_and(|mut d| d.next().is_some());
+                if has_files && !force {
+                    bail!(
+                        "{} is not empty, rerun with --force to write into it",
+                        out.display()
+                    );
+                }
+
+                if cli.dry_run && !Path::new(&name).is_dir() {
+                    outln!(
+                        "Would decrypt {} and export it to {}",
+                        diary_file(&name, file)?.display(),
+                        out.display()
+                    );
+                    return Ok(());
+                }
+                let (dir, _temp) = readable_diary(&name, &password, file)?;
+
+                let entries = load_entries(&dir)?;
+                if cli.dry_run {
+                    outln!(
+                        "Would export {} entries of {name} to {}",
+                        entries.entries.len(),
+                        out.display()
+                    );
+                    return Ok(());
+                }
+
+                let key = entry_key(&entries, entries.entries.values())?;
+                let exported =
+                    html::export(&name, &entries, key.as_ref(), &dir, &out, cli.no_local_time)?;
+                info!("Exported {exported} entries to {}", out.display());
+            }
+            ExportCommand::Metadata {
+                name,
+                format,
+                out,
+                force,
+                password,
+                file,
+            } => {
+                let (dir, _temp) = readable_diary(&name, &password, file)?;
+                let entries = load_entries(&dir)?;
+
+                let mut sorted = entries.entries.iter().collect::<Vec<_>>();
+                sorted.sort_by_key(|(name, entry)| (entry.timestamp, name.as_str()));
+                let metadata = entry_metadata(&sorted, format)?;
+
+                let Some(out) = out else {
+                    out!("{metadata}");
+                    return Ok(());
+                };
+                if cli.dry_run {
+                    outln!(
+                        "Would export metadata of {} entries to {}",
+                        sorted.len(),
+                        out.display()
+                    );
+                    return Ok(());
+                }
+
+                let mut file = if force {
+                    File::create(&out)
+                } else {
+                    File::create_new(&out)
+                }
+                .with_context(|| format!("Failed to create {}", out.display()))?;
+                file.write_all(metadata.as_bytes())
+                    .context("Failed to write metadata")?;
+
+                info!(
+                    "Exported metadata of {} entries to {}",
+                    sorted.len(),
+                    out.display()
+                );
+            }
+        },
+        Command::Sync { sync_command } => match sync_command {
+            SyncCommand::Git { git_command } => match git_command {
+                GitCommand::Init { remote } => {
+                    if cli.dry_run {
+                        outln!("Would make the current directory a repository for its diary files");
+                        return Ok(());
+                    }
+
+                    sync::init(Path::new("."), remote.as_deref())?;
+                    info!("Diary files in the current directory are now synced with git.");
+                }
+                GitCommand::Push => {
+                    if !sync::enabled(Path::new(".")) {
+                        bail!("Current directory is not synced, set it up with sync git init");
+                    }
+                    if cli.dry_run {
+                        outln!("Would commit and push the diary files");
+                        return Ok(());
+                    }
+
+                    sync::push(Path::new("."))?;
+                    info!("Pushed diary files.");
+                }
+                GitCommand::Pull => {
+                    if !sync::enabled(Path::new(".")) {
+                        bail!("Current directory is not synced, set it up with sync git init");
+                    }
+                    if cli.dry_run {
+                        outln!("Would pull the diary files");
+                        return Ok(());
+                    }
+
+                    sync::pull(Path::new("."))?;
+                    info!("Pulled diary files.");
+                }
+            },
+        },
+        Command::Import { import_command } => match import_command {
+            ImportCommand::Jrnl { file, ext } => {
+                let mut entries = load_entries(Path::new("."))?;
+                let text = fs::read(&file)
+                    .with_context(|| format!("Failed to read {}", file.display()))?;
+                let Ok(text) = String::from_utf8(text) else {
+                    bail!(
+                        "{} is not text, export encrypted journals with `jrnl --export json` first",
+                        file.display()
+                    );
+                };
+                let records = jrnl::parse(&text)?;
+
+                // Entries imported before are recognised by their name and time, so importing
+                // the same journal again only adds what is new
+                let mut new = Vec::new();
+                for record in &records {
+                    let mut name: String =
+                        record.title.chars().take(MAX_ENTRY_NAME_LENGTH).collect();
+                    if parse_entry_name(&name).is_err() {
+                        name = record
+                            .timestamp
+                            .format(format_description!("[year]-[month]-[day] [hour]:[minute]"))
+                            .context("Failed to format entry date")?;
+                    }
+
+                    if entries
+                        .entries
+                        .get(&name)
+                        .is_some_and(|entry| entry.timestamp == record.timestamp)
+                    {
+                        continue;
+                    }
+
+                    let name = unique_name(&entries.entries, &name);
+                    let id = Uuid::new_v4();
+                    let entry = Entry {
+                        tags: record.tags.clone(),
+                        pinned: record.starred,
+                        ..Entry::new(
+                            id,
+                            entries.entry_path(PathBuf::from(format!("{id}.{ext}"))),
+                            record.timestamp,
+                        )
+                    };
+                    new.push((entry.path.clone(), record));
+                    entries.entries.insert(name, entry);
+                }
+
+                let skipped = records.len() - new.len();
+                if cli.dry_run {
+                    outln!(
+                        "Would import {} entries from {} ({skipped} already imported)",
+                        new.len(),
+                        file.display()
+                    );
+                    return Ok(());
+                }
+
+                entries.create_entries_dir(Path::new("."))?;
+                let mut written = Vec::new();
+                let persisted = new
+                    .iter()
+                    .try_for_each(|(path, record)| {
+                        File::create_new(path)
+                            .and_then(|mut f| f.write_all(record.content().as_bytes()))
+                            .with_context(|| format!("Failed to write {}", path.display()))?;
+                        written.push(path);
+                        Ok(())
+                    })
+                    .and_then(|()| Ok(save_entries(&entries, Path::new("."))?));
+
+                // Nothing is kept unless the diary knows about every new entry
+                if let Err(e) = persisted {
+                    for path in written {
+                        fs::remove_file(path).context("Failed to remove imported entry file")?;
+                    }
+                    return Err(e);
+                }
+
+                emit(serde_json::json!({ "imported": new.len(), "skipped": skipped }));
+                info!(
+                    "Imported {} entries from {} ({skipped} already imported)",
+                    new.len(),
+                    file.display()
+                );
+            }
+        },
+        Command::Tui { editor } => {
+            tui::run(editor, cli.no_local_time)?;
+        }
+        Command::Entry { entry_command } => {
+            let mut entries = load_entries(Path::new("."))?;
+
+            match entry_command {
+                EntryCommand::Add {
+                    name,
+                    description,
+                    location,
+                    date,
+                    tags,
+                    encrypt,
+                    ext,
+                    commit,
+                    edit,
+                    editor,
+                    no_format,
+                    mood,
+                    template,
+                } => {
+                    let id = Uuid::new_v4();
+                    let timestamp = date
+                        .unwrap_or_else(OffsetDateTime::now_utc)
+                        .to_offset(UtcOffset::UTC);
+                    let path = entries.entry_path(PathBuf::from(format!("{id}.{ext}")));
+
+                    if entries.entries.contains_key(&name) {
+                        return Err(DiaryError::EntryExists(name).into());
+                    }
+
+                    let template = template.as_deref().map(read_template).transpose()?;
+
+                    let entry = Entry {
+                        location,
+                        description,
+                        tags,
+                        encrypted: encrypt,
+                        mood,
+                        ..Entry::new(id, path, timestamp)
+                    };
+
+                    let mut diary = Diary::load(".")?;
+                    if encrypt {
+                        unlock_diary_entries(&mut diary)?;
+                    }
+
+                    let content = template
+                        .map(|template| fill_template(&template, &name, &entry, cli.no_local_time))
+                        .unwrap_or_default();
+                    diary.insert_entry(&name, entry, content.as_bytes())?;
+                    let entry = &diary.entries().entries[&name];
+                    let path = entry.path.clone();
+
+                    info!("Created entry {} at path {}", name, path.display());
+                    emit(serde_json::json!({ "name": name, "id": id, "path": path }));
+
+                    if edit {
+                        let status = edit_entry(entry, diary.entry_key(), editor.as_deref())?;
+                        if !status.success() {
+                            outln!("Editor exited with {status}, entry {name} was kept");
+                        } else if !no_format {
+                            format_entry(entry, diary.entry_key(), None)?;
+                        }
+                    }
+
+                    if commit {
+                        git_commit(
+                            &[&path, &metadata_path(Path::new(""))],
+                            &format!("Add entry {name}"),
+                        )?;
+                    }
+                }
+                EntryCommand::Duplicate { source, new } => {
+                    if entries.entries.contains_key(&new) {
+                        bail!("An entry named {new} already exists");
+                    }
+                    let Some(original) = entries.entries.get(&source) else {
+                        bail!("Entry {source} does not exist");
+                    };
+
+                    let id = Uuid::new_v4();
+                    let timestamp = OffsetDateTime::now_utc();
+                    let path = entries.entry_path(entry_file_name(id, &original.path));
+
+                    if path.exists() {
+                        bail!("File {} already exists", path.display());
+                    }
+
+                    entries.create_entries_dir(Path::new("."))?;
+                    let entry = Entry {
+                        id,
+                        path,
+                        timestamp,
+                        modified: None,
+                        attachments: original
+                            .attachments
+                            .iter()
+                            .map(|a| attachments_dir(id).join(a.file_name().unwrap_or_default()))
+                            .collect(),
+                        ..original.clone()
+                    };
+                    let key = entry_key(&entries, [original])?;
+                    original.copy_content(
+                        Path::new("."),
+                        key.as_ref(),
+                        &entry,
+                        Path::new("."),
+                        key.as_ref(),
+                    )?;
+                    original.copy_attachments(Path::new("."), &entry, Path::new("."))?;
+
+                    info!(
+                        "Duplicated entry {} as {} at path {}",
+                        source,
+                        new,
+                        entry.path.display()
+                    );
+
+                    entries.entries.insert(new, entry);
+
+                    save_entries(&entries, Path::new("."))?;
+                }
+                EntryCommand::Attach { name, file } => {
+                    let Some(entry) = entries.entries.get_mut(&name) else {
+                        bail!("Entry {name} does not exist");
+                    };
+                    let Some(file_name) = file.file_name() else {
+                        bail!("{} is not a file", file.display());
+                    };
+
+                    let path = attachments_dir(entry.id).join(file_name);
+                    if entry.attachments.contains(&path) || path.exists() {
+                        bail!(
+                            "Entry {name} already has an attachment named {}",
+                            file_name.display()
+                        );
+                    }
+
+                    if cli.dry_run {
+                        outln!("Would copy {} to {}", file.display(), path.display());
+                        return Ok(());
+                    }
+
+                    fs::create_dir_all(attachments_dir(entry.id))
+                        .context("Failed to create attachments directory")?;
+                    let size = fs::copy(&file, &path)
+                        .with_context(|| format!("Failed to copy {}", file.display()))?;
+                    entry.attachments.push(path.clone());
+
+                    if let Err(e) = save_entries(&entries, Path::new(".")) {
+                        fs::remove_file(&path).context("Failed to remove new attachment")?;
+                        return Err(e.into());
+                    }
+
+                    info!(
+                        "Attached {} to entry {name} ({size} bytes)",
+                        file_name.display()
+                    );
+                }
+                EntryCommand::Attachments { name } => {
+                    let Some(entry) = entries.entries.get(&name) else {
+                        bail!("Entry {name} does not exist");
+                    };
+
+                    for attachment in &entry.attachments {
+                        match fs::metadata(attachment) {
+                            Ok(metadata) => {
+                                outln!("{}\t{} bytes", attachment.display(), metadata.len())
+                            }
+                            Err(_) => outln!("{}\tmissing", attachment.display()),
+                        }
+                    }
+                }
+                EntryCommand::Remove { name } => {
+                    let name = match name {
+                        Some(name) => name,
+                        None => pick_entry(&entries, cli.no_local_time)?,
+                    };
+
+                    if cli.dry_run {
+                        match entries.entries.get(&name) {
+                            Some(entry) => outln!(
+                                "Would move entry {} ({}) and its file {} to the trash",
+                                name,
+                                entry.id,
+                                entry.path.display()
+                            ),
+                            None => outln!("Entry does not exist :("),
+                        }
+                        return Ok(());
+                    }
+
+                    match Diary::load(".")?.trash_entry(&name) {
+                        Ok(id) => info!("Moved entry {name} ({id}) to the trash"),
+                        Err(DiaryError::NoSuchEntry(_)) => outln!("Entry does not exist :("),
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                EntryCommand::Edit {
+                    name,
+                    editor,
+                    no_format,
+                    commit,
+                } => {
+                    let name = match name {
+                        Some(name) => name,
+                        None => pick_entry(&entries, cli.no_local_time)?,
+                    };
+
+                    let mut diary = Diary::load(".")?;
+                    if entries.entries.get(&name).is_some_and(|e| e.encrypted) {
+                        unlock_to_read(&mut diary)?;
+                    }
+                    edit_and_save(&mut diary, &name, editor.as_deref(), no_format)?;
+
+                    info!("Edited entry {name}");
+
+                    if commit {
+                        git_commit(
+                            &[&entries.entries[&name].path, &metadata_path(Path::new(""))],
+                            &format!("Edit entry {name}"),
+                        )?;
+                    }
+                }
+                EntryCommand::Format { name, wrap } => {
+                    let Some(entry) = entries.entries.get(&name) else {
+                        bail!("Entry {name} does not exist");
+                    };
+
+                    let key = entry_key(&entries, [entry])?;
+                    if format_entry(entry, key.as_ref(), wrap.map(|w| w as usize))? {
+                        info!("Formatted entry {name}");
+                    } else {
+                        info!("Entry {name} is already formatted");
+                    }
+                }
+                EntryCommand::Mood { name, mood, clear } => {
+                    let Some(entry) = entries.entries.get_mut(&name) else {
+                        bail!("Entry {name} does not exist");
+                    };
+
+                    if mood.is_none() && !clear {
+                        emit(entry.mood);
+                        match entry.mood {
+                            Some(mood) => outln!("{mood}"),
+                            None => info!("Entry {name} has no mood"),
+                        }
+                        return Ok(());
+                    }
+
+                    entry.mood = mood;
+                    save_entries(&entries, Path::new("."))?;
+
+                    match mood {
+                        Some(mood) => info!("Set mood of entry {name} to {mood}"),
+                        None => info!("Removed mood of entry {name}"),
+                    }
+                }
+                EntryCommand::Touch { name, date } => {
+                    let Some(entry) = entries.entries.get_mut(&name) else {
+                        bail!("Entry {name} does not exist");
+                    };
+                    entry.timestamp = date
+                        .unwrap_or_else(OffsetDateTime::now_utc)
+                        .to_offset(UtcOffset::UTC);
+                    info!(
+                        "Set timestamp of entry {} to {}",
+                        name,
+                        format_time(entry.timestamp, cli.no_local_time)
+                    );
+
+                    save_entries(&entries, Path::new("."))?;
+                }
+                EntryCommand::Append {
+                    name,
+                    text,
+                    timestamp,
+                } => {
+                    let Some(entry) = entries.entries.get(&name) else {
+                        bail!("Entry {name} does not exist");
+                    };
+
+                    let text = match text {
+                        Some(text) => text,
+                        None => io::read_to_string(io::stdin()).context("Failed to read stdin")?,
+                    };
+
+                    let mut content = String::new();
+                    if timestamp {
+                        let now = display_time(OffsetDateTime::now_utc(), cli.no_local_time);
+                        content.push_str(&format!(
+                            "\n## {}\n\n",
+                            now.format(format_description!(
+                                "[year]-[month]-[day] [hour]:[minute]"
+                            ))?
+                        ));
+                    }
+                    content.push_str(text.trim_end_matches('\n'));
+                    content.push('\n');
+
+                    let key = entry_key(&entries, [entry])?;
+                    entry.append_content(Path::new("."), key.as_ref(), content.as_bytes())?;
+
+                    info!("Appended to entry {name}");
+                }
+                EntryCommand::Show { name, metadata } => {
+                    let name = match name {
+                        Some(name) => name,
+                        None => pick_entry(&entries, cli.no_local_time)?,
+                    };
+
+                    let Some(entry) = entries.entries.get(&name) else {
+                        bail!("Entry {name} does not exist");
+                    };
+
+                    let mut content = if metadata {
+                        front_matter(&name, entry)?.into_bytes()
+                    } else {
+                        Vec::new()
+                    };
+                    let key = entry_key(&entries, [entry])?;
+                    content.extend(entry.read_content(Path::new("."), key.as_ref())?);
+
+                    CommandOutput
+                        .write_all(&content)
+                        .and_then(|()| CommandOutput.flush())
+                        .context("Failed to print entry")?;
+                }
+                EntryCommand::Export { name, out, force } => {
+                    let Some(entry) = entries.entries.get(&name) else {
+                        bail!("Entry {name} does not exist");
+                    };
+
+                    let mut content = front_matter(&name, entry)?.into_bytes();
+                    let key = entry_key(&entries, [entry])?;
+                    content.extend(entry.read_content(Path::new("."), key.as_ref())?);
+
+                    let mut file = if force {
+                        File::create(&out)
+                    } else {
+                        File::create_new(&out)
+                    }
+                    .with_context(|| format!("Failed to create {}", out.display()))?;
+                    file.write_all(&content)
+                        .context("Failed to write exported entry")?;
+
+                    info!("Exported entry {} to {}", name, out.display());
+                }
+                EntryCommand::History { name } => {
+                    let Some(entry) = entries.entries.get(&name) else {
+                        bail!("Entry {name} does not exist");
+                    };
+
+                    if !Path::new(".git").exists() {
+                        bail!("Diary directory is not a git repository");
+                    }
+
+                    let log = process::Command::new("git")
+                        .args([
+                            "log",
+                            "--follow",
+                            "--format=%h %ad %s",
+                            "--date=short",
+                            "--",
+                        ])
+                        .arg(&entry.path)
+                        .stderr(process::Stdio::inherit())
+                        .output()
+                        .context("Failed to run git")?;
+
+                    if !log.status.success() {
+                        bail!("git log exited with {}", log.status);
+                    }
+                    out!("{}", String::from_utf8_lossy(&log.stdout));
+                }
+                EntryCommand::Encrypt { name } => {
+                    set_entry_encrypted(&mut entries, &name, true)?;
+                }
+                EntryCommand::Decrypt { name } => {
+                    set_entry_encrypted(&mut entries, &name, false)?;
+                }
+                EntryCommand::Tag { tag_command } => match tag_command {
+                    TagCommand::Add { name, tags } => {
+                        set_entry_tags(&mut entries, &name, &tags, true)?
+                    }
+                    TagCommand::Remove { name, tags } => {
+                        set_entry_tags(&mut entries, &name, &tags, false)?
+                    }
+                    TagCommand::List { name: Some(name) } => {
+                        let Some(entry) = entries.entries.get(&name) else {
+                            bail!("Entry {name} does not exist");
+                        };
+
+                        emit(&entry.tags);
+                        for tag in &entry.tags {
+                            outln!("{tag}");
+                        }
+                    }
+                    TagCommand::List { name: None } => {
+                        let mut counts = BTreeMap::<&str, usize>::new();
+                        for tag in entries.entries.values().flat_map(|e| &e.tags) {
+                            *counts.entry(tag).or_default() += 1;
+                        }
+
+                        emit(&counts);
+                        for (tag, count) in counts {
+                            outln!("{tag} ({count})");
+                        }
+                    }
+                },
+                EntryCommand::Restore { name } => match Diary::load(".")?.restore_entry(&name) {
+                    Ok(id) => info!("Restored entry {name} ({id})"),
+                    Err(DiaryError::NoSuchEntry(_)) => outln!("Entry is not in the trash :("),
+                    Err(e) => return Err(e.into()),
+                },
+                EntryCommand::EmptyTrash => {
+                    if cli.dry_run {
+                        for (name, entry) in entries.trashed.iter() {
+                            outln!(
+                                "Would permanently delete entry {} ({}) and {}",
+                                name,
+                                entry.id,
+                                trash_path(&entry.path).display()
+                            );
+                        }
+                        return Ok(());
+                    }
+
+                    for (name, id) in Diary::load(".")?.empty_trash()? {
+                        info!("Permanently deleted entry {name} ({id})");
+                    }
+                }
+                EntryCommand::Pin { name } => {
+                    set_entry_pinned(&mut entries, &name, true)?;
+                }
+                EntryCommand::Unpin { name } => {
+                    set_entry_pinned(&mut entries, &name, false)?;
+                }
+                EntryCommand::Archive { name } => {
+                    set_entry_archived(&mut entries, &name, true)?;
+                }
+                EntryCommand::Unarchive { name } => {
+                    set_entry_archived(&mut entries, &name, false)?;
+                }
+                EntryCommand::Link { from, to } => {
+                    if from == to {
+                        bail!("Entry {from} can't link to itself");
+                    }
+                    let Some(target) = entries.entries.get(&to) else {
+                        bail!("Entry {to} does not exist");
+                    };
+                    let id = target.id;
+
+                    let Some(entry) = entries.entries.get_mut(&from) else {
+                        bail!("Entry {from} does not exist");
+                    };
+
+                    if entry.links.contains(&id) {
+                        info!("Entry {from} already links to {to}");
+                        return Ok(());
+                    }
+
+                    entry.links.push(id);
+                    save_entries(&entries, Path::new("."))?;
+
+                    info!("Linked entry {from} to {to}");
+                }
+                EntryCommand::Backlinks { name } => {
+                    let Some(target) = entries.entries.get(&name) else {
+                        bail!("Entry {name} does not exist");
+                    };
+
+                    let mut linking = entries
+                        .entries
+                        .iter()
+                        .filter(|(_, e)| e.links.contains(&target.id))
+                        .collect::<Vec<_>>();
+                    linking.sort_by_key(|(_, e)| e.timestamp);
+
+                    for (key, entry) in linking {
+                        print_entry(key, entry, cli.no_local_time);
+                    }
+                }
+                EntryCommand::List {
+                    names_only,
+                    pinned_only,
+                    count,
+                    tags,
+                    archived,
+                    dates,
+                    sort,
+                    reverse,
+                    format,
+                } => {
+                    let mut listed = entries
+                        .entries
+                        .iter()
+                        .filter(|(_, e)| !pinned_only || e.pinned)
+                        .filter(|(_, e)| tags.iter().all(|t| e.tags.contains(t)))
+                        .filter(|(_, e)| archived.shows(e.archived))
+                        .filter(|(_, e)| dates.contains(e.timestamp))
+                        .collect::<Vec<_>>();
+                    listed.sort_by(|(a_name, a), (b_name, b)| {
+                        let order = match sort {
+                            SortOrder::Name => a_name.cmp(b_name),
+                            SortOrder::Created => a.timestamp.cmp(&b.timestamp),
+                            SortOrder::Modified => a
+                                .modified
+                                .unwrap_or(a.timestamp)
+                                .cmp(&b.modified.unwrap_or(b.timestamp)),
+                        }
+                        .then_with(|| a_name.cmp(b_name));
+
+                        let order = if reverse { order.reverse() } else { order };
+                        b.pinned.cmp(&a.pinned).then(order)
+                    });
+
+                    if count {
+                        emit(listed.len());
+                        outln!("{}", listed.len());
+                        return Ok(());
+                    }
+
+                    emit(metadata_rows(&listed));
+                    if let Some(format) = format {
+                        out!("{}", entry_metadata(&listed, format)?);
+                        return Ok(());
+                    }
+
+                    for (key, entry) in listed {
+                        if names_only {
+                            outln!("{key}");
+                        } else {
+                            print_entry(key, entry, cli.no_local_time);
+                        }
+                    }
+                }
+                EntryCommand::Search {
+                    query,
+                    count,
+                    tags,
+                    archived,
+                    dates,
+                } => {
+                    let matches = entries
+                        .entries
+                        .iter()
+                        .filter(|(k, v)| {
+                            archived.shows(v.archived)
+                                && dates.contains(v.timestamp)
+                                && tags.iter().all(|t| v.tags.contains(t))
+                                && matches_query(k, v, &query)
+                        })
+                        .collect::<Vec<_>>();
+
+                    if count {
+                        emit(matches.len());
+                        outln!("{}", matches.len());
+                        return Ok(());
+                    }
+
+                    emit(metadata_rows(&matches));
+
+                    for (key, entry) in matches {
+                        print_entry(key, entry, cli.no_local_time);
+                    }
+                }
+                EntryCommand::Grep {
+                    query,
+                    context,
+                    archived,
+                } => {
+                    let query = query.to_lowercase();
+                    let sorted = entries
+                        .entries
+                        .iter()
+                        .filter(|(_, e)| archived.shows(e.archived))
+                        .collect::<BTreeMap<_, _>>();
+
+                    let key = entry_key(&entries, sorted.values().copied())?;
+
+                    let mut matched = 0;
+                    for (name, entry) in sorted {
+                        let content = match entry.read_content(Path::new("."), key.as_ref()) {
+                            Ok(content) => content,
+                            Err(e) => {
+                                log::warn!("Skipping entry {name}: {e:#}");
+                                continue;
+                            }
+                        };
+                        let text = String::from_utf8_lossy(&content);
+                        let lines = text.lines().collect::<Vec<_>>();
+
+                        let hits = lines
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, line)| line.to_lowercase().contains(&query))
+                            .map(|(i, _)| i)
+                            .collect::<BTreeSet<_>>();
+                        if hits.is_empty() {
+                            continue;
+                        }
+                        matched += 1;
+
+                        let shown = hits
+                            .iter()
+                            .flat_map(|&i| i.saturating_sub(context)..=(i + context))
+                            .filter(|&i| i < lines.len())
+                            .collect::<BTreeSet<_>>();
+
+                        let mut previous = None;
+                        for i in shown {
+                            if context > 0 && previous.is_some_and(|p| p + 1 != i) {
+                                outln!("--");
+                            }
+                            let separator = if hits.contains(&i) { ':' } else { '-' };
+                            outln!(
+                                "{name}{separator}{}{separator} {}",
+                                i + 1,
+                                snippet(lines[i], &query)
+                            );
+                            previous = Some(i);
+                        }
+                    }
+
+                    info!("{matched} matching entries");
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// `line` trimmed and, if it is long, cut down to the text around the first match of the
+/// lowercase `query`
+fn snippet(line: &str, query: &str) -> String {
+    const WIDTH: usize = 100;
+
+    let line = line.trim();
+    if line.chars().count() <= WIDTH {
+        return line.to_string();
+    }
+
+    // Lowercasing can change lengths outside ASCII, then the match is only found by chars
+    let lower = line.to_lowercase();
+    let at = lower
+        .find(query)
+        .filter(|_| lower.len() == line.len())
+        .and_then(|at| line.get(..at))
+        .map_or(0, |before| before.chars().count());
+
+    let start = at.saturating_sub(WIDTH / 3);
+    let text = line.chars().skip(start).take(WIDTH).collect::<String>();
+
+    format!(
+        "{}{text}{}",
+        if start > 0 { "..." } else { "" },
+        if start + WIDTH < line.chars().count() {
+            "..."
+        } else {
+            ""
+        }
+    )
+}
+
+/// Replace the diary file `file` with `diary` compressed with `compression`, see
+/// [`Diary::replace`]
+fn replace_diary(diary: &Diary, file: &Path, compression: Compression) -> color_eyre::Result<()> {
+    diary.replace(
+        file,
+        &PackOptions {
+            compression,
+            level: config::get().level.unwrap_or(1),
+            cipher: diary.entries().cipher,
+            kdf: config::get().kdf(),
+            comment: diary.entries().comment.clone(),
+            ..Default::default()
+        },
+    )?;
+
+    Ok(())
+}
+
+/// Rename `from` to `to` without replacing anything at `to`, even if it appears after a check.
+/// A file is linked under its new name before the old one is removed, a directory is renamed over
+/// an empty one made for it, which fails if anything else made it first
+fn rename_new(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(from)?.is_dir() {
+        fs::create_dir(to)?;
+        fs::rename(from, to).inspect_err(|_| {
+            let _ = fs::remove_dir(to);
+        })
+    } else {
+        fs::hard_link(from, to)?;
+        fs::remove_file(from)
+    }
+}
+
+/// Run `command` through the platform shell
+fn shell_command(command: &str) -> process::Command {
+    #[cfg(windows)]
+    let mut shell = process::Command::new("cmd");
+    #[cfg(windows)]
+    shell.arg("/C");
+    #[cfg(not(windows))]
+    let mut shell = process::Command::new("sh");
+    #[cfg(not(windows))]
+    shell.arg("-c");
+
+    shell.arg(command);
+    shell
+}
+
+/// The diary file for the diary directory `name`, `file` if given or `{name}.diary` otherwise
+fn diary_file(name: &str, file: Option<PathBuf>) -> color_eyre::Result<PathBuf> {
+    let Some(file) = file else {
+        return Ok(PathBuf::from(format!("{name}.diary")));
+    };
+
+    let parent = file.parent().filter(|p| !p.as_os_str().is_empty());
+    let inside = match (
+        fs::canonicalize(name),
+        fs::canonicalize(parent.unwrap_or(Path::new("."))),
+    ) {
+        (Ok(dir), Ok(parent)) => parent.starts_with(dir),
+        _ => file.starts_with(name),
+    };
+
+    if inside {
+        bail!(
+            "Diary file {} must not be inside the diary directory {name}",
+            file.display()
+        );
+    }
+
+    Ok(file)
+}
+
+/// Make sure a diary name refers to a file or directory in the current directory
+fn validate_diary_name(name: &str) -> color_eyre::Result<()> {
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.contains(['/', '\\'])
+        || Path::new(name).is_absolute()
+    {
+        bail!("Invalid diary name {name:?}");
+    }
+
+    Ok(())
+}
+
+/// Directory on a RAM-backed filesystem for `open --memory`, preferring the per-user
+/// $XDG_RUNTIME_DIR over the shared /dev/shm
+fn ram_dir() -> color_eyre::Result<PathBuf> {
+    [
+        std::env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from),
+        Some(PathBuf::from("/dev/shm")),
+    ]
+    .into_iter()
+    .flatten()
+    .find(|dir| dir.is_dir())
+    .ok_or_else(|| eyre!("No RAM-backed directory found, neither $XDG_RUNTIME_DIR nor /dev/shm"))
+}
+
+#[cfg(unix)]
+fn link_dir(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(not(unix))]
+fn link_dir(_target: &Path, _link: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "opening in memory is only supported on unix",
+    ))
+}
+
+/// Read the header of the diary file `file` and ask for its password with `prompt` until it
+/// matches the canary, giving up after `tries` attempts. A password from a file or command is
+/// only tried once, since it would be the same every time
+fn unlock_diary(
+    file: &Path,
+    password: &PasswordArgs,
+    prompt: &str,
+    tries: u32,
+) -> color_eyre::Result<Unlocked> {
+    // A file that isn't a diary is refused before asking for anything
+    debug!("Reading header");
+    Header::read(File::open(file).context("Failed to open diary file")?)?;
+
+    match password::supplied_password(password)? {
+        Some(key) => unlock_with_tries(file, 1, || Ok(key.clone())),
+        None => unlock_with_tries(file, tries, || password::read_password(password, prompt)),
+    }
+}
+
+/// Unlock the diary in `file` with the passwords returned by `next_password`, giving up after
+/// `tries` wrong ones
+fn unlock_with_tries(
+    file: &Path,
+    tries: u32,
+    mut next_password: impl FnMut() -> color_eyre::Result<String>,
+) -> color_eyre::Result<Unlocked> {
+    for attempt in 1..=tries {
+        match Diary::unlock(file, &next_password()?) {
+            Ok(unlocked) => return Ok(unlocked),
+            Err(DiaryError::WrongPassword) if attempt < tries => {
+                outln!("Incorrect password, try again");
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Err(DiaryError::WrongPassword.into())
+}
+
+/// Password of encrypted entries, from `--entry-password-command` or else asked for with `prompt`
+fn read_entry_password(prompt: &str) -> color_eyre::Result<String> {
+    match ENTRY_PASSWORD_COMMAND.get() {
+        Some(command) => password::run_password_command(command),
+        None => rpassword::prompt_password(prompt).context("Failed to read entry password"),
+    }
+}
+
+/// Key of the encrypted entries of `entries`, asking for the entry password if the diary has one
+fn unlock_entries(entries: &Entries) -> color_eyre::Result<EntryKey> {
+    match &entries.entry_lock {
+        Some(lock) => Ok(lock.unlock(&read_entry_password("Enter entry password: ")?)?),
+        None => Ok(EntryKey::legacy(&entries.key)),
+    }
+}
+
+/// Key for reading the entries of `entries` in `reading`, if any of them is encrypted
+fn entry_key<'a>(
+    entries: &Entries,
+    reading: impl IntoIterator<Item = &'a Entry>,
+) -> color_eyre::Result<Option<EntryKey>> {
+    if reading.into_iter().any(|entry| entry.encrypted) {
+        unlock_entries(entries).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Key for encrypting entries of the diary in `dir`, choosing the entry password if it has none
+/// yet. Entries encrypted before then, with the diary password, are re-encrypted with it
+fn lock_entries(entries: &mut Entries, dir: &Path) -> color_eyre::Result<EntryKey> {
+    if entries.entry_lock.is_some() {
+        return unlock_entries(entries);
+    }
+
+    let key = entries.lock(dir, &choose_entry_password(entries)?, config::get().kdf())?;
+
+    info!("Entry password set, encrypted entries need it from now on");
+    Ok(key)
+}
+
+/// Unlock the encrypted entries of `diary` for reading and writing, choosing the entry password
+/// if it has none yet
+fn unlock_diary_entries(diary: &mut Diary) -> color_eyre::Result<()> {
+    if diary.entries().entry_lock.is_some() {
+        return Ok(diary.unlock_entries(&read_entry_password("Enter entry password: ")?)?);
+    }
+
+    let password = choose_entry_password(diary.entries())?;
+    diary.lock_entries(&password, config::get().kdf())?;
+
+    info!("Entry password set, encrypted entries need it from now on");
+    Ok(())
+}
+
+/// Unlock the encrypted entries of `diary` for reading, asking for the entry password if it has
+/// one
+fn unlock_to_read(diary: &mut Diary) -> color_eyre::Result<()> {
+    if diary.entry_key().is_none() {
+        diary.unlock_entries(&read_entry_password("Enter entry password: ")?)?;
+    }
+
+    Ok(())
+}
+
+/// New password for the encrypted entries of `entries`, which must differ from the diary password
+fn choose_entry_password(entries: &Entries) -> color_eyre::Result<String> {
+    let password = match ENTRY_PASSWORD_COMMAND.get() {
+        Some(command) => password::run_password_command(command)?,
+        None => {
+            let p1 = rpassword::prompt_password("Choose entry password: ")?;
+            let p2 = rpassword::prompt_password("Re-enter entry password: ")?;
+            if p1 != p2 {
+                bail!("Passwords do not match");
+            }
+            p1
+        }
+    };
+
+    // diary.json holds the diary password while the diary is open, so it can't protect entries
+    if password == entries.key {
+        bail!("The entry password has to differ from the diary password");
+    }
+    password::check_strength(&password, &[], false)?;
+
+    Ok(password)
+}
+
+/// Whether `query` appears in the name, location, description or tags of an entry, ignoring case
+fn matches_query(name: &str, entry: &Entry, query: &str) -> bool {
+    let query = query.to_lowercase();
+    let contains = |text: &str| text.to_lowercase().contains(&query);
+
+    contains(name)
+        || entry.location.as_deref().is_some_and(contains)
+        || entry.description.as_deref().is_some_and(contains)
+        || entry.tags.iter().any(|t| contains(t))
+}
+
+/// Rewrite an entry's file in the current diary so it is stored encrypted or in plain text
+fn set_entry_encrypted(entries: &mut Entries, name: &str, encrypt: bool) -> color_eyre::Result<()> {
+    let Some(entry) = entries.entries.get(name) else {
+        bail!("Entry {name} does not exist");
+    };
+
+    let state = if encrypt { "encrypted" } else { "decrypted" };
+
+    if entry.encrypted == encrypt {
+        info!("Entry {name} is already {state}");
+        return Ok(());
+    }
+
+    let key = if encrypt {
+        lock_entries(entries, Path::new("."))?
+    } else {
+        unlock_entries(entries)?
+    };
+
+    let entry = entries
+        .entries
+        .get_mut(name)
+        .expect("entry was looked up above");
+    let content = entry.read_content(Path::new("."), Some(&key))?;
+    entry.encrypted = encrypt;
+    entry.write_content(Path::new("."), Some(&key), &content)?;
+
+    info!("Entry {name} is now {state}");
+
+    Ok(save_entries(entries, Path::new("."))?)
+}
+
+/// Add `tags` to an entry of the current diary, or remove them from it
+fn set_entry_tags(
+    entries: &mut Entries,
+    name: &str,
+    tags: &[String],
+    add: bool,
+) -> color_eyre::Result<()> {
+    let Some(entry) = entries.entries.get_mut(name) else {
+        bail!("Entry {name} does not exist");
+    };
+
+    if add {
+        for tag in tags {
+            if !entry.tags.contains(tag) {
+                entry.tags.push(tag.clone());
+            }
+        }
+    } else {
+        entry.tags.retain(|t| !tags.contains(t));
+    }
+
+    info!("Tags of entry {}: {}", name, entry.tags.join(", "));
+
+    Ok(save_entries(entries, Path::new("."))?)
+}
+
+/// Pin or unpin an entry in the current diary
+fn set_entry_pinned(entries: &mut Entries, name: &str, pinned: bool) -> color_eyre::Result<()> {
+    let Some(entry) = entries.entries.get_mut(name) else {
+        bail!("Entry {name} does not exist");
+    };
+
+    let state = if pinned { "pinned" } else { "unpinned" };
+
+    if entry.pinned == pinned {
+        info!("Entry {name} is already {state}");
+        return Ok(());
+    }
+
+    entry.pinned = pinned;
+
+    info!("Entry {name} is now {state}");
+
+    Ok(save_entries(entries, Path::new("."))?)
+}
+
+/// Archive or unarchive an entry in the current diary
+fn set_entry_archived(entries: &mut Entries, name: &str, archived: bool) -> color_eyre::Result<()> {
+    let Some(entry) = entries.entries.get_mut(name) else {
+        bail!("Entry {name} does not exist");
+    };
+
+    let state = if archived { "archived" } else { "unarchived" };
+
+    if entry.archived == archived {
+        info!("Entry {name} is already {state}");
+        return Ok(());
+    }
+
+    entry.archived = archived;
+
+    info!("Entry {name} is now {state}");
+
+    Ok(save_entries(entries, Path::new("."))?)
+}
+
+/// `timestamp` as it is displayed, in the configured time_format if there is one
+fn format_time(timestamp: OffsetDateTime, utc: bool) -> String {
+    let time = display_time(timestamp, utc);
+    config::get()
+        .time_format()
+        .and_then(|format| time.format(format).ok())
+        .unwrap_or_else(|| time.to_string())
+}
+
+/// Contents of the template found by `find_template`
+fn read_template(name: &str) -> color_eyre::Result<String> {
+    let path = find_template(name)?;
+    fs::read_to_string(&path).with_context(|| format!("Failed to read template {}", path.display()))
+}
+
+/// Template `name` in the diary's templates directory, where it can be given without its
+/// extension, or else the file at path `name`
+fn find_template(name: &str) -> color_eyre::Result<PathBuf> {
+    let dir = Path::new(TEMPLATES_DIR);
+    let exact = dir.join(name);
+    if exact.is_file() {
+        return Ok(exact);
+    }
+
+    if let Ok(templates) = fs::read_dir(dir) {
+        let mut matches = templates
+            .flatten()
+            .map(|t| t.path())
+            .filter(|t| t.is_file() && t.file_stem().is_some_and(|stem| stem == name))
+            .collect::<Vec<_>>();
+        matches.sort();
+
+        match matches.len() {
+            0 => {}
+            1 => return Ok(matches.remove(0)),
+            _ => bail!(
+                "Several templates are named {name}, give one with its extension: {}",
+                matches
+                    .iter()
+                    .filter_map(|t| t.file_name())
+                    .map(|t| t.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+
+    let path = PathBuf::from(name);
+    if path.is_file() {
+        return Ok(path);
+    }
+    bail!("No template named {name} in {TEMPLATES_DIR}/ and no file at that path");
+}
+
+/// Replace the {{name}}, {{title}}, {{id}}, {{date}} and {{time}} placeholders of an entry
+/// template
+fn fill_template(template: &str, name: &str, entry: &Entry, utc: bool) -> String {
+    let timestamp = display_time(entry.timestamp, utc);
+    template
+        .replace("{{name}}", name)
+        .replace("{{title}}", name)
+        .replace("{{id}}", &entry.id.to_string())
+        .replace("{{date}}", &timestamp.date().to_string())
+        .replace(
+            "{{time}}",
+            &timestamp
+                .format(format_description!("[hour]:[minute]"))
+                .unwrap_or_default(),
+        )
+}
+
+/// Let the user choose an entry from a list sorted by timestamp
+fn pick_entry(entries: &Entries, utc: bool) -> color_eyre::Result<String> {
+    if !std::io::stdin().is_terminal() {
+        bail!("No entry name given and not running in a terminal to pick one");
+    }
+
+    let mut names = entries.entries.iter().collect::<Vec<_>>();
+    if names.is_empty() {
+        bail!("Diary has no entries");
+    }
+    names.sort_by_key(|(_, e)| e.timestamp);
+
+    let items = names
+        .iter()
+        .map(|(name, e)| format!("{name} ({})", format_time(e.timestamp, utc)))
+        .collect::<Vec<_>>();
+
+    let selected = dialoguer::Select::new()
+        .with_prompt("Select an entry")
+        .items(&items)
+        .default(items.len() - 1)
+        .interact()?;
+
+    Ok(names[selected].0.clone())
+}
+
+fn print_entry(name: &str, entry: &Entry, utc: bool) {
+    outln!(
+        "{} ({}):{}{}\n\tpath: {}\n\ttimestamp: {}{}{}{}{}{}{}",
+        name,
+        entry.id,
+        if entry.pinned { " [pinned]" } else { "" },
+        if entry.archived { " [archived]" } else { "" },
+        entry.path.display(),
+        format_time(entry.timestamp, utc),
+        {
+            match entry.modified {
+                Some(m) => format!("\n\tmodified: {}", format_time(m, utc)),
+                None => String::new(),
+            }
+        },
+        {
+            match entry.location.as_ref() {
+                Some(l) => format!("\n\tlocation: {l}"),
+                None => String::new(),
+            }
+        },
+        {
+            match entry.description.as_ref() {
+                Some(d) => format!("\n\tdescription: {d}"),
+                None => String::new(),
+            }
+        },
+        {
+            if entry.tags.is_empty() {
+                String::new()
+            } else {
+                format!("\n\ttags: {}", entry.tags.join(", "))
+            }
+        },
+        {
+            if entry.attachments.is_empty() {
+                String::new()
+            } else {
+                format!("\n\tattachments: {}", entry.attachments.len())
+            }
+        },
+        {
+            match entry.mood {
+                Some(m) => format!("\n\tmood: {m}/{MAX_MOOD}"),
+                None => String::new(),
+            }
+        },
+    );
+}
+
+/// File name for `entry` made from a slug of its `name` and its id, cut short unless `full_id`,
+/// in the same directory as its current file
+fn readable_file_name(name: &str, entry: &Entry, full_id: bool) -> PathBuf {
+    let mut slug = String::new();
+    for c in name.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.chars().take(60).collect::<String>();
+    let slug = slug.trim_end_matches('-');
+
+    let id = entry.id.simple().to_string();
+    let id = if full_id { &id[..] } else { &id[..8] };
+
+    let file_name = match entry.path.extension() {
+        Some(ext) if slug.is_empty() => format!("{id}.{}", ext.display()),
+        Some(ext) => format!("{slug}-{id}.{}", ext.display()),
+        None if slug.is_empty() => id.to_string(),
+        None => format!("{slug}-{id}"),
+    };
+
+    entry.path.with_file_name(file_name)
+}
+
+/// Name of a backup of diary `name` made now, which sorts by when it was made
+fn backup_name(name: &str) -> String {
+    let now = OffsetDateTime::now_utc();
+    let now = now.to_offset(UtcOffset::local_offset_at(now).unwrap_or(UtcOffset::UTC));
+    format!(
+        "{name}-{:04}{:02}{:02}-{:02}{:02}{:02}.diary",
+        now.year(),
+        u8::from(now.month()),
+        now.day(),
+        now.hour(),
+        now.minute(),
+        now.second()
+    )
+}
+
+/// Whether `file_name` is the name of a backup of diary `name` made by `backup_name`
+fn is_backup_of(file_name: &str, name: &str) -> bool {
+    file_name
+        .strip_prefix(&format!("{name}-"))
+        .and_then(|f| f.strip_suffix(".diary"))
+        .is_some_and(|stamp| {
+            stamp.len() == 15
+                && stamp.char_indices().all(
+                    |(i, c)| {
+                        if i == 8 { c == '-' } else { c.is_ascii_digit() }
+                    },
+                )
+        })
+}
+
+/// Upload diary file `file` to backup target `target`, applying its retention
+fn upload_backup(name: &str, file: &Path, target: &str, remote: &Remote) -> color_eyre::Result<()> {
+    let backup = backup_name(name);
+    let removed = remote.upload(file, name, &backup)?;
+
+    info!("Backed up {} to {target} as {backup}", file.display());
+    for old in removed {
+        info!("Removed old backup {old} from {target}");
+    }
+    Ok(())
+}
+
+/// Directory a diary file is in, which is synced when it is a repository set up by `sync git init`
+fn repo_dir(file: &Path) -> &Path {
+    file.parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+}
+
+/// Directory with the entries of diary `name`: the diary itself if it is open, or else a
+/// temporary directory it is unpacked into, which is removed along with the returned guard
+fn readable_diary(
+    name: &str,
+    password: &PasswordArgs,
+    file: Option<PathBuf>,
+) -> color_eyre::Result<(PathBuf, Option<tempfile::TempDir>)> {
+    if Path::new(name).is_dir() {
+        return Ok((PathBuf::from(name), None));
+    }
+
+    let file = diary_file(name, file)?;
+    let unlocked = unlock_diary(&file, password, "Enter password: ", 1)?;
+
+    let temp = tempfile::Builder::new()
+        .prefix("diary-")
+        .tempdir()
+        .context("Failed to create temporary directory")?;
+    let diary = unlocked.unpack(temp.path().join(name), &UnpackOptions::default())?;
+    Ok((diary.dir().to_path_buf(), Some(temp)))
+}
+
+/// Open an entry's file in `editor`, or else `$VISUAL` or `$EDITOR`, going through a decrypted
+/// temporary copy if the entry is encrypted
+fn edit_entry(
+    entry: &Entry,
+    key: Option<&EntryKey>,
+    editor: Option<&str>,
+) -> color_eyre::Result<process::ExitStatus> {
+    let editor = match editor.or(config::get().editor.as_deref()) {
+        Some(editor) => editor.to_string(),
+        None => std::env::var("VISUAL")
+            .or_else(|_| std::env::var("EDITOR"))
+            .unwrap_or_else(|_| "vi".to_string()),
+    };
+
+    let mut args = editor.split_whitespace();
+    let mut command = process::Command::new(args.next().unwrap_or("vi"));
+    command.args(args);
+
+    if !entry.encrypted {
+        return command
+            .arg(&entry.path)
+            .status()
+            .with_context(|| format!("Failed to run editor {editor}"));
+    }
+
+    let suffix = entry
+        .path
+        .extension()
+        .map(|e| format!(".{}", e.display()))
+        .unwrap_or_default();
+    // Kept inside the diary directory rather than the shared temporary directory, readable only
+    // by the owner, and overwritten once the editor is done
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(".diary-edit-").suffix(&suffix);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        builder.permissions(fs::Permissions::from_mode(0o600));
+    }
+    let plain = builder
+        .tempfile_in(".")
+        .context("Failed to create temporary file")?
+        .into_temp_path();
+
+    let edited = (|| {
+        fs::write(&plain, entry.read_content(Path::new("."), key)?)
+            .context("Failed to write temporary file")?;
+
+        let status = command
+            .arg(&plain)
+            .status()
+            .with_context(|| format!("Failed to run editor {editor}"))?;
+
+        entry.write_content(
+            Path::new("."),
+            key,
+            &fs::read(&plain).context("Failed to read temporary file")?,
+        )?;
+
+        Ok(status)
+    })();
+
+    shred::remove_file(&plain).context("Failed to remove temporary file")?;
+
+    edited
+}
+
+/// Edit entry `name` of the current `diary` with `edit_entry`, tidy it with `format_entry` unless
+/// `no_format` is set and save when it was modified. An encrypted entry needs the entries of the
+/// diary to be unlocked
+fn edit_and_save(
+    diary: &mut Diary,
+    name: &str,
+    editor: Option<&str>,
+    no_format: bool,
+) -> color_eyre::Result<()> {
+    let Some(entry) = diary.entries().entries.get(name) else {
+        bail!("Entry {name} does not exist");
+    };
+
+    let status = edit_entry(entry, diary.entry_key(), editor)?;
+    if !status.success() {
+        bail!("Editor exited with {status}");
+    }
+
+    if !no_format {
+        format_entry(entry, diary.entry_key(), None)?;
+    }
+
+    diary.update_entry(name, |entry| {
+        entry.modified = Some(OffsetDateTime::now_utc())
+    })?;
+    Ok(())
+}
+
+/// Commit `paths` in the git repository of the current diary, if it has one
+fn git_commit(paths: &[&Path], message: &str) -> color_eyre::Result<()> {
+    if !Path::new(".git").exists() {
+        bail!("Diary directory is not a git repository");
+    }
+
+    for args in [
+        vec!["add", "--"],
+        vec!["commit", "--quiet", "--message", message, "--"],
+    ] {
+        let status = process::Command::new("git")
+            .args(args)
+            .args(paths)
+            .status()
+            .context("Failed to run git")?;
+
+        if !status.success() {
+            bail!("git exited with {status}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Tidy the markdown of `entry`'s file with `tidy_markdown`, returning whether it changed
+fn format_entry(
+    entry: &Entry,
+    key: Option<&EntryKey>,
+    wrap: Option<usize>,
+) -> color_eyre::Result<bool> {
+    let content = entry.read_content(Path::new("."), key)?;
+    let Ok(text) = std::str::from_utf8(&content) else {
+        bail!("Entry file {} is not UTF-8 text", entry.path.display());
+    };
+
+    let tidy = tidy_markdown(text, wrap);
+    if tidy == text {
+        return Ok(false);
+    }
+
+    entry.write_content(Path::new("."), key, tidy.as_bytes())?;
+    Ok(true)
+}
+
+/// Trim trailing whitespace from every line of `text` and end it with exactly one newline,
+/// breaking lines longer than `wrap` characters at spaces. Code blocks, tables and headings are
+/// never broken, and continuation lines keep the indentation of list items
+fn tidy_markdown(text: &str, wrap: Option<usize>) -> String {
+    let mut tidy = String::with_capacity(text.len());
+    let mut in_code = false;
+
+    for line in text.lines() {
+        let line = line.trim_end();
+        let trimmed = line.trim_start();
+
+        let fence = trimmed.starts_with("```") || trimmed.starts_with("~~~");
+        let verbatim = in_code
+            || fence
+            || trimmed.starts_with('#')
+            || trimmed.starts_with('|')
+            || line.starts_with("    ")
+            || line.starts_with('\t');
+        if fence {
+            in_code = !in_code;
+        }
+
+        match wrap {
+            Some(width) if !verbatim && line.chars().count() > width => {
+                wrap_line(&mut tidy, line, width)
+            }
+            _ => {
+                tidy.push_str(line);
+                tidy.push('\n');
+            }
+        }
+    }
+
+    let end = tidy.trim_end().len();
+    tidy.truncate(end);
+    if !tidy.is_empty() {
+        tidy.push('\n');
+    }
+    tidy
+}
+
+/// Push `line` onto `out` broken at spaces into lines of at most `width` characters where
+/// possible, indenting continuations past any list marker
+fn wrap_line(out: &mut String, line: &str, width: usize) {
+    let trimmed = line.trim_start();
+    let mut indent = line.len() - trimmed.len();
+
+    let marker = trimmed.split_once(' ').map_or("", |(marker, _)| marker);
+    let numbered = marker
+        .strip_suffix(['.', ')'])
+        .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()));
+    if matches!(marker, "-" | "*" | "+" | ">") || numbered {
+        indent += marker.len() + 1;
+    }
+    let continuation = " ".repeat(indent);
+
+    let mut current = String::from(&line[..line.len() - trimmed.len()]);
+    let mut empty = true;
+    for word in trimmed.split(' ').filter(|w| !w.is_empty()) {
+        if !empty && current.chars().count() + 1 + word.chars().count() > width {
+            out.push_str(&current);
+            out.push('\n');
+            current.clone_from(&continuation);
+            empty = true;
+        }
+
+        if !empty {
+            current.push(' ');
+        }
+        current.push_str(word);
+        empty = false;
+    }
+
+    out.push_str(&current);
+    out.push('\n');
+}
+
+/// YAML front-matter describing `entry`, strings are written as JSON which YAML accepts as-is
+fn front_matter(name: &str, entry: &Entry) -> color_eyre::Result<String> {
+    let mut yaml = String::from("---\n");
+    yaml.push_str(&format!("name: {}\n", serde_json::to_string(name)?));
+    yaml.push_str(&format!("id: {}\n", entry.id));
+    yaml.push_str(&format!(
+        "timestamp: {}\n",
+        entry.timestamp.format(&Rfc3339)?
+    ));
+    if let Some(location) = &entry.location {
+        yaml.push_str(&format!("location: {}\n", serde_json::to_string(location)?));
+    }
+    if let Some(description) = &entry.description {
+        yaml.push_str(&format!(
+            "description: {}\n",
+            serde_json::to_string(description)?
+        ));
+    }
+    yaml.push_str(&format!("tags: {}\n", serde_json::to_string(&entry.tags)?));
+    yaml.push_str("---\n");
+    Ok(yaml)
+}
+
+/// Metadata of one entry, as written by `entry list --format` and `export metadata`
+#[derive(Serialize)]
+struct EntryMetadata<'a> {
+    name: &'a str,
+    id: Uuid,
+    #[serde(with = "time::serde::rfc3339")]
+    timestamp: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    modified: Option<OffsetDateTime>,
+    tags: &'a [String],
+    path: &'a Path,
+    location: Option<&'a str>,
+    description: Option<&'a str>,
+    pinned: bool,
+    archived: bool,
+    encrypted: bool,
+    mood: Option<i8>,
+}
+
+/// Render the metadata of `listed`, in order, as a JSON array or as CSV with a header row
+fn entry_metadata(
+    listed: &[(&String, &Entry)],
+    format: ExportFormat,
+) -> color_eyre::Result<String> {
+    let rows = metadata_rows(listed);
+
+    match format {
+        ExportFormat::Json => Ok(serde_json::to_string_pretty(&rows)? + "\n"),
+        ExportFormat::Csv => {
+            let mut csv = String::from(
+                "name,id,timestamp,modified,tags,path,location,description,pinned,archived,encrypted,mood\n",
+            );
+            for row in rows {
+                let fields = [
+                    row.name.to_string(),
+                    row.id.to_string(),
+                    row.timestamp.format(&Rfc3339)?,
+                    row.modified
+                        .map(|m| m.format(&Rfc3339))
+                        .transpose()?
+                        .unwrap_or_default(),
+                    row.tags.join(";"),
+                    row.path.to_string_lossy().into_owned(),
+                    row.location.unwrap_or_default().to_string(),
+                    row.description.unwrap_or_default().to_string(),
+                    row.pinned.to_string(),
+                    row.archived.to_string(),
+                    row.encrypted.to_string(),
+                    row.mood.map(|m| m.to_string()).unwrap_or_default(),
+                ];
+                let fields = fields
+                    .iter()
+                    .map(|field| csv_field(field))
+                    .collect::<Vec<_>>();
+                csv.push_str(&fields.join(","));
+                csv.push('\n');
+            }
+            Ok(csv)
+        }
+    }
+}
+
+fn metadata_rows<'a>(listed: &[(&'a String, &'a Entry)]) -> Vec<EntryMetadata<'a>> {
+    listed
+        .iter()
+        .map(|(name, entry)| EntryMetadata {
+            name,
+            id: entry.id,
+            timestamp: entry.timestamp,
+            modified: entry.modified,
+            tags: &entry.tags,
+            path: &entry.path,
+            location: entry.location.as_deref(),
+            description: entry.description.as_deref(),
+            pinned: entry.pinned,
+            archived: entry.archived,
+            encrypted: entry.encrypted,
+            mood: entry.mood,
+        })
+        .collect()
+}
+
+/// Quote a CSV field if it has a comma, quote or line break in it, doubling any quotes
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn ratio(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 / whole as f64 * 100.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use rand::Rng;
+
+    /// Commands work on the current directory, which every test in the process shares
+    static CURRENT_DIR: Mutex<()> = Mutex::new(());
+
+    const PASSWORD: &str = "--password-command=echo correct horse battery staple";
+
+    /// Run `f` with `dir` as the current directory, one test at a time
+    fn in_dir<T>(dir: &Path, f: impl FnOnce() -> T) -> T {
+        let _lock = CURRENT_DIR.lock().unwrap_or_else(|e| e.into_inner());
+        let previous = std::env::current_dir().unwrap();
+        std::env::set_current_dir(dir).unwrap();
+        let result = f();
+        std::env::set_current_dir(previous).unwrap();
+        result
+    }
+
+    /// Run `diary` with `args` in `dir`
+    fn diary(dir: &Path, args: &[&str]) -> color_eyre::Result<()> {
+        in_dir(dir, || {
+            run(Cli::try_parse_from([&["diary"], args].concat())?)
+        })
+    }
+
+    /// Run `diary` with `args` in `dir`, returning what it printed as command output
+    fn output(dir: &Path, args: &[&str]) -> String {
+        in_dir(dir, || {
+            JSON_OUTPUT.store(true, Ordering::Relaxed);
+            let result = run(Cli::try_parse_from([&["diary"], args].concat()).unwrap());
+            JSON_OUTPUT.store(false, Ordering::Relaxed);
+            let outcome = outcome(&result, String::new(), None);
+            result.unwrap();
+            outcome.output
+        })
+    }
+
+    /// Run `diary` with `args` in `dir` as `main` does, global flags included, returning everything
+    /// it would report
+    fn run_with_flags(dir: &Path, args: &[&str]) -> Outcome {
+        in_dir(dir, || {
+            let mut cli = Cli::try_parse_from([&["diary"], args].concat()).unwrap();
+            apply_global_flags(&mut cli);
+            let result = run(cli);
+            apply_global_flags(&mut Cli::try_parse_from(["diary", "status"]).unwrap());
+            outcome(&result, String::new(), None)
+        })
+    }
+
+    #[test]
+    fn dry_run_close_keeps_diary_open() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+
+        diary(temp.path(), &["--dry-run", "close", "x", PASSWORD]).unwrap();
+        assert!(temp.path().join("x").join("diary.json").is_file());
+        assert!(!temp.path().join("x.diary").exists());
+
+        diary(temp.path(), &["close", "x", PASSWORD]).unwrap();
+        assert!(!temp.path().join("x").exists());
+        assert!(temp.path().join("x.diary").is_file());
+    }
+
+    #[test]
+    fn backup_keeps_the_newest() {
+        let temp = tempfile::tempdir().unwrap();
+        fs::write(temp.path().join("x.diary"), b"not really a diary").unwrap();
+        let dest = temp.path().join("backups");
+        fs::create_dir(&dest).unwrap();
+        for old in ["x-20200101-000000.diary", "x-20210101-000000.diary"] {
+            fs::write(dest.join(old), b"old").unwrap();
+        }
+        fs::write(dest.join("y-20200101-000000.diary"), b"other diary").unwrap();
+
+        assert!(Cli::try_parse_from(["diary", "backup", "x", "backups", "--keep", "0"]).is_err());
+        diary(temp.path(), &["backup", "x", "backups", "--keep", "2"]).unwrap();
+
+        let mut left = fs::read_dir(&dest)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(left.len(), 3);
+        assert_eq!(left[0], "x-20210101-000000.diary");
+        assert!(is_backup_of(&left[1], "x"));
+        assert_eq!(
+            fs::read(dest.join(&left[1])).unwrap(),
+            b"not really a diary"
+        );
+        assert_eq!(left[2], "y-20200101-000000.diary");
+    }
+
+    #[test]
+    fn shell_keeps_session_that_fails_to_close() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        diary(temp.path(), &["close", "x", PASSWORD]).unwrap();
+
+        let command = "--command=echo edits > notes.txt && echo broken > diary.json";
+        let error = diary(temp.path(), &["shell", "x", PASSWORD, command]).unwrap_err();
+
+        let message = error.to_string();
+        let kept = PathBuf::from(
+            message
+                .strip_prefix("Failed to close diary, the session is kept in ")
+                .unwrap(),
+        );
+        assert_eq!(
+            fs::read(kept.join("x").join("notes.txt")).unwrap(),
+            b"edits\n"
+        );
+        assert!(temp.path().join("x.diary").is_file());
+        fs::remove_dir_all(kept).unwrap();
+    }
+
+    #[test]
+    fn recover_keeps_files_before_a_damaged_chunk() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let mut x = Diary::load(temp.path().join("x")).unwrap();
+        x.add_entry("a", b"").unwrap();
+        x.add_entry("b", b"").unwrap();
+        // Archived in file name order, so the large entry spills into the second chunk
+        let mut paths = x
+            .entries()
+            .entries
+            .values()
+            .map(|e| x.dir().join(&e.path))
+            .collect::<Vec<_>>();
+        paths.sort();
+        fs::write(&paths[0], b"intact").unwrap();
+        let mut large = vec![0; 3 << 20];
+        rand::rng().fill_bytes(&mut large);
+        fs::write(&paths[1], &large).unwrap();
+        diary(temp.path(), &["close", "x", PASSWORD, "--level=0"]).unwrap();
+
+        let file = temp.path().join("x.diary");
+        let mut damaged = fs::read(&file).unwrap();
+        *damaged.last_mut().unwrap() ^= 1;
+        fs::write(&file, damaged).unwrap();
+        diary(temp.path(), &["recover", "x", PASSWORD, "--secure-delete"]).unwrap();
+
+        let recovered = Diary::load(temp.path().join("x-recovered")).unwrap();
+        let names = recovered
+            .entries()
+            .entries
+            .iter()
+            .map(|(name, e)| (e.path.clone(), name.clone()))
+            .collect::<BTreeMap<_, _>>();
+        let first = names.values().next().unwrap();
+        assert_eq!(recovered.read_entry(first).unwrap(), b"intact");
+        let last = names.values().last().unwrap();
+        assert_ne!(recovered.read_entry(last).ok(), Some(large));
+
+        let mut left = fs::read_dir(temp.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(left, ["x-recovered", "x.diary"]);
+    }
+
+    #[test]
+    fn touch_refuses_missing_entry() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        let before = fs::read(dir.join("diary.json")).unwrap();
+
+        let touched = diary(&dir, &["entry", "touch", "missing"]);
+        assert!(
+            touched
+                .unwrap_err()
+                .to_string()
+                .contains("missing does not exist")
+        );
+        assert_eq!(fs::read(dir.join("diary.json")).unwrap(), before);
+
+        Diary::load(&dir).unwrap().add_entry("today", b"").unwrap();
+        diary(&dir, &["entry", "touch", "today", "2024-01-02T03:04:05Z"]).unwrap();
+        let entries = load_entries(&dir).unwrap();
+        assert_eq!(
+            entries.entries["today"].timestamp,
+            time::macros::datetime!(2024-01-02 03:04:05 UTC)
+        );
+    }
+
+    #[test]
+    fn list_names_only_prints_names() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        let mut x = Diary::load(&dir).unwrap();
+        for name in ["monday", "tuesday", "with space"] {
+            x.add_entry(name, b"").unwrap();
+        }
+
+        let listed = output(&dir, &["entry", "list", "--names-only", "--sort", "name"]);
+        assert_eq!(listed, "monday\ntuesday\nwith space\n");
+        assert_eq!(
+            output(&dir, &["entry", "list", "-n", "--sort", "name"]),
+            listed
+        );
+    }
+
+    #[test]
+    fn link_refuses_self_link() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        let mut x = Diary::load(&dir).unwrap();
+        x.add_entry("monday", b"").unwrap();
+        x.add_entry("tuesday", b"").unwrap();
+
+        assert!(diary(&dir, &["entry", "link", "monday", "monday"]).is_err());
+        assert!(
+            load_entries(&dir).unwrap().entries["monday"]
+                .links
+                .is_empty()
+        );
+
+        diary(&dir, &["entry", "link", "monday", "tuesday"]).unwrap();
+        let entries = load_entries(&dir).unwrap();
+        assert_eq!(
+            entries.entries["monday"].links,
+            [entries.entries["tuesday"].id]
+        );
+    }
+
+    #[test]
+    fn rename_new_never_replaces() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = |name| temp.path().join(name);
+        fs::write(path("a.diary"), "a").unwrap();
+        fs::write(path("b.diary"), "b").unwrap();
+        fs::create_dir(path("a")).unwrap();
+        fs::write(path("a").join("diary.json"), "a").unwrap();
+        fs::create_dir(path("b")).unwrap();
+
+        let renamed = rename_new(&path("a.diary"), &path("b.diary"));
+        assert_eq!(renamed.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(path("a.diary")).unwrap(), b"a");
+        assert_eq!(fs::read(path("b.diary")).unwrap(), b"b");
+        assert!(rename_new(&path("a"), &path("b")).is_err());
+        assert!(path("a").join("diary.json").is_file());
+
+        rename_new(&path("a.diary"), &path("c.diary")).unwrap();
+        rename_new(&path("a"), &path("c")).unwrap();
+        assert_eq!(fs::read(path("c.diary")).unwrap(), b"a");
+        assert!(path("c").join("diary.json").is_file());
+        assert!(!path("a.diary").exists() && !path("a").exists());
+    }
+
+    #[test]
+    fn merge_keeps_both_entries_of_a_name() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "a", PASSWORD]).unwrap();
+        diary(temp.path(), &["new", "b", PASSWORD]).unwrap();
+        Diary::load(temp.path().join("a"))
+            .unwrap()
+            .add_entry("today", b"from a")
+            .unwrap();
+        Diary::load(temp.path().join("b"))
+            .unwrap()
+            .add_entry("today", b"from b")
+            .unwrap();
+
+        diary(temp.path(), &["merge", "a", "b"]).unwrap();
+
+        let merged = Diary::load(temp.path().join("a")).unwrap();
+        let mut names = merged.entries().entries.keys().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names.len(), 2);
+        assert_eq!(names[0], "today");
+        let contents = names
+            .iter()
+            .map(|name| merged.read_entry(name).unwrap())
+            .collect::<BTreeSet<_>>();
+        assert_eq!(
+            contents,
+            BTreeSet::from([b"from a".to_vec(), b"from b".to_vec()])
+        );
+
+        let source = Diary::load(temp.path().join("b")).unwrap();
+        assert_eq!(source.entries().entries.len(), 1);
+        assert_eq!(source.read_entry("today").unwrap(), b"from b");
+    }
+
+    #[test]
+    fn trashed_entries_restore_and_purge() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        let path = Diary::load(&dir)
+            .unwrap()
+            .add_entry("today", b"hello")
+            .unwrap()
+            .path
+            .clone();
+
+        diary(&dir, &["entry", "remove", "today"]).unwrap();
+        let trashed = Diary::load(&dir).unwrap();
+        assert!(trashed.entries().entries.is_empty());
+        assert!(trashed.entries().trashed.contains_key("today"));
+        assert!(!dir.join(&path).exists());
+        assert!(dir.join(trash_path(&path)).exists());
+
+        diary(&dir, &["entry", "restore", "today"]).unwrap();
+        let restored = Diary::load(&dir).unwrap();
+        assert!(restored.entries().trashed.is_empty());
+        assert_eq!(restored.read_entry("today").unwrap(), b"hello");
+
+        diary(&dir, &["entry", "remove", "today"]).unwrap();
+        diary(&dir, &["entry", "empty-trash"]).unwrap();
+        let emptied = Diary::load(&dir).unwrap();
+        assert!(emptied.entries().entries.is_empty());
+        assert!(emptied.entries().trashed.is_empty());
+        assert!(!dir.join(&path).exists());
+        assert!(!dir.join(trash_path(&path)).exists());
+    }
+
+    #[test]
+    fn migrate_opens_legacy_diary() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        Diary::load(&dir)
+            .unwrap()
+            .add_entry("today", b"hello")
+            .unwrap();
+
+        // The first versions wrote a bare salt, then a gzipped tar encrypted with AES-GCM-SIV
+        let mut archive = tar::Builder::new(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        ));
+        archive.append_dir_all(".", &dir).unwrap();
+        let archive = archive.into_inner().unwrap().finish().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut salt = [0u8; SALT_LENGTH];
+        rand::rng().fill_bytes(&mut salt);
+        let key =
+            hash_password(b"correct horse battery staple", &salt, KdfParams::default()).unwrap();
+        let mut legacy = salt.to_vec();
+        cipher::encrypt(&archive[..], &mut legacy, key, Algorithm::Aes256GcmSiv).unwrap();
+        let file = temp.path().join("x.diary");
+        fs::write(&file, legacy).unwrap();
+
+        diary(temp.path(), &["migrate", "x", PASSWORD]).unwrap();
+
+        let migrated = Diary::open(&file, &dir, "correct horse battery staple").unwrap();
+        assert_eq!(migrated.read_entry("today").unwrap(), b"hello");
+    }
+
+    #[test]
+    fn json_output_reports_new_diary() {
+        let temp = tempfile::tempdir().unwrap();
+        let cli = Cli::try_parse_from(["diary", "--output", "json", "new", "x", PASSWORD]).unwrap();
+        let diary_name = cli.command.diary_name().map(str::to_string);
+
+        let outcome = in_dir(temp.path(), || {
+            JSON_OUTPUT.store(true, Ordering::Relaxed);
+            let result = run(cli);
+            JSON_OUTPUT.store(false, Ordering::Relaxed);
+            outcome(&result, "new".to_string(), diary_name)
+        });
+
+        let outcome: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&outcome).unwrap()).unwrap();
+        assert_eq!(outcome["success"], true, "{outcome}");
+        assert_eq!(outcome["command"], "new");
+        assert_eq!(outcome["diary"], "x");
+        assert!(temp.path().join("x").join("diary.json").is_file());
+    }
+
+    #[test]
+    fn tags_filter_listing_and_survive_close() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        diary(
+            &dir,
+            &["entry", "add", "monday", "--tag", "work", "--tag", "travel"],
+        )
+        .unwrap();
+        diary(&dir, &["entry", "add", "tuesday", "--tag", "work"]).unwrap();
+        diary(&dir, &["entry", "add", "wednesday"]).unwrap();
+
+        diary(&dir, &["entry", "tag", "add", "wednesday", "home"]).unwrap();
+        diary(&dir, &["entry", "tag", "remove", "monday", "work"]).unwrap();
+        let listed = |tag| {
+            output(
+                &dir,
+                &["entry", "list", "-n", "--sort", "name", "--tag", tag],
+            )
+        };
+        assert_eq!(listed("work"), "tuesday\n");
+        assert_eq!(listed("travel"), "monday\n");
+        assert_eq!(listed("home"), "wednesday\n");
+
+        diary(temp.path(), &["close", "x", PASSWORD]).unwrap();
+        diary(temp.path(), &["open", "x", PASSWORD]).unwrap();
+        let entries = load_entries(&dir).unwrap();
+        assert_eq!(entries.entries["monday"].tags, ["travel"]);
+        assert_eq!(entries.entries["tuesday"].tags, ["work"]);
+        assert_eq!(entries.entries["wednesday"].tags, ["home"]);
+    }
+
+    #[test]
+    fn duplicate_copies_content_under_a_new_id() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        diary(&dir, &["entry", "add", "a", "--tag", "work"]).unwrap();
+        diary(&dir, &["entry", "append", "a", "hello"]).unwrap();
+
+        diary(&dir, &["entry", "duplicate", "a", "b"]).unwrap();
+        assert!(diary(&dir, &["entry", "duplicate", "a", "b"]).is_err());
+
+        let entries = load_entries(&dir).unwrap();
+        let (a, b) = (&entries.entries["a"], &entries.entries["b"]);
+        assert_ne!(a.id, b.id);
+        assert_ne!(a.path, b.path);
+        assert_eq!(b.tags, ["work"]);
+        assert_eq!(
+            fs::read(dir.join(&a.path)).unwrap(),
+            fs::read(dir.join(&b.path)).unwrap()
+        );
+    }
+
+    #[test]
+    fn stored_level_round_trips() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        diary(&dir, &["entry", "add", "a"]).unwrap();
+        diary(&dir, &["entry", "append", "a", "hello"]).unwrap();
+        let content = fs::read(dir.join(&load_entries(&dir).unwrap().entries["a"].path)).unwrap();
+
+        assert!(diary(temp.path(), &["close", "x", PASSWORD, "--level=10"]).is_err());
+        assert!(dir.is_dir());
+        for compression in ["gzip", "zstd"] {
+            diary(
+                temp.path(),
+                &[
+                    "close",
+                    "x",
+                    PASSWORD,
+                    "--level=0",
+                    "--compression",
+                    compression,
+                ],
+            )
+            .unwrap();
+            diary(temp.path(), &["open", "x", PASSWORD]).unwrap();
+            let entries = load_entries(&dir).unwrap();
+            assert_eq!(
+                fs::read(dir.join(&entries.entries["a"].path)).unwrap(),
+                content
+            );
+        }
+    }
+
+    #[test]
+    fn move_entry_moves_between_diaries() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "a", PASSWORD]).unwrap();
+        diary(temp.path(), &["new", "b", PASSWORD]).unwrap();
+        let (a, b) = (temp.path().join("a"), temp.path().join("b"));
+        diary(&a, &["entry", "add", "today", "--tag", "work"]).unwrap();
+        diary(&a, &["entry", "append", "today", "hello"]).unwrap();
+        let moved = load_entries(&a).unwrap().entries["today"].clone();
+        let content = fs::read(a.join(&moved.path)).unwrap();
+
+        diary(temp.path(), &["move-entry", "today", "a", "b"]).unwrap();
+        assert!(load_entries(&a).unwrap().entries.is_empty());
+        assert!(!a.join(&moved.path).exists());
+        let entry = &load_entries(&b).unwrap().entries["today"];
+        assert_eq!(entry.tags, ["work"]);
+        assert_eq!(fs::read(b.join(&entry.path)).unwrap(), content);
+
+        assert!(diary(temp.path(), &["move-entry", "today", "a", "b"]).is_err());
+    }
+
+    #[test]
+    fn close_reports_diary_size() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        diary(&temp.path().join("x"), &["entry", "add", "a"]).unwrap();
+
+        let printed = output(temp.path(), &["close", "x", PASSWORD]);
+        let size = fs::metadata(temp.path().join("x.diary")).unwrap().len();
+        assert!(
+            printed.contains(&format!("{size} bytes in x.diary (")),
+            "{printed}"
+        );
+        assert!(printed.ends_with("%)\n"), "{printed}");
+        assert_eq!(ratio(1, 0), 0.0);
+        assert_eq!(ratio(1, 4), 25.0);
+    }
+
+    #[test]
+    fn password_command_opens_diary() {
+        let temp = tempfile::tempdir().unwrap();
+        let hunter2 = "--password-command=echo hunter2";
+        diary(temp.path(), &["new", "x", hunter2]).unwrap();
+        diary(temp.path(), &["close", "x", hunter2]).unwrap();
+
+        assert!(diary(temp.path(), &["open", "x", PASSWORD]).is_err());
+        assert!(diary(temp.path(), &["open", "x", "--password-command=false"]).is_err());
+        assert!(!temp.path().join("x").exists());
+        diary(temp.path(), &["open", "x", hunter2]).unwrap();
+        assert!(temp.path().join("x").join("diary.json").is_file());
+    }
+
+    #[test]
+    fn extract_leaves_diary_file_in_place() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        diary(&temp.path().join("x"), &["entry", "add", "a"]).unwrap();
+        diary(temp.path(), &["close", "x", PASSWORD]).unwrap();
+        let file = temp.path().join("x.diary");
+        let closed = fs::read(&file).unwrap();
+
+        diary(temp.path(), &["extract", "x", PASSWORD]).unwrap();
+        assert_eq!(fs::read(&file).unwrap(), closed);
+        assert!(
+            load_entries(&temp.path().join("x"))
+                .unwrap()
+                .entries
+                .contains_key("a")
+        );
+        let mut left = fs::read_dir(temp.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(left, ["x", "x.diary"]);
+
+        // Refuses to unpack over the extracted directory
+        assert!(diary(temp.path(), &["extract", "x", PASSWORD]).is_err());
+        assert_eq!(fs::read(&file).unwrap(), closed);
+    }
+
+    #[test]
+    fn resume_finishes_interrupted_close() {
+        let temp = tempfile::tempdir().unwrap();
+        let (dir, file) = (temp.path().join("x"), temp.path().join("x.diary"));
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        diary(&dir, &["entry", "add", "a"]).unwrap();
+
+        // Written but the directory left behind
+        diary(temp.path(), &["close", "x", PASSWORD, "--keep-open"]).unwrap();
+        let written = fs::read(&file).unwrap();
+        diary(temp.path(), &["close", "x", PASSWORD, "--resume"]).unwrap();
+        assert!(!dir.exists());
+        assert_eq!(fs::read(&file).unwrap(), written);
+
+        // Cut short while writing
+        diary(temp.path(), &["open", "x", PASSWORD]).unwrap();
+        diary(temp.path(), &["close", "x", PASSWORD, "--keep-open"]).unwrap();
+        fs::write(&file, &fs::read(&file).unwrap()[..100]).unwrap();
+        diary(temp.path(), &["close", "x", PASSWORD, "--resume"]).unwrap();
+        assert!(!dir.exists());
+        diary(temp.path(), &["open", "x", PASSWORD]).unwrap();
+        assert!(load_entries(&dir).unwrap().entries.contains_key("a"));
+
+        // Changed since it was written
+        diary(temp.path(), &["close", "x", PASSWORD, "--keep-open"]).unwrap();
+        diary(&dir, &["entry", "add", "b"]).unwrap();
+        assert!(diary(temp.path(), &["close", "x", PASSWORD, "--resume"]).is_err());
+        assert!(load_entries(&dir).unwrap().entries.contains_key("b"));
+    }
+
+    #[test]
+    fn rename_moves_a_closed_diary() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        diary(&temp.path().join("x"), &["entry", "add", "a"]).unwrap();
+        diary(temp.path(), &["close", "x", PASSWORD]).unwrap();
+        diary(temp.path(), &["new", "taken", PASSWORD]).unwrap();
+
+        assert!(diary(temp.path(), &["rename", "x", "taken"]).is_err());
+        assert!(diary(temp.path(), &["rename", "x", "../y"]).is_err());
+        diary(temp.path(), &["rename", "x", "y"]).unwrap();
+        assert!(diary(temp.path(), &["open", "x", PASSWORD]).is_err());
+        diary(temp.path(), &["open", "y", PASSWORD]).unwrap();
+        assert!(
+            load_entries(&temp.path().join("y"))
+                .unwrap()
+                .entries
+                .contains_key("a")
+        );
+    }
+
+    #[test]
+    fn named_entries_skip_the_picker() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        diary(&dir, &["entry", "add", "a"]).unwrap();
+        diary(&dir, &["entry", "add", "b"]).unwrap();
+        diary(&dir, &["entry", "append", "b", "hello"]).unwrap();
+
+        assert!(output(&dir, &["entry", "show", "b"]).contains("hello"));
+        diary(&dir, &["entry", "remove", "a"]).unwrap();
+        let entries = load_entries(&dir).unwrap();
+        assert!(entries.trashed.contains_key("a"));
+        assert!(entries.entries.contains_key("b"));
+
+        if !io::stdin().is_terminal() {
+            assert!(diary(&dir, &["entry", "show"]).is_err());
+            assert!(diary(&dir, &["entry", "remove"]).is_err());
+            assert!(load_entries(&dir).unwrap().entries.contains_key("b"));
+        }
+    }
+
+    #[test]
+    fn encrypted_entry_needs_entry_password() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        diary(&dir, &["entry", "add", "a"]).unwrap();
+        diary(&dir, &["entry", "append", "a", "secret words"]).unwrap();
+        let file = dir.join(&load_entries(&dir).unwrap().entries["a"].path);
+        // Set by main from --entry-password-command, once for the whole process
+        let _ = ENTRY_PASSWORD_COMMAND.set("echo tiger lantern quietly folds maps".to_string());
+
+        diary(&dir, &["entry", "encrypt", "a"]).unwrap();
+        let contains =
+            |needle: &str| String::from_utf8_lossy(&fs::read(&file).unwrap()).contains(needle);
+        assert!(!contains("secret words"));
+        assert!(!contains("tiger lantern"));
+        assert!(output(&dir, &["entry", "show", "a"]).contains("secret words"));
+
+        diary(temp.path(), &["close", "x", PASSWORD]).unwrap();
+        diary(temp.path(), &["open", "x", PASSWORD]).unwrap();
+        assert!(load_entries(&dir).unwrap().entries["a"].encrypted);
+        assert!(!contains("secret words"));
+        diary(&dir, &["entry", "decrypt", "a"]).unwrap();
+        assert!(contains("secret words"));
+    }
+
+    #[test]
+    fn timestamps_are_stored_in_utc() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        diary(
+            &dir,
+            &[
+                "entry",
+                "add",
+                "east",
+                "--date",
+                "2024-01-01T10:00:00+05:00",
+            ],
+        )
+        .unwrap();
+        diary(
+            &dir,
+            &["entry", "add", "west", "--date", "2024-01-01T06:00:00Z"],
+        )
+        .unwrap();
+
+        let entries = load_entries(&dir).unwrap();
+        let east = entries.entries["east"].timestamp;
+        assert_eq!(east.offset(), UtcOffset::UTC);
+        assert_eq!(east, time::macros::datetime!(2024-01-01 05:00 UTC));
+        assert_eq!(display_time(east, true).offset(), UtcOffset::UTC);
+        assert_eq!(display_time(east, false), east);
+
+        // Sorted by the instant, not by the wall-clock time they were written at
+        assert_eq!(
+            output(&dir, &["entry", "list", "-n", "--sort", "created"]),
+            "east\nwest\n"
+        );
+    }
+
+    #[test]
+    fn threaded_compression_round_trips() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        diary(&dir, &["entry", "add", "a"]).unwrap();
+        let path = dir.join(&load_entries(&dir).unwrap().entries["a"].path);
+        // Compressible but spanning several of the blocks compressed in parallel
+        let content = (0..200_000)
+            .map(|i| format!("line {i}\n"))
+            .collect::<String>();
+        fs::write(&path, &content).unwrap();
+
+        for compression in ["gzip", "zstd"] {
+            for threads in ["0", "1", "4"] {
+                diary(
+                    temp.path(),
+                    &[
+                        "close",
+                        "x",
+                        PASSWORD,
+                        "--compression",
+                        compression,
+                        "--threads",
+                        threads,
+                    ],
+                )
+                .unwrap();
+                diary(temp.path(), &["open", "x", PASSWORD]).unwrap();
+                assert_eq!(fs::read_to_string(&path).unwrap(), content);
+            }
+        }
+    }
+
+    #[test]
+    fn list_shows_open_and_closed_diaries() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "closed", PASSWORD]).unwrap();
+        diary(temp.path(), &["close", "closed", PASSWORD]).unwrap();
+        diary(temp.path(), &["new", "open", PASSWORD]).unwrap();
+        fs::create_dir(temp.path().join("photos")).unwrap();
+        fs::write(temp.path().join("notes.txt"), "").unwrap();
+        // Left by `open --memory` after a reboot
+        #[cfg(unix)]
+        link_dir(&temp.path().join("gone"), &temp.path().join("dangling")).unwrap();
+
+        let printed = output(temp.path(), &["list"]);
+        let listed = printed
+            .lines()
+            .filter(|line| !line.starts_with('\t'))
+            .collect::<Vec<_>>();
+        assert_eq!(listed, ["closed (closed):", "open (open):"]);
+        let size = fs::metadata(temp.path().join("closed.diary"))
+            .unwrap()
+            .len();
+        assert!(printed.contains(&format!("\tsize: {size} bytes\n")));
+    }
+
+    #[test]
+    fn shell_closes_session_with_its_changes() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        diary(temp.path(), &["close", "x", PASSWORD]).unwrap();
+
+        diary(
+            temp.path(),
+            &["shell", "x", PASSWORD, "--command=echo edits > notes.txt"],
+        )
+        .unwrap();
+        assert!(!temp.path().join("x").exists());
+
+        diary(temp.path(), &["open", "x", PASSWORD]).unwrap();
+        assert_eq!(
+            fs::read(temp.path().join("x").join("notes.txt")).unwrap(),
+            b"edits\n"
+        );
+    }
+
+    #[test]
+    fn append_adds_lines_in_order() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        diary(&dir, &["entry", "add", "a"]).unwrap();
+
+        diary(&dir, &["entry", "append", "a", "first"]).unwrap();
+        diary(&dir, &["entry", "append", "a", "second\n\n"]).unwrap();
+        diary(&dir, &["entry", "append", "a", "third", "--timestamp"]).unwrap();
+        assert!(diary(&dir, &["entry", "append", "missing", "text"]).is_err());
+
+        let path = dir.join(&load_entries(&dir).unwrap().entries["a"].path);
+        let content = fs::read_to_string(path).unwrap();
+        let (start, heading) = content.split_once("\n## ").unwrap();
+        assert_eq!(start, "first\nsecond\n");
+        let (heading, end) = heading.split_once('\n').unwrap();
+        assert_eq!(heading.len(), "2026-10-15 08:00".len());
+        assert_eq!(end, "\nthird\n");
+    }
+
+    #[test]
+    fn entry_extension_survives_close() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        diary(&dir, &["entry", "add", "notes", "--ext", ".txt"]).unwrap();
+        diary(&dir, &["entry", "add", "plan", "--ext", "org"]).unwrap();
+        diary(&dir, &["entry", "add", "default"]).unwrap();
+        assert!(diary(&dir, &["entry", "add", "bad", "--ext", "../md"]).is_err());
+        diary(&dir, &["entry", "append", "notes", "hello"]).unwrap();
+
+        diary(temp.path(), &["close", "x", PASSWORD]).unwrap();
+        diary(temp.path(), &["open", "x", PASSWORD]).unwrap();
+        let entries = load_entries(&dir).unwrap();
+        let ext = |name: &str| entries.entries[name].path.extension().unwrap().to_owned();
+        assert_eq!(ext("notes"), "txt");
+        assert_eq!(ext("plan"), "org");
+        assert_eq!(ext("default"), "md");
+        assert!(!entries.entries.contains_key("bad"));
+        let notes = dir.join(&entries.entries["notes"].path);
+        assert_eq!(fs::read(notes).unwrap(), b"hello\n");
+    }
+
+    #[test]
+    fn info_json_counts_entries() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        diary(&dir, &["entry", "add", "a", "--location", "home"]).unwrap();
+        diary(
+            &dir,
+            &[
+                "entry",
+                "add",
+                "b",
+                "--tag",
+                "work",
+                "--date",
+                "2020-01-01T00:00:00Z",
+            ],
+        )
+        .unwrap();
+        diary(&dir, &["entry", "append", "b", "hello"]).unwrap();
+
+        let info: serde_json::Value =
+            serde_json::from_str(&output(&dir, &["info", "--format", "json"])).unwrap();
+        assert_eq!(info["entries"], 2);
+        assert_eq!(info["total_bytes"], 6);
+        assert_eq!(info["with_location"], 1);
+        assert_eq!(info["with_description"], 0);
+        assert_eq!(info["with_tags"], 1);
+        assert_eq!(info["earliest"], "2020-01-01T00:00:00Z");
+    }
+
+    #[test]
+    fn wrong_passwords_are_retried() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        diary(temp.path(), &["close", "x", PASSWORD]).unwrap();
+        let file = temp.path().join("x.diary");
+        let attempts = |tries, passwords: &[&str]| {
+            let mut passwords = passwords.iter();
+            let mut asked = 0;
+            let unlocked = unlock_with_tries(&file, tries, || {
+                asked += 1;
+                Ok(passwords.next().unwrap().to_string())
+            });
+            (unlocked.is_ok(), asked)
+        };
+
+        let right = "correct horse battery staple";
+        assert_eq!(attempts(3, &["wrong", "also wrong", right]), (true, 3));
+        assert_eq!(attempts(3, &[right, "never asked"]), (true, 1));
+        assert_eq!(attempts(2, &["wrong", "also wrong", right]), (false, 2));
+
+        // A supplied password is only tried once
+        let error = diary(temp.path(), &["open", "x", "--password-command=echo wrong"]);
+        assert!(matches!(
+            error.unwrap_err().downcast_ref(),
+            Some(DiaryError::WrongPassword)
+        ));
+    }
+
+    #[test]
+    fn export_starts_with_front_matter() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        let date = "--date=2024-03-01T09:30:00Z";
+        diary(
+            &dir,
+            &[
+                "entry", "add", "a", date, "-L", "\"home\"", "-t", "x", "-t", "y",
+            ],
+        )
+        .unwrap();
+        diary(&dir, &["entry", "append", "a", "hello"]).unwrap();
+        let id = load_entries(&dir).unwrap().entries["a"].id;
+
+        let out = temp.path().join("a.md");
+        let export = ["entry", "export", "a", out.to_str().unwrap()];
+        diary(&dir, &export).unwrap();
+        assert_eq!(
+            fs::read_to_string(&out).unwrap(),
+            format!(
+                "---\nname: \"a\"\nid: {id}\ntimestamp: 2024-03-01T09:30:00Z\n\
+                 location: \"\\\"home\\\"\"\ntags: [\"x\",\"y\"]\n---\nhello\n"
+            )
+        );
+
+        // Only replaced with --force
+        fs::write(&out, "mine").unwrap();
+        assert!(diary(&dir, &export).is_err());
+        assert_eq!(fs::read(&out).unwrap(), b"mine");
+        diary(&dir, &[&export[..], &["--force"]].concat()).unwrap();
+        assert!(fs::read_to_string(&out).unwrap().ends_with("---\nhello\n"));
+    }
+
+    #[test]
+    fn close_leaves_out_excluded_files() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        diary(&dir, &["entry", "add", "a"]).unwrap();
+        fs::write(dir.join(".notes.md.swp"), "editor state").unwrap();
+        fs::write(dir.join("notes.md"), "kept").unwrap();
+
+        diary(temp.path(), &["close", "x", PASSWORD, "--exclude", "*.swp"]).unwrap();
+        assert!(!dir.exists());
+        diary(temp.path(), &["open", "x", PASSWORD]).unwrap();
+        assert!(!dir.join(".notes.md.swp").exists());
+        assert_eq!(fs::read(dir.join("notes.md")).unwrap(), b"kept");
+
+        // diary.json is kept even when it doesn't match
+        diary(temp.path(), &["close", "x", PASSWORD, "--include", "*.txt"]).unwrap();
+        diary(temp.path(), &["open", "x", PASSWORD]).unwrap();
+        assert!(load_entries(&dir).unwrap().entries.contains_key("a"));
+        assert!(!dir.join("notes.md").exists());
+    }
+
+    #[test]
+    fn history_lists_commits_of_an_entry() {
+        if process::Command::new("git")
+            .arg("--version")
+            .output()
+            .is_err()
+        {
+            return;
+        }
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        assert!(diary(&dir, &["entry", "add", "a", "--commit"]).is_err());
+        diary(&dir, &["entry", "add", "b"]).unwrap();
+        let git = |args: &[&str]| {
+            let status = process::Command::new("git")
+                .arg("-C")
+                .arg(&dir)
+                .args(args)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        git(&["init", "--quiet"]);
+        git(&["config", "user.name", "Test"]);
+        git(&["config", "user.email", "test@example.com"]);
+
+        diary(&dir, &["entry", "add", "c", "--commit"]).unwrap();
+        diary(&dir, &["entry", "append", "c", "hello"]).unwrap();
+        git(&["commit", "--quiet", "--all", "--message", "Write c"]);
+
+        let history = output(&dir, &["entry", "history", "c"]);
+        let subjects = history
+            .lines()
+            .map(|line| line.splitn(3, ' ').nth(2).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(subjects, ["Write c", "Add entry c"]);
+        assert_eq!(output(&dir, &["entry", "history", "b"]), "");
+        assert!(diary(&dir, &["entry", "history", "missing"]).is_err());
+    }
+
+    #[test]
+    fn status_tracks_unsaved_changes() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        diary(&dir, &["entry", "add", "a"]).unwrap();
+        let clean = "Diary is clean.\n";
+        let dirty = "Diary has unsaved changes.\n";
+        assert_eq!(output(&dir, &["status"]), dirty);
+
+        diary(temp.path(), &["close", "x", PASSWORD]).unwrap();
+        diary(temp.path(), &["open", "x", PASSWORD]).unwrap();
+        assert_eq!(output(&dir, &["status"]), clean);
+
+        diary(&dir, &["entry", "append", "a", "hello"]).unwrap();
+        assert_eq!(output(&dir, &["status"]), dirty);
+        diary(temp.path(), &["close", "x", PASSWORD, "--keep-open"]).unwrap();
+        assert_eq!(output(&dir, &["status"]), clean);
+
+        assert!(diary(temp.path(), &["status"]).is_err());
+    }
+
+    #[test]
+    fn quiet_drops_messages_but_not_output() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+
+        let loud = run_with_flags(&dir, &["--json", "entry", "add", "a"]);
+        assert!(loud.success);
+        assert!(!loud.messages.is_empty());
+        let quiet = run_with_flags(&dir, &["--json", "--quiet", "entry", "add", "b"]);
+        assert!(quiet.success);
+        assert!(quiet.messages.is_empty());
+
+        let listed = run_with_flags(
+            &dir,
+            &["--json", "-q", "entry", "list", "-n", "--sort", "name"],
+        );
+        assert_eq!(listed.output, "a\nb\n");
+        let failed = run_with_flags(&dir, &["--json", "-q", "entry", "add", "a"]);
+        assert!(failed.error.is_some());
+        assert!(!QUIET.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn failed_close_keeps_the_directory() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        diary(&temp.path().join("x"), &["entry", "add", "a"]).unwrap();
+
+        let missing = temp.path().join("missing").join("x.diary");
+        let file = format!("--file={}", missing.display());
+        assert!(diary(temp.path(), &["close", "x", PASSWORD, &file]).is_err());
+        assert!(
+            load_entries(&temp.path().join("x"))
+                .unwrap()
+                .entries
+                .contains_key("a")
+        );
+        let left = fs::read_dir(temp.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(left, ["x"]);
+    }
+
+    #[test]
+    fn pinned_entries_are_listed_first() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        for name in ["a", "b", "c", "d"] {
+            diary(&dir, &["entry", "add", name]).unwrap();
+        }
+        diary(&dir, &["entry", "pin", "c"]).unwrap();
+        diary(&dir, &["entry", "pin", "b"]).unwrap();
+        diary(&dir, &["entry", "pin", "d"]).unwrap();
+        diary(&dir, &["entry", "unpin", "d"]).unwrap();
+        assert!(diary(&dir, &["entry", "pin", "missing"]).is_err());
+
+        let listed = |args: &[&str]| {
+            output(
+                &dir,
+                &[&["entry", "list", "-n", "--sort", "name"], args].concat(),
+            )
+        };
+        assert_eq!(listed(&[]), "b\nc\na\nd\n");
+        assert_eq!(listed(&["--reverse"]), "c\nb\nd\na\n");
+        assert_eq!(listed(&["--pinned-only"]), "b\nc\n");
+
+        diary(temp.path(), &["close", "x", PASSWORD]).unwrap();
+        diary(temp.path(), &["open", "x", PASSWORD]).unwrap();
+        assert_eq!(listed(&["--pinned-only"]), "b\nc\n");
+    }
+
+    #[test]
+    fn diary_file_can_be_given_explicitly() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        diary(&temp.path().join("x"), &["entry", "add", "a"]).unwrap();
+        fs::create_dir(temp.path().join("vault")).unwrap();
+        let file = "--file=vault/journal.diary";
+
+        let inside = ["close", "x", PASSWORD, "--file=x/x.diary"];
+        assert!(diary(temp.path(), &inside).is_err());
+        diary(
+            temp.path(),
+            &["close", "x", PASSWORD, "--comment=mine", file],
+        )
+        .unwrap();
+        assert!(temp.path().join("vault").join("journal.diary").is_file());
+        assert!(!temp.path().join("x.diary").exists());
+
+        assert!(output(temp.path(), &["peek", "x", file]).contains("mine"));
+        assert!(diary(temp.path(), &["open", "x", PASSWORD]).is_err());
+        diary(temp.path(), &["open", "x", PASSWORD, file]).unwrap();
+        assert!(
+            load_entries(&temp.path().join("x"))
+                .unwrap()
+                .entries
+                .contains_key("a")
+        );
+    }
+
+    #[test]
+    fn diff_shows_changed_entries() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        for name in ["a", "c", "d"] {
+            diary(&dir, &["entry", "add", name]).unwrap();
+        }
+        diary(temp.path(), &["close", "x", PASSWORD, "--keep-open"]).unwrap();
+        fs::rename(temp.path().join("x.diary"), temp.path().join("old.diary")).unwrap();
+
+        diary(&dir, &["entry", "append", "a", "hello"]).unwrap();
+        diary(&dir, &["entry", "add", "b"]).unwrap();
+        diary(&dir, &["entry", "remove", "c"]).unwrap();
+        diary(temp.path(), &["close", "x", PASSWORD]).unwrap();
+
+        assert_eq!(
+            output(temp.path(), &["diff", "old.diary", "x.diary", PASSWORD]),
+            "~ a\n+ b\n- c\n1 added, 1 removed, 1 modified\n"
+        );
+        let content = output(
+            temp.path(),
+            &["diff", "old.diary", "x.diary", PASSWORD, "-c"],
+        );
+        assert!(content.contains("\n+hello\n"), "{content}");
+        assert_eq!(
+            output(temp.path(), &["diff", "x.diary", "x.diary", PASSWORD]),
+            "0 added, 0 removed, 0 modified\n"
+        );
+    }
+
+    #[test]
+    fn entry_commands_refuse_to_run_outside_a_diary() {
+        let temp = tempfile::tempdir().unwrap();
+        fs::write(temp.path().join("notes.md"), "not a diary").unwrap();
+
+        for args in [
+            &["entry", "add", "a"][..],
+            &["entry", "add", "a", "--ext", "txt"],
+            &["entry", "list"],
+            &["today"],
+        ] {
+            let error = diary(temp.path(), args).unwrap_err();
+            assert!(
+                matches!(error.downcast_ref(), Some(DiaryError::NotInsideDiary)),
+                "{args:?}: {error}"
+            );
+        }
+        let left = fs::read_dir(temp.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(left, ["notes.md"]);
+    }
+
+    /// Messages logged by every test in the process once `verbose_logs_each_step` installs it
+    static LOGGED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct TestLogger;
+
+    impl log::Log for TestLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            LOGGED.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn verbose_logs_each_step() {
+        assert_eq!(log_level(0), LevelFilter::Warn);
+        assert_eq!(log_level(1), LevelFilter::Debug);
+        assert_eq!(log_level(2), LevelFilter::Trace);
+        assert_eq!(log_level(9), LevelFilter::Trace);
+
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        log::set_logger(&TestLogger).unwrap();
+        let logged = |verbose, args: &[&str]| {
+            log::set_max_level(log_level(verbose));
+            LOGGED.lock().unwrap().clear();
+            diary(temp.path(), args).unwrap();
+            log::set_max_level(LevelFilter::Off);
+            std::mem::take(&mut *LOGGED.lock().unwrap())
+        };
+
+        let closing = logged(1, &["close", "x", PASSWORD]);
+        assert!(
+            closing.contains(&"Hashing archive".to_string()),
+            "{closing:?}"
+        );
+        assert!(
+            closing.contains(&"Encrypting into x.diary".to_string()),
+            "{closing:?}"
+        );
+        let opening = logged(1, &["open", "x", PASSWORD]);
+        assert!(
+            opening.contains(&"Reading header".to_string()),
+            "{opening:?}"
+        );
+        assert!(
+            opening.iter().any(|m| m.starts_with("Decrypting into")),
+            "{opening:?}"
+        );
+        assert!(logged(0, &["close", "x", PASSWORD]).is_empty());
+    }
+
+    #[test]
+    fn each_cipher_round_trips() {
+        let temp = tempfile::tempdir().unwrap();
+        let file = temp.path().join("x.diary");
+        let cipher = || Header::read(File::open(&file).unwrap()).unwrap().cipher;
+        let reopen = || {
+            diary(temp.path(), &["open", "x", PASSWORD]).unwrap();
+            let dir = temp.path().join("x");
+            fs::read(dir.join(&load_entries(&dir).unwrap().entries["a"].path)).unwrap()
+        };
+
+        for (name, algorithm) in [
+            ("aes256gcmsiv", Algorithm::Aes256GcmSiv),
+            ("xchacha20", Algorithm::XChaCha20Poly1305),
+        ] {
+            diary(temp.path(), &["new", "x", PASSWORD, "--cipher", name]).unwrap();
+            let dir = temp.path().join("x");
+            diary(&dir, &["entry", "add", "a"]).unwrap();
+            diary(&dir, &["entry", "append", "a", name]).unwrap();
+            diary(temp.path(), &["close", "x", PASSWORD]).unwrap();
+            assert_eq!(cipher(), algorithm);
+            assert_eq!(reopen(), format!("{name}\n").as_bytes());
+
+            // Switched on close and remembered for the next one
+            let other = ["aes256gcmsiv", "xchacha20"]
+                .into_iter()
+                .find(|n| *n != name)
+                .unwrap();
+            diary(temp.path(), &["close", "x", PASSWORD, "--cipher", other]).unwrap();
+            assert_ne!(cipher(), algorithm);
+            assert_eq!(reopen(), format!("{name}\n").as_bytes());
+            diary(temp.path(), &["close", "x", PASSWORD]).unwrap();
+            assert_ne!(cipher(), algorithm);
+            fs::remove_file(&file).unwrap();
+        }
+    }
+
+    #[test]
+    fn add_opens_the_new_entry_in_the_editor() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        let written = temp.path().join("written.md");
+        fs::write(&written, "dear diary   \n\n\n").unwrap();
+        let editor = format!("--editor=cp {}", written.display());
+        let content = |name: &str| {
+            fs::read_to_string(dir.join(&load_entries(&dir).unwrap().entries[name].path)).unwrap()
+        };
+
+        assert!(diary(&dir, &["entry", "add", "a", &editor]).is_err());
+        diary(&dir, &["entry", "add", "a", "--edit", &editor]).unwrap();
+        assert_eq!(content("a"), "dear diary\n");
+        diary(
+            &dir,
+            &["entry", "add", "b", "--edit", "--no-format", &editor],
+        )
+        .unwrap();
+        assert_eq!(content("b"), "dear diary   \n\n\n");
+
+        // Kept even if the editor fails
+        diary(&dir, &["entry", "add", "c", "--edit", "--editor=false"]).unwrap();
+        assert_eq!(content("c"), "");
+    }
+
+    #[test]
+    fn keep_open_writes_a_snapshot() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        diary(&dir, &["entry", "add", "a"]).unwrap();
+        diary(&dir, &["entry", "append", "a", "first"]).unwrap();
+        let get = |out: &str| {
+            diary(temp.path(), &["get", "x", "a", PASSWORD, "-o", out]).unwrap();
+            fs::read_to_string(temp.path().join(out)).unwrap()
+        };
+
+        diary(temp.path(), &["close", "x", PASSWORD, "--keep-open"]).unwrap();
+        assert!(load_entries(&dir).unwrap().entries.contains_key("a"));
+        assert_eq!(get("1.md"), "first\n");
+
+        diary(&dir, &["entry", "append", "a", "second"]).unwrap();
+        diary(temp.path(), &["close", "x", PASSWORD, "--snapshot"]).unwrap();
+        assert_eq!(get("2.md"), "first\nsecond\n");
+        let mut left = fs::read_dir(temp.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(left, ["1.md", "2.md", "x", "x.diary"]);
+        assert!(
+            diary(
+                temp.path(),
+                &["close", "x", PASSWORD, "--keep-open", "--resume"]
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn archived_entries_are_hidden() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        diary(&dir, &["entry", "add", "old", "--location", "Paris"]).unwrap();
+        diary(&dir, &["entry", "add", "new", "--location", "Paris"]).unwrap();
+        diary(&dir, &["entry", "archive", "old"]).unwrap();
+        let listed = |args: &[&str]| {
+            output(
+                &dir,
+                &[&["entry", "list", "-n", "--sort", "name"], args].concat(),
+            )
+        };
+        let searched =
+            |args: &[&str]| output(&dir, &[&["entry", "search", "paris", "-c"], args].concat());
+
+        assert_eq!(listed(&[]), "new\n");
+        assert_eq!(listed(&["-a"]), "new\nold\n");
+        assert_eq!(listed(&["--archived-only"]), "old\n");
+        assert_eq!(searched(&[]), "1\n");
+        assert_eq!(searched(&["-a"]), "2\n");
+
+        diary(temp.path(), &["close", "x", PASSWORD]).unwrap();
+        diary(temp.path(), &["open", "x", PASSWORD]).unwrap();
+        assert_eq!(listed(&["--archived-only"]), "old\n");
+        diary(&dir, &["entry", "unarchive", "old"]).unwrap();
+        assert_eq!(listed(&[]), "new\nold\n");
+    }
+
+    #[test]
+    fn templates_seed_new_entries() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        fs::create_dir(dir.join(TEMPLATES_DIR)).unwrap();
+        let daily = "# {{title}}\n{{name}} {{id}} {{date}} {{time}} {{unknown}}\n";
+        fs::write(dir.join(TEMPLATES_DIR).join("daily.md"), daily).unwrap();
+        fs::write(temp.path().join("other.txt"), "from {{name}}\n").unwrap();
+        let date = "--date=2024-03-01T09:30:00Z";
+        let entry = |name: &str| {
+            let entry = load_entries(&dir).unwrap().entries[name].clone();
+            (entry.id, fs::read_to_string(dir.join(&entry.path)).unwrap())
+        };
+
+        diary(
+            &dir,
+            &[
+                "--no-local-time",
+                "entry",
+                "add",
+                "monday",
+                date,
+                "--template",
+                "daily",
+            ],
+        )
+        .unwrap();
+        let (id, content) = entry("monday");
+        assert_eq!(
+            content,
+            format!("# monday\nmonday {id} 2024-03-01 09:30 {{{{unknown}}}}\n")
+        );
+
+        let other = temp.path().join("other.txt");
+        diary(
+            &dir,
+            &[
+                "entry",
+                "add",
+                "tuesday",
+                "--template",
+                other.to_str().unwrap(),
+            ],
+        )
+        .unwrap();
+        assert_eq!(entry("tuesday").1, "from tuesday\n");
+
+        assert!(
+            diary(
+                &dir,
+                &["entry", "add", "wednesday", "--template", "missing"]
+            )
+            .is_err()
+        );
+        assert!(
+            !load_entries(&dir)
+                .unwrap()
+                .entries
+                .contains_key("wednesday")
+        );
+    }
+
+    #[test]
+    fn backlinks_list_linking_entries() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        for (name, date) in [
+            ("a", "2024-01-01"),
+            ("b", "2024-01-02"),
+            ("c", "2024-01-03"),
+        ] {
+            let date = format!("--date={date}T00:00:00Z");
+            diary(&dir, &["entry", "add", name, &date]).unwrap();
+        }
+        diary(&dir, &["entry", "link", "b", "c"]).unwrap();
+        diary(&dir, &["entry", "link", "a", "c"]).unwrap();
+        diary(&dir, &["entry", "link", "a", "c"]).unwrap();
+        assert!(diary(&dir, &["entry", "link", "a", "missing"]).is_err());
+        let backlinks = |name| {
+            output(&dir, &["entry", "backlinks", name])
+                .lines()
+                .filter(|line| !line.starts_with('\t'))
+                .map(|line| line.split(' ').next().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(backlinks("c"), ["a", "b"]);
+        assert!(backlinks("a").is_empty());
+        diary(temp.path(), &["close", "x", PASSWORD]).unwrap();
+        diary(temp.path(), &["open", "x", PASSWORD]).unwrap();
+        assert_eq!(backlinks("c"), ["a", "b"]);
+        let entries = load_entries(&dir).unwrap();
+        assert_eq!(entries.entries["a"].links, [entries.entries["c"].id]);
+    }
+
+    #[test]
+    fn entries_dir_holds_entry_files() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD, "--entries-dir"]).unwrap();
+        let dir = temp.path().join("x");
+        diary(&dir, &["entry", "add", "a"]).unwrap();
+        diary(&dir, &["entry", "append", "a", "hello"]).unwrap();
+        diary(&dir, &["entry", "duplicate", "a", "b"]).unwrap();
+
+        diary(temp.path(), &["close", "x", PASSWORD]).unwrap();
+        diary(temp.path(), &["open", "x", PASSWORD]).unwrap();
+        let entries = load_entries(&dir).unwrap();
+        assert_eq!(entries.entries_dir.as_deref(), Some(Path::new(ENTRIES_DIR)));
+        for name in ["a", "b"] {
+            let path = &entries.entries[name].path;
+            assert!(path.starts_with(ENTRIES_DIR), "{}", path.display());
+            assert_eq!(fs::read(dir.join(path)).unwrap(), b"hello\n");
+        }
+        let mut top = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .filter(|name| name != SAVED_MARKER)
+            .collect::<Vec<_>>();
+        top.sort();
+        assert_eq!(top, ["diary.json", ENTRIES_DIR]);
+    }
+
+    #[test]
+    fn max_entry_size_refuses_larger_entries() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        diary(&dir, &["entry", "add", "a"]).unwrap();
+        let path = dir.join(&load_entries(&dir).unwrap().entries["a"].path);
+        fs::write(&path, "x".repeat(2048)).unwrap();
+
+        let refused = run_with_flags(
+            &dir,
+            &["--json", "--max-entry-size=1K", "entry", "show", "a"],
+        );
+        assert_eq!(refused.code, Some("too_large"));
+        assert_eq!(
+            refused.error.unwrap(),
+            "Entry file is 2048 bytes, larger than the limit of 1024 bytes (see --max-entry-size)"
+        );
+        let shown = run_with_flags(
+            &dir,
+            &["--json", "--max-entry-size=2K", "entry", "show", "a"],
+        );
+        assert_eq!(shown.output.len(), 2048);
+        assert!(Cli::try_parse_from(["diary", "--max-entry-size=1X", "status"]).is_err());
+        assert_eq!(entries::MAX_ENTRY_SIZE.load(Ordering::Relaxed), 50 << 20);
+    }
+
+    #[test]
+    fn normalize_names_files_after_entries() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        for name in ["My Trip: Day 1!", "my trip day 1", "!!!"] {
+            diary(&dir, &["entry", "add", name]).unwrap();
+        }
+        diary(&dir, &["entry", "add", "notes", "--ext", "txt"]).unwrap();
+        diary(&dir, &["entry", "append", "notes", "hello"]).unwrap();
+        // Same slug and the same start of their ids
+        let mut x = Diary::load(&dir).unwrap();
+        for (name, id) in [("My Trip: Day 1!", 1), ("my trip day 1", 2)] {
+            x.update_entry(name, |entry| entry.id = Uuid::from_u128(id))
+                .unwrap();
+        }
+        let before = load_entries(&dir).unwrap();
+
+        diary(&dir, &["--dry-run", "normalize"]).unwrap();
+        assert_eq!(
+            load_entries(&dir).unwrap().entries["notes"].path,
+            before.entries["notes"].path
+        );
+
+        diary(&dir, &["normalize"]).unwrap();
+        let after = load_entries(&dir).unwrap();
+        let path = |name: &str| {
+            let id = before.entries[name].id.simple().to_string();
+            (after.entries[name].path.to_str().unwrap().to_string(), id)
+        };
+        let (trip, id) = path("My Trip: Day 1!");
+        assert_eq!(trip, format!("my-trip-day-1-{id}.md"));
+        let (same_slug, id) = path("my trip day 1");
+        assert_eq!(same_slug, format!("my-trip-day-1-{id}.md"));
+        let (empty, id) = path("!!!");
+        assert_eq!(empty, format!("{}.md", &id[..8]));
+        let (notes, id) = path("notes");
+        assert_eq!(notes, format!("notes-{}.txt", &id[..8]));
+        assert_eq!(fs::read(dir.join(notes)).unwrap(), b"hello\n");
+
+        diary(&dir, &["normalize"]).unwrap();
+        assert_eq!(
+            load_entries(&dir).unwrap().entries["notes"].path,
+            after.entries["notes"].path
+        );
+    }
+
+    #[test]
+    fn comment_is_shown_by_peek() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let comment = |args: &[&str]| {
+            diary(temp.path(), &[&["close", "x", PASSWORD], args].concat()).unwrap();
+            let peeked = output(temp.path(), &["peek", "x"]);
+            diary(temp.path(), &["open", "x", PASSWORD]).unwrap();
+            peeked
+                .lines()
+                .find_map(|line| line.strip_prefix("\tcomment: "))
+                .map(str::to_string)
+        };
+
+        assert_eq!(comment(&[]), None);
+        assert_eq!(
+            comment(&["--comment=work journal"]).as_deref(),
+            Some("work journal")
+        );
+        // Remembered until it is cleared
+        assert_eq!(comment(&[]).as_deref(), Some("work journal"));
+        assert_eq!(comment(&["--comment="]), None);
+
+        let long = format!("--comment={}", "x".repeat(consts::MAX_COMMENT_LENGTH + 1));
+        assert!(Cli::try_parse_from(["diary", "close", "x", &long]).is_err());
+    }
+
+    #[test]
+    fn format_tidies_and_wraps_markdown() {
+        let messy = "# A heading far too long to wrap   \n\
+                     \n\
+                     some words here that go on\t\n\
+                     - a list item that wraps around\n\
+                     ```\n\
+                     code that is also much too long\n\
+                     ```\n\
+                     | a table | row that is long |\n\
+                     \n\n\n";
+        assert_eq!(
+            tidy_markdown(messy, None),
+            "# A heading far too long to wrap\n\
+             \n\
+             some words here that go on\n\
+             - a list item that wraps around\n\
+             ```\n\
+             code that is also much too long\n\
+             ```\n\
+             | a table | row that is long |\n"
+        );
+        assert_eq!(tidy_markdown(" \n\n", None), "");
+
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        diary(&dir, &["entry", "add", "a"]).unwrap();
+        let path = dir.join(&load_entries(&dir).unwrap().entries["a"].path);
+        fs::write(&path, messy).unwrap();
+
+        diary(&dir, &["entry", "format", "a", "--wrap", "12"]).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "# A heading far too long to wrap\n\
+             \n\
+             some words\n\
+             here that go\n\
+             on\n\
+             - a list\n\
+             \x20 item that\n\
+             \x20 wraps\n\
+             \x20 around\n\
+             ```\n\
+             code that is also much too long\n\
+             ```\n\
+             | a table | row that is long |\n"
+        );
+        assert!(diary(&dir, &["entry", "format", "a", "--wrap", "0"]).is_err());
+    }
+
+    #[test]
+    fn count_prints_only_the_number_of_matches() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        diary(&dir, &["entry", "add", "beach", "--tag", "holiday"]).unwrap();
+        diary(
+            &dir,
+            &["entry", "add", "hills", "--description", "Holiday walk"],
+        )
+        .unwrap();
+        diary(&dir, &["entry", "add", "office", "--tag", "work"]).unwrap();
+
+        assert_eq!(
+            output(&dir, &["entry", "search", "holiday", "--count"]),
+            "2\n"
+        );
+        assert_eq!(
+            output(&dir, &["entry", "search", "holiday", "-c", "-t", "work"]),
+            "0\n"
+        );
+        assert_eq!(output(&dir, &["entry", "search", "nothing", "-c"]), "0\n");
+        assert_eq!(output(&dir, &["entry", "list", "--count"]), "3\n");
+        assert_eq!(
+            output(&dir, &["entry", "list", "-c", "-t", "holiday"]),
+            "1\n"
+        );
+        assert!(diary(&dir, &["entry", "list", "-c", "-n"]).is_err());
+    }
+
+    #[test]
+    fn get_writes_only_the_entry() {
+        let temp = tempfile::tempdir().unwrap();
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        let dir = temp.path().join("x");
+        diary(&dir, &["entry", "add", "a"]).unwrap();
+        diary(&dir, &["entry", "append", "a", "hello"]).unwrap();
+        diary(temp.path(), &["close", "x", PASSWORD]).unwrap();
+        let closed = fs::read(temp.path().join("x.diary")).unwrap();
+
+        assert_eq!(output(temp.path(), &["get", "x", "a", PASSWORD]), "hello\n");
+        diary(temp.path(), &["get", "x", "a", PASSWORD, "-o", "a.md"]).unwrap();
+        assert_eq!(fs::read(temp.path().join("a.md")).unwrap(), b"hello\n");
+        assert!(diary(temp.path(), &["get", "x", "a", PASSWORD, "-o", "a.md"]).is_err());
+        assert!(
+            diary(
+                temp.path(),
+                &["get", "x", "missing", PASSWORD, "-o", "b.md"]
+            )
+            .is_err()
+        );
+
+        let mut left = fs::read_dir(temp.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(left, ["a.md", "x.diary"]);
+        assert_eq!(fs::read(temp.path().join("x.diary")).unwrap(), closed);
+    }
+
+    #[test]
+    fn password_file_opens_diary() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("password");
+        fs::write(&path, "correct horse battery staple\n").unwrap();
+        let file = format!("--password-file={}", path.display());
+        diary(temp.path(), &["new", "x", PASSWORD]).unwrap();
+        diary(temp.path(), &["close", "x", &file]).unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+            assert!(diary(temp.path(), &["open", "x", &file, "--strict-perms"]).is_err());
+            assert!(!temp.path().join("x").exists());
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+            diary(temp.path(), &["open", "x", &file, "--strict-perms"]).unwrap();
+            diary(temp.path(), &["close", "x", &file]).unwrap();
+        }
+
+        fs::write(&path, "wrong\n").unwrap();
+        assert!(diary(temp.path(), &["open", "x", &file]).is_err());
+        fs::write(&path, "correct horse battery staple").unwrap();
+        diary(temp.path(), &["open", "x", &file]).unwrap();
+        assert!(temp.path().join("x").join("diary.json").is_file());
+    }
 }
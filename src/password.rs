@@ -0,0 +1,185 @@
+use crate::{cipher, cli::PasswordArgs};
+use color_eyre::eyre::{Context, bail};
+use std::{
+    fs::{self, File},
+    path::Path,
+};
+use zxcvbn::{Score, zxcvbn};
+
+/// Get the password from a non-interactive source, if one was given, combined with the keyfile
+pub fn supplied_password(args: &PasswordArgs) -> color_eyre::Result<Option<String>> {
+    let password = if let Some(command) = &args.password_command {
+        run_password_command(command)?
+    } else if let Some(file) = &args.password_file {
+        read_password_file(file, args.strict_perms)?
+    } else if args.no_password {
+        String::new()
+    } else {
+        return Ok(None);
+    };
+
+    with_keyfile(args, password).map(Some)
+}
+
+/// Get the password from the configured source, prompting with `prompt` if there is none
+pub fn read_password(args: &PasswordArgs, prompt: &str) -> color_eyre::Result<String> {
+    match supplied_password(args)? {
+        Some(password) => Ok(password),
+        None => with_keyfile(args, rpassword::prompt_password(prompt)?),
+    }
+}
+
+/// Get a new password, asking for it twice when prompting
+pub fn read_new_password(args: &PasswordArgs) -> color_eyre::Result<String> {
+    if let Some(password) = supplied_password(args)? {
+        return Ok(password);
+    }
+
+    let p1 = rpassword::prompt_password("Enter password: ")?;
+    let p2 = rpassword::prompt_password("Re-enter password: ")?;
+
+    if p1 != p2 {
+        bail!("Passwords do not match");
+    }
+
+    with_keyfile(args, p1)
+}
+
+/// Combine `password` with the SHA-256 digest of the keyfile, if one was given, so the diary
+/// can only be opened with both. The digest comes first and has a fixed length, so no password
+/// and keyfile pair can collide with another
+fn with_keyfile(args: &PasswordArgs, password: String) -> color_eyre::Result<String> {
+    let Some(path) = &args.keyfile else {
+        return Ok(password);
+    };
+
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    if file.metadata()?.len() == 0 {
+        bail!("Keyfile {} is empty", path.display());
+    }
+
+    let digest =
+        cipher::digest(file).with_context(|| format!("Failed to read {}", path.display()))?;
+    let digest: String = digest.iter().map(|b| format!("{b:02x}")).collect();
+
+    Ok(format!("keyfile:{digest}:{password}"))
+}
+
+/// Estimate how hard `password` is to guess, also matching it against `user_inputs` like the
+/// diary name. Weak passwords are warned about, or refused if `require_strong` is set
+pub fn check_strength(
+    password: &str,
+    user_inputs: &[&str],
+    require_strong: bool,
+) -> color_eyre::Result<()> {
+    let estimate = zxcvbn(password, user_inputs);
+    if estimate.score() >= Score::Three {
+        return Ok(());
+    }
+
+    // Argon2 makes guessing slow, so the slow hashing rate is the closest estimate
+    let mut message = format!(
+        "Password is weak: about {} guesses, cracked offline in {}",
+        estimate.guesses(),
+        estimate.crack_times().offline_slow_hashing_1e4_per_second()
+    );
+    if let Some(warning) = estimate.feedback().and_then(|f| f.warning()) {
+        message.push_str(&format!(". {warning}"));
+    }
+
+    if require_strong {
+        bail!(message);
+    }
+    log::warn!("{message}");
+
+    Ok(())
+}
+
+fn read_password_file(path: &Path, strict_perms: bool) -> color_eyre::Result<String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = fs::metadata(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?
+            .permissions()
+            .mode();
+
+        if mode & 0o004 != 0 {
+            if strict_perms {
+                bail!(
+                    "Password file {} is readable by every user, restrict it with chmod o-r",
+                    path.display()
+                );
+            }
+            log::warn!("Password file {} is readable by every user", path.display());
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = strict_perms;
+
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+
+    let password = contents.lines().next().unwrap_or_default();
+    if password.is_empty() {
+        bail!("Password file {} starts with an empty line", path.display());
+    }
+
+    Ok(password.to_string())
+}
+
+pub fn run_password_command(command: &str) -> color_eyre::Result<String> {
+    let output = crate::shell_command(command)
+        .output()
+        .context("Failed to run password command")?;
+
+    if !output.status.success() {
+        bail!("Password command exited with {}", output.status);
+    }
+
+    let mut password =
+        String::from_utf8(output.stdout).context("Password command printed invalid UTF-8")?;
+
+    if password.ends_with('\n') {
+        password.pop();
+        if password.ends_with('\r') {
+            password.pop();
+        }
+    }
+
+    if password.is_empty() {
+        bail!("Password command printed nothing");
+    }
+
+    Ok(password)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weak_password_is_refused_when_strong_required() {
+        assert!(check_strength("password1", &[], true).is_err());
+        assert!(check_strength("mydiary", &["mydiary"], true).is_err());
+        assert!(check_strength("password1", &[], false).is_ok());
+
+        assert!(check_strength("correct horse battery staple", &[], true).is_ok());
+    }
+
+    #[test]
+    fn password_file_gives_its_first_line() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("password");
+
+        fs::write(&path, "correct horse battery staple\r\nsecond line\n").unwrap();
+        assert_eq!(
+            read_password_file(&path, false).unwrap(),
+            "correct horse battery staple"
+        );
+        fs::write(&path, "\ncorrect horse battery staple\n").unwrap();
+        assert!(read_password_file(&path, false).is_err());
+        assert!(read_password_file(&temp.path().join("missing"), false).is_err());
+    }
+}
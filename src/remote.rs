@@ -0,0 +1,538 @@
+//! `diary backup remote`: targets that closed diary files are uploaded to, either S3-compatible
+//! object storage or a WebDAV server. Diary files are already encrypted, so neither has to be
+//! trusted with more than their size and when they changed
+
+use crate::{config, is_backup_of, password::run_password_command};
+use color_eyre::eyre::{Context, bail};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+};
+use time::{OffsetDateTime, macros::format_description};
+use ureq::{
+    Agent,
+    http::{Method, Request},
+};
+
+/// Where backups go and how to sign in there
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Target {
+    /// A bucket of an S3-compatible service, addressed by path so any endpoint works
+    S3 {
+        endpoint: String,
+        bucket: String,
+        region: String,
+        access_key: String,
+        /// Shell command printing the secret key, so it is never stored
+        secret_command: String,
+        /// Prepended to the name of every backup, such as `diaries/`
+        #[serde(default)]
+        prefix: String,
+    },
+    /// A WebDAV collection, such as a Nextcloud folder
+    Webdav {
+        url: String,
+        username: Option<String>,
+        /// Shell command printing the password, so it is never stored
+        password_command: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Remote {
+    #[serde(flatten)]
+    pub target: Target,
+    /// How many of the newest backups of each diary to keep, all of them if unset
+    pub keep: Option<usize>,
+    /// Whether `close` uploads the new diary file
+    pub on_close: bool,
+}
+
+/// The configured targets by name, kept in `remotes.json` in the configuration directory
+#[derive(Serialize, Deserialize, Default)]
+pub struct Remotes {
+    pub remotes: BTreeMap<String, Remote>,
+}
+
+impl Remotes {
+    pub fn load() -> color_eyre::Result<Self> {
+        let path = remotes_path()?;
+        match fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .with_context(|| format!("Failed to parse {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    pub fn save(&self) -> color_eyre::Result<()> {
+        let path = remotes_path()?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context("Failed to create configuration directory")?;
+        }
+        fs::write(&path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+fn remotes_path() -> color_eyre::Result<PathBuf> {
+    Ok(config::dir()?.join("remotes.json"))
+}
+
+impl Remote {
+    /// Upload `file` as `backup`, a backup of diary `name`, then remove all but the newest `keep`
+    /// backups of that diary, returning the names of the removed backups
+    pub fn upload(&self, file: &Path, name: &str, backup: &str) -> color_eyre::Result<Vec<String>> {
+        let agent = agent();
+        self.target.put(&agent, backup, file)?;
+
+        let Some(keep) = self.keep else {
+            return Ok(Vec::new());
+        };
+
+        let mut backups = self
+            .target
+            .list(&agent, &format!("{name}-"))?
+            .into_iter()
+            .filter(|b| is_backup_of(b, name))
+            .collect::<Vec<_>>();
+        backups.sort();
+
+        let old = backups
+            .drain(..backups.len().saturating_sub(keep))
+            .collect::<Vec<_>>();
+        for backup in &old {
+            self.target.delete(&agent, backup)?;
+        }
+        Ok(old)
+    }
+
+    /// Where backups go, for listing the targets
+    pub fn location(&self) -> String {
+        match &self.target {
+            Target::S3 {
+                endpoint,
+                bucket,
+                prefix,
+                ..
+            } => format!("s3 {}/{bucket}/{prefix}", endpoint.trim_end_matches('/')),
+            Target::Webdav { url, .. } => format!("webdav {url}"),
+        }
+    }
+}
+
+fn agent() -> Agent {
+    Agent::config_builder()
+        .allow_non_standard_methods(true)
+        .build()
+        .into()
+}
+
+impl Target {
+    fn put(&self, agent: &Agent, backup: &str, file: &Path) -> color_eyre::Result<()> {
+        let open = || File::open(file).context("Failed to open diary file");
+        let digest = crate::cipher::digest(open()?).context("Failed to read diary file")?;
+
+        let request = self
+            .request(Method::PUT, backup, &[], &hex(&digest))?
+            .body(open()?)?;
+        agent
+            .run(request)
+            .with_context(|| format!("Failed to upload {backup}"))?;
+        Ok(())
+    }
+
+    /// Names of the backups starting with `start`
+    fn list(&self, agent: &Agent, start: &str) -> color_eyre::Result<Vec<String>> {
+        match self {
+            Target::S3 { prefix, .. } => {
+                let start = format!("{prefix}{start}");
+                let mut names = Vec::new();
+                let mut token = None;
+
+                // Listings come in pages of up to 1000 keys
+                loop {
+                    let mut query = vec![("list-type", "2".to_string()), ("prefix", start.clone())];
+                    if let Some(token) = token.take() {
+                        query.push(("continuation-token", token));
+                    }
+
+                    let request = self
+                        .request(Method::GET, "", &query, &hex(&Sha256::digest(b"")))?
+                        .body(())?;
+                    let body = read_response(agent, request)?;
+
+                    names.extend(
+                        xml_values(&body, "Key")
+                            .into_iter()
+                            .filter_map(|key| key.strip_prefix(prefix.as_str()).map(String::from)),
+                    );
+
+                    match xml_values(&body, "NextContinuationToken").pop() {
+                        Some(next) if xml_values(&body, "IsTruncated") == ["true"] => {
+                            token = Some(next)
+                        }
+                        _ => break,
+                    }
+                }
+                Ok(names)
+            }
+            Target::Webdav { .. } => {
+                let request = self
+                    .request(Method::from_bytes(b"PROPFIND")?, "", &[], "")?
+                    .header("Depth", "1")
+                    .body(())?;
+                let body = read_response(agent, request)?;
+
+                Ok(xml_values(&body, "href")
+                    .into_iter()
+                    .filter_map(|href| {
+                        let name = href.trim_end_matches('/').rsplit('/').next()?;
+                        Some(decode(name))
+                    })
+                    .filter(|name| name.starts_with(start))
+                    .collect())
+            }
+        }
+    }
+
+    fn delete(&self, agent: &Agent, backup: &str) -> color_eyre::Result<()> {
+        let request = self
+            .request(Method::DELETE, backup, &[], &hex(&Sha256::digest(b"")))?
+            .body(())?;
+        agent
+            .run(request)
+            .with_context(|| format!("Failed to remove old backup {backup}"))?;
+        Ok(())
+    }
+
+    /// A request for `backup`, or for the bucket or collection itself if it is empty, signed or
+    /// authenticated for the target. `payload_hash` is the hex SHA-256 of the body S3 is sent
+    fn request(
+        &self,
+        method: Method,
+        backup: &str,
+        query: &[(&str, String)],
+        payload_hash: &str,
+    ) -> color_eyre::Result<ureq::http::request::Builder> {
+        match self {
+            Target::S3 {
+                endpoint,
+                bucket,
+                region,
+                access_key,
+                secret_command,
+                prefix,
+            } => {
+                let Some((scheme, host)) = endpoint.trim_end_matches('/').split_once("://") else {
+                    bail!("S3 endpoint {endpoint} needs to start with http:// or https://");
+                };
+
+                let path = if backup.is_empty() {
+                    format!("/{}", encode(bucket, true))
+                } else {
+                    format!(
+                        "/{}/{}",
+                        encode(bucket, true),
+                        encode(&format!("{prefix}{backup}"), false)
+                    )
+                };
+
+                let mut query = query
+                    .iter()
+                    .map(|(k, v)| format!("{}={}", encode(k, true), encode(v, true)))
+                    .collect::<Vec<_>>();
+                query.sort();
+                let query = query.join("&");
+
+                let now = OffsetDateTime::now_utc();
+                let date = now.format(format_description!("[year][month][day]"))?;
+                let timestamp = now.format(format_description!(
+                    "[year][month][day]T[hour][minute][second]Z"
+                ))?;
+
+                let canonical =
+                    canonical_request(&method, &path, &query, host, &timestamp, payload_hash);
+                let scope = format!("{date}/{region}/s3/aws4_request");
+                let secret = run_password_command(secret_command)?;
+                let signature = signature(&secret, &scope, &timestamp, &canonical);
+
+                let uri = if query.is_empty() {
+                    format!("{scheme}://{host}{path}")
+                } else {
+                    format!("{scheme}://{host}{path}?{query}")
+                };
+                Ok(Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header("x-amz-date", timestamp)
+                    .header("x-amz-content-sha256", payload_hash)
+                    .header(
+                        "Authorization",
+                        format!(
+                            "AWS4-HMAC-SHA256 Credential={access_key}/{scope}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={signature}"
+                        ),
+                    ))
+            }
+            Target::Webdav {
+                url,
+                username,
+                password_command,
+            } => {
+                let uri = format!("{}/{}", url.trim_end_matches('/'), encode(backup, true));
+                let mut request = Request::builder().method(method).uri(uri);
+
+                if let Some(username) = username {
+                    let password = password_command
+                        .as_deref()
+                        .map(run_password_command)
+                        .transpose()?
+                        .unwrap_or_default();
+                    request = request.header(
+                        "Authorization",
+                        format!(
+                            "Basic {}",
+                            base64(format!("{username}:{password}").as_bytes())
+                        ),
+                    );
+                }
+                Ok(request)
+            }
+        }
+    }
+}
+
+/// Canonical form of an S3 request for AWS Signature Version 4, signing the host, date and
+/// payload hash headers
+fn canonical_request(
+    method: &Method,
+    path: &str,
+    query: &str,
+    host: &str,
+    timestamp: &str,
+    payload_hash: &str,
+) -> String {
+    format!(
+        "{method}\n{path}\n{query}\nhost:{host}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{timestamp}\n\nhost;x-amz-content-sha256;x-amz-date\n{payload_hash}"
+    )
+}
+
+/// AWS Signature Version 4 of `canonical` made at `timestamp` with `secret`, for the credential
+/// scope `scope` of the form `date/region/s3/aws4_request`
+fn signature(secret: &str, scope: &str, timestamp: &str, canonical: &str) -> String {
+    let to_sign = format!(
+        "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+        hex(&Sha256::digest(canonical.as_bytes()))
+    );
+
+    let mut key = format!("AWS4{secret}").into_bytes();
+    for part in scope.split('/') {
+        key = hmac(&key, part.as_bytes());
+    }
+    hex(&hmac(&key, to_sign.as_bytes()))
+}
+
+fn read_response(agent: &Agent, request: Request<()>) -> color_eyre::Result<String> {
+    let mut response = agent.run(request).context("Failed to list backups")?;
+    let mut body = String::new();
+    response
+        .body_mut()
+        .as_reader()
+        .read_to_string(&mut body)
+        .context("Failed to read backup listing")?;
+    Ok(body)
+}
+
+/// Text of every `tag` element in `xml`, whatever namespace prefix it has
+fn xml_values(xml: &str, tag: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let Some(end) = rest.find('>') else {
+            break;
+        };
+        let name = rest[..end].split_whitespace().next().unwrap_or_default();
+        let local = name.rsplit(':').next().unwrap_or(name);
+        let closing = name.starts_with('/');
+        rest = &rest[end + 1..];
+
+        if local == tag && !closing {
+            let value = rest.find('<').map_or(rest, |end| &rest[..end]);
+            values.push(
+                value
+                    .replace("&lt;", "<")
+                    .replace("&gt;", ">")
+                    .replace("&quot;", "\"")
+                    .replace("&apos;", "'")
+                    .replace("&amp;", "&"),
+            );
+        }
+    }
+    values
+}
+
+/// Percent-encode everything but unreserved characters, and `/` unless `slash` is set
+fn encode(s: &str, slash: bool) -> String {
+    let mut encoded = String::new();
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) || (b == b'/' && !slash) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{b:02X}"));
+        }
+    }
+    encoded
+}
+
+fn decode(s: &str) -> String {
+    let mut decoded = Vec::new();
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%'
+            && let Some(hex) = bytes.clone().take(2).collect::<Vec<_>>().get(..2)
+            && let Ok(b) = u8::from_str_radix(&String::from_utf8_lossy(hex), 16)
+        {
+            decoded.push(b);
+            bytes.nth(1);
+        } else {
+            decoded.push(b);
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMPTY_HASH: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    const SECRET: &str = "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY";
+    const SCOPE: &str = "20130524/us-east-1/s3/aws4_request";
+
+    /// The examples of signing S3 requests in the AWS Signature Version 4 documentation
+    #[test]
+    fn signs_aws_examples() {
+        let sign = |query| {
+            let canonical = canonical_request(
+                &Method::GET,
+                "/",
+                query,
+                "examplebucket.s3.amazonaws.com",
+                "20130524T000000Z",
+                EMPTY_HASH,
+            );
+            signature(SECRET, SCOPE, "20130524T000000Z", &canonical)
+        };
+
+        assert_eq!(
+            sign("lifecycle="),
+            "fea454ca298b7da1c68078a5d1bdbfbbe0d65c699e0f91ac7a200a0136783543"
+        );
+        assert_eq!(
+            sign("max-keys=2&prefix=J"),
+            "34b48302e7b5fa45bde8084f4b7868a86f0a534bc59db6670ed5711ef69dc6f7"
+        );
+    }
+
+    /// The test vectors of RFC 4648
+    #[test]
+    fn encodes_base64() {
+        for (plain, encoded) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(base64(plain.as_bytes()), encoded);
+        }
+    }
+
+    #[test]
+    fn percent_decodes() {
+        assert_eq!(
+            decode("my%20diary-20240101-000000.diary"),
+            "my diary-20240101-000000.diary"
+        );
+        assert_eq!(decode("caf%C3%A9"), "café");
+        assert_eq!(decode("100%"), "100%");
+        assert_eq!(decode("%zz%4"), "%zz%4");
+
+        let name = "a/b c&d~é";
+        assert_eq!(decode(&encode(name, true)), name);
+        assert_eq!(encode(name, false), "a/b%20c%26d~%C3%A9");
+    }
+
+    #[test]
+    fn scrapes_xml_values() {
+        let s3 = r#"<?xml version="1.0" encoding="UTF-8"?>
+<ListBucketResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+  <IsTruncated>true</IsTruncated>
+  <Contents><Key>x-20240101-000000.diary</Key></Contents>
+  <Contents><Key>tom &amp; jerry-20240101-000000.diary</Key></Contents>
+  <NextContinuationToken>1ueGcxLPRx1Tr/XYExHnhbYLgveDs2J/wm36Hy4vbOwM=</NextContinuationToken>
+</ListBucketResult>"#;
+        assert_eq!(
+            xml_values(s3, "Key"),
+            [
+                "x-20240101-000000.diary",
+                "tom & jerry-20240101-000000.diary"
+            ]
+        );
+        assert_eq!(xml_values(s3, "IsTruncated"), ["true"]);
+        assert_eq!(
+            xml_values(s3, "NextContinuationToken"),
+            ["1ueGcxLPRx1Tr/XYExHnhbYLgveDs2J/wm36Hy4vbOwM="]
+        );
+
+        let webdav = r#"<?xml version="1.0"?>
+<D:multistatus xmlns:D="DAV:">
+  <D:response><D:href>/dav/</D:href></D:response>
+  <D:response><D:href xmlns:D="DAV:">/dav/my%20diary-20240101-000000.diary</D:href></D:response>
+</D:multistatus>"#;
+        assert_eq!(
+            xml_values(webdav, "href"),
+            ["/dav/", "/dav/my%20diary-20240101-000000.diary"]
+        );
+        assert!(xml_values(webdav, "Key").is_empty());
+    }
+}
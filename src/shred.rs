@@ -0,0 +1,85 @@
+use rand::Rng;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+/// Overwrite the contents of the file at `path` with random bytes and sync them to disk before
+/// unlinking it. This is best-effort: SSDs and copy-on-write filesystems may keep the old blocks
+pub fn remove_file(path: &Path) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    let mut remaining = file.metadata()?.len();
+
+    let mut noise = vec![0u8; 64 * 1024];
+    while remaining > 0 {
+        let n = remaining.min(noise.len() as u64) as usize;
+        rand::rng().fill_bytes(&mut noise[..n]);
+        file.write_all(&noise[..n])?;
+        remaining -= n as u64;
+    }
+
+    file.sync_all()?;
+    drop(file);
+
+    fs::remove_file(path)
+}
+
+/// Overwrite every regular file below `path` like `remove_file`, then remove the directory
+pub fn remove_dir_all(path: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            remove_dir_all(&entry.path())?;
+        } else if file_type.is_file() {
+            remove_file(&entry.path())?;
+        }
+    }
+
+    fs::remove_dir_all(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removed_file_is_overwritten_first() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("archive.tar");
+        let secret = b"dear diary ".repeat(10_000);
+        fs::write(&path, &secret).unwrap();
+        // Still reaches the same blocks once the file is unlinked
+        let link = temp.path().join("link");
+        fs::hard_link(&path, &link).unwrap();
+
+        remove_file(&path).unwrap();
+        assert!(!path.exists());
+        let left = fs::read(&link).unwrap();
+        assert_eq!(left.len(), secret.len());
+        assert_ne!(left, secret);
+        assert!(remove_file(&path).is_err());
+    }
+
+    #[test]
+    fn removed_dir_is_overwritten_but_not_what_it_links_to() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path().join("diary");
+        fs::create_dir_all(dir.join("entries")).unwrap();
+        fs::write(dir.join("diary.json"), "{}").unwrap();
+        fs::write(dir.join("entries").join("a.md"), "dear diary").unwrap();
+        let link = temp.path().join("a.md");
+        fs::hard_link(dir.join("entries").join("a.md"), &link).unwrap();
+        let outside = temp.path().join("outside.md");
+        fs::write(&outside, "not in the diary").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&outside, dir.join("outside.md")).unwrap();
+
+        remove_dir_all(&dir).unwrap();
+        assert!(!dir.exists());
+        assert_ne!(fs::read(&link).unwrap(), b"dear diary");
+        assert_eq!(fs::read(&outside).unwrap(), b"not in the diary");
+    }
+}
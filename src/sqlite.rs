@@ -0,0 +1,283 @@
+use crate::{
+    cipher::Algorithm,
+    entries::{Entries, Entry, MetadataFormat},
+    error::{Context, DiaryError},
+};
+use rusqlite::{Connection, OptionalExtension, params};
+use std::{collections::HashMap, path::PathBuf};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS diary (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        key TEXT NOT NULL,
+        cipher TEXT NOT NULL,
+        entries_dir TEXT,
+        comment TEXT,
+        entry_lock TEXT
+    );
+    CREATE TABLE IF NOT EXISTS entries (
+        name TEXT NOT NULL,
+        trashed INTEGER NOT NULL,
+        id TEXT NOT NULL,
+        path TEXT NOT NULL,
+        timestamp TEXT NOT NULL,
+        location TEXT,
+        description TEXT,
+        encrypted INTEGER NOT NULL,
+        pinned INTEGER NOT NULL,
+        archived INTEGER NOT NULL,
+        links TEXT NOT NULL,
+        modified TEXT,
+        mood INTEGER,
+        PRIMARY KEY (trashed, name)
+    );
+    CREATE INDEX IF NOT EXISTS entries_timestamp ON entries (timestamp);
+    CREATE TABLE IF NOT EXISTS tags (
+        trashed INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        tag TEXT NOT NULL,
+        position INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS tags_tag ON tags (tag);
+    CREATE TABLE IF NOT EXISTS attachments (
+        trashed INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        path TEXT NOT NULL,
+        position INTEGER NOT NULL
+    );
+";
+
+/// Read the entries of the database `conn`, as `save` wrote them
+pub fn load(conn: &Connection) -> Result<Entries, DiaryError> {
+    // Databases written before entries had their own password don't have the column
+    let entry_lock = if has_column(conn, "diary", "entry_lock")? {
+        "entry_lock"
+    } else {
+        "NULL"
+    };
+    let (key, cipher, entries_dir, comment, entry_lock) = conn
+        .query_row(
+            &format!("SELECT key, cipher, entries_dir, comment, {entry_lock} FROM diary"),
+            [],
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                ))
+            },
+        )
+        .optional()?
+        .ok_or_else(|| DiaryError::Corrupt("diary database is empty".to_string()))?;
+
+    let mut tags = HashMap::<(bool, String), Vec<String>>::new();
+    let mut statement = conn.prepare("SELECT trashed, name, tag FROM tags ORDER BY position")?;
+    for row in statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))? {
+        let (trashed, name, tag): (bool, String, String) = row?;
+        tags.entry((trashed, name)).or_default().push(tag);
+    }
+
+    // Databases written before attachments existed don't have the table
+    let mut attachments = HashMap::<(bool, String), Vec<PathBuf>>::new();
+    let has_attachments = conn
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'attachments'",
+            [],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    if has_attachments {
+        let mut statement =
+            conn.prepare("SELECT trashed, name, path FROM attachments ORDER BY position")?;
+        for row in statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))? {
+            let (trashed, name, path): (bool, String, String) = row?;
+            attachments
+                .entry((trashed, name))
+                .or_default()
+                .push(PathBuf::from(path));
+        }
+    }
+
+    let mut entries = Entries {
+        entries: HashMap::new(),
+        key,
+        trashed: HashMap::new(),
+        cipher: serde_json::from_value(serde_json::Value::String(cipher))
+            .context("Diary database names an unknown cipher")?,
+        entries_dir: entries_dir.map(PathBuf::from),
+        comment,
+        entry_lock: entry_lock
+            .map(|lock| serde_json::from_str(&lock))
+            .transpose()
+            .context("Invalid entry lock")?,
+        format: MetadataFormat::Sqlite,
+    };
+
+    // Databases written before moods existed don't have the column
+    let mood = if has_column(conn, "entries", "mood")? {
+        "mood"
+    } else {
+        "NULL"
+    };
+    let mut statement = conn.prepare(&format!(
+        "SELECT name, trashed, id, path, timestamp, location, description, encrypted, pinned,
+                archived, links, modified, {mood} FROM entries",
+    ))?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let name: String = row.get(0)?;
+        let trashed: bool = row.get(1)?;
+
+        let entry = Entry {
+            id: Uuid::parse_str(&row.get::<_, String>(2)?)
+                .map_err(|e| DiaryError::Corrupt(format!("invalid entry id: {e}")))?,
+            path: PathBuf::from(row.get::<_, String>(3)?),
+            timestamp: parse_time(&row.get::<_, String>(4)?)?,
+            location: row.get(5)?,
+            description: row.get(6)?,
+            tags: tags.remove(&(trashed, name.clone())).unwrap_or_default(),
+            encrypted: row.get(7)?,
+            pinned: row.get(8)?,
+            archived: row.get(9)?,
+            links: serde_json::from_str(&row.get::<_, String>(10)?)
+                .context("Invalid entry links")?,
+            modified: row
+                .get::<_, Option<String>>(11)?
+                .map(|m| parse_time(&m))
+                .transpose()?,
+            attachments: attachments
+                .remove(&(trashed, name.clone()))
+                .unwrap_or_default(),
+            mood: row.get(12)?,
+        };
+
+        if trashed {
+            entries.trashed.insert(name, entry);
+        } else {
+            entries.entries.insert(name, entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Replace everything in the database `conn` with `entries` in one transaction
+pub fn save(entries: &Entries, conn: &mut Connection) -> Result<(), DiaryError> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(SCHEMA)?;
+    if !has_column(&tx, "entries", "mood")? {
+        tx.execute_batch("ALTER TABLE entries ADD COLUMN mood INTEGER")?;
+    }
+    if !has_column(&tx, "diary", "entry_lock")? {
+        tx.execute_batch("ALTER TABLE diary ADD COLUMN entry_lock TEXT")?;
+    }
+    tx.execute_batch(
+        "DELETE FROM diary; DELETE FROM entries; DELETE FROM tags; DELETE FROM attachments;",
+    )?;
+
+    tx.execute(
+        "INSERT INTO diary (id, key, cipher, entries_dir, comment, entry_lock)
+            VALUES (0, ?1, ?2, ?3, ?4, ?5)",
+        params![
+            entries.key,
+            cipher_name(entries.cipher)?,
+            entries
+                .entries_dir
+                .as_ref()
+                .map(|d| d.to_string_lossy().into_owned()),
+            entries.comment,
+            entries
+                .entry_lock
+                .as_ref()
+                .map(serde_json::to_string)
+                .transpose()
+                .context("Failed to save entry lock")?,
+        ],
+    )?;
+
+    {
+        let mut insert_entry = tx.prepare(
+            "INSERT INTO entries (name, trashed, id, path, timestamp, location, description,
+                encrypted, pinned, archived, links, modified, mood)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+        )?;
+        let mut insert_tag =
+            tx.prepare("INSERT INTO tags (trashed, name, tag, position) VALUES (?1, ?2, ?3, ?4)")?;
+        let mut insert_attachment = tx.prepare(
+            "INSERT INTO attachments (trashed, name, path, position) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+
+        let all = entries
+            .entries
+            .iter()
+            .map(|e| (false, e))
+            .chain(entries.trashed.iter().map(|e| (true, e)));
+        for (trashed, (name, entry)) in all {
+            insert_entry.execute(params![
+                name,
+                trashed,
+                entry.id.to_string(),
+                entry.path.to_string_lossy(),
+                format_time(entry.timestamp)?,
+                entry.location,
+                entry.description,
+                entry.encrypted,
+                entry.pinned,
+                entry.archived,
+                serde_json::to_string(&entry.links).context("Failed to save entry links")?,
+                entry.modified.map(format_time).transpose()?,
+                entry.mood,
+            ])?;
+
+            for (position, tag) in entry.tags.iter().enumerate() {
+                insert_tag.execute(params![trashed, name, tag, position as i64])?;
+            }
+
+            for (position, path) in entry.attachments.iter().enumerate() {
+                insert_attachment.execute(params![
+                    trashed,
+                    name,
+                    path.to_string_lossy(),
+                    position as i64
+                ])?;
+            }
+        }
+    }
+
+    Ok(tx.commit()?)
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool, DiaryError> {
+    Ok(conn
+        .query_row(
+            "SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2",
+            params![table, column],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some())
+}
+
+/// Timestamps are stored as RFC 3339 in UTC, so they sort as text
+fn format_time(time: OffsetDateTime) -> Result<String, DiaryError> {
+    time.to_offset(time::UtcOffset::UTC)
+        .format(&time::format_description::well_known::Rfc3339)
+        .map_err(|e| DiaryError::Corrupt(format!("timestamp {time} can't be stored: {e}")))
+}
+
+fn parse_time(text: &str) -> Result<OffsetDateTime, DiaryError> {
+    OffsetDateTime::parse(text, &time::format_description::well_known::Rfc3339)
+        .map_err(|e| DiaryError::Corrupt(format!("invalid timestamp {text}: {e}")))
+}
+
+fn cipher_name(cipher: Algorithm) -> Result<String, DiaryError> {
+    match serde_json::to_value(cipher).context("Failed to save cipher")? {
+        serde_json::Value::String(name) => Ok(name),
+        _ => unreachable!("ciphers serialize as strings"),
+    }
+}
@@ -0,0 +1,381 @@
+//! Counts over the entries of a diary, as printed by `info`, `report`, `streak` and `stats`
+
+use crate::{
+    cipher::EntryKey,
+    entries::Entries,
+    error::{Context, DiaryError},
+};
+use serde::Serialize;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+};
+use time::{Date, OffsetDateTime, UtcOffset};
+
+/// Shift a stored timestamp into local time for display, or UTC if `utc` is set
+pub fn display_time(timestamp: OffsetDateTime, utc: bool) -> OffsetDateTime {
+    let offset = if utc {
+        UtcOffset::UTC
+    } else {
+        UtcOffset::local_offset_at(timestamp).unwrap_or(UtcOffset::UTC)
+    };
+    timestamp.to_offset(offset)
+}
+
+/// Current and longest runs of consecutive days in `days`, where the current run ends today, or
+/// yesterday if nothing has been written yet today
+pub fn streaks(days: &BTreeSet<Date>, today: Date) -> (usize, usize) {
+    let mut longest = 0;
+    let mut run = 0;
+    let mut previous: Option<Date> = None;
+    for &day in days {
+        run = match previous {
+            Some(p) if p.next_day() == Some(day) => run + 1,
+            _ => 1,
+        };
+        longest = longest.max(run);
+        previous = Some(day);
+    }
+
+    let mut current = 0;
+    let mut day = if days.contains(&today) {
+        Some(today)
+    } else {
+        today.previous_day()
+    };
+    while let Some(d) = day.filter(|d| days.contains(d)) {
+        current += 1;
+        day = d.previous_day();
+    }
+
+    (current, longest)
+}
+
+/// Counts over the entries of a diary, as printed by `info`
+#[derive(Serialize)]
+pub struct Summary {
+    pub entries: usize,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub earliest: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub latest: Option<OffsetDateTime>,
+    pub total_bytes: u64,
+    pub with_location: usize,
+    pub with_description: usize,
+    pub with_tags: usize,
+}
+
+impl Summary {
+    pub(crate) fn new(entries: &Entries, dir: &Path) -> Result<Self, DiaryError> {
+        let mut total_bytes = 0;
+        for entry in entries.entries.values() {
+            total_bytes += fs::metadata(dir.join(&entry.path))
+                .with_context(|| format!("Failed to read entry file {}", entry.path.display()))?
+                .len();
+        }
+
+        let timestamps = entries.entries.values().map(|e| e.timestamp);
+
+        Ok(Self {
+            entries: entries.entries.len(),
+            earliest: timestamps.clone().min(),
+            latest: timestamps.max(),
+            total_bytes,
+            with_location: entries
+                .entries
+                .values()
+                .filter(|e| e.location.is_some())
+                .count(),
+            with_description: entries
+                .entries
+                .values()
+                .filter(|e| e.description.is_some())
+                .count(),
+            with_tags: entries
+                .entries
+                .values()
+                .filter(|e| !e.tags.is_empty())
+                .count(),
+        })
+    }
+}
+
+/// Entries and words per month of a diary, as printed by `report`
+#[derive(Serialize)]
+pub struct Report {
+    pub months: Vec<MonthReport>,
+    pub longest: Option<EntryWords>,
+    pub shortest: Option<EntryWords>,
+}
+
+#[derive(Serialize)]
+pub struct MonthReport {
+    /// Year and month, such as 2026-10
+    pub month: String,
+    pub entries: usize,
+    pub words: usize,
+}
+
+#[derive(Serialize, Clone)]
+pub struct EntryWords {
+    pub name: String,
+    pub words: usize,
+}
+
+impl Report {
+    /// Group the entries of the diary in `dir` by the month of their timestamp, in UTC if `utc`
+    /// is set, reading encrypted ones with `key`. Entries whose file is missing are left out with
+    /// a warning
+    pub(crate) fn new(
+        entries: &Entries,
+        dir: &Path,
+        key: Option<&EntryKey>,
+        utc: bool,
+    ) -> Result<Self, DiaryError> {
+        let mut months = BTreeMap::<String, MonthReport>::new();
+        let mut counted = Vec::new();
+
+        let mut names = entries.entries.keys().collect::<Vec<_>>();
+        names.sort();
+
+        for name in names {
+            let entry = &entries.entries[name];
+
+            if !dir.join(&entry.path).exists() {
+                log::warn!(
+                    "Skipping entry {name}, its file {} is missing",
+                    entry.path.display()
+                );
+                continue;
+            }
+
+            let content = entry.read_content(dir, key)?;
+            let words = String::from_utf8_lossy(&content).split_whitespace().count();
+
+            let month = month_of(entry.timestamp, utc);
+            let report = months.entry(month.clone()).or_insert(MonthReport {
+                month,
+                entries: 0,
+                words: 0,
+            });
+            report.entries += 1;
+            report.words += words;
+
+            counted.push(EntryWords {
+                name: name.clone(),
+                words,
+            });
+        }
+
+        Ok(Self {
+            months: months.into_values().collect(),
+            longest: counted.iter().max_by_key(|e| e.words).cloned(),
+            shortest: counted.iter().min_by_key(|e| e.words).cloned(),
+        })
+    }
+}
+
+/// Writing statistics of a diary, as printed by `stats`
+#[derive(Serialize)]
+pub struct Stats {
+    pub entries: usize,
+    pub words: usize,
+    pub words_per_entry: f64,
+    /// Averages over the months from the first entry to the last, counting months without any
+    pub entries_per_month: f64,
+    pub words_per_month: f64,
+    pub current_streak: usize,
+    pub longest_streak: usize,
+    /// Most used tags and how many entries have them, most used first
+    pub top_tags: Vec<(String, usize)>,
+    pub months: Vec<MonthReport>,
+    pub mood: MoodStats,
+}
+
+impl Stats {
+    /// Count the entries of the diary in `dir` by reading their files, as `Report` does, keeping
+    /// the `top_tags` most used tags
+    pub(crate) fn new(
+        entries: &Entries,
+        dir: &Path,
+        key: Option<&EntryKey>,
+        top_tags: usize,
+        utc: bool,
+    ) -> Result<Self, DiaryError> {
+        let report = Report::new(entries, dir, key, utc)?;
+
+        let counted = report.months.iter().map(|m| m.entries).sum::<usize>();
+        let words = report.months.iter().map(|m| m.words).sum::<usize>();
+
+        let dates = entries
+            .entries
+            .values()
+            .map(|e| display_time(e.timestamp, utc).date())
+            .collect::<BTreeSet<_>>();
+        let today = display_time(OffsetDateTime::now_utc(), utc).date();
+        let (current_streak, longest_streak) = streaks(&dates, today);
+
+        let span = match (dates.first(), dates.last()) {
+            (Some(first), Some(last)) => {
+                let months =
+                    (last.year() - first.year()) * 12 + last.month() as i32 - first.month() as i32;
+                months as usize + 1
+            }
+            _ => 0,
+        };
+
+        let mut tags = BTreeMap::<&str, usize>::new();
+        for tag in entries.entries.values().flat_map(|e| &e.tags) {
+            *tags.entry(tag).or_default() += 1;
+        }
+        let mut tags = tags.into_iter().collect::<Vec<_>>();
+        // Stable, so tags used equally often stay in alphabetical order
+        tags.sort_by_key(|(_, count)| Reverse(*count));
+
+        Ok(Self {
+            entries: entries.entries.len(),
+            words,
+            words_per_entry: average(words, counted),
+            entries_per_month: average(entries.entries.len(), span),
+            words_per_month: average(words, span),
+            current_streak,
+            longest_streak,
+            top_tags: tags
+                .into_iter()
+                .take(top_tags)
+                .map(|(tag, count)| (tag.to_string(), count))
+                .collect(),
+            months: report.months,
+            mood: MoodStats::new(entries, utc),
+        })
+    }
+}
+
+/// Moods of the rated entries of a diary, as printed by `stats --mood`
+#[derive(Serialize)]
+pub struct MoodStats {
+    pub rated: usize,
+    pub average: Option<f64>,
+    /// Months with rated entries, oldest first
+    pub months: Vec<MonthMood>,
+}
+
+#[derive(Serialize)]
+pub struct MonthMood {
+    /// Year and month, such as 2026-10
+    pub month: String,
+    pub rated: usize,
+    pub average: f64,
+}
+
+impl MoodStats {
+    /// Average the moods of the entries by the month of their timestamp, in UTC if `utc` is set
+    pub(crate) fn new(entries: &Entries, utc: bool) -> Self {
+        let mut months = BTreeMap::<String, (usize, i64)>::new();
+        for entry in entries.entries.values() {
+            let Some(mood) = entry.mood else {
+                continue;
+            };
+
+            let month = month_of(entry.timestamp, utc);
+            let (rated, total) = months.entry(month).or_default();
+            *rated += 1;
+            *total += i64::from(mood);
+        }
+
+        let rated = months.values().map(|(rated, _)| rated).sum::<usize>();
+        let total = months.values().map(|(_, total)| total).sum::<i64>();
+
+        Self {
+            rated,
+            average: (rated > 0).then(|| total as f64 / rated as f64),
+            months: months
+                .into_iter()
+                .map(|(month, (rated, total))| MonthMood {
+                    month,
+                    rated,
+                    average: total as f64 / rated as f64,
+                })
+                .collect(),
+        }
+    }
+}
+
+fn average(total: usize, count: usize) -> f64 {
+    if count == 0 {
+        0.0
+    } else {
+        total as f64 / count as f64
+    }
+}
+
+/// Year and month of `timestamp` as it is displayed, such as 2026-10
+fn month_of(timestamp: OffsetDateTime, utc: bool) -> String {
+    let time = display_time(timestamp, utc);
+    format!("{:04}-{:02}", time.year(), u8::from(time.month()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entries::Entry;
+    use std::path::PathBuf;
+    use time::macros::{date, datetime};
+    use uuid::Uuid;
+
+    #[test]
+    fn streaks_count_consecutive_days() {
+        let days = [
+            date!(2026 - 09 - 28),
+            date!(2026 - 09 - 29),
+            date!(2026 - 09 - 30),
+            date!(2026 - 10 - 01),
+            date!(2026 - 10 - 05),
+            date!(2026 - 10 - 06),
+        ]
+        .into_iter()
+        .collect::<BTreeSet<_>>();
+
+        assert_eq!(streaks(&days, date!(2026 - 10 - 06)), (2, 4));
+        // Nothing written yet today doesn't break the run
+        assert_eq!(streaks(&days, date!(2026 - 10 - 07)), (2, 4));
+        assert_eq!(streaks(&days, date!(2026 - 10 - 08)), (0, 4));
+        assert_eq!(streaks(&BTreeSet::new(), date!(2026 - 10 - 08)), (0, 0));
+    }
+
+    #[test]
+    fn report_counts_entries_and_words_per_month() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut entries: Entries =
+            serde_json::from_str(r#"{"entries": {}, "key": "password"}"#).unwrap();
+        for (name, timestamp, content) in [
+            ("a", datetime!(2026-09-30 23:30 UTC), Some("one two three")),
+            ("b", datetime!(2026-10-01 00:30 UTC), Some("one")),
+            (
+                "c",
+                datetime!(2026-10-15 12:00 -02:00),
+                Some("one two\nthree four"),
+            ),
+            ("missing", datetime!(2026-10-16 12:00 UTC), None),
+        ] {
+            let path = PathBuf::from(format!("{name}.md"));
+            if let Some(content) = content {
+                fs::write(temp.path().join(&path), content).unwrap();
+            }
+            let entry = Entry::new(Uuid::new_v4(), path, timestamp);
+            entries.entries.insert(name.to_string(), entry);
+        }
+
+        let report = Report::new(&entries, temp.path(), None, true).unwrap();
+        let months = report
+            .months
+            .iter()
+            .map(|m| (m.month.as_str(), m.entries, m.words))
+            .collect::<Vec<_>>();
+        assert_eq!(months, [("2026-09", 1, 3), ("2026-10", 2, 5)]);
+        assert_eq!(report.longest.unwrap().name, "c");
+        assert_eq!(report.shortest.unwrap().name, "b");
+    }
+}
@@ -0,0 +1,157 @@
+//! `diary sync git`: keeping the encrypted diary files of a directory in a git repository, so
+//! any git host can hold a versioned copy without ever seeing an entry
+
+use color_eyre::eyre::{Context, bail};
+use std::{
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    process::{self, Stdio},
+};
+
+/// Everything but diary files is ignored, so open diaries and their archives are never committed
+const GITIGNORE: &str = "*\n!*.diary\n!.gitignore\n";
+
+/// Make `dir` a repository for its diary files, tracking `remote` as origin if given
+pub fn init(dir: &Path, remote: Option<&str>) -> color_eyre::Result<()> {
+    if !dir.join(".git").exists() {
+        git(dir, &["init", "--quiet"])?;
+    }
+
+    let gitignore = dir.join(".gitignore");
+    if !gitignore.exists() {
+        fs::write(&gitignore, GITIGNORE).context("Failed to write .gitignore")?;
+    }
+    git(dir, &["config", "diary.sync", "true"])?;
+
+    if let Some(remote) = remote {
+        let command = if has_remote(dir) { "set-url" } else { "add" };
+        git(dir, &["remote", command, "origin", remote])?;
+    }
+
+    let mut files = diary_files(dir)?;
+    files.push(gitignore);
+    commit(dir, &files, "Track diary files")?;
+    Ok(())
+}
+
+/// Whether `dir` was set up with `init`
+pub fn enabled(dir: &Path) -> bool {
+    process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["config", "--bool", "diary.sync"])
+        .stderr(Stdio::null())
+        .output()
+        .is_ok_and(|output| output.stdout.trim_ascii() == b"true")
+}
+
+/// Whether `dir` has a remote to push to and pull from
+pub fn has_remote(dir: &Path) -> bool {
+    process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .arg("remote")
+        .output()
+        .is_ok_and(|output| !output.stdout.trim_ascii().is_empty())
+}
+
+/// Commit the current contents of `files`, returning whether there was anything to commit
+pub fn commit(dir: &Path, files: &[PathBuf], message: &str) -> color_eyre::Result<bool> {
+    if files.is_empty() {
+        return Ok(false);
+    }
+
+    let mut add = vec![OsStr::new("add"), OsStr::new("--")];
+    add.extend(
+        files
+            .iter()
+            .map(|f| f.strip_prefix(dir).unwrap_or(f).as_os_str()),
+    );
+    git(dir, &add)?;
+
+    // Exits with 1 when something is staged
+    let staged = process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["diff", "--cached", "--quiet"])
+        .status()
+        .context("Failed to run git")?;
+    if staged.success() {
+        return Ok(false);
+    }
+
+    git(dir, &["commit", "--quiet", "--message", message])?;
+    Ok(true)
+}
+
+/// Commit every diary file in `dir` and push them. Diary files that are missing because their
+/// diary is open are left alone rather than committed as removed
+pub fn push(dir: &Path) -> color_eyre::Result<()> {
+    commit(dir, &diary_files(dir)?, "Update diary files")?;
+
+    if !has_remote(dir) {
+        bail!("Repository has no remote, add one with sync git init --remote <url>");
+    }
+    git(
+        dir,
+        &["push", "--quiet", "--set-upstream", "origin", "HEAD"],
+    )
+}
+
+/// Whether the current branch tracks a remote branch to pull from, which it does once pushed or
+/// when the repository was cloned
+pub fn has_upstream(dir: &Path) -> bool {
+    process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["rev-parse", "--quiet", "--verify", "@{upstream}"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Fetch the remote's diary files and rebase any local commits onto them
+pub fn pull(dir: &Path) -> color_eyre::Result<()> {
+    if !has_upstream(dir) {
+        bail!("Nothing to pull from, push first or clone the repository with the diary files");
+    }
+
+    let pulled = git(dir, &["pull", "--quiet", "--rebase", "--autostash"]);
+    let git_dir = dir.join(".git");
+    if pulled.is_err()
+        && (git_dir.join("rebase-merge").exists() || git_dir.join("rebase-apply").exists())
+    {
+        bail!(
+            "Both copies changed the same diary file, keep one with git checkout --ours or --theirs and run git rebase --continue"
+        );
+    }
+    pulled
+}
+
+fn diary_files(dir: &Path) -> color_eyre::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).context("Failed to read directory")? {
+        let path = entry.context("Failed to read directory")?.path();
+        if path.is_file() && path.extension().is_some_and(|e| e == "diary") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn git(dir: &Path, args: &[impl AsRef<OsStr>]) -> color_eyre::Result<()> {
+    let status = process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .status()
+        .context("Failed to run git")?;
+
+    if !status.success() {
+        bail!("git exited with {status}");
+    }
+    Ok(())
+}
@@ -0,0 +1,325 @@
+//! `diary tui`: a full screen browser for the entries of the open diary in the current directory
+
+use crate::{
+    Diary, cli::parse_entry_name, display_time, edit_and_save, format_time, matches_query,
+    unlock_to_read,
+};
+use color_eyre::eyre::Context;
+use ratatui::{
+    DefaultTerminal, Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Style, Stylize},
+    text::{Line, Text},
+    widgets::{Block, List, ListItem, ListState, Paragraph, Wrap},
+};
+use std::path::Path;
+
+/// What the keys typed go to
+enum Mode {
+    Browse,
+    /// Typing a query that filters the entries as it changes
+    Search,
+    /// Typing the name of a new entry
+    Add(String),
+    /// Asking before moving the selected entry to the trash
+    ConfirmDelete,
+}
+
+struct App {
+    /// The open diary, with its encrypted entries unlocked once before the interface starts
+    diary: Diary,
+    /// Names of the entries shown, newest first
+    names: Vec<String>,
+    list: ListState,
+    query: String,
+    mode: Mode,
+    preview: Text<'static>,
+    scroll: u16,
+    /// Result of the last action, shown in the footer until the next key
+    status: Option<String>,
+    editor: Option<String>,
+    utc: bool,
+}
+
+/// Run the interface until it is quit, editing entries with `editor` or $VISUAL/$EDITOR
+pub fn run(editor: Option<String>, utc: bool) -> color_eyre::Result<()> {
+    let mut diary = Diary::load(".")?;
+    if diary.entries().entries.values().any(|e| e.encrypted) {
+        unlock_to_read(&mut diary)?;
+    }
+    let mut app = App {
+        diary,
+        names: Vec::new(),
+        list: ListState::default(),
+        query: String::new(),
+        mode: Mode::Browse,
+        preview: Text::default(),
+        scroll: 0,
+        status: None,
+        editor,
+        utc,
+    };
+    app.refresh(None);
+
+    let mut terminal = ratatui::init();
+    let result = app.run(&mut terminal);
+    ratatui::restore();
+    result
+}
+
+impl App {
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> color_eyre::Result<()> {
+        loop {
+            terminal
+                .draw(|frame| self.draw(frame))
+                .context("Failed to draw")?;
+
+            let Event::Key(key) = event::read().context("Failed to read key")? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            self.status = None;
+
+            match &mut self.mode {
+                Mode::Browse => match key.code {
+                    KeyCode::Char('q') => return Ok(()),
+                    KeyCode::Esc if self.query.is_empty() => return Ok(()),
+                    KeyCode::Esc => {
+                        self.query.clear();
+                        self.refresh(self.selected());
+                    }
+                    KeyCode::Down | KeyCode::Char('j') => {
+                        self.select(self.list.selected().map_or(0, |i| i + 1))
+                    }
+                    KeyCode::Up | KeyCode::Char('k') => {
+                        self.select(self.list.selected().map_or(0, |i| i.saturating_sub(1)))
+                    }
+                    KeyCode::Home | KeyCode::Char('g') => self.select(0),
+                    KeyCode::End | KeyCode::Char('G') => {
+                        self.select(self.names.len().saturating_sub(1))
+                    }
+                    KeyCode::PageDown => self.scroll = self.scroll.saturating_add(10),
+                    KeyCode::PageUp => self.scroll = self.scroll.saturating_sub(10),
+                    KeyCode::Char('/') => self.mode = Mode::Search,
+                    KeyCode::Char('a') => self.mode = Mode::Add(String::new()),
+                    KeyCode::Char('e') | KeyCode::Enter => {
+                        if let Some(name) = self.selected() {
+                            self.edit(terminal, name);
+                        }
+                    }
+                    KeyCode::Char('d') if self.selected().is_some() => {
+                        self.mode = Mode::ConfirmDelete
+                    }
+                    _ => {}
+                },
+                Mode::Search => {
+                    match key.code {
+                        KeyCode::Enter => self.mode = Mode::Browse,
+                        KeyCode::Esc => {
+                            self.query.clear();
+                            self.mode = Mode::Browse;
+                        }
+                        KeyCode::Backspace => {
+                            self.query.pop();
+                        }
+                        KeyCode::Char(c) => self.query.push(c),
+                        _ => continue,
+                    }
+                    self.refresh(self.selected());
+                }
+                Mode::Add(name) => match key.code {
+                    KeyCode::Esc => self.mode = Mode::Browse,
+                    KeyCode::Backspace => {
+                        name.pop();
+                    }
+                    KeyCode::Char(c) => name.push(c),
+                    KeyCode::Enter => {
+                        let name = std::mem::take(name);
+                        self.mode = Mode::Browse;
+                        self.add(terminal, name);
+                    }
+                    _ => {}
+                },
+                Mode::ConfirmDelete => {
+                    self.mode = Mode::Browse;
+                    if key.code == KeyCode::Char('y')
+                        && let Some(name) = self.selected()
+                    {
+                        self.delete(name);
+                    }
+                }
+            }
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, footer] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+        let [sidebar, preview] =
+            Layout::horizontal([Constraint::Percentage(30), Constraint::Percentage(70)])
+                .areas(main);
+
+        let items = self.names.iter().map(|name| {
+            let entry = &self.diary.entries().entries[name];
+            let date = display_time(entry.timestamp, self.utc).date();
+            let pin = if entry.pinned { " *" } else { "" };
+            ListItem::new(format!("{date} {name}{pin}"))
+        });
+        let title = if self.query.is_empty() {
+            format!(" Entries ({}) ", self.names.len())
+        } else {
+            format!(" Entries matching {:?} ({}) ", self.query, self.names.len())
+        };
+        let list = List::new(items)
+            .block(Block::bordered().title(title))
+            .highlight_style(Style::new().reversed());
+        frame.render_stateful_widget(list, sidebar, &mut self.list);
+
+        let title = self
+            .selected()
+            .map(|name| format!(" {name} "))
+            .unwrap_or_default();
+        let paragraph = Paragraph::new(self.preview.clone())
+            .block(Block::bordered().title(title))
+            .wrap(Wrap { trim: false })
+            .scroll((self.scroll, 0));
+        frame.render_widget(paragraph, preview);
+
+        let line = match (&self.mode, &self.status) {
+            (Mode::Browse, Some(status)) => Line::from(status.clone()),
+            (Mode::Browse, None) => {
+                Line::from("q quit  j/k move  PgUp/PgDn scroll  / search  a add  e edit  d delete")
+                    .dim()
+            }
+            (Mode::Search, _) => Line::from(format!("/{}", self.query)),
+            (Mode::Add(name), _) => Line::from(format!("New entry name: {name}")),
+            (Mode::ConfirmDelete, _) => Line::from(format!(
+                "Move {} to the trash? (y/n)",
+                self.selected().unwrap_or_default()
+            )),
+        };
+        frame.render_widget(line, footer);
+    }
+
+    fn selected(&self) -> Option<String> {
+        self.list
+            .selected()
+            .and_then(|i| self.names.get(i))
+            .cloned()
+    }
+
+    fn select(&mut self, index: usize) {
+        if self.names.is_empty() {
+            return;
+        }
+        self.list
+            .select(Some(index.min(self.names.len().saturating_sub(1))));
+        self.load_preview();
+    }
+
+    /// Filter and sort the entries again, keeping `keep` selected if it is still shown
+    fn refresh(&mut self, keep: Option<String>) {
+        self.names = self
+            .diary
+            .entries()
+            .entries
+            .iter()
+            .filter(|(name, entry)| !entry.archived && matches_query(name, entry, &self.query))
+            .map(|(name, _)| name.clone())
+            .collect();
+        self.names.sort_by_key(|name| {
+            std::cmp::Reverse((self.diary.entries().entries[name].timestamp, name.clone()))
+        });
+
+        let index = keep
+            .and_then(|keep| self.names.iter().position(|name| *name == keep))
+            .unwrap_or(0);
+        if self.names.is_empty() {
+            self.list.select(None);
+            self.load_preview();
+        } else {
+            self.select(index);
+        }
+    }
+
+    fn load_preview(&mut self) {
+        self.scroll = 0;
+        let Some(entry) = self
+            .selected()
+            .map(|name| &self.diary.entries().entries[&name])
+        else {
+            self.preview = Text::default();
+            return;
+        };
+
+        let mut lines = vec![Line::from(format_time(entry.timestamp, self.utc)).dim()];
+        if let Some(location) = &entry.location {
+            lines.push(Line::from(format!("location: {location}")).dim());
+        }
+        if let Some(description) = &entry.description {
+            lines.push(Line::from(format!("description: {description}")).dim());
+        }
+        if !entry.tags.is_empty() {
+            lines.push(Line::from(format!("tags: {}", entry.tags.join(", "))).dim());
+        }
+        lines.push(Line::default());
+
+        match entry.read_content(Path::new("."), self.diary.entry_key()) {
+            Ok(content) => lines.extend(
+                String::from_utf8_lossy(&content)
+                    .lines()
+                    .map(|line| Line::from(line.to_string())),
+            ),
+            Err(e) => lines.push(Line::from(format!("Failed to read entry: {e:#}")).red()),
+        }
+
+        self.preview = Text::from(lines);
+    }
+
+    /// Reload the entries after a change, reporting `result` in the footer
+    fn reload(&mut self, result: color_eyre::Result<String>, keep: Option<String>) {
+        self.status = Some(match result {
+            Ok(message) => message,
+            Err(e) => format!("Error: {e:#}"),
+        });
+
+        if let Err(e) = self.diary.reload() {
+            self.status = Some(format!("Error: {e:#}"));
+        }
+        self.refresh(keep);
+    }
+
+    /// Suspend the interface while the editor runs
+    fn edit(&mut self, terminal: &mut DefaultTerminal, name: String) {
+        ratatui::restore();
+        let result = edit_and_save(&mut self.diary, &name, self.editor.as_deref(), false);
+        *terminal = ratatui::init();
+
+        self.reload(result.map(|()| format!("Edited entry {name}")), Some(name));
+    }
+
+    fn add(&mut self, terminal: &mut DefaultTerminal, name: String) {
+        let added = parse_entry_name(&name)
+            .map_err(|e| color_eyre::eyre::eyre!(e))
+            .and_then(|name| Ok(self.diary.add_entry(&name, b"").map(|_| ())?));
+        if let Err(e) = added {
+            self.reload(Err(e), self.selected());
+            return;
+        }
+
+        self.edit(terminal, name);
+    }
+
+    fn delete(&mut self, name: String) {
+        let result = self
+            .diary
+            .trash_entry(&name)
+            .map(|id| format!("Moved entry {name} ({id}) to the trash"))
+            .map_err(Into::into);
+
+        self.reload(result, None);
+    }
+}